@@ -0,0 +1,100 @@
+use wave_stream::{samples_by_channel::SamplesByChannel, wave_header::Channels};
+
+// Ambisonic channels are written in ACN order, with SN3D normalization (AmbiX)
+// Wav files label each channel with a speaker position; the ambisonic channels are written into the first speaker
+// positions, in order. (The labels are meaningless for ambisonics)
+
+const SQRT_3_OVER_2: f32 = 0.866_025_4; // 3.0.sqrt() / 2.0
+
+pub const MAX_ORDER: usize = 2;
+
+pub fn num_channels(order: usize) -> usize {
+    (order + 1) * (order + 1)
+}
+
+// The speaker positions that the ambisonic channels are written into
+pub fn channels(order: usize) -> Channels {
+    let channels = Channels::new()
+        .front_left()
+        .front_right()
+        .front_center()
+        .low_frequency();
+
+    if order < 2 {
+        channels
+    } else {
+        channels
+            .back_left()
+            .back_right()
+            .front_left_of_center()
+            .front_right_of_center()
+            .back_center()
+    }
+}
+
+pub fn channel_names(order: usize) -> Vec<&'static str> {
+    let all_channel_names = [
+        "acn0", "acn1", "acn2", "acn3", "acn4", "acn5", "acn6", "acn7", "acn8",
+    ];
+
+    all_channel_names[0..num_channels(order)].to_vec()
+}
+
+// Converts a pan into an azimuth on the horizontal plane, in radians. 0 is front, positive is to the left
+// Sounds panned hard left or right in the front are at +/- 45 degrees, and in the rear are at +/- 135 degrees
+pub fn azimuth(left_to_right: f32, back_to_front: f32) -> f32 {
+    let towards_left = -left_to_right;
+    let towards_front = 1.0 - (2.0 * back_to_front);
+
+    towards_left.atan2(towards_front)
+}
+
+// The real spherical harmonics, for a direction on the horizontal plane, in ACN order
+// (Components that only vary with elevation are constant)
+pub fn encode(order: usize, azimuth: f32) -> Vec<f32> {
+    let mut gains = Vec::with_capacity(num_channels(order));
+
+    // W
+    gains.push(1.0);
+
+    if order >= 1 {
+        // Y, Z, X
+        gains.push(azimuth.sin());
+        gains.push(0.0);
+        gains.push(azimuth.cos());
+    }
+
+    if order >= 2 {
+        // V, T, R, S, U
+        gains.push(SQRT_3_OVER_2 * (2.0 * azimuth).sin());
+        gains.push(0.0);
+        gains.push(-0.5);
+        gains.push(0.0);
+        gains.push(SQRT_3_OVER_2 * (2.0 * azimuth).cos());
+    }
+
+    gains
+}
+
+// Places each ambisonic channel's sample into its speaker position
+pub fn samples_by_channel(samples: &[f32]) -> SamplesByChannel<f32> {
+    let mut samples_by_channel = SamplesByChannel::new();
+
+    for (channel_ctr, sample) in samples.iter().enumerate() {
+        let sample = *sample;
+        samples_by_channel = match channel_ctr {
+            0 => samples_by_channel.front_left(sample),
+            1 => samples_by_channel.front_right(sample),
+            2 => samples_by_channel.front_center(sample),
+            3 => samples_by_channel.low_frequency(sample),
+            4 => samples_by_channel.back_left(sample),
+            5 => samples_by_channel.back_right(sample),
+            6 => samples_by_channel.front_left_of_center(sample),
+            7 => samples_by_channel.front_right_of_center(sample),
+            8 => samples_by_channel.back_center(sample),
+            _ => panic!("Ambisonic orders above {} are not supported", MAX_ORDER),
+        };
+    }
+
+    samples_by_channel
+}
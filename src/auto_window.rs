@@ -0,0 +1,163 @@
+use std::{f32::consts::PI, io::Result, path::Path};
+
+use rustfft::{num_complex::Complex, FftPlanner};
+use wave_stream::{open_wav::OpenWav, read_wav_from_file_path, wave_reader::StreamOpenWavReader};
+
+use crate::{reader::left_and_right, window_sizes::get_ideal_window_size};
+
+// -auto-window only analyzes the start of the source, so that it doesn't delay upmixing
+const ANALYSIS_SECONDS: usize = 5;
+
+// Onsets are detected in 10ms blocks, as a jump in level over the preceding blocks
+const ONSET_BLOCKS_PER_SECOND: usize = 100;
+const ONSET_HISTORY_BLOCKS: usize = 8;
+// 6 dB
+const ONSET_ENERGY_RATIO: f64 = 4.0;
+// More onsets than this per second is transient-heavy
+const TRANSIENT_ONSETS_PER_SECOND: f64 = 2.0;
+
+// Tonality is measured as the spectral flatness of frames of this size. Flatness is 1 for white noise, and close to
+// 0 for a pure tone
+const TONALITY_FRAME_SIZE: usize = 4096;
+// Less flat than this is tonal
+const TONAL_FLATNESS: f64 = 0.1;
+
+// Blocks and frames quieter than this (-60 dBFS, as a mean square) are skipped, so that silence isn't analyzed
+const SILENCE_ENERGY: f64 = 0.000001;
+
+// Chooses a larger window than -low needs for tonal material, for better frequency resolution and steering accuracy.
+// Transient-heavy material keeps the smallest window, to reduce pre-echo
+pub fn choose_window_size(source_wav_path: &Path, min_window_size: usize) -> Result<usize> {
+    let source_wav = read_wav_from_file_path(source_wav_path)?;
+    let sample_rate = source_wav.sample_rate() as usize;
+    let source_wav_reader = source_wav.get_stream_f32_reader()?;
+
+    // The channels are analyzed separately, because out-of-phase material cancels out when mixed to mono
+    let mut left = Vec::with_capacity(sample_rate * ANALYSIS_SECONDS);
+    let mut right = Vec::with_capacity(sample_rate * ANALYSIS_SECONDS);
+    for samples_result in source_wav_reader
+        .into_iter()
+        .take(sample_rate * ANALYSIS_SECONDS)
+    {
+        let (left_sample, right_sample) = left_and_right(&samples_result?);
+        left.push(left_sample);
+        right.push(right_sample);
+    }
+
+    let onsets_per_second = measure_onsets_per_second(&left, &right, sample_rate);
+    let flatness = measure_flatness(&[&left, &right]);
+
+    let (description, multiplier) = if onsets_per_second > TRANSIENT_ONSETS_PER_SECOND {
+        ("transient-heavy", 1)
+    } else if flatness < TONAL_FLATNESS {
+        ("tonal", 4)
+    } else {
+        ("neither tonal nor transient-heavy", 2)
+    };
+
+    // The largest windows may not be available for larger multipliers
+    let window_size = get_ideal_window_size(min_window_size * multiplier)
+        .or_else(|_| get_ideal_window_size(min_window_size))?;
+
+    println!(
+        "Auto window: {:.1} onsets / second, spectral flatness {:.3}. The source is {}, using a window size of {} samples",
+        onsets_per_second, flatness, description, window_size
+    );
+
+    Ok(window_size)
+}
+
+fn measure_onsets_per_second(left: &[f32], right: &[f32], sample_rate: usize) -> f64 {
+    let block_size = (sample_rate / ONSET_BLOCKS_PER_SECOND).max(1);
+    let block_energies: Vec<f64> = left
+        .chunks_exact(block_size)
+        .zip(right.chunks_exact(block_size))
+        .map(|(left_block, right_block)| {
+            left_block
+                .iter()
+                .chain(right_block.iter())
+                .map(|sample| (*sample as f64) * (*sample as f64))
+                .sum::<f64>()
+                / (block_size * 2) as f64
+        })
+        .collect();
+
+    if block_energies.len() <= ONSET_HISTORY_BLOCKS {
+        return 0.0;
+    }
+
+    let mut onsets = 0usize;
+    let mut previous_block_was_onset = false;
+    for block_ctr in ONSET_HISTORY_BLOCKS..block_energies.len() {
+        let energy = block_energies[block_ctr];
+        let history = &block_energies[(block_ctr - ONSET_HISTORY_BLOCKS)..block_ctr];
+        let average = history.iter().sum::<f64>() / ONSET_HISTORY_BLOCKS as f64;
+
+        // An onset that spans two blocks is only counted once
+        let is_onset = energy > SILENCE_ENERGY && energy > average * ONSET_ENERGY_RATIO;
+        if is_onset && !previous_block_was_onset {
+            onsets += 1;
+        }
+        previous_block_was_onset = is_onset;
+    }
+
+    let seconds = (left.len() as f64) / (sample_rate as f64);
+    onsets as f64 / seconds
+}
+
+// The average spectral flatness of all frames, in all channels, that aren't silent: The geometric mean of the power
+// spectrum divided by its arithmetic mean. 1 (not tonal) if every frame is silent
+fn measure_flatness(channels: &[&[f32]]) -> f64 {
+    let fft = FftPlanner::new().plan_fft_forward(TONALITY_FRAME_SIZE);
+
+    // A Hann window keeps each frame's edges from spreading tones across the spectrum
+    let hann: Vec<f32> = (0..TONALITY_FRAME_SIZE)
+        .map(|sample_ctr| {
+            0.5 - 0.5 * (2.0 * PI * sample_ctr as f32 / TONALITY_FRAME_SIZE as f32).cos()
+        })
+        .collect();
+
+    let mut flatness_sum = 0.0f64;
+    let mut num_frames = 0usize;
+
+    for frame in channels
+        .iter()
+        .flat_map(|channel| channel.chunks_exact(TONALITY_FRAME_SIZE))
+    {
+        let energy = frame
+            .iter()
+            .map(|sample| (*sample as f64) * (*sample as f64))
+            .sum::<f64>()
+            / TONALITY_FRAME_SIZE as f64;
+        if energy <= SILENCE_ENERGY {
+            continue;
+        }
+
+        let mut transformed: Vec<Complex<f32>> = frame
+            .iter()
+            .zip(hann.iter())
+            .map(|(sample, level)| Complex {
+                re: sample * level,
+                im: 0.0,
+            })
+            .collect();
+        fft.process(&mut transformed);
+
+        let powers: Vec<f64> = transformed[1..(TONALITY_FRAME_SIZE / 2)]
+            .iter()
+            .map(|bin| bin.norm_sqr() as f64 + f64::MIN_POSITIVE)
+            .collect();
+
+        let log_mean = powers.iter().map(|power| power.ln()).sum::<f64>() / powers.len() as f64;
+        let mean = powers.iter().sum::<f64>() / powers.len() as f64;
+
+        flatness_sum += log_mean.exp() / mean;
+        num_frames += 1;
+    }
+
+    if num_frames == 0 {
+        return 1.0;
+    }
+
+    flatness_sum / num_frames as f64
+}
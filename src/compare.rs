@@ -0,0 +1,80 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use wave_stream::{
+    open_wav::OpenWav,
+    read_wav_from_file_path,
+    samples_by_channel::SamplesByChannel,
+    wave_header::{SampleFormat, WavHeader},
+    wave_reader::StreamOpenWavReader,
+    write_wav_to_file_path,
+};
+
+// Writes B - A, for each channel, so that the effect of a setting can be heard (or viewed) in isolation
+pub fn write_difference(
+    a_wav_path: &Path,
+    b_wav_path: &Path,
+    difference_wav_path: &Path,
+) -> Result<()> {
+    let a_wav = read_wav_from_file_path(a_wav_path)?;
+    let b_wav = read_wav_from_file_path(b_wav_path)?;
+
+    if a_wav.channels() != b_wav.channels() || a_wav.sample_rate() != b_wav.sample_rate() {
+        let error = format!(
+            "{} and {} do not have the same channels and sample rate",
+            a_wav_path.display(),
+            b_wav_path.display()
+        );
+        return Err(Error::new(ErrorKind::InvalidData, error));
+    }
+
+    let header = WavHeader {
+        sample_format: SampleFormat::Float,
+        channels: *a_wav.channels(),
+        sample_rate: a_wav.sample_rate(),
+    };
+
+    let mut difference_wav_writer =
+        write_wav_to_file_path(difference_wav_path, header)?.get_random_access_f32_writer()?;
+
+    let a_wav_reader = a_wav.get_stream_f32_reader()?;
+    let b_wav_reader = b_wav.get_stream_f32_reader()?;
+
+    for (sample_ctr, (a_samples, b_samples)) in
+        a_wav_reader.into_iter().zip(b_wav_reader).enumerate()
+    {
+        difference_wav_writer.write_samples(sample_ctr, difference(&a_samples?, &b_samples?))?;
+    }
+
+    difference_wav_writer.flush()
+}
+
+fn difference(a: &SamplesByChannel<f32>, b: &SamplesByChannel<f32>) -> SamplesByChannel<f32> {
+    let subtract = |a: Option<f32>, b: Option<f32>| match (a, b) {
+        (Some(a), Some(b)) => Some(b - a),
+        _ => None,
+    };
+
+    SamplesByChannel {
+        front_left: subtract(a.front_left, b.front_left),
+        front_right: subtract(a.front_right, b.front_right),
+        front_center: subtract(a.front_center, b.front_center),
+        low_frequency: subtract(a.low_frequency, b.low_frequency),
+        back_left: subtract(a.back_left, b.back_left),
+        back_right: subtract(a.back_right, b.back_right),
+        front_left_of_center: subtract(a.front_left_of_center, b.front_left_of_center),
+        front_right_of_center: subtract(a.front_right_of_center, b.front_right_of_center),
+        back_center: subtract(a.back_center, b.back_center),
+        side_left: subtract(a.side_left, b.side_left),
+        side_right: subtract(a.side_right, b.side_right),
+        top_center: subtract(a.top_center, b.top_center),
+        top_front_left: subtract(a.top_front_left, b.top_front_left),
+        top_front_center: subtract(a.top_front_center, b.top_front_center),
+        top_front_right: subtract(a.top_front_right, b.top_front_right),
+        top_back_left: subtract(a.top_back_left, b.top_back_left),
+        top_back_center: subtract(a.top_back_center, b.top_back_center),
+        top_back_right: subtract(a.top_back_right, b.top_back_right),
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use rustfft::num_complex::Complex;
+use serde_json::{Map, Value};
+
+use crate::{
+    matrix::{shift_in_place, AmplitudeAdjustments, Matrix, CENTER_AMPLITUDE_ADJUSTMENT},
+    structs::FrequencyPans,
+};
+
+// The output channels that a matrix file must specify, in the order that they are stored
+const CHANNEL_NAMES: [&str; 4] = ["left_front", "right_front", "left_rear", "right_rear"];
+const INPUT_NAMES: [&str; 2] = ["left", "right"];
+
+// Two inputs can not decode into four channels without crosstalk: A tone that is only in one output channel still
+// decodes at a lower level in the others. Each channel's match is raised to this power, so that frequencies are
+// steered towards the channel that they match best
+const STEERING_SHARPNESS: i32 = 8;
+
+// -matrix file:[path] - a matrix whose decode coefficients are read from a JSON file, so that matrixes can be
+// experimented with without recompiling. Each output channel is derived from the left and right input with an
+// amplitude and a phase shift (in degrees):
+//
+// {
+//     "left_front": { "left": { "amplitude": 1.0, "phase": 0 }, "right": { "amplitude": 0.0, "phase": 0 } },
+//     "right_front": { "left": { "amplitude": 0.0, "phase": 0 }, "right": { "amplitude": 1.0, "phase": 0 } },
+//     "left_rear": { "left": { "amplitude": 0.7, "phase": -90 }, "right": { "amplitude": 0.7, "phase": 90 } },
+//     "right_rear": { "left": { "amplitude": 0.7, "phase": 90 }, "right": { "amplitude": 0.7, "phase": -90 } }
+// }
+//
+// Each frequency is steered towards the output channels whose coefficients it matches best
+pub struct FileMatrix {
+    // Indexed by CHANNEL_NAMES, then by INPUT_NAMES
+    coefficients: [[Complex<f32>; 2]; 4],
+    // Via -center-law
+    center_adjustment: f32,
+}
+
+impl FileMatrix {
+    pub fn open(path: &Path) -> Result<FileMatrix> {
+        let json = fs::read_to_string(path)?;
+        FileMatrix::parse(&json)
+    }
+
+    // The file is opened before all options are parsed
+    pub fn with_center_adjustment(self, center_adjustment: f32) -> FileMatrix {
+        FileMatrix {
+            center_adjustment,
+            ..self
+        }
+    }
+
+    fn parse(json: &str) -> Result<FileMatrix> {
+        let value: Value = serde_json::from_str(json)
+            .map_err(|error| invalid_matrix(format!("Not valid JSON: {}", error)))?;
+
+        let channels = value
+            .as_object()
+            .ok_or_else(|| invalid_matrix("Must be a JSON object".to_string()))?;
+        check_keys(channels, &CHANNEL_NAMES, "The matrix")?;
+
+        let mut coefficients = [[Complex { re: 0.0, im: 0.0 }; 2]; 4];
+        for (channel_ctr, channel_name) in CHANNEL_NAMES.iter().enumerate() {
+            let inputs = channels
+                .get(*channel_name)
+                .ok_or_else(|| invalid_matrix(format!("{} is missing", channel_name)))?
+                .as_object()
+                .ok_or_else(|| invalid_matrix(format!("{} must be a JSON object", channel_name)))?;
+            check_keys(inputs, &INPUT_NAMES, channel_name)?;
+
+            for (input_ctr, input_name) in INPUT_NAMES.iter().enumerate() {
+                let description = format!("{}.{}", channel_name, input_name);
+                let coefficient = inputs
+                    .get(*input_name)
+                    .ok_or_else(|| invalid_matrix(format!("{} is missing", description)))?
+                    .as_object()
+                    .ok_or_else(|| {
+                        invalid_matrix(format!("{} must be a JSON object", description))
+                    })?;
+                check_keys(coefficient, &["amplitude", "phase"], &description)?;
+
+                let amplitude =
+                    parse_number(coefficient, "amplitude", &description)?.ok_or_else(|| {
+                        invalid_matrix(format!("{}.amplitude is missing", description))
+                    })?;
+                if amplitude < 0.0 {
+                    return Err(invalid_matrix(format!(
+                        "{}.amplitude can not be negative: {}. (Use a phase of 180 to invert polarity.)",
+                        description, amplitude
+                    )));
+                }
+
+                // The phase is optional, and defaults to 0
+                let phase = parse_number(coefficient, "phase", &description)?.unwrap_or(0.0);
+                if phase.abs() > 360.0 {
+                    return Err(invalid_matrix(format!(
+                        "{}.phase must be between -360 and 360 degrees: {}",
+                        description, phase
+                    )));
+                }
+
+                coefficients[channel_ctr][input_ctr] =
+                    Complex::from_polar(amplitude, phase.to_radians());
+            }
+
+            if coefficients[channel_ctr]
+                .iter()
+                .all(|coefficient| coefficient.norm() == 0.0)
+            {
+                return Err(invalid_matrix(format!(
+                    "{} has no amplitude from either input",
+                    channel_name
+                )));
+            }
+        }
+
+        Ok(FileMatrix {
+            coefficients,
+            center_adjustment: CENTER_AMPLITUDE_ADJUSTMENT,
+        })
+    }
+
+    // How closely the input matches each output channel, from 0 to 1, in the order of CHANNEL_NAMES. 1 is when a
+    // channel decodes the input at full level
+    fn match_channels(&self, left: Complex<f32>, right: Complex<f32>) -> [f32; 4] {
+        let input_norm = (left.norm_sqr() + right.norm_sqr()).sqrt();
+
+        self.coefficients
+            .map(|[left_coefficient, right_coefficient]| {
+                let coefficient_norm =
+                    (left_coefficient.norm_sqr() + right_coefficient.norm_sqr()).sqrt();
+                let decoded = (left * left_coefficient + right * right_coefficient).norm();
+
+                (decoded / (input_norm * coefficient_norm))
+                    .min(1.0)
+                    .powi(STEERING_SHARPNESS)
+            })
+    }
+}
+
+impl Matrix for FileMatrix {
+    fn steer(
+        &self,
+        left_amplitude: f32,
+        left_phase: f32,
+        right_amplitude: f32,
+        right_phase: f32,
+    ) -> FrequencyPans {
+        let amplitude_sum = left_amplitude + right_amplitude;
+
+        if amplitude_sum == 0.0 {
+            return FrequencyPans {
+                amplitude: 0.0,
+                left_to_right: 0.0,
+                back_to_front: 0.0,
+            };
+        }
+
+        let [left_front, right_front, left_rear, right_rear] = self.match_channels(
+            Complex::from_polar(left_amplitude, left_phase),
+            Complex::from_polar(right_amplitude, right_phase),
+        );
+        let match_sum = left_front + right_front + left_rear + right_rear;
+
+        // The input doesn't match any channel, so it's kept in the front
+        let (left_to_right, back_to_front) = if match_sum == 0.0 {
+            ((right_amplitude - left_amplitude) / amplitude_sum, 0.0)
+        } else {
+            (
+                (((right_front + right_rear) - (left_front + left_rear)) / match_sum)
+                    .clamp(-1.0, 1.0),
+                ((left_rear + right_rear) / match_sum).clamp(0.0, 1.0),
+            )
+        };
+        let front_to_back = 1.0 - back_to_front;
+
+        let fraction_in_side = left_to_right.abs();
+        let fraction_in_center = 1.0 - fraction_in_side;
+
+        let amplitude_front = ((fraction_in_side * amplitude_sum) +
+            // Items panned to the center are usually lowered to .707 so they are the same volume as when panned to the side
+            (fraction_in_center * amplitude_sum * self.center_adjustment))
+            * front_to_back;
+
+        let amplitude_back = amplitude_sum * back_to_front;
+
+        FrequencyPans {
+            amplitude: amplitude_back + amplitude_front,
+            left_to_right,
+            back_to_front,
+        }
+    }
+
+    // Each output channel is shifted by the phase of the coefficient from the input on the same side
+    fn phase_shift(
+        &self,
+        left_front_phase: &mut f32,
+        right_front_phase: &mut f32,
+        left_rear_phase: &mut f32,
+        right_rear_phase: &mut f32,
+    ) {
+        shift_in_place(left_front_phase, self.coefficients[0][0].arg());
+        shift_in_place(right_front_phase, self.coefficients[1][1].arg());
+        shift_in_place(left_rear_phase, self.coefficients[2][0].arg());
+        shift_in_place(right_rear_phase, self.coefficients[3][1].arg());
+    }
+
+    fn print_debugging_information(&self) {}
+
+    fn amplitude_adjustments(&self, loud: bool) -> AmplitudeAdjustments {
+        // Quiet output keeps the original levels, loud output undoes the center adjustment
+        if loud {
+            AmplitudeAdjustments::uniform(1.0 / self.center_adjustment)
+        } else {
+            AmplitudeAdjustments::uniform(1.0)
+        }
+    }
+
+    fn steer_right_left(&self) -> bool {
+        false
+    }
+}
+
+fn invalid_matrix(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+// Misspelled keys are errors, instead of silently using a default
+fn check_keys(object: &Map<String, Value>, allowed: &[&str], description: &str) -> Result<()> {
+    for key in object.keys() {
+        if !allowed.contains(&key.as_str()) {
+            return Err(invalid_matrix(format!(
+                "{} has an unknown key: {}. Allowed keys are: {}",
+                description,
+                key,
+                allowed.join(", ")
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_number(object: &Map<String, Value>, key: &str, description: &str) -> Result<Option<f32>> {
+    match object.get(key) {
+        None => Ok(None),
+        Some(value) => match value.as_f64() {
+            Some(number) if number.is_finite() => Ok(Some(number as f32)),
+            _ => Err(invalid_matrix(format!(
+                "{}.{} must be a number: {}",
+                description, key, value
+            ))),
+        },
+    }
+}
@@ -0,0 +1,45 @@
+use std::{io::Result, path::Path};
+
+use wave_stream::{open_wav::OpenWav, read_wav_from_file_path, wave_header::Channels};
+
+use crate::report;
+
+// soft_matrix -info [source] - prints details about the source, and if it can be upmixed, without upmixing
+pub fn print_info(source_wav_path: &Path) -> Result<()> {
+    let source_wav = read_wav_from_file_path(source_wav_path)?;
+
+    let length_seconds = (source_wav.len_samples() as f64) / (source_wav.sample_rate() as f64);
+
+    println!("\tSource: {}", source_wav_path.display());
+    println!("\tChannels: {}", source_wav.num_channels());
+    println!(
+        "\tChannel layout: {}",
+        report::channel_names(source_wav.channels()).join(", ")
+    );
+    println!("\tSample format: {:?}", source_wav.sample_format());
+    println!("\tBits per sample: {}", source_wav.bits_per_sample());
+    println!(
+        "\tSample rate: {} samples / second",
+        source_wav.sample_rate()
+    );
+    println!("\tLength: {} samples", source_wav.len_samples());
+    println!("\tDuration: {} seconds", length_seconds);
+
+    if source_wav.num_channels() != 2 {
+        println!("\tCan be upmixed: no, only 2-channel wavs can be upmixed");
+    } else if is_labeled_stereo(source_wav.channels()) {
+        println!("\tCan be upmixed: yes");
+    } else {
+        println!(
+            "\tCan be upmixed: yes, the first channel is upmixed as left, and the second as right"
+        );
+    }
+
+    Ok(())
+}
+
+// Upmixing can happen from any 2-channel wav, but ones that aren't labeled front_left and front_right are assumed to be
+// left and right
+pub fn is_labeled_stereo(channels: &Channels) -> bool {
+    channels == &Channels::new().front_left().front_right()
+}
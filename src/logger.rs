@@ -4,11 +4,21 @@ use std::{
     time::{Duration, Instant},
 };
 
+#[cfg(feature = "progress-bar")]
+use std::io::IsTerminal;
+
+#[cfg(feature = "progress-bar")]
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+
 use crate::structs::ThreadState;
 
 pub struct Logger {
     total_samples_to_write_f64: f64,
     logging_state: Mutex<LoggingState>,
+
+    // Only present when stdout is a terminal. Otherwise, progress is logged as plain text
+    #[cfg(feature = "progress-bar")]
+    progress_bar: Option<ProgressBar>,
 }
 
 pub struct LoggingState {
@@ -26,8 +36,10 @@ impl Logger {
             logging_state: Mutex::new(LoggingState {
                 started: now,
                 next_log: now,
-                logging_frequency: logging_frequency,
+                logging_frequency,
             }),
+            #[cfg(feature = "progress-bar")]
+            progress_bar: new_progress_bar(total_samples_to_write),
         }
     }
 
@@ -54,24 +66,53 @@ impl Logger {
             let fraction_complete = (fraction_read + fraction_written) / 2.0;
             let estimated_seconds = elapsed_seconds / fraction_complete;
 
-            let mut stdout = stdout();
-            stdout.write(
-                format!(
-                    "\rWriting: {:.2}% complete, {:.0} elapsed seconds, {:.2} estimated total seconds, {} threads         ",
-                    100.0 * fraction_complete,
-                    elapsed_seconds,
-                    estimated_seconds,
-                    thread_state.upmixer.num_running_threads(),
-                )
-                .as_bytes(),
-            )?;
-            stdout.flush()?;
-
             let logging_frequency = logging_state.logging_frequency;
             logging_state.next_log += logging_frequency;
+
+            // -meters: The peaks since the last log
+            let meters = match thread_state.upmixer.panner_and_writer.take_meters() {
+                Some(meters) => format!(" | {}", meters),
+                None => String::new(),
+            };
+
+            // -verbose: Where the loudest frequency since the last log is steered
+            let dominant_source = match thread_state
+                .upmixer
+                .reader
+                .take_dominant_source(thread_state.upmixer.window_size)
+            {
+                Some(dominant_source) => format!(" | {}", dominant_source),
+                None => String::new(),
+            };
+
+            #[cfg(feature = "progress-bar")]
+            if let Some(progress_bar) = &self.progress_bar {
+                // indicatif calculates the ETA from how quickly samples are written
+                progress_bar.set_position(total_samples_written as u64);
+                progress_bar.set_message(format!(
+                    "{} threads{}{}",
+                    thread_state.upmixer.num_running_threads(),
+                    meters,
+                    dominant_source
+                ));
+
+                return Ok(());
+            }
+
+            // print!, instead of writing to stdout directly, so that the output is captured when testing
+            print!(
+                "\rWriting: {:.2}% complete, {:.0} elapsed seconds, {:.2} estimated total seconds, {} threads{}{}         ",
+                100.0 * fraction_complete,
+                elapsed_seconds,
+                estimated_seconds,
+                thread_state.upmixer.num_running_threads(),
+                meters,
+                dominant_source
+            );
+            stdout().flush()?;
         }
 
-        return Ok(());
+        Ok(())
     }
 
     pub fn finish_logging(self: &Logger) -> Result<()> {
@@ -83,18 +124,40 @@ impl Logger {
         let now = Instant::now();
         let elapsed_seconds = (now - logging_state.started).as_secs_f64();
 
-        let mut stdout = stdout();
-        stdout.write(
-            format!(
-                "\rTotal time to complete: {:.0} seconds                                                             ",
-                elapsed_seconds,
-            )
-            .as_bytes(),
-        )?;
-        stdout.flush()?;
+        #[cfg(feature = "progress-bar")]
+        if let Some(progress_bar) = &self.progress_bar {
+            progress_bar.finish_and_clear();
+        }
+
+        print!(
+            "\rTotal time to complete: {:.0} seconds                                                             ",
+            elapsed_seconds,
+        );
+        stdout().flush()?;
 
         println!();
 
         Ok(())
     }
 }
+
+#[cfg(feature = "progress-bar")]
+fn new_progress_bar(total_samples_to_write: usize) -> Option<ProgressBar> {
+    // Plain text is easier to read when output is redirected to a file
+    if !stdout().is_terminal() {
+        return None;
+    }
+
+    let progress_bar = ProgressBar::with_draw_target(
+        Some(total_samples_to_write as u64),
+        ProgressDrawTarget::stdout(),
+    );
+    progress_bar.set_style(
+        ProgressStyle::with_template(
+            "{wide_bar} {percent}% | {elapsed_precise} elapsed | {eta_precise} remaining | {msg}",
+        )
+        .expect("Invalid progress bar template"),
+    );
+
+    Some(progress_bar)
+}
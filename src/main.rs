@@ -1,34 +1,179 @@
 use std::ffi::OsStr;
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 use wave_stream::open_wav::OpenWav;
-use wave_stream::wave_header::{Channels, SampleFormat, WavHeader};
+use wave_stream::wave_header::{SampleFormat, WavHeader};
 use wave_stream::{read_wav_from_file_path, write_wav_to_file_path};
 
+mod ambisonics;
+mod auto_window;
+mod compare;
+mod file_matrix;
+mod info;
 mod logger;
 mod matrix;
+mod memory_limit;
 mod options;
 mod panner_and_writer;
 mod panning_averager;
 mod reader;
+mod report;
+mod resume;
+mod stdout_output;
 mod structs;
+mod test_generator;
+#[cfg(test)]
+mod test_support;
 mod upmixer;
 mod vecdeque_ext;
+mod viz;
+mod window_dump;
 mod window_sizes;
 
 use upmixer::upmix;
 
-use crate::options::Options;
+use crate::options::{
+    CompareOptions, GenerateTestOptions, InfoOptions, ManifestOptions, MatrixFormat, Options,
+};
+use crate::resume::PartialOutput;
+use crate::stdout_output::StdoutOutput;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 fn main() {
+    // This must happen before anything is printed
+    let stdout_output = if StdoutOutput::is_requested() {
+        match StdoutOutput::redirect_messages_to_stderr() {
+            Ok(stdout_output) => Some(stdout_output),
+            Err(error) => {
+                eprintln!("Can not write to stdout: {:?}", error);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
     println!("Soft Matrix: Upmixes stereo wav files to surround");
     println!("https://github.com/GWBasic/soft_matrix");
     println!("Version {}", VERSION);
 
+    if GenerateTestOptions::is_requested() {
+        let generate_test_options = match GenerateTestOptions::parse() {
+            Some(generate_test_options) => generate_test_options,
+            None => {
+                println!("See https://github.com/GWBasic/soft_matrix/blob/{}/options.md for more information about options", env!("GIT_HASH"));
+                return;
+            }
+        };
+
+        match test_generator::generate_test(&generate_test_options) {
+            Err(error) => {
+                println!(
+                    "Can not write {}: {:?}",
+                    &generate_test_options.target_wav_path.display(),
+                    error
+                );
+            }
+            _ => {
+                println!(
+                    "Test file written to {}",
+                    &generate_test_options.target_wav_path.display()
+                );
+            }
+        }
+
+        return;
+    }
+
+    // Doesn't need a source or a target
+    if MatrixFormat::list_requested() {
+        MatrixFormat::print_list();
+        return;
+    }
+
+    if InfoOptions::is_requested() {
+        let info_options = match InfoOptions::parse() {
+            Some(info_options) => info_options,
+            None => {
+                println!("See https://github.com/GWBasic/soft_matrix/blob/{}/options.md for more information about options", env!("GIT_HASH"));
+                return;
+            }
+        };
+
+        if let Err(error) = info::print_info(&info_options.source_wav_path) {
+            println!(
+                "Can not open {}: {:?}",
+                &info_options.source_wav_path.display(),
+                error
+            );
+        }
+
+        return;
+    }
+
+    if ManifestOptions::is_requested() {
+        let manifest_options = match ManifestOptions::parse() {
+            Some(manifest_options) => manifest_options,
+            None => {
+                println!("See https://github.com/GWBasic/soft_matrix/blob/{}/options.md for more information about options", env!("GIT_HASH"));
+                return;
+            }
+        };
+
+        upmix_manifest(manifest_options);
+        return;
+    }
+
     // See https://en.wikipedia.org/wiki/Matrix_decoder for information about all the different matrixes
 
+    if CompareOptions::is_requested() {
+        let compare_options = match CompareOptions::parse() {
+            Some(compare_options) => compare_options,
+            None => {
+                println!("See https://github.com/GWBasic/soft_matrix/blob/{}/options.md for more information about options", env!("GIT_HASH"));
+                return;
+            }
+        };
+
+        let target_a_wav_path = compare_options.options_a.target_wav_path.clone();
+        let target_b_wav_path = compare_options.options_b.target_wav_path.clone();
+
+        println!("Upmixing with settings A: {}", compare_options.settings_a);
+        if !upmix_to_target(compare_options.options_a, None) {
+            return;
+        }
+
+        println!("Upmixing with settings B: {}", compare_options.settings_b);
+        if !upmix_to_target(compare_options.options_b, None) {
+            return;
+        }
+
+        match compare::write_difference(
+            &target_a_wav_path,
+            &target_b_wav_path,
+            &compare_options.difference_wav_path,
+        ) {
+            Err(error) => {
+                println!(
+                    "Can not write {}: {:?}",
+                    &compare_options.difference_wav_path.display(),
+                    error
+                );
+            }
+            _ => {
+                println!(
+                    "Difference (B - A) written to {}",
+                    &compare_options.difference_wav_path.display()
+                );
+            }
+        }
+
+        return;
+    }
+
     let options = match Options::parse() {
         Some(options) => options,
         None => {
@@ -37,6 +182,62 @@ fn main() {
         }
     };
 
+    upmix_to_target(options, stdout_output);
+}
+
+// Upmixes each line of the manifest, one after the other. A line that fails doesn't stop the lines after it
+fn upmix_manifest(manifest_options: ManifestOptions) {
+    let started = Instant::now();
+    let num_entries = manifest_options.entries.len();
+    let mut failed_line_numbers = Vec::new();
+
+    for (entry_ctr, entry) in manifest_options.entries.into_iter().enumerate() {
+        println!();
+        println!(
+            "Manifest: {} of {} (line {}): {} to {}, {:.0} elapsed seconds",
+            entry_ctr + 1,
+            num_entries,
+            entry.line_number,
+            entry.source,
+            entry.destination,
+            started.elapsed().as_secs_f64()
+        );
+
+        let succeeded = match entry.options {
+            Some(options) => upmix_to_target(options, None),
+            None => {
+                println!("Skipped because its options are invalid");
+                false
+            }
+        };
+
+        if !succeeded {
+            failed_line_numbers.push(entry.line_number.to_string());
+        }
+    }
+
+    println!();
+    println!(
+        "Manifest complete: {} of {} upmixed in {:.0} seconds",
+        num_entries - failed_line_numbers.len(),
+        num_entries,
+        started.elapsed().as_secs_f64()
+    );
+
+    if !failed_line_numbers.is_empty() {
+        println!("Failed lines: {}", failed_line_numbers.join(", "));
+    }
+}
+
+// -atomic: Where a target is written until upmixing succeeds
+fn temporary_path(target_wav_path: &Path) -> PathBuf {
+    let mut temporary_path = target_wav_path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+    PathBuf::from(temporary_path)
+}
+
+// Upmixes according to the options. Returns false if upmixing failed. (The error is already printed)
+fn upmix_to_target(options: Options, stdout_output: Option<StdoutOutput>) -> bool {
     let open_source_wav_result = read_wav_from_file_path(&options.source_wav_path);
 
     let source_wav = match open_source_wav_result {
@@ -46,27 +247,32 @@ fn main() {
                 &options.source_wav_path.display(),
                 error
             );
-            return;
+            return false;
         }
         Ok(source_wav) => source_wav,
     };
 
     // Check that source is 2 channels
-    let expected_channels = Channels::new().front_left().front_right();
-
-    if source_wav.channels() != &expected_channels {
+    if source_wav.num_channels() != 2 {
         println!(
-            "Upmixing can only happen from a 2-channel wav. {} has {} channel(s). (Extended format wavs must specify front_left and front_right",
+            "Upmixing can only happen from a 2-channel wav. {} has {} channel(s).",
             &options.source_wav_path.display(),
             source_wav.num_channels()
         );
 
-        return;
+        return false;
+    }
+
+    if !info::is_labeled_stereo(source_wav.channels()) {
+        println!(
+            "Warning: {} is not labeled as front_left and front_right. The first channel is upmixed as left, and the second as right.",
+            &options.source_wav_path.display()
+        );
     }
 
     let header = WavHeader {
         sample_format: SampleFormat::Float,
-        channels: options.channels.clone(),
+        channels: options.written_channels,
         sample_rate: source_wav.sample_rate(),
     };
 
@@ -74,11 +280,97 @@ fn main() {
     // when upmixing a file over (approximately) 58 minutes in length. 6 channels @ 32 bits / sample (float) adds up quickly
 
     let max_samples_in_file = header.max_samples();
-    let mut num_target_files = source_wav.len_samples() / max_samples_in_file;
-    if source_wav.len_samples() % max_samples_in_file > 0 {
-        num_target_files += 1;
+    let samples_to_upmix =
+        options.samples_to_upmix(source_wav.len_samples(), source_wav.sample_rate());
+    let num_target_files = samples_to_upmix.div_ceil(max_samples_in_file);
+
+    if num_target_files > 1 {
+        if stdout_output.is_some() {
+            println!(
+                "{} is too long to upmix to stdout: It is {} samples long, but a wav with {} channels can only hold {} samples. Upmix to fewer channels via -channels, or upmix to a file instead.",
+                &options.source_wav_path.display(),
+                samples_to_upmix,
+                header.channels.count(),
+                max_samples_in_file
+            );
+            return false;
+        }
+
+        if options.no_split {
+            println!(
+                "{} is too long to upmix into a single wav file: It is {} samples long, but a wav with {} channels can only hold {} samples. Upmix to fewer channels via -channels, use a source with a lower sample rate, or remove -no-split to split the output into {} files.",
+                &options.source_wav_path.display(),
+                samples_to_upmix,
+                header.channels.count(),
+                max_samples_in_file,
+                num_target_files
+            );
+            return false;
+        }
+
+        // Scripts may expect the output at the exact path given, so this is announced before any files are created
+        // (The names of each file are listed below)
+        println!(
+            "The upmixed output exceeds the 4GB maximum size of a wav file, so it will be split into {} files. (Specify -no-split to fail instead.)",
+            num_target_files
+        );
     }
 
+    // -atomic: Each target is written to a temporary file, which is renamed to the target once upmixing succeeds
+    let atomic = options.atomic;
+    let writing_path = |target_wav_path: &Path| {
+        if atomic {
+            temporary_path(target_wav_path)
+        } else {
+            target_wav_path.to_path_buf()
+        }
+    };
+
+    // The partial output must be moved aside before the target is re-created
+    let partial_output = if options.resume {
+        if num_target_files > 1 {
+            println!("-resume is not supported when the output is split into multiple files");
+            return false;
+        }
+
+        // With -atomic, an interrupted upmix is left in the temporary file
+        match PartialOutput::open(&writing_path(&options.target_wav_path), &header) {
+            Err(error) => {
+                println!(
+                    "Can not resume {}: {:?}",
+                    &options.target_wav_path.display(),
+                    error
+                );
+                return false;
+            }
+            Ok(partial_output) => partial_output,
+        }
+    } else if options.append {
+        if num_target_files > 1 {
+            println!("-append is not supported when the output is split into multiple files");
+            return false;
+        }
+
+        // The previous output is complete, so it's at the target even with -atomic
+        match PartialOutput::open_appended(
+            &options.target_wav_path,
+            &header,
+            source_wav.len_samples(),
+        ) {
+            Err(error) => {
+                println!(
+                    "Can not append to {}: {:?}",
+                    &options.target_wav_path.display(),
+                    error
+                );
+                return false;
+            }
+            Ok(partial_output) => partial_output,
+        }
+    } else {
+        None
+    };
+
     let mut target_paths = Vec::with_capacity(num_target_files);
     let mut target_open_wav_writers = Vec::with_capacity(num_target_files);
 
@@ -91,14 +383,14 @@ fn main() {
                     "Not a valid filename: {}",
                     options.target_wav_path.display()
                 );
-                return;
+                return false;
             }
         };
         let extension = options
             .target_wav_path
             .extension()
             .unwrap_or(OsStr::new("wav"));
-        let folder = options.target_wav_path.parent().unwrap_or(&Path::new("/"));
+        let folder = options.target_wav_path.parent().unwrap_or(Path::new("/"));
 
         for file_ctr in 1..(num_target_files + 1) {
             let target_wav_filename_string = format!(
@@ -111,12 +403,13 @@ fn main() {
 
             let target_wav_path = folder.join(target_wav_filename_string);
 
-            let open_target_wav_result = write_wav_to_file_path(&target_wav_path, header);
+            let open_target_wav_result =
+                write_wav_to_file_path(&writing_path(&target_wav_path), header);
 
             let target_wav = match open_target_wav_result {
                 Err(error) => {
                     println!("Can not open {}: {:?}", &target_wav_path.display(), error);
-                    return;
+                    return false;
                 }
                 Ok(target_wav) => target_wav,
             };
@@ -125,7 +418,10 @@ fn main() {
             target_paths.push(target_wav_path);
         }
     } else {
-        let open_target_wav_result = write_wav_to_file_path(&options.target_wav_path, header);
+        let open_target_wav_result = match &stdout_output {
+            Some(stdout_output) => stdout_output.open_wav_writer(header),
+            None => write_wav_to_file_path(&writing_path(&options.target_wav_path), header),
+        };
 
         let target_wav = match open_target_wav_result {
             Err(error) => {
@@ -134,7 +430,7 @@ fn main() {
                     &options.target_wav_path.display(),
                     error
                 );
-                return;
+                return false;
             }
             Ok(target_wav) => target_wav,
         };
@@ -154,7 +450,7 @@ fn main() {
         println!("\tTarget: {}", target_paths[0].display());
     } else {
         println!("\tTargets:");
-        for target_path in target_paths {
+        for target_path in &target_paths {
             println!("\t\t{}", target_path.display());
         }
     }
@@ -166,7 +462,7 @@ fn main() {
             &options.target_wav_path.display()
         );
 
-        let awake_handle = match keepawake::Builder::new()
+        match keepawake::Builder::new()
             .display(false)
             .idle(true)
             .sleep(true)
@@ -175,26 +471,79 @@ fn main() {
             .app_reverse_domain("io.github.gwbasic.soft_matrix")
             .create()
         {
-            Ok(awake_handle) => awake_handle,
+            Ok(awake_handle) => Some(awake_handle),
             Err(error) => {
-                println!("Cannot keep the computer awake: {}", error);
-                return;
+                // Not every platform supports this, and it's not worth failing the upmix over
+                println!(
+                    "Cannot keep the computer awake, continuing anyway: {}",
+                    error
+                );
+                None
             }
-        };
+        }
+    } else {
+        None
+    };
 
-        Some(awake_handle)
+    // -append: Remembered once upmixing succeeds, so that next time only what's appended to the source is upmixed
+    let append_state = if options.append {
+        Some((options.target_wav_path.clone(), source_wav.len_samples()))
     } else {
         None
     };
 
-    match upmix(options, source_wav, target_open_wav_writers) {
+    let succeeded = match upmix(
+        options,
+        source_wav,
+        target_open_wav_writers,
+        partial_output.as_ref(),
+    ) {
         Err(error) => {
             println!("Error upmixing: {:?}", error);
+            false
         }
         _ => {
+            if let Some(partial_output) = partial_output {
+                if let Err(error) = partial_output.remove() {
+                    println!("Can not remove the partial output: {:?}", error);
+                }
+            }
+
+            if atomic {
+                for target_path in &target_paths {
+                    if let Err(error) = fs::rename(writing_path(target_path), target_path) {
+                        println!("Can not rename to {}: {:?}", target_path.display(), error);
+                        return false;
+                    }
+                }
+            }
+
+            if let Some((target_wav_path, appended_samples)) = append_state {
+                if let Err(error) = resume::write_append_state(&target_wav_path, appended_samples) {
+                    println!(
+                        "Can not remember how much of the source was upmixed: {:?}",
+                        error
+                    );
+                    return false;
+                }
+            }
+
             println!("Upmixing completed successfully");
+
+            match stdout_output {
+                Some(stdout_output) => match stdout_output.write_to_stdout() {
+                    Err(error) => {
+                        println!("Can not write to stdout: {:?}", error);
+                        false
+                    }
+                    _ => true,
+                },
+                None => true,
+            }
         }
-    }
+    };
 
     _keepawake = None;
+
+    succeeded
 }
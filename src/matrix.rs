@@ -1,6 +1,6 @@
 use std::{
     cell::Cell,
-    f32::consts::{PI, TAU},
+    f32::consts::{FRAC_1_SQRT_2, PI, TAU},
 };
 
 const HALF_PI: f32 = PI / 2.0;
@@ -14,7 +14,10 @@ use crate::structs::FrequencyPans;
 // (Based on https://music.arts.uci.edu/dobrian/maxcookbook/constant-power-panning-using-square-root-intensity)
 // Thus, if a tone has a 1.0 amplitude in both speakers, its real amplitude is 1.414213562373094
 // Items panned to the center are usually lowered by 0.707106781186548 in order to be the same volume as when panned to the edge
-pub const CENTER_AMPLITUDE_ADJUSTMENT: f32 = 0.707106781186548; // 2.0.sqrt() / 2.0;
+pub const CENTER_AMPLITUDE_ADJUSTMENT: f32 = FRAC_1_SQRT_2;
+
+// The lowest frequency steered when neither -low nor the matrix chooses one
+pub const DEFAULT_LOW_FREQUENCY: f32 = 20.0;
 
 pub trait Matrix {
     fn steer(
@@ -35,9 +38,232 @@ pub trait Matrix {
 
     fn print_debugging_information(&self);
 
-    fn amplitude_adjustment(&self) -> f32;
+    // The gain of each channel, according to -loud. Matrixes that encode the rear channels at a lower level raise
+    // them back up here
+    fn amplitude_adjustments(&self, loud: bool) -> AmplitudeAdjustments;
 
     fn steer_right_left(&self) -> bool;
+
+    // The lowest frequency to steer when -low isn't specified. Matrixes that can't steer very low frequencies
+    // suggest a higher frequency, which also makes the window smaller
+    fn default_low_frequency(&self) -> f32 {
+        DEFAULT_LOW_FREQUENCY
+    }
+
+    // If the default layout, when -channels isn't specified, has an LFE channel. (5.1 when true, 5 when false)
+    fn default_lfe(&self) -> bool {
+        true
+    }
+
+    // Adjusts the amplitudes of a frequency after it is steered, and before it is phase shifted
+    // All level adjustments that a matrix needs happen here, via amplitude_adjustments()
+    // (There is no LFE amplitude: The LFE is filtered from the unsteered signal, and does not depend on the matrix)
+    fn adjust_levels(
+        &self,
+        left_front: &mut f32,
+        right_front: &mut f32,
+        left_rear: &mut f32,
+        right_rear: &mut f32,
+        center: Option<&mut f32>,
+        loud: bool,
+    ) {
+        let amplitude_adjustments = self.amplitude_adjustments(loud);
+
+        *left_front *= amplitude_adjustments.front;
+        *right_front *= amplitude_adjustments.front;
+        *left_rear *= amplitude_adjustments.rear;
+        *right_rear *= amplitude_adjustments.rear;
+
+        if let Some(center) = center {
+            *center *= amplitude_adjustments.center;
+        }
+    }
+
+    // Pushes left_to_right towards the sides, after averaging. An amount of 0 does nothing
+    // back_to_front is intentionally not adjusted: Widening it results in poor audio quality, and favors too much
+    // steering to the rear
+    fn widen(&self, left_to_right: &mut f32, amount: f32) {
+        let widened = left_to_right.abs().powf(1.0 / (1.0 + amount));
+        *left_to_right = widened.copysign(*left_to_right);
+    }
+
+    // An S-curve on left_to_right, before averaging. A focus of 1 does nothing. Above 1, pans that are closer to the
+    // center than to the side move towards the center, and the rest move towards the side. Below 1, pans move towards
+    // halfway between the center and the side
+    // Pans beyond the sides (horseshoe) are not adjusted
+    fn focus_center(&self, left_to_right: &mut f32, focus: f32) {
+        let side = left_to_right.abs();
+        if side >= 1.0 {
+            return;
+        }
+
+        let towards_side = side.powf(focus);
+        let towards_center = (1.0 - side).powf(focus);
+        let focused = towards_side / (towards_side + towards_center);
+        *left_to_right = focused.copysign(*left_to_right);
+    }
+}
+
+// Gains for each channel, from Matrix::amplitude_adjustments()
+pub struct AmplitudeAdjustments {
+    pub front: f32,
+    pub center: f32,
+    pub rear: f32,
+}
+
+impl AmplitudeAdjustments {
+    // The same gain for every channel
+    pub fn uniform(adjustment: f32) -> AmplitudeAdjustments {
+        AmplitudeAdjustments {
+            front: adjustment,
+            center: adjustment,
+            rear: adjustment,
+        }
+    }
+}
+
+// Options that only some matrixes use
+#[derive(Clone, Copy, PartialEq)]
+pub enum MatrixSetting {
+    PhaseDeadzone,
+    PhaseWeight,
+    Widen,
+}
+
+impl MatrixSetting {
+    pub fn flag(&self) -> &'static str {
+        match self {
+            MatrixSetting::PhaseDeadzone => "-phase-deadzone",
+            MatrixSetting::PhaseWeight => "-phase-weight",
+            MatrixSetting::Widen => "-widen",
+        }
+    }
+}
+
+// The values of every MatrixSetting, including defaults for the ones that aren't specified
+pub struct MatrixSettings {
+    pub phase_deadzone: f32,
+    pub phase_weight: f32,
+    pub widen_factor: f32,
+    // -center-law: The level of a sound panned to the center, in each front. CENTER_AMPLITUDE_ADJUSTMENT unless
+    // specified
+    pub center_adjustment: f32,
+}
+
+// A matrix that -matrix accepts by name. (-matrix file:[path] is handled separately, because it reads a file)
+pub struct NamedMatrix {
+    pub name: &'static str,
+    // For -matrix list
+    pub description: &'static str,
+    // The settings that new() uses. Specifying any other setting is an error
+    pub settings: &'static [MatrixSetting],
+    pub new: fn(&MatrixSettings) -> Box<dyn Matrix>,
+}
+
+// Adding a matrix only requires adding it here
+pub static NAMED_MATRIXES: [NamedMatrix; 7] = [
+    NamedMatrix {
+        name: "default",
+        description:
+            "Out-of-phase sounds are steered to the rear, and in-phase sounds to the front",
+        settings: &[MatrixSetting::PhaseWeight],
+        new: |settings| {
+            Box::new(DefaultMatrix::new(
+                settings.phase_weight,
+                settings.center_adjustment,
+            ))
+        },
+    },
+    NamedMatrix {
+        name: "qs",
+        description: "The QS matrix. Like default, with some minor widening",
+        settings: &[MatrixSetting::PhaseWeight],
+        new: |settings| {
+            Box::new(DefaultMatrix::qs(
+                settings.phase_weight,
+                settings.center_adjustment,
+            ))
+        },
+    },
+    NamedMatrix {
+        name: "rm",
+        description: "Synonym for qs, because qs recordings were often mislabeled as rm",
+        settings: &[MatrixSetting::PhaseWeight],
+        new: |settings| {
+            Box::new(DefaultMatrix::qs(
+                settings.phase_weight,
+                settings.center_adjustment,
+            ))
+        },
+    },
+    NamedMatrix {
+        name: "horseshoe",
+        description:
+            "Sounds panned to the far left and right are steered to the rear. (See -widen)",
+        settings: &[MatrixSetting::PhaseWeight, MatrixSetting::Widen],
+        new: |settings| {
+            Box::new(DefaultMatrix::horseshoe(
+                settings.widen_factor,
+                settings.phase_weight,
+                settings.center_adjustment,
+            ))
+        },
+    },
+    NamedMatrix {
+        name: "dolby",
+        description: "Dolby Stereo, also known as LtRt, Dolby Surround, and Dolby Pro-Logic",
+        settings: &[MatrixSetting::PhaseWeight],
+        new: |settings| {
+            Box::new(DefaultMatrix::dolby_stereo(
+                settings.phase_weight,
+                settings.center_adjustment,
+            ))
+        },
+    },
+    NamedMatrix {
+        name: "sq",
+        description: "EXPERIMENTAL: The SQ matrix. Only for material encoded in SQ",
+        settings: &[MatrixSetting::PhaseDeadzone],
+        new: |settings| {
+            Box::new(SQMatrix::sq(
+                settings.phase_deadzone,
+                settings.center_adjustment,
+            ))
+        },
+    },
+    NamedMatrix {
+        name: "sqexperimental",
+        description: "EXPERIMENTAL: Another SQ decoder, which may be removed in a future release",
+        settings: &[MatrixSetting::PhaseDeadzone],
+        new: |settings| {
+            Box::new(SQMatrixExperimental::sq(
+                settings.phase_deadzone,
+                settings.center_adjustment,
+            ))
+        },
+    },
+];
+
+pub fn named_matrix(name: &str) -> Option<&'static NamedMatrix> {
+    NAMED_MATRIXES
+        .iter()
+        .find(|named_matrix| named_matrix.name.eq(name))
+}
+
+// For errors: "the sq and sqexperimental matrixes"
+pub fn names_of_matrixes_with(setting: MatrixSetting) -> String {
+    let names: Vec<&str> = NAMED_MATRIXES
+        .iter()
+        .filter(|named_matrix| named_matrix.settings.contains(&setting))
+        .map(|named_matrix| named_matrix.name)
+        .collect();
+
+    match names.split_last() {
+        Some((last, [])) => format!("the {} matrix", last),
+        Some((last, [first])) => format!("the {} and {} matrixes", first, last),
+        Some((last, rest)) => format!("the {}, and {} matrixes", rest.join(", "), last),
+        None => "no matrixes".to_string(),
+    }
 }
 
 pub struct DefaultMatrix {
@@ -45,20 +271,38 @@ pub struct DefaultMatrix {
     left_rear_shift: f32,
     right_rear_shift: f32,
     rear_adjustment: f32,
+    // How much steering to the rear comes from the phase difference, and from panning past the sides. (See
+    // -phase-weight)
+    phase_weight: f32,
+    panning_weight: f32,
+    // False for formats that never had an LFE channel
+    default_lfe: bool,
+    // Via -center-law
+    center_adjustment: f32,
 }
 
+// -widen: Sounds panned more than halfway to a side are steered past it, towards the rear
+pub const DEFAULT_HORSESHOE_WIDEN_FACTOR: f32 = 2.0;
+
+// -phase-weight: Both phase and panning fully steer to the rear
+pub const DEFAULT_PHASE_WEIGHT: f32 = 0.5;
+
 // Note that it is intended that DefaultMatrix can be configured to support the old quad matrixes
 impl DefaultMatrix {
-    pub fn new() -> DefaultMatrix {
+    pub fn new(phase_weight: f32, center_adjustment: f32) -> DefaultMatrix {
         DefaultMatrix {
             widen_factor: 1.0,
             left_rear_shift: -0.5 * PI,
             right_rear_shift: 0.5 * PI,
             rear_adjustment: 1.0,
+            phase_weight: (phase_weight * 2.0).min(1.0),
+            panning_weight: ((1.0 - phase_weight) * 2.0).min(1.0),
+            default_lfe: true,
+            center_adjustment,
         }
     }
 
-    pub fn qs() -> DefaultMatrix {
+    pub fn qs(phase_weight: f32, center_adjustment: f32) -> DefaultMatrix {
         let largest_sum = 0.924 + 0.383;
         let largest_pan = (0.924 / largest_sum) * 2.0 - 1.0;
 
@@ -67,24 +311,41 @@ impl DefaultMatrix {
             left_rear_shift: -0.5 * PI,
             right_rear_shift: 0.5 * PI,
             rear_adjustment: 1.0,
+            phase_weight: (phase_weight * 2.0).min(1.0),
+            panning_weight: ((1.0 - phase_weight) * 2.0).min(1.0),
+            default_lfe: true,
+            center_adjustment,
         }
     }
 
-    pub fn horseshoe() -> DefaultMatrix {
+    pub fn horseshoe(
+        widen_factor: f32,
+        phase_weight: f32,
+        center_adjustment: f32,
+    ) -> DefaultMatrix {
         DefaultMatrix {
-            widen_factor: 2.0,
+            widen_factor,
             left_rear_shift: -0.5 * PI,
             right_rear_shift: 0.5 * PI,
             rear_adjustment: 1.0,
+            phase_weight: (phase_weight * 2.0).min(1.0),
+            panning_weight: ((1.0 - phase_weight) * 2.0).min(1.0),
+            default_lfe: true,
+            center_adjustment,
         }
     }
 
-    pub fn dolby_stereo() -> DefaultMatrix {
+    pub fn dolby_stereo(phase_weight: f32, center_adjustment: f32) -> DefaultMatrix {
         DefaultMatrix {
             widen_factor: 1.0,
             left_rear_shift: -0.5 * PI,
             right_rear_shift: 0.5 * PI,
             rear_adjustment: 2.0f32.sqrt(),
+            phase_weight: (phase_weight * 2.0).min(1.0),
+            panning_weight: ((1.0 - phase_weight) * 2.0).min(1.0),
+            // Dolby Stereo and Dolby Surround have no LFE channel; bass is in the left, center, and right
+            default_lfe: false,
+            center_adjustment,
         }
     }
 }
@@ -133,17 +394,19 @@ impl Matrix for DefaultMatrix {
         let fraction_in_side = left_to_right.abs();
         let fraction_in_center = 1.0 - fraction_in_side;
         let back_to_front_from_panning = (left_to_right.abs() - 1.0).max(0.0);
-        let back_to_front = (back_to_front_from_panning + back_to_front_from_phase).min(1.0);
+        let back_to_front = ((back_to_front_from_panning * self.panning_weight)
+            + (back_to_front_from_phase * self.phase_weight))
+            .min(1.0);
         let front_to_back = 1.0 - back_to_front;
 
         let amplitude_front = ((fraction_in_side * amplitude_sum) +
             // Items panned to the center are usually lowered to .707 so they are the same volume as when panned to the side
-            (fraction_in_center * amplitude_sum * CENTER_AMPLITUDE_ADJUSTMENT))
+            (fraction_in_center * amplitude_sum * self.center_adjustment))
             * front_to_back;
 
         let amplitude_back = amplitude_sum * back_to_front * self.rear_adjustment;
 
-        left_to_right = left_to_right.min(1.0).max(-1.0);
+        left_to_right = left_to_right.clamp(-1.0, 1.0);
 
         FrequencyPans {
             amplitude: amplitude_back + amplitude_front,
@@ -165,27 +428,57 @@ impl Matrix for DefaultMatrix {
 
     fn print_debugging_information(&self) {}
 
-    fn amplitude_adjustment(&self) -> f32 {
-        CENTER_AMPLITUDE_ADJUSTMENT
+    fn amplitude_adjustments(&self, loud: bool) -> AmplitudeAdjustments {
+        // Quiet output keeps the original levels, loud output undoes the center adjustment
+        let adjustment = if loud {
+            1.0 / self.center_adjustment
+        } else {
+            1.0
+        };
+
+        // Only the rears are raised when they are encoded at a lower level, (such as Dolby Stereo's -3dB surround)
+        AmplitudeAdjustments {
+            front: adjustment,
+            center: adjustment,
+            rear: adjustment * self.rear_adjustment,
+        }
     }
 
     fn steer_right_left(&self) -> bool {
         false
     }
+
+    fn default_lfe(&self) -> bool {
+        self.default_lfe
+    }
 }
 
 // https://en.wikipedia.org/wiki/Stereo_Quadraphonic
 //const SQ_LOWER: f32 = 0.7;
 const SQ_RAISE: f32 = 1.0 / 0.7;
 const SQ_LEFT_REAR_SHIFT: f32 = PI / 2.0;
-const SQ_RIGHT_REAR_SHIFT: f32 = SQ_LEFT_REAR_SHIFT * -1.0;
+const SQ_RIGHT_REAR_SHIFT: f32 = -SQ_LEFT_REAR_SHIFT;
+
+// SQ encoders' 90 degree phase shift networks drift at the lowest frequencies, so the rears can't be steered reliably
+// below this. (Lower frequencies are still in the LFE)
+const SQ_LOW_FREQUENCY: f32 = 40.0;
+
+// Frequencies with a phase difference (in radians) less than this are steered to the front. (See -phase-deadzone)
+pub const DEFAULT_PHASE_DEADZONE: f32 = 0.01;
 
 // Uses the Soft Matrix approach of closely inspecting phase and amplitude, but it doesn't work very well
-pub struct SQMatrix {}
+pub struct SQMatrix {
+    phase_deadzone: f32,
+    // Via -center-law
+    center_adjustment: f32,
+}
 
 impl SQMatrix {
-    pub fn sq() -> SQMatrix {
-        SQMatrix {}
+    pub fn sq(phase_deadzone: f32, center_adjustment: f32) -> SQMatrix {
+        SQMatrix {
+            phase_deadzone,
+            center_adjustment,
+        }
     }
 }
 
@@ -280,38 +573,29 @@ impl Matrix for SQMatrix {
         bring_phase_in_range(&mut phase_difference);
 
         if amplitude_sum == 0.0 {
-            return FrequencyPans {
+            FrequencyPans {
                 amplitude: 0.0,
                 left_to_right: 0.0,
                 back_to_front: 0.0,
-            };
-        } else if phase_difference.abs() < 0.01
+            }
+        } else if phase_difference.abs() < self.phase_deadzone
             || left_total_amplitude < 0.01
             || right_total_amplitude < 0.01
         {
             // Sound is in phase: Front isolated
-            let left_to_right = (left_total_amplitude / amplitude_sum) * -2.0 + 1.0;
-
-            let fraction_in_side = left_to_right.abs();
-            let fraction_in_center = 1.0 - fraction_in_side;
-
-            let amplitude_front = (fraction_in_side * amplitude_sum) +
-                // Items panned to the center are usually lowered to .707 so they are the same volume as when panned to the side
-                (fraction_in_center * amplitude_sum * CENTER_AMPLITUDE_ADJUSTMENT);
-
-            return FrequencyPans {
-                amplitude: amplitude_front,
-                left_to_right,
-                back_to_front: 0.0,
-            };
+            steer_front_isolated(
+                left_total_amplitude,
+                right_total_amplitude,
+                self.center_adjustment,
+            )
         } else {
             let left_to_right: f32;
             let back_to_front: f32;
 
-            if phase_difference < 0.0 && phase_difference > (-1.0 * HALF_PI) {
+            if phase_difference < 0.0 && phase_difference > -HALF_PI {
                 // Right-isolated, front -> back pan comes from phase
                 left_to_right = 1.0;
-                back_to_front = (-1.0 * phase_difference) / HALF_PI;
+                back_to_front = -phase_difference / HALF_PI;
             } else if phase_difference > HALF_PI
                 && phase_difference <= PI
                 && left_total_amplitude > right_total_amplitude
@@ -319,29 +603,37 @@ impl Matrix for SQMatrix {
                 // Left-isolated, front -> back pan comes from phase
                 left_to_right = -1.0;
                 back_to_front = 1.0 - ((phase_difference - HALF_PI) / HALF_PI);
-            } else if phase_difference <= (-1.0 * HALF_PI) {
+            } else if phase_difference <= -HALF_PI {
                 // Between right rear and rear center
                 // right rear to rear center: -(pi/2) -> -pi
                 // Sound is out-of-phase, but amplitude is the same: Rear isolated, right -> left pan comes from phase
-                left_to_right = (-1.0 * phase_difference / HALF_PI).min(0.0).max(1.0);
+                left_to_right = (2.0 + (phase_difference / HALF_PI)).clamp(0.0, 1.0);
                 back_to_front = 1.0;
             } else {
                 // Between left rear and rear center
                 // rear center to left rear: pi -> (pi/2)
                 // Sound is out-of-phase, but amplitude is the same: Rear isolated, right -> left pan comes from phase
-                left_to_right = (-1.0 * (HALF_PI - (phase_difference - HALF_PI)) / HALF_PI)
-                    .min(-1.0)
-                    .max(0.0);
+                left_to_right =
+                    (-(HALF_PI - (phase_difference - HALF_PI)) / HALF_PI).clamp(-1.0, 0.0);
                 back_to_front = 1.0;
             }
 
             let front_to_back = 1.0 - back_to_front;
-            return FrequencyPans {
+            let frequency_pans = FrequencyPans {
                 amplitude: (amplitude_sum * front_to_back)
                     + (amplitude_sum * back_to_front * SQ_RAISE),
                 left_to_right,
                 back_to_front,
             };
+
+            ease_out_of_phase_deadzone(
+                frequency_pans,
+                left_total_amplitude,
+                right_total_amplitude,
+                phase_difference,
+                self.phase_deadzone,
+                self.center_adjustment,
+            )
         }
     }
 
@@ -358,19 +650,26 @@ impl Matrix for SQMatrix {
 
     fn print_debugging_information(&self) {}
 
-    fn amplitude_adjustment(&self) -> f32 {
-        CENTER_AMPLITUDE_ADJUSTMENT
+    fn amplitude_adjustments(&self, loud: bool) -> AmplitudeAdjustments {
+        sq_amplitude_adjustments(loud, self.center_adjustment)
     }
 
     fn steer_right_left(&self) -> bool {
         true
     }
+
+    fn default_low_frequency(&self) -> f32 {
+        SQ_LOW_FREQUENCY
+    }
 }
 
 // Attempts to follow a "by the book" dematrixer, except for when something is in the front
 // Doesn't work very well
 
 pub struct SQMatrixExperimental {
+    phase_deadzone: f32,
+    // Via -center-law
+    center_adjustment: f32,
     min_back_to_front: Cell<f32>,
     max_back_to_front: Cell<f32>,
     min_left_to_right: Cell<f32>,
@@ -378,8 +677,10 @@ pub struct SQMatrixExperimental {
 }
 
 impl SQMatrixExperimental {
-    pub fn sq() -> SQMatrixExperimental {
+    pub fn sq(phase_deadzone: f32, center_adjustment: f32) -> SQMatrixExperimental {
         SQMatrixExperimental {
+            phase_deadzone,
+            center_adjustment,
             min_back_to_front: Cell::new(f32::INFINITY),
             max_back_to_front: Cell::new(f32::NEG_INFINITY),
             min_left_to_right: Cell::new(f32::INFINITY),
@@ -402,30 +703,21 @@ impl Matrix for SQMatrixExperimental {
         bring_phase_in_range(&mut phase_difference);
 
         if amplitude_sum == 0.0 {
-            return FrequencyPans {
+            FrequencyPans {
                 amplitude: 0.0,
                 left_to_right: 0.0,
                 back_to_front: 0.0,
-            };
-        } else if phase_difference.abs() < 0.01
+            }
+        } else if phase_difference.abs() < self.phase_deadzone
             || left_total_amplitude < 0.01
             || right_total_amplitude < 0.01
         {
             // Sound is in phase: Front isolated
-            let left_to_right = (left_total_amplitude / amplitude_sum) * -2.0 + 1.0;
-
-            let fraction_in_side = left_to_right.abs();
-            let fraction_in_center = 1.0 - fraction_in_side;
-
-            let amplitude_front = (fraction_in_side * amplitude_sum) +
-                // Items panned to the center are usually lowered to .707 so they are the same volume as when panned to the side
-                (fraction_in_center * amplitude_sum * CENTER_AMPLITUDE_ADJUSTMENT);
-
-            return FrequencyPans {
-                amplitude: amplitude_front,
-                left_to_right,
-                back_to_front: 0.0,
-            };
+            steer_front_isolated(
+                left_total_amplitude,
+                right_total_amplitude,
+                self.center_adjustment,
+            )
         } else {
             // http://www.hi-ho.ne.jp/odaka/quad/index-e.html
             /*
@@ -455,16 +747,14 @@ impl Matrix for SQMatrixExperimental {
                 Complex::from_polar(right_total_amplitude * SQ_RAISE / 2.0, shift(right_phase, HALF_PI * -1.0));
             */
             let left_back =
-                Complex::from_polar(
-                    left_total_amplitude / 2.0,
-                    shift(left_phase, -1.0 * HALF_PI),
-                ) + Complex::from_polar(right_total_amplitude / 2.0, shift(right_phase, HALF_PI));
+                Complex::from_polar(left_total_amplitude / 2.0, shift(left_phase, -HALF_PI))
+                    + Complex::from_polar(right_total_amplitude / 2.0, shift(right_phase, HALF_PI));
 
             let right_back =
                 Complex::from_polar(left_total_amplitude / 2.0, shift(left_phase, HALF_PI))
                     + Complex::from_polar(
                         right_total_amplitude / 2.0,
-                        shift(right_phase, HALF_PI * -1.0),
+                        shift(right_phase, -HALF_PI),
                     );
 
             let (left_back_amplitude, _) = left_back.to_polar();
@@ -497,11 +787,20 @@ impl Matrix for SQMatrixExperimental {
             self.max_left_to_right
                 .replace(left_to_right.max(self.max_left_to_right.get()));
 
-            FrequencyPans {
+            let frequency_pans = FrequencyPans {
                 amplitude: total_amplitude,
                 left_to_right,
                 back_to_front,
-            }
+            };
+
+            ease_out_of_phase_deadzone(
+                frequency_pans,
+                left_total_amplitude,
+                right_total_amplitude,
+                phase_difference,
+                self.phase_deadzone,
+                self.center_adjustment,
+            )
         }
     }
 
@@ -529,13 +828,82 @@ impl Matrix for SQMatrixExperimental {
         */
     }
 
-    fn amplitude_adjustment(&self) -> f32 {
-        CENTER_AMPLITUDE_ADJUSTMENT
+    fn amplitude_adjustments(&self, loud: bool) -> AmplitudeAdjustments {
+        sq_amplitude_adjustments(loud, self.center_adjustment)
     }
 
     fn steer_right_left(&self) -> bool {
         true
     }
+
+    fn default_low_frequency(&self) -> f32 {
+        SQ_LOW_FREQUENCY
+    }
+}
+
+// Used by the phase-based matrixes when a frequency is in phase
+fn steer_front_isolated(
+    left_total_amplitude: f32,
+    right_total_amplitude: f32,
+    center_adjustment: f32,
+) -> FrequencyPans {
+    let amplitude_sum = left_total_amplitude + right_total_amplitude;
+    let left_to_right = (left_total_amplitude / amplitude_sum) * -2.0 + 1.0;
+
+    let fraction_in_side = left_to_right.abs();
+    let fraction_in_center = 1.0 - fraction_in_side;
+
+    let amplitude_front = (fraction_in_side * amplitude_sum) +
+        // Items panned to the center are usually lowered to .707 so they are the same volume as when panned to the side
+        (fraction_in_center * amplitude_sum * center_adjustment);
+
+    FrequencyPans {
+        amplitude: amplitude_front,
+        left_to_right,
+        back_to_front: 0.0,
+    }
+}
+
+// Just outside of the phase deadzone, crossfades from front isolated to the phase-based steering, so that frequencies
+// near the edge of the deadzone don't flip between the front and the rear
+// (Frequencies are steered in parallel, out of order, so there is no previous steering to compare against)
+fn ease_out_of_phase_deadzone(
+    frequency_pans: FrequencyPans,
+    left_total_amplitude: f32,
+    right_total_amplitude: f32,
+    phase_difference: f32,
+    phase_deadzone: f32,
+    center_adjustment: f32,
+) -> FrequencyPans {
+    let fraction_steered = (phase_difference.abs() - phase_deadzone) / phase_deadzone;
+    if fraction_steered >= 1.0 {
+        return frequency_pans;
+    }
+
+    let front_isolated = steer_front_isolated(
+        left_total_amplitude,
+        right_total_amplitude,
+        center_adjustment,
+    );
+    let fraction_front = 1.0 - fraction_steered;
+
+    FrequencyPans {
+        amplitude: (frequency_pans.amplitude * fraction_steered)
+            + (front_isolated.amplitude * fraction_front),
+        left_to_right: (frequency_pans.left_to_right * fraction_steered)
+            + (front_isolated.left_to_right * fraction_front),
+        back_to_front: frequency_pans.back_to_front * fraction_steered,
+    }
+}
+
+// SQ's amplitudes already account for a tone panned between speakers, so loud output keeps them as-is, and quiet
+// output lowers everything
+fn sq_amplitude_adjustments(loud: bool, center_adjustment: f32) -> AmplitudeAdjustments {
+    if loud {
+        AmplitudeAdjustments::uniform(1.0)
+    } else {
+        AmplitudeAdjustments::uniform(center_adjustment)
+    }
 }
 
 fn shift(phase: f32, shift: f32) -> f32 {
@@ -544,7 +912,7 @@ fn shift(phase: f32, shift: f32) -> f32 {
     phase_mut
 }
 
-fn shift_in_place(phase: &mut f32, shift: f32) {
+pub fn shift_in_place(phase: &mut f32, shift: f32) {
     *phase += shift;
     bring_phase_in_range(phase);
 }
@@ -556,3 +924,151 @@ fn bring_phase_in_range(phase: &mut f32) {
         *phase += TAU;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A tone in one or both of the source's channels, and where every matrix should steer it
+    struct CanonicalPoint {
+        name: &'static str,
+        left_amplitude: f32,
+        left_phase: f32,
+        right_amplitude: f32,
+        right_phase: f32,
+        front: bool,
+        // -1 is left, 1 is right, 0 is either the center or both
+        side: f32,
+    }
+
+    fn canonical_points() -> Vec<CanonicalPoint> {
+        let point =
+            |name, left_amplitude, left_phase, right_amplitude, right_phase, front, side| {
+                CanonicalPoint {
+                    name,
+                    left_amplitude,
+                    left_phase,
+                    right_amplitude,
+                    right_phase,
+                    front,
+                    side,
+                }
+            };
+
+        vec![
+            point("hard left", 1.0, 0.0, 0.0, 0.0, true, -1.0),
+            point("hard right", 0.0, 0.0, 1.0, 0.0, true, 1.0),
+            point("in-phase center", 0.7, 0.4, 0.7, 0.4, true, 0.0),
+            point("right 90 degrees ahead", 0.7, 0.0, 0.7, HALF_PI, false, 0.0),
+            point(
+                "right 90 degrees behind",
+                0.7,
+                0.0,
+                0.7,
+                -HALF_PI,
+                false,
+                0.0,
+            ),
+            point("out-of-phase", 0.7, 0.4, 0.7, 0.4 - PI, false, 0.0),
+        ]
+    }
+
+    fn default_settings() -> MatrixSettings {
+        MatrixSettings {
+            phase_deadzone: DEFAULT_PHASE_DEADZONE,
+            phase_weight: DEFAULT_PHASE_WEIGHT,
+            widen_factor: DEFAULT_HORSESHOE_WIDEN_FACTOR,
+            center_adjustment: CENTER_AMPLITUDE_ADJUSTMENT,
+        }
+    }
+
+    #[test]
+    fn every_matrix_steers_canonical_points_to_the_expected_region() {
+        for named_matrix in NAMED_MATRIXES.iter() {
+            let matrix = (named_matrix.new)(&default_settings());
+
+            for point in canonical_points() {
+                let frequency_pans = matrix.steer(
+                    point.left_amplitude,
+                    point.left_phase,
+                    point.right_amplitude,
+                    point.right_phase,
+                );
+                let where_steered = format!(
+                    "{} steered {} to left_to_right {}, back_to_front {}",
+                    named_matrix.name,
+                    point.name,
+                    frequency_pans.left_to_right,
+                    frequency_pans.back_to_front
+                );
+
+                assert!(frequency_pans.amplitude > 0.0, "{}", where_steered);
+                assert!(
+                    (-1.0..=1.0).contains(&frequency_pans.left_to_right),
+                    "{}",
+                    where_steered
+                );
+                assert!(
+                    (0.0..=1.0).contains(&frequency_pans.back_to_front),
+                    "{}",
+                    where_steered
+                );
+
+                // qs and horseshoe steer sounds panned all the way to a side past it, towards the rear, so only the
+                // side is checked
+                let widens_past_the_sides =
+                    ["qs", "rm", "horseshoe"].contains(&named_matrix.name) && point.side != 0.0;
+                if !widens_past_the_sides {
+                    if point.front {
+                        assert!(frequency_pans.back_to_front < 0.01, "{}", where_steered);
+                    } else {
+                        assert!(frequency_pans.back_to_front >= 0.5, "{}", where_steered);
+                    }
+                }
+
+                if point.side == 0.0 {
+                    if !matrix.steer_right_left() || point.front {
+                        assert!(
+                            frequency_pans.left_to_right.abs() < 0.01,
+                            "{}",
+                            where_steered
+                        );
+                    }
+                } else {
+                    assert_eq!(
+                        point.side,
+                        frequency_pans.left_to_right.signum(),
+                        "{}",
+                        where_steered
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sq_steers_quadrature_to_the_left_and_right_rear() {
+        // SQ encodes the left rear with the left 90 degrees ahead of the right, and the right rear 90 degrees behind.
+        // (sqexperimental only pans from the amplitudes, so it can't tell them apart)
+        let matrix = (named_matrix("sq").unwrap().new)(&default_settings());
+
+        let left_rear = matrix.steer(0.7, HALF_PI, 0.7, 0.0);
+        assert_eq!(-1.0, left_rear.left_to_right);
+        assert_eq!(1.0, left_rear.back_to_front);
+
+        let right_rear = matrix.steer(0.7, 0.0, 0.7, HALF_PI);
+        assert_eq!(1.0, right_rear.left_to_right);
+        assert_eq!(1.0, right_rear.back_to_front);
+
+        // Between them, the pan follows the phase difference through the rear center
+        let rear_center = matrix.steer(0.7, PI, 0.7, 0.0);
+        assert!(rear_center.left_to_right.abs() < 0.01);
+        assert_eq!(1.0, rear_center.back_to_front);
+
+        let right_of_rear_center = matrix.steer(0.7, 0.0, 0.7, PI * 0.75);
+        assert!((right_of_rear_center.left_to_right - 0.5).abs() < 0.01);
+
+        let left_of_rear_center = matrix.steer(0.7, PI * 0.75, 0.7, 0.0);
+        assert!((left_of_rear_center.left_to_right + 0.5).abs() < 0.01);
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    mem::size_of,
+};
+
+use rustfft::num_complex::Complex;
+
+use crate::{options::Options, structs::FrequencyPans, upmixer::max_threads};
+
+const BYTES_PER_MB: usize = 1024 * 1024;
+
+// The number of threads, and the number of windows that can be transformed ahead of averaging, that fit within
+// -max-memory
+pub struct MemoryLimits {
+    pub num_threads: usize,
+    pub max_windows_in_flight: usize,
+}
+
+// Memory use is estimated from the window size. (It's approximate: Buffers and the wav writer aren't counted.) Most
+// memory is used by the windows that are being averaged, which can only be reduced by raising -low
+pub fn limit_memory(
+    options: &Options,
+    window_size: usize,
+    source_len_samples: usize,
+    max_memory_mb: usize,
+) -> Result<MemoryLimits> {
+    let window_midpoint = window_size / 2;
+    let max_memory = max_memory_mb * BYTES_PER_MB;
+
+    // -reverse reads the left and right of the entire source into memory
+    let source_bytes = if options.reverse {
+        source_len_samples * size_of::<(f32, f32)>()
+    } else {
+        0
+    };
+    if max_memory < source_bytes {
+        let error = format!(
+            "-max-memory {} MB is too low: -reverse reads the entire source into memory, which needs {} MB",
+            max_memory_mb,
+            source_bytes.div_ceil(BYTES_PER_MB)
+        );
+        return Err(Error::new(ErrorKind::InvalidInput, error));
+    }
+    let max_memory = max_memory - source_bytes;
+
+    // A transformed window, and the pans of each of its frequencies
+    let num_transforms = if options.transform_mono { 3 } else { 2 };
+    let transform_bytes = num_transforms * window_size * size_of::<Complex<f32>>();
+    let pans_bytes = window_midpoint * size_of::<FrequencyPans>();
+    let window_bytes = transform_bytes + pans_bytes;
+
+    // Each averaging pass keeps a window of pans, and the transforms in the first half of the window
+    let averaging_bytes = options.smoothing_passes
+        * ((window_size * pans_bytes) + (window_midpoint * transform_bytes));
+
+    // Each thread has FFT scratch space, and transforms each output channel
+    let thread_bytes =
+        (options.channels.count() as usize + 2) * window_size * size_of::<Complex<f32>>();
+
+    let min_memory = averaging_bytes + thread_bytes + window_bytes;
+    if max_memory < min_memory {
+        let error = format!(
+            "-max-memory {} MB is too low: A window of {} samples needs at least {} MB. Raise the lowest frequency via -low, or use fewer -smoothing-passes",
+            max_memory_mb,
+            window_size,
+            min_memory.div_ceil(BYTES_PER_MB)
+        );
+        return Err(Error::new(ErrorKind::InvalidInput, error));
+    }
+
+    let available_threads = max_threads(options);
+
+    // Each thread needs at least one window in flight
+    let remaining_memory = max_memory - averaging_bytes;
+    let num_threads =
+        (remaining_memory / (thread_bytes + window_bytes)).clamp(1, available_threads);
+    let max_windows_in_flight = (remaining_memory - (num_threads * thread_bytes)) / window_bytes;
+
+    Ok(MemoryLimits {
+        num_threads,
+        max_windows_in_flight,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::limit_memory;
+    use crate::test_support::parse_flags;
+
+    #[test]
+    fn reverse_counts_the_source_against_max_memory() {
+        let flags = ["-low", "1000", "-channels", "4", "-max-memory", "1"];
+        let options = parse_flags(&flags).unwrap();
+        let reversed = parse_flags(&[&flags[..], &["-reverse"]].concat()).unwrap();
+
+        // The left and right of a sample are 8 bytes, so 1 MB holds 131072 samples
+        assert!(limit_memory(&options, 48, 200_000, 1).is_ok());
+        assert!(limit_memory(&reversed, 48, 100_000, 1).is_ok());
+
+        let error = limit_memory(&reversed, 48, 200_000, 1)
+            .err()
+            .expect("A reversed source larger than -max-memory was accepted");
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        assert!(error.to_string().contains("-reverse"));
+
+        // The source leaves less memory for threads and windows in flight
+        let streamed = limit_memory(&options, 48, 100_000, 1).unwrap();
+        let in_memory = limit_memory(&reversed, 48, 100_000, 1).unwrap();
+        assert!(in_memory.max_windows_in_flight < streamed.max_windows_in_flight);
+    }
+}
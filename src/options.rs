@@ -1,48 +1,360 @@
 use std::env;
+use std::f32::consts::FRAC_PI_2;
+use std::fs;
 use std::path::Path;
+use std::time::Duration;
 
-use wave_stream::wave_header::Channels;
+use wave_stream::{open_wav::OpenWav, read_wav_from_file_path, wave_header::Channels};
 
 use crate::{
-    matrix::{DefaultMatrix, Matrix, SQMatrix, SQMatrixExperimental},
-    panner_and_writer,
+    ambisonics,
+    file_matrix::FileMatrix,
+    matrix::{self, Matrix, MatrixSetting, MatrixSettings, NamedMatrix},
+    panner_and_writer, reader, report, upmixer, window_sizes,
 };
 
+// Each smoothing pass delays writing by half of a window, and keeps another window of transforms in memory
+const MAX_SMOOTHING_PASSES: usize = 4;
+
+// -widen: At 4, sounds panned halfway to a side are already entirely in the rear
+const MAX_WIDEN_FACTOR: f32 = 4.0;
+
+// -draft: The highest lowest frequency that still derives the LFE, (panner_and_writer::LFE_START,) which halves the
+// window at 44.1khz
+const DRAFT_LOW_FREQUENCY: f32 = 40.0;
+
 pub struct Options {
     pub source_wav_path: Box<Path>,
     pub target_wav_path: Box<Path>,
     pub num_threads: Option<usize>,
+    // Additional threads start after the first thread transforms this many windows
+    pub thread_ramp: usize,
+    // Percent of the time that each thread runs, the rest of the time it sleeps
+    pub max_cpu: Option<f32>,
+    // Approximate memory budget, in megabytes. Limits the number of threads and windows in flight
+    pub max_memory_mb: Option<usize>,
     pub transform_mono: bool,
+    // The channels that are upmixed
     pub channels: Channels,
+    // The channels that are written to the output. The same as channels, unless -write-channels or -lfe-file-only
+    // leaves some out
+    pub written_channels: Channels,
+    // When set, the output is ambisonic instead of a speaker layout
+    pub ambisonic_order: Option<usize>,
     pub low_frequency: f32,
+    // Normalized by the window size: A full-scale sine wave is 0.5
     pub minimum_steered_amplitude: f32,
+    // Multiplied with every sample read. Specified in dB via -input-gain
+    pub input_gain: f32,
+    // When set, increases left-right separation by this amount
+    pub widen_image: Option<f32>,
+    // When set, pans close to the center are steered further to the center, and the rest further to the sides
+    pub center_focus: Option<f32>,
+    // -channels 5.1-mono: Steers towards the center and away from the rears, after averaging
+    pub mono_surround: bool,
+    // When set, scales the side (L - R) of the front channels after upmixing. 0 is mono, 1 is unchanged
+    pub front_width: Option<f32>,
+    // When set, this fraction of each rear channel is moved into its front channel after upmixing
+    pub crossfeed: Option<f32>,
+    // Via -rear-channels: The number of positions that the rears are spread across. 2 is the rear left and right
+    pub rear_channels: usize,
+    // Swaps the front and rear: Sounds that are steered to the rear are steered to the front, and vice versa
+    pub flip_front_back: bool,
+    pub averaging: Averaging,
+    // The number of times pans are averaged. Additional passes are always a mean
+    pub smoothing_passes: usize,
+    // Via -freq-smooth: Each frequency's pans are also averaged with this many neighboring frequencies on each side. 0
+    // is no averaging across frequencies
+    pub frequency_smoothing: usize,
+    // Skips averaging, so that each transform is steered by its own pans. For testing steering
+    pub no_average: bool,
+    pub silent_channel: SilentChannel,
+    pub analysis: Analysis,
+    // When set, frequencies quieter than this (in dBFS) are steered less to the rear
+    pub rear_gate: Option<f32>,
+    // When set, only frequencies from the low to the high frequency (in hz) are steered to the rear
+    pub rear_band: Option<(f32, f32)>,
+    // When set, no frequency is steered further to the rear than this, from 0 (front) to 1 (rear)
+    pub max_rear: Option<f32>,
+    // Via -mono-detect: Windows where the side is this many dB below the mid aren't steered to the rear
+    pub mono_detect: Option<f32>,
+    pub remove_dc: bool,
+    // Via -balance-input: The left and right are adjusted so that they have the same RMS
+    pub balance_input: bool,
+    // Replaces NaN and infinity, in the source and after steering, with silence
+    pub sanitize: bool,
+    // Detect and compensate for the left and right channels being offset by a few samples
+    pub align_input: bool,
+    // When set, frequencies in the center channel below this are attenuated
+    pub center_highpass: Option<f32>,
+    pub center_highpass_redirect: CenterHighpassRedirect,
+    pub center_method: CenterMethod,
+    pub center_phase: CenterPhase,
+    // Via -center-law: The level of a sound panned to the center, in each front, when steering and when deriving the
+    // center
+    pub center_adjustment: f32,
+    // Via -center-band: The center is only fully extracted between these frequencies (low, high)
+    pub center_band: Option<(f32, f32)>,
+    pub lfe_source: LfeSource,
+    // When set, the LFE is low-passed with a Butterworth response of this many dB per octave, instead of a gentle taper
+    pub lfe_slope: Option<u32>,
+    pub rear_phase: RearPhase,
+    // Output EQ, via -eq. Empty when there is no EQ
+    pub eq: Vec<EqBand>,
+    // Writes the source to the fronts, instead of the fronts from the inverse transforms. For testing how transparent
+    // the fronts are
+    pub stereo_passthrough: bool,
+    // Steers the upper half of each transform on its own, instead of mirroring the lower half. For testing that the
+    // mirroring is correct
+    pub no_symmetry_shortcut: bool,
+    // Writes panner_and_writer::ALIGN_PULSE at the start of each output file, for aligning files in an editor
+    pub align_pulse: bool,
+    // When set, every sample written is rounded to this many bits of mantissa, for devices that can't play full
+    // precision floats
+    pub mantissa_bits: Option<u32>,
+    // When set, the output is lowered just enough to keep it from clipping, and then recovers over this many seconds
+    pub auto_headroom: Option<f32>,
+    // When set, the center is limited to this level, in dBFS. What's removed is moved to the fronts
+    pub center_ceiling: Option<f32>,
+    // The source is upsampled by this factor before steering, and the output is downsampled back. 1 when not
+    // oversampling
+    pub oversample: usize,
+    // Prints the peak of each channel while upmixing
+    pub meters: bool,
+    // Print where the loudest frequency is steered while upmixing
+    pub verbose: bool,
+    // When set, the output is flushed this often, so that less is lost if upmixing is interrupted
+    pub flush_interval: Option<Duration>,
+    // Continue an interrupted upmix instead of starting over
+    pub resume: bool,
+    // Only upmixes what was appended to the source since the last upmix, via -append
+    pub append: bool,
+    // Writes to [destination].tmp, which is renamed to the destination once upmixing succeeds
+    pub atomic: bool,
+    // When set, silence quieter than this (in dBFS) is trimmed from the start and end of the source
+    pub trim_silence: Option<f32>,
+    // -trim-silence only trims silence that is at least this long, in seconds
+    pub trim_silence_min_seconds: f32,
+    // Via -max-duration: Only this many seconds from the start of the source are upmixed
+    pub max_duration: Option<f32>,
+    // Debugging: Upmixes the source backwards, and writes the output backwards
+    pub reverse: bool,
+    // Sources shorter than the window are upmixed as a single transform, instead of padding a minimum-sized window
+    pub single_window: bool,
+    // Reads the entire source into memory before upmixing, instead of streaming it. (Short sources always are)
+    pub in_memory: bool,
+    // Chooses a larger window than -low needs for tonal sources
+    pub auto_window: bool,
+    // A quick preview: Upmixes with a smaller window than the matrix's default lowest frequency needs
+    pub draft: bool,
+    // Via -window: Upmixes with this window size, instead of the smallest window that -low needs
+    pub window_size: Option<usize>,
+    // Fail instead of splitting outputs that exceed the maximum length of a wav file
+    pub no_split: bool,
     pub keep_awake: bool,
+    // Print the configuration as JSON to stderr, for bug reports
+    pub diag: bool,
     pub loud: bool,
 
+    // When set, per-channel levels and the settings used are written to this file when upmixing completes
+    pub report_file_path: Option<Box<Path>>,
+    // When set, a mono downmix of the output is written here
+    pub mono_check_path: Option<Box<Path>>,
+    // When set, the LFE is also written here, even if the layout doesn't have an LFE channel
+    pub lfe_file_path: Option<Box<Path>>,
+    // When set, the mono transform is written here before it's steered, for debugging the center
+    pub debug_center_path: Option<Box<Path>>,
+    // When set, the analysis of the window centered on this sample is written to this file, for debugging
+    pub dump_window: Option<(usize, Box<Path>)>,
+    // When set, where each frequency band is steered is streamed here, for visualizers
+    pub viz_path: Option<Box<Path>>,
+
     // Performs additional adjustments according to the specific chosen matrix
     // SQ, QS, RM, ect
     pub matrix: Box<dyn Matrix>,
+
+    // The name of the matrix, as specified by -matrix
+    pub matrix_name: String,
 }
 
 pub enum ChannelLayout {
+    // Front left, center, and right, without rears, such as for a soundbar
+    Three,
     Four,
     Five,
     FiveOne,
+    // 5.1 for spoken word and mono sources: Almost everything is steered to the center, with gentle ambience in the
+    // rears
+    FiveOneMono,
+    // 5.1 with a rear center, as in surround EX. The rear center is extracted from the rear left and right
+    SixOne,
+    // Front left, center, and right, with a single surround channel (LCRS,) as in Dolby Surround
+    Surround,
+}
+
+// How to steer a frequency when one channel is below the minimum steered amplitude
+pub enum SilentChannel {
+    // Copy the phase from the louder channel, which hard-pans to the louder channel
+    Copy,
+    // Steer as if the frequency is in-phase and centered
+    Center,
+    // Use the silent channel's phase as-is
+    Keep,
+}
+
+// How each frequency is compared between the left and right before it is steered, via -analysis
+pub enum Analysis {
+    // The phases of the left and right
+    LeftRight,
+    // The ratio of the side (L - R) to the mid (L + R), and the side's phase relative to the mid
+    MidSide,
+}
+
+// Where the lows removed from the center channel by -center-highpass go
+pub enum CenterHighpassRedirect {
+    Discard,
+    // Split evenly between the front left and right channels
+    Fronts,
+    Lfe,
+}
+
+// How the amplitude of the center is derived from the front left and right, via -center-method
+pub enum CenterMethod {
+    // The mono sum, extracted by how close to the center the frequency is panned
+    Mono,
+    // Only what's common to the left and right, estimated from the cross-spectrum
+    Correlated,
+}
+
+// Where the phase of the center comes from, via -center-phase
+pub enum CenterPhase {
+    // The mono sum, so louder channels pull the phase towards themselves
+    Mono,
+    // The average of the front left and right phases, regardless of their levels
+    Fronts,
+}
+
+// What the LFE channel is derived from, via -lfe-source
+pub enum LfeSource {
+    // (L + R) / 2. Out-of-phase bass cancels out
+    Mono,
+    // The louder of left and right, for each frequency
+    Max,
+    // The RMS of left and right, for each frequency, with the phase of the louder channel
+    Rms,
+    Left,
+    Right,
+}
+
+// How the rear channels are phase shifted, via -rear-phase
+pub enum RearPhase {
+    // The matrix's phase shift, the same at every frequency
+    Constant,
+    // The matrix's phase shift, plus an all-pass phase that rotates with frequency, as in analog decoders' 90 degree
+    // phase shift networks. Both rears are rotated the same, so the phase difference between them is unchanged
+    Broadband90,
+}
+
+// A point on an output channel's EQ curve. Gains are interpolated between points
+pub struct EqBand {
+    pub channel: OutputChannel,
+    pub frequency: f32,
+    pub gain_db: f32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputChannel {
+    Left,
+    Right,
+    Center,
+    Lfe,
+    RearLeft,
+    RearRight,
+    // The single rear channel in the surround layout, or the rear center in 6.1
+    Surround,
+}
+
+impl OutputChannel {
+    fn is_in(&self, channels: &Channels) -> bool {
+        match self {
+            OutputChannel::Left => channels.front_left,
+            OutputChannel::Right => channels.front_right,
+            OutputChannel::Center => channels.front_center,
+            OutputChannel::Lfe => channels.low_frequency,
+            OutputChannel::RearLeft => channels.back_left,
+            OutputChannel::RearRight => channels.back_right,
+            OutputChannel::Surround => channels.back_center,
+        }
+    }
+
+    fn add_to(&self, channels: &mut Channels) {
+        match self {
+            OutputChannel::Left => channels.front_left = true,
+            OutputChannel::Right => channels.front_right = true,
+            OutputChannel::Center => channels.front_center = true,
+            OutputChannel::Lfe => channels.low_frequency = true,
+            OutputChannel::RearLeft => channels.back_left = true,
+            OutputChannel::RearRight => channels.back_right = true,
+            OutputChannel::Surround => channels.back_center = true,
+        }
+    }
+}
+
+// How pans are averaged over time
+pub enum Averaging {
+    // A running average. Fast
+    Mean,
+    // More robust to brief outliers, such as transient phase glitches, but slower
+    Median,
 }
 
 pub enum MatrixFormat {
-    Default,
-    QS,
-    HorseShoe,
-    DolbyStereo,
-    SQ,
-    SQExperimental,
+    Named(&'static NamedMatrix),
+    File(FileMatrix),
+}
+
+impl MatrixFormat {
+    pub fn list_requested() -> bool {
+        let args: Vec<String> = env::args().collect();
+        args.windows(2)
+            .any(|flag_and_value| flag_and_value[0].eq("-matrix") && flag_and_value[1].eq("list"))
+    }
+
+    // For -matrix list
+    pub fn print_list() {
+        println!("Matrixes:");
+        for named_matrix in matrix::NAMED_MATRIXES.iter() {
+            println!("\t{}: {}", named_matrix.name, named_matrix.description);
+        }
+        println!("\tfile:[path]: Reads the matrix's coefficients from a JSON file");
+    }
+
+    fn uses(&self, setting: MatrixSetting) -> bool {
+        match self {
+            MatrixFormat::Named(named_matrix) => named_matrix.settings.contains(&setting),
+            MatrixFormat::File(_) => false,
+        }
+    }
 }
 
 impl Options {
     pub fn parse() -> Option<Options> {
-        let args: Vec<String> = env::args().collect();
+        Options::parse_args(env::args().collect())
+    }
+
+    // The number of samples that are upmixed from a source that's len_samples long. This is shorter than the source
+    // with -max-duration
+    pub fn samples_to_upmix(&self, len_samples: usize, sample_rate: u32) -> usize {
+        match self.max_duration {
+            Some(max_duration) => {
+                len_samples.min((max_duration as f64 * sample_rate as f64).round() as usize)
+            }
+            None => len_samples,
+        }
+    }
 
+    // The first argument is the executable name
+    pub fn parse_args(args: Vec<String>) -> Option<Options> {
         if args.len() < 3 {
             println!("Usage: soft_matrix [source] [destination]");
             return None;
@@ -57,23 +369,100 @@ impl Options {
         let source_wav_path = Path::new(source_wav_path.as_str());
 
         let target_wav_path = args_iter.next().unwrap();
+        // "-" writes the upmixed wav to stdout
+        let write_to_stdout = target_wav_path.eq("-");
         let target_wav_path = Path::new(target_wav_path.as_str());
 
         let mut num_threads = None;
+        let mut thread_ramp = upmixer::DEFAULT_THREAD_RAMP;
+        let mut max_cpu = None;
+        let mut max_memory_mb = None;
 
-        let mut channel_layout = ChannelLayout::FiveOne;
-        let mut matrix_format = MatrixFormat::Default;
-        let mut low_frequency = 20.0f32;
+        let mut channel_layout = None;
+        let mut matrix_format = MatrixFormat::Named(&matrix::NAMED_MATRIXES[0]);
+        let mut matrix_name = "default".to_string();
+        let mut ambisonic_order = None;
+        let mut low_frequency = None;
+        let mut window_size = None;
 
-        let mut minimum_steered_amplitude = 0.01;
+        let mut minimum_steered_amplitude = 0.000005;
+        let mut input_gain = 1.0f32;
+        let mut silent_channel = SilentChannel::Copy;
+        let mut analysis = Analysis::LeftRight;
+        let mut rear_gate = None;
+        let mut rear_band = None;
+        let mut max_rear = None;
+        let mut mono_detect = false;
+        let mut mono_detect_threshold = None;
+        let mut widen_image = None;
+        let mut center_focus = None;
+        let mut phase_deadzone = None;
+        let mut phase_weight = None;
+        let mut widen_factor = None;
+        let mut front_width = None;
+        let mut crossfeed = None;
+        let mut rear_channels = 2;
+        let mut flip_front_back = false;
+        let mut averaging = Averaging::Mean;
+        let mut smoothing_passes = 1;
+        let mut frequency_smoothing = 0;
+        let mut no_average = false;
+        let mut remove_dc = false;
+        let mut balance_input = false;
+        let mut sanitize = false;
+        let mut align_input = false;
+        let mut center_highpass = None;
+        let mut center_highpass_redirect = CenterHighpassRedirect::Discard;
+        let mut center_method = CenterMethod::Mono;
+        let mut center_phase = CenterPhase::Mono;
+        let mut center_adjustment = matrix::CENTER_AMPLITUDE_ADJUSTMENT;
+        let mut center_band = None;
+        let mut lfe_source = None;
+        let mut lfe_slope = None;
+        let mut rear_phase = RearPhase::Constant;
+        let mut eq = Vec::new();
+        let mut resume = false;
+        let mut append = false;
+        let mut atomic = false;
+        let mut trim_silence = None;
+        let mut trim_silence_min_seconds = None;
+        let mut max_duration = None;
+        let mut reverse = false;
+        let mut single_window = false;
+        let mut in_memory = false;
+        let mut auto_window = false;
+        let mut draft = false;
+        let mut no_split = false;
 
         let mut keep_awake = true;
+        let mut diag = false;
 
         let mut loud: Option<bool> = None;
 
+        let mut report_file_path = None;
+        let mut mono_check_path = None;
+        let mut lfe_file_path = None;
+        let mut debug_center_path = None;
+        let mut dump_window = None;
+        let mut viz_path = None;
+        let mut lfe_file_only = false;
+        let mut write_channels: Option<Vec<OutputChannel>> = None;
+        let mut match_layout: Option<Channels> = None;
+        let mut mantissa_bits = None;
+        let mut stereo_passthrough = false;
+        let mut no_symmetry_shortcut = false;
+        let mut align_pulse = false;
+        let mut auto_headroom = false;
+        let mut auto_headroom_release = None;
+        let mut center_ceiling = None;
+        let mut oversample = 1;
+        let mut flush_interval = None;
+        let mut meters = false;
+        let mut verbose = false;
+
         // Iterate through the options
         // -channels
-        // 4 or 5 or 5.1
+        // 4 or 5 or 5.1 or surround
 
         loop {
             match args_iter.next() {
@@ -82,12 +471,20 @@ impl Options {
                     if flag.eq("-channels") {
                         match args_iter.next() {
                             Some(channels_string) => {
-                                if channels_string.eq("4") {
-                                    channel_layout = ChannelLayout::Four
+                                if channels_string.eq("3.0") {
+                                    channel_layout = Some(ChannelLayout::Three)
+                                } else if channels_string.eq("4") {
+                                    channel_layout = Some(ChannelLayout::Four)
                                 } else if channels_string.eq("5") {
-                                    channel_layout = ChannelLayout::Five
+                                    channel_layout = Some(ChannelLayout::Five)
                                 } else if channels_string.eq("5.1") {
-                                    channel_layout = ChannelLayout::FiveOne
+                                    channel_layout = Some(ChannelLayout::FiveOne)
+                                } else if channels_string.eq("5.1-mono") {
+                                    channel_layout = Some(ChannelLayout::FiveOneMono)
+                                } else if channels_string.eq("6.1") {
+                                    channel_layout = Some(ChannelLayout::SixOne)
+                                } else if channels_string.eq("surround") {
+                                    channel_layout = Some(ChannelLayout::Surround)
                                 } else {
                                     println!("Unknown channel configuration: {}", channels_string);
                                     return None;
@@ -101,30 +498,72 @@ impl Options {
                     } else if flag.eq("-matrix") {
                         match args_iter.next() {
                             Some(matrix_format_string) => {
-                                if matrix_format_string.eq("default") {
-                                    matrix_format = MatrixFormat::Default
-                                } else if matrix_format_string.eq("qs") {
-                                    matrix_format = MatrixFormat::QS
-                                } else if matrix_format_string.eq("rm") {
-                                    matrix_format = MatrixFormat::QS
-                                } else if matrix_format_string.eq("horseshoe") {
-                                    matrix_format = MatrixFormat::HorseShoe
-                                } else if matrix_format_string.eq("dolby") {
-                                    matrix_format = MatrixFormat::DolbyStereo
-                                } else if matrix_format_string.eq("sq") {
-                                    matrix_format = MatrixFormat::SQ
-                                } else if matrix_format_string.eq("sqexperimental") {
-                                    matrix_format = MatrixFormat::SQExperimental
+                                if let Some(named_matrix) =
+                                    matrix::named_matrix(&matrix_format_string)
+                                {
+                                    matrix_format = MatrixFormat::Named(named_matrix)
+                                } else if let Some(matrix_path) =
+                                    matrix_format_string.strip_prefix("file:")
+                                {
+                                    match FileMatrix::open(Path::new(matrix_path)) {
+                                        Ok(file_matrix) => {
+                                            matrix_format = MatrixFormat::File(file_matrix)
+                                        }
+                                        Err(error) => {
+                                            println!(
+                                                "Can not use matrix file {}: {}",
+                                                matrix_path, error
+                                            );
+                                            return None;
+                                        }
+                                    }
                                 } else {
-                                    println!("Unknown matrix format: {}", matrix_format_string);
+                                    println!(
+                                        "Unknown matrix format: {}. (Use -matrix list to list the matrixes)",
+                                        matrix_format_string
+                                    );
                                     return None;
                                 }
+
+                                matrix_name = matrix_format_string;
                             }
                             None => {
                                 println!("Matrix unspecified");
                                 return None;
                             }
                         }
+                    } else if flag.eq("-ambisonic") {
+                        match args_iter.next() {
+                            Some(ambisonic_order_string) => {
+                                match ambisonic_order_string.parse::<usize>() {
+                                    Ok(ambisonic_order_value) => {
+                                        if !(1..=ambisonics::MAX_ORDER)
+                                            .contains(&ambisonic_order_value)
+                                        {
+                                            println!(
+                                                "Ambisonic order must be between 1 and {}: {}",
+                                                ambisonics::MAX_ORDER,
+                                                ambisonic_order_value
+                                            );
+                                            return None;
+                                        }
+
+                                        ambisonic_order = Some(ambisonic_order_value)
+                                    }
+                                    Err(_) => {
+                                        println!(
+                                            "Ambisonic order must be an integer: {}",
+                                            ambisonic_order_string
+                                        );
+                                        return None;
+                                    }
+                                }
+                            }
+                            None => {
+                                println!("Ambisonic order unspecified");
+                                return None;
+                            }
+                        }
                     } else if flag.eq("-low") {
                         match args_iter.next() {
                             Some(low_frequency_string) => {
@@ -138,7 +577,7 @@ impl Options {
                                             return None;
                                         }
 
-                                        low_frequency = low_frequency_arg
+                                        low_frequency = Some(low_frequency_arg)
                                     }
                                     Err(_) => {
                                         println!(
@@ -154,10 +593,50 @@ impl Options {
                                 return None;
                             }
                         }
+                    } else if flag.eq("-window") {
+                        match args_iter.next() {
+                            Some(window_size_string) => match window_size_string.parse::<usize>() {
+                                Ok(window_size_arg) => {
+                                    if !window_sizes::is_window_size(window_size_arg) {
+                                        let nearby_window_sizes: Vec<String> =
+                                            window_sizes::nearby_window_sizes(window_size_arg)
+                                                .iter()
+                                                .map(|window_size| window_size.to_string())
+                                                .collect();
+                                        println!(
+                                            "{} is not one of the optimized window sizes. Nearby window sizes are: {}",
+                                            window_size_arg,
+                                            nearby_window_sizes.join(", ")
+                                        );
+                                        return None;
+                                    }
+
+                                    window_size = Some(window_size_arg)
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Window size must be a whole number of samples: {}",
+                                        window_size_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Window size unspecified");
+                                return None;
+                            }
+                        }
                     } else if flag.eq("-threads") {
                         match args_iter.next() {
                             Some(num_threads_string) => match num_threads_string.parse::<usize>() {
-                                Ok(num_threads_value) => num_threads = Some(num_threads_value),
+                                Ok(num_threads_value) => {
+                                    if num_threads_value == 0 {
+                                        println!("The number of threads must be at least 1");
+                                        return None;
+                                    }
+
+                                    num_threads = Some(num_threads_value)
+                                }
                                 Err(_) => {
                                     println!(
                                         "Can not parse the number of threads: {}",
@@ -171,6 +650,68 @@ impl Options {
                                 return None;
                             }
                         }
+                    } else if flag.eq("-thread-ramp") {
+                        match args_iter.next() {
+                            Some(thread_ramp_string) => match thread_ramp_string.parse::<usize>() {
+                                Ok(thread_ramp_value) => thread_ramp = thread_ramp_value,
+                                Err(_) => {
+                                    println!(
+                                        "Can not parse the thread ramp: {}",
+                                        thread_ramp_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Thread ramp unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-max-cpu") {
+                        match args_iter.next() {
+                            Some(max_cpu_string) => match max_cpu_string.parse::<f32>() {
+                                Ok(max_cpu_value) => {
+                                    if max_cpu_value <= 0.0 || max_cpu_value > 100.0 {
+                                        println!("-max-cpu must be greater than 0 and no more than 100: {}", max_cpu_string);
+                                        return None;
+                                    }
+
+                                    max_cpu = Some(max_cpu_value)
+                                }
+                                Err(_) => {
+                                    println!("Can not parse the maximum CPU: {}", max_cpu_string);
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Maximum CPU unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-max-memory") {
+                        match args_iter.next() {
+                            Some(max_memory_string) => match max_memory_string.parse::<usize>() {
+                                Ok(max_memory_value) => {
+                                    if max_memory_value == 0 {
+                                        println!("-max-memory must be greater than 0");
+                                        return None;
+                                    }
+
+                                    max_memory_mb = Some(max_memory_value)
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Can not parse the maximum memory: {}",
+                                        max_memory_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Maximum memory unspecified");
+                                return None;
+                            }
+                        }
                     } else if flag.eq("-minimum") {
                         match args_iter.next() {
                             Some(minimum_steered_amplitude_string) => {
@@ -192,111 +733,2416 @@ impl Options {
                                 return None;
                             }
                         }
-                    } else if flag.eq("-keepawake") {
+                    } else if flag.eq("-input-gain") {
                         match args_iter.next() {
-                            Some(keep_awake_string) => match keep_awake_string.parse::<bool>() {
-                                Ok(keep_awake_value) => keep_awake = keep_awake_value,
+                            Some(input_gain_string) => match input_gain_string.parse::<f32>() {
+                                Ok(input_gain_db) => {
+                                    input_gain = 10.0f32.powf(input_gain_db / 20.0)
+                                }
                                 Err(_) => {
+                                    println!("Can not parse the input gain: {}", input_gain_string);
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Input gain unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-silent-channel") {
+                        match args_iter.next() {
+                            Some(silent_channel_string) => {
+                                if silent_channel_string.eq("copy") {
+                                    silent_channel = SilentChannel::Copy
+                                } else if silent_channel_string.eq("center") {
+                                    silent_channel = SilentChannel::Center
+                                } else if silent_channel_string.eq("keep") {
+                                    silent_channel = SilentChannel::Keep
+                                } else {
                                     println!(
-                                        "Can not parse the keep awake value: {}",
-                                        keep_awake_string
+                                        "Unknown silent channel handling: {}",
+                                        silent_channel_string
                                     );
                                     return None;
                                 }
-                            },
+                            }
                             None => {
-                                println!("Keepawake value unspecified");
+                                println!("Silent channel handling unspecified");
                                 return None;
                             }
                         }
-                    } else if flag.eq("-loud") {
-                        loud = Some(true);
-                    } else if flag.eq("-quiet") {
-                        loud = Some(false);
-                    } else {
-                        println!("Unknown flag: {}", flag);
-                        return None;
-                    }
-                }
-                None => {
-                    // No more flags left, interpret the options and return them
-                    let transform_mono: bool;
-                    let channels: Channels;
-
-                    match channel_layout {
-                        ChannelLayout::Four => {
-                            transform_mono = false;
-                            channels = Channels::new()
-                                .front_left()
-                                .front_right()
-                                .back_left()
-                                .back_right();
+                    } else if flag.eq("-analysis") {
+                        match args_iter.next() {
+                            Some(analysis_string) => {
+                                if analysis_string.eq("lr") {
+                                    analysis = Analysis::LeftRight
+                                } else if analysis_string.eq("ms") {
+                                    analysis = Analysis::MidSide
+                                } else {
+                                    println!("Unknown analysis: {}", analysis_string);
+                                    return None;
+                                }
+                            }
+                            None => {
+                                println!("Analysis unspecified");
+                                return None;
+                            }
                         }
-                        ChannelLayout::Five => {
-                            transform_mono = true;
-                            channels = Channels::new()
-                                .front_left()
-                                .front_right()
-                                .front_center()
-                                .back_left()
-                                .back_right();
+                    } else if flag.eq("-rear-gate") {
+                        match args_iter.next() {
+                            Some(rear_gate_string) => match rear_gate_string.parse::<f32>() {
+                                Ok(rear_gate_db) => {
+                                    if rear_gate_db > 0.0 {
+                                        println!("Rear gate must be <= 0 dBFS: {}", rear_gate_db);
+                                        return None;
+                                    }
+
+                                    rear_gate = Some(rear_gate_db)
+                                }
+                                Err(_) => {
+                                    println!("Can not parse the rear gate: {}", rear_gate_string);
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Rear gate unspecified");
+                                return None;
+                            }
                         }
-                        ChannelLayout::FiveOne => {
-                            transform_mono = true;
-                            channels = Channels::new()
-                                .front_left()
-                                .front_right()
-                                .front_center()
-                                .low_frequency()
-                                .back_left()
-                                .back_right();
+                    } else if flag.eq("-rear-band") {
+                        let mut band = [0.0f32; 2];
+                        for (frequency, name) in band.iter_mut().zip(["low", "high"]) {
+                            match args_iter.next() {
+                                Some(frequency_string) => match frequency_string.parse::<f32>() {
+                                    Ok(frequency_arg) => *frequency = frequency_arg,
+                                    Err(_) => {
+                                        println!(
+                                            "Rear band {} frequency must be a number: {}",
+                                            name, frequency_string
+                                        );
+                                        return None;
+                                    }
+                                },
+                                None => {
+                                    println!("Rear band {} frequency unspecified", name);
+                                    return None;
+                                }
+                            }
                         }
-                    }
-
-                    let matrix: Box<dyn Matrix> = match matrix_format {
-                        MatrixFormat::Default => Box::new(DefaultMatrix::new()),
-                        MatrixFormat::QS => Box::new(DefaultMatrix::qs()),
-                        MatrixFormat::HorseShoe => Box::new(DefaultMatrix::horseshoe()),
-                        MatrixFormat::DolbyStereo => Box::new(DefaultMatrix::dolby_stereo()),
-                        MatrixFormat::SQ => Box::new(SQMatrix::sq()),
-                        MatrixFormat::SQExperimental => Box::new(SQMatrixExperimental::sq()),
-                    };
 
-                    if (low_frequency as f32) > panner_and_writer::LFE_START
-                        && channels.low_frequency
-                    {
-                        println!(
-                            "LFE channel not supported when the lowest frequency to steer ({}hz) is greater than {}hz",
-                            low_frequency,
-                            panner_and_writer::LFE_START);
-                        return None;
-                    }
-
-                    let loud = if transform_mono {
-                        loud.unwrap_or(false)
-                    } else {
-                        if loud.is_some() {
-                            println!("-loud and -quiet only work when upmixing with an LFE or a center channel");
+                        let [low, high] = band;
+                        if low <= 0.0 || high <= low {
+                            println!(
+                                "Rear band frequencies must be > 0, and the low frequency must be lower than the high frequency: {} {}",
+                                low, high
+                            );
                             return None;
                         }
 
-                        true
-                    };
+                        rear_band = Some((low, high))
+                    } else if flag.eq("-max-rear") {
+                        match args_iter.next() {
+                            Some(max_rear_string) => match max_rear_string.parse::<f32>() {
+                                Ok(max_rear_arg) => {
+                                    if !(0.0..=1.0).contains(&max_rear_arg) {
+                                        println!(
+                                            "Max rear must be between 0 and 1: {}",
+                                            max_rear_arg
+                                        );
+                                        return None;
+                                    }
 
-                    return Some(Options {
-                        source_wav_path: source_wav_path.into(),
-                        target_wav_path: target_wav_path.into(),
-                        num_threads,
-                        transform_mono,
-                        channels,
-                        matrix,
-                        low_frequency,
-                        minimum_steered_amplitude,
-                        keep_awake,
-                        loud,
+                                    max_rear = Some(max_rear_arg)
+                                }
+                                Err(_) => {
+                                    println!("Can not parse the max rear: {}", max_rear_string);
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Max rear unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-mono-detect") {
+                        mono_detect = true;
+                    } else if flag.eq("-mono-detect-threshold") {
+                        match args_iter.next() {
+                            Some(threshold_string) => match threshold_string.parse::<f32>() {
+                                Ok(threshold_db) => {
+                                    if !(threshold_db < 0.0 && threshold_db.is_finite()) {
+                                        println!(
+                                            "Mono detect threshold must be below 0 dB: {}",
+                                            threshold_string
+                                        );
+                                        return None;
+                                    }
+
+                                    mono_detect_threshold = Some(threshold_db)
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Can not parse the mono detect threshold: {}",
+                                        threshold_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Mono detect threshold unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-front-width") {
+                        match args_iter.next() {
+                            Some(front_width_string) => match front_width_string.parse::<f32>() {
+                                Ok(front_width_arg) => {
+                                    if !(0.0..=2.0).contains(&front_width_arg) {
+                                        println!(
+                                            "Front width must be between 0 and 2: {}",
+                                            front_width_arg
+                                        );
+                                        return None;
+                                    }
+
+                                    front_width = Some(front_width_arg)
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Front width must be a number: {}",
+                                        front_width_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Front width unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-crossfeed") {
+                        match args_iter.next() {
+                            Some(crossfeed_string) => match crossfeed_string.parse::<f32>() {
+                                Ok(crossfeed_arg) => {
+                                    if !(0.0..=1.0).contains(&crossfeed_arg) {
+                                        println!(
+                                            "Crossfeed must be between 0 and 1: {}",
+                                            crossfeed_arg
+                                        );
+                                        return None;
+                                    }
+
+                                    crossfeed = Some(crossfeed_arg)
+                                }
+                                Err(_) => {
+                                    println!("Crossfeed must be a number: {}", crossfeed_string);
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Crossfeed unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-rear-channels") {
+                        match args_iter.next() {
+                            Some(rear_channels_string) => {
+                                match rear_channels_string.parse::<usize>() {
+                                    Ok(rear_channels_arg) => {
+                                        if !(2..=panner_and_writer::MAX_REAR_CHANNELS)
+                                            .contains(&rear_channels_arg)
+                                        {
+                                            println!(
+                                                "Rear channels must be between 2 and {}: {}",
+                                                panner_and_writer::MAX_REAR_CHANNELS,
+                                                rear_channels_arg
+                                            );
+                                            return None;
+                                        }
+
+                                        rear_channels = rear_channels_arg
+                                    }
+                                    Err(_) => {
+                                        println!(
+                                            "Rear channels must be a whole number: {}",
+                                            rear_channels_string
+                                        );
+                                        return None;
+                                    }
+                                }
+                            }
+                            None => {
+                                println!("Rear channels unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-phase-weight") {
+                        match args_iter.next() {
+                            Some(phase_weight_string) => match phase_weight_string.parse::<f32>() {
+                                Ok(phase_weight_arg) => {
+                                    if !(0.0..=1.0).contains(&phase_weight_arg) {
+                                        println!(
+                                            "Phase weight must be between 0 and 1: {}",
+                                            phase_weight_arg
+                                        );
+                                        return None;
+                                    }
+
+                                    phase_weight = Some(phase_weight_arg)
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Phase weight must be a number: {}",
+                                        phase_weight_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Phase weight unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-widen") {
+                        match args_iter.next() {
+                            Some(widen_factor_string) => match widen_factor_string.parse::<f32>() {
+                                Ok(widen_factor_arg) => {
+                                    if !(1.0..=MAX_WIDEN_FACTOR).contains(&widen_factor_arg) {
+                                        println!(
+                                            "Widen factor must be between 1 and {}: {}",
+                                            MAX_WIDEN_FACTOR, widen_factor_arg
+                                        );
+                                        return None;
+                                    }
+
+                                    widen_factor = Some(widen_factor_arg)
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Widen factor must be a number: {}",
+                                        widen_factor_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Widen factor unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-phase-deadzone") {
+                        match args_iter.next() {
+                            Some(phase_deadzone_string) => {
+                                match phase_deadzone_string.parse::<f32>() {
+                                    Ok(phase_deadzone_arg) => {
+                                        if phase_deadzone_arg <= 0.0
+                                            || phase_deadzone_arg >= FRAC_PI_2
+                                        {
+                                            println!(
+                                                "Phase deadzone must be greater than 0 and less than pi / 2: {}",
+                                                phase_deadzone_arg
+                                            );
+                                            return None;
+                                        }
+
+                                        phase_deadzone = Some(phase_deadzone_arg)
+                                    }
+                                    Err(_) => {
+                                        println!(
+                                            "Phase deadzone must be a number: {}",
+                                            phase_deadzone_string
+                                        );
+                                        return None;
+                                    }
+                                }
+                            }
+                            None => {
+                                println!("Phase deadzone unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-flip-front-back") {
+                        flip_front_back = true;
+                    } else if flag.eq("-widen-image") {
+                        match args_iter.next() {
+                            Some(widen_image_string) => match widen_image_string.parse::<f32>() {
+                                Ok(widen_image_arg) => {
+                                    if widen_image_arg < 0.0 {
+                                        println!(
+                                            "Widen image amount must be >= 0: {}",
+                                            widen_image_arg
+                                        );
+                                        return None;
+                                    }
+
+                                    widen_image = Some(widen_image_arg)
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Widen image amount must be a number: {}",
+                                        widen_image_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Widen image amount unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-center-focus") {
+                        match args_iter.next() {
+                            Some(center_focus_string) => match center_focus_string.parse::<f32>() {
+                                Ok(center_focus_arg) => {
+                                    if center_focus_arg <= 0.0 {
+                                        println!("Center focus must be > 0: {}", center_focus_arg);
+                                        return None;
+                                    }
+
+                                    center_focus = Some(center_focus_arg)
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Center focus must be a number: {}",
+                                        center_focus_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Center focus unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-average") {
+                        match args_iter.next() {
+                            Some(averaging_string) => {
+                                if averaging_string.eq("mean") {
+                                    averaging = Averaging::Mean
+                                } else if averaging_string.eq("median") {
+                                    averaging = Averaging::Median
+                                } else {
+                                    println!("Unknown averaging: {}", averaging_string);
+                                    return None;
+                                }
+                            }
+                            None => {
+                                println!("Averaging unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-smoothing-passes") {
+                        match args_iter.next() {
+                            Some(smoothing_passes_string) => {
+                                match smoothing_passes_string.parse::<usize>() {
+                                    Ok(smoothing_passes_value) => {
+                                        if !(1..=MAX_SMOOTHING_PASSES)
+                                            .contains(&smoothing_passes_value)
+                                        {
+                                            println!(
+                                                "Smoothing passes must be between 1 and {}: {}",
+                                                MAX_SMOOTHING_PASSES, smoothing_passes_value
+                                            );
+                                            return None;
+                                        }
+
+                                        smoothing_passes = smoothing_passes_value
+                                    }
+                                    Err(_) => {
+                                        println!(
+                                            "Can not parse the number of smoothing passes: {}",
+                                            smoothing_passes_string
+                                        );
+                                        return None;
+                                    }
+                                }
+                            }
+                            None => {
+                                println!("Smoothing passes unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-freq-smooth") {
+                        match args_iter.next() {
+                            Some(frequency_smoothing_string) => {
+                                match frequency_smoothing_string.parse::<usize>() {
+                                    Ok(frequency_smoothing_value) => {
+                                        frequency_smoothing = frequency_smoothing_value
+                                    }
+                                    Err(_) => {
+                                        println!(
+                                            "Frequencies to smooth across must be a whole number: {}",
+                                            frequency_smoothing_string
+                                        );
+                                        return None;
+                                    }
+                                }
+                            }
+                            None => {
+                                println!("Frequencies to smooth across unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-remove-dc") {
+                        remove_dc = true;
+                    } else if flag.eq("-balance-input") {
+                        balance_input = true;
+                    } else if flag.eq("-sanitize") {
+                        sanitize = true;
+                    } else if flag.eq("-align-input") {
+                        align_input = true;
+                    } else if flag.eq("-center-highpass") {
+                        match args_iter.next() {
+                            Some(center_highpass_string) => {
+                                match center_highpass_string.parse::<f32>() {
+                                    Ok(center_highpass_arg) => {
+                                        if center_highpass_arg <= 0.0 {
+                                            println!(
+                                                "Center highpass frequency must be > 0: {}",
+                                                center_highpass_arg
+                                            );
+                                            return None;
+                                        }
+
+                                        center_highpass = Some(center_highpass_arg)
+                                    }
+                                    Err(_) => {
+                                        println!(
+                                            "Center highpass frequency must be a number: {}",
+                                            center_highpass_string
+                                        );
+                                        return None;
+                                    }
+                                }
+                            }
+                            None => {
+                                println!("Center highpass frequency unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-eq") {
+                        match args_iter.next() {
+                            Some(eq_string) => {
+                                for band_string in eq_string.split(',') {
+                                    eq.push(parse_eq_band(band_string)?);
+                                }
+                            }
+                            None => {
+                                println!("EQ unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-center-method") {
+                        match args_iter.next() {
+                            Some(center_method_string) => {
+                                if center_method_string.eq("mono") {
+                                    center_method = CenterMethod::Mono
+                                } else if center_method_string.eq("correlated") {
+                                    center_method = CenterMethod::Correlated
+                                } else {
+                                    println!("Unknown center method: {}", center_method_string);
+                                    return None;
+                                }
+                            }
+                            None => {
+                                println!("Center method unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-center-phase") {
+                        match args_iter.next() {
+                            Some(center_phase_string) => {
+                                if center_phase_string.eq("mono") {
+                                    center_phase = CenterPhase::Mono
+                                } else if center_phase_string.eq("fronts") {
+                                    center_phase = CenterPhase::Fronts
+                                } else {
+                                    println!("Unknown center phase: {}", center_phase_string);
+                                    return None;
+                                }
+                            }
+                            None => {
+                                println!("Center phase unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-center-law") {
+                        match args_iter.next() {
+                            Some(center_law_string) => {
+                                center_adjustment = if center_law_string.eq("constant-power") {
+                                    matrix::CENTER_AMPLITUDE_ADJUSTMENT
+                                } else if center_law_string.eq("-3")
+                                    || center_law_string.eq("-4.5")
+                                    || center_law_string.eq("-6")
+                                {
+                                    let center_law_db: f32 = center_law_string.parse().unwrap();
+                                    10f32.powf(center_law_db / 20.0)
+                                } else {
+                                    println!(
+                                        "Unknown center law: {}. Use -3, -4.5, -6, or constant-power",
+                                        center_law_string
+                                    );
+                                    return None;
+                                }
+                            }
+                            None => {
+                                println!("Center law unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-center-band") {
+                        let mut parse_frequency = |name: &str| match args_iter.next() {
+                            Some(frequency_string) => match frequency_string.parse::<f32>() {
+                                Ok(frequency) => {
+                                    if frequency <= 0.0 {
+                                        println!(
+                                            "Center band {} frequency must be > 0: {}",
+                                            name, frequency
+                                        );
+                                        None
+                                    } else {
+                                        Some(frequency)
+                                    }
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Center band {} frequency must be a number: {}",
+                                        name, frequency_string
+                                    );
+                                    None
+                                }
+                            },
+                            None => {
+                                println!("Center band {} frequency unspecified", name);
+                                None
+                            }
+                        };
+
+                        let center_band_low = parse_frequency("low")?;
+                        let center_band_high = parse_frequency("high")?;
+
+                        if center_band_high <= center_band_low {
+                            println!(
+                                "Center band high frequency ({}) must be greater than the low frequency ({})",
+                                center_band_high, center_band_low
+                            );
+                            return None;
+                        }
+
+                        center_band = Some((center_band_low, center_band_high));
+                    } else if flag.eq("-center-highpass-redirect") {
+                        match args_iter.next() {
+                            Some(redirect_string) => {
+                                if redirect_string.eq("discard") {
+                                    center_highpass_redirect = CenterHighpassRedirect::Discard
+                                } else if redirect_string.eq("fronts") {
+                                    center_highpass_redirect = CenterHighpassRedirect::Fronts
+                                } else if redirect_string.eq("lfe") {
+                                    center_highpass_redirect = CenterHighpassRedirect::Lfe
+                                } else {
+                                    println!(
+                                        "Unknown center highpass redirect: {}",
+                                        redirect_string
+                                    );
+                                    return None;
+                                }
+                            }
+                            None => {
+                                println!("Center highpass redirect unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-lfe-slope") {
+                        match args_iter.next() {
+                            Some(lfe_slope_string) => match lfe_slope_string.parse::<u32>() {
+                                Ok(lfe_slope_arg) => {
+                                    if !lfe_slope_arg.is_multiple_of(6)
+                                        || !(6..=panner_and_writer::MAX_LFE_SLOPE)
+                                            .contains(&lfe_slope_arg)
+                                    {
+                                        println!(
+                                            "LFE slope must be 6, 12, 18, ... up to {} dB per octave: {}",
+                                            panner_and_writer::MAX_LFE_SLOPE,
+                                            lfe_slope_arg
+                                        );
+                                        return None;
+                                    }
+
+                                    lfe_slope = Some(lfe_slope_arg)
+                                }
+                                Err(_) => {
+                                    println!("Can not parse the LFE slope: {}", lfe_slope_string);
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("LFE slope unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-lfe-source") {
+                        match args_iter.next() {
+                            Some(lfe_source_string) => {
+                                if lfe_source_string.eq("mono") {
+                                    lfe_source = Some(LfeSource::Mono)
+                                } else if lfe_source_string.eq("max") {
+                                    lfe_source = Some(LfeSource::Max)
+                                } else if lfe_source_string.eq("rms") {
+                                    lfe_source = Some(LfeSource::Rms)
+                                } else if lfe_source_string.eq("left") {
+                                    lfe_source = Some(LfeSource::Left)
+                                } else if lfe_source_string.eq("right") {
+                                    lfe_source = Some(LfeSource::Right)
+                                } else {
+                                    println!("Unknown LFE source: {}", lfe_source_string);
+                                    return None;
+                                }
+                            }
+                            None => {
+                                println!("LFE source unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-rear-phase") {
+                        match args_iter.next() {
+                            Some(rear_phase_string) => {
+                                if rear_phase_string.eq("constant") {
+                                    rear_phase = RearPhase::Constant
+                                } else if rear_phase_string.eq("broadband-90") {
+                                    rear_phase = RearPhase::Broadband90
+                                } else {
+                                    println!("Unknown rear phase: {}", rear_phase_string);
+                                    return None;
+                                }
+                            }
+                            None => {
+                                println!("Rear phase unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-resume") {
+                        resume = true;
+                    } else if flag.eq("-append") {
+                        append = true;
+                    } else if flag.eq("-atomic") {
+                        atomic = true;
+                    } else if flag.eq("-reverse") {
+                        reverse = true;
+                    } else if flag.eq("-diag") {
+                        diag = true;
+                    } else if flag.eq("-no-average") {
+                        no_average = true;
+                    } else if flag.eq("-single-window") {
+                        single_window = true;
+                    } else if flag.eq("-in-memory") {
+                        in_memory = true;
+                    } else if flag.eq("-auto-window") {
+                        auto_window = true;
+                    } else if flag.eq("-draft") {
+                        draft = true;
+                    } else if flag.eq("-no-split") {
+                        no_split = true;
+                    } else if flag.eq("-keepawake") {
+                        match args_iter.next() {
+                            Some(keep_awake_string) => match keep_awake_string.parse::<bool>() {
+                                Ok(keep_awake_value) => keep_awake = keep_awake_value,
+                                Err(_) => {
+                                    println!(
+                                        "Can not parse the keep awake value: {}",
+                                        keep_awake_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Keepawake value unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-no-keepawake") {
+                        keep_awake = false;
+                    } else if flag.eq("-loud") {
+                        loud = Some(true);
+                    } else if flag.eq("-quiet") {
+                        loud = Some(false);
+                    } else if flag.eq("-report-file") {
+                        match args_iter.next() {
+                            Some(report_file_path_string) => {
+                                report_file_path =
+                                    Some(Path::new(report_file_path_string.as_str()).into())
+                            }
+                            None => {
+                                println!("Report file unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-mantissa-bits") {
+                        match args_iter.next() {
+                            Some(mantissa_bits_string) => match mantissa_bits_string.parse::<u32>()
+                            {
+                                Ok(mantissa_bits_arg) => {
+                                    if mantissa_bits_arg > panner_and_writer::MAX_MANTISSA_BITS {
+                                        println!(
+                                            "Mantissa bits must be between 0 and {}: {}",
+                                            panner_and_writer::MAX_MANTISSA_BITS,
+                                            mantissa_bits_arg
+                                        );
+                                        return None;
+                                    }
+
+                                    mantissa_bits = Some(mantissa_bits_arg)
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Mantissa bits must be a whole number: {}",
+                                        mantissa_bits_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Mantissa bits unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-stereo-passthrough") {
+                        stereo_passthrough = true;
+                    } else if flag.eq("-no-symmetry-shortcut") {
+                        no_symmetry_shortcut = true;
+                    } else if flag.eq("-align-pulse") {
+                        align_pulse = true;
+                    } else if flag.eq("-auto-headroom") {
+                        auto_headroom = true;
+                    } else if flag.eq("-auto-headroom-release") {
+                        match args_iter.next() {
+                            Some(release_string) => match release_string.parse::<f32>() {
+                                Ok(release_seconds) => {
+                                    if !(release_seconds > 0.0 && release_seconds.is_finite()) {
+                                        println!(
+                                            "-auto-headroom-release must be greater than 0 seconds: {}",
+                                            release_string
+                                        );
+                                        return None;
+                                    }
+
+                                    auto_headroom_release = Some(release_seconds)
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Can not parse the auto headroom release: {}",
+                                        release_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Auto headroom release unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-center-ceiling") {
+                        match args_iter.next() {
+                            Some(center_ceiling_string) => {
+                                match center_ceiling_string.parse::<f32>() {
+                                    Ok(center_ceiling_dbfs) => {
+                                        if !(center_ceiling_dbfs <= 0.0
+                                            && center_ceiling_dbfs.is_finite())
+                                        {
+                                            println!(
+                                                "-center-ceiling must be 0 dBFS or lower: {}",
+                                                center_ceiling_string
+                                            );
+                                            return None;
+                                        }
+
+                                        center_ceiling = Some(center_ceiling_dbfs)
+                                    }
+                                    Err(_) => {
+                                        println!(
+                                            "Can not parse the center ceiling: {}",
+                                            center_ceiling_string
+                                        );
+                                        return None;
+                                    }
+                                }
+                            }
+                            None => {
+                                println!("Center ceiling unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-meters") {
+                        meters = true;
+                    } else if flag.eq("-verbose") {
+                        verbose = true;
+                    } else if flag.eq("-trim-silence") {
+                        match args_iter.next() {
+                            Some(trim_silence_string) => match trim_silence_string.parse::<f32>() {
+                                Ok(trim_silence_db) => {
+                                    if trim_silence_db > 0.0 {
+                                        println!(
+                                            "Trim silence threshold must be <= 0 dBFS: {}",
+                                            trim_silence_db
+                                        );
+                                        return None;
+                                    }
+
+                                    trim_silence = Some(trim_silence_db)
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Can not parse the trim silence threshold: {}",
+                                        trim_silence_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Trim silence threshold unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-trim-silence-min") {
+                        match args_iter.next() {
+                            Some(min_seconds_string) => match min_seconds_string.parse::<f32>() {
+                                Ok(min_seconds) => {
+                                    if !(min_seconds >= 0.0 && min_seconds.is_finite()) {
+                                        println!(
+                                            "-trim-silence-min must be 0 seconds or longer: {}",
+                                            min_seconds_string
+                                        );
+                                        return None;
+                                    }
+
+                                    trim_silence_min_seconds = Some(min_seconds)
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Can not parse the minimum silence to trim: {}",
+                                        min_seconds_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Minimum silence to trim unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-max-duration") {
+                        match args_iter.next() {
+                            Some(max_duration_string) => match max_duration_string.parse::<f32>() {
+                                Ok(max_duration_seconds) => {
+                                    if !(max_duration_seconds > 0.0
+                                        && max_duration_seconds.is_finite())
+                                    {
+                                        println!(
+                                            "-max-duration must be longer than 0 seconds: {}",
+                                            max_duration_string
+                                        );
+                                        return None;
+                                    }
+
+                                    max_duration = Some(max_duration_seconds)
+                                }
+                                Err(_) => {
+                                    println!(
+                                        "Can not parse the maximum duration: {}",
+                                        max_duration_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Maximum duration unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-flush-interval") {
+                        match args_iter.next() {
+                            Some(flush_interval_string) => {
+                                match flush_interval_string.parse::<f32>() {
+                                    Ok(flush_interval_value) => {
+                                        if !(flush_interval_value > 0.0
+                                            && flush_interval_value.is_finite())
+                                        {
+                                            println!("-flush-interval must be greater than 0 seconds: {}", flush_interval_string);
+                                            return None;
+                                        }
+
+                                        flush_interval =
+                                            Some(Duration::from_secs_f32(flush_interval_value))
+                                    }
+                                    Err(_) => {
+                                        println!(
+                                            "Can not parse the flush interval: {}",
+                                            flush_interval_string
+                                        );
+                                        return None;
+                                    }
+                                }
+                            }
+                            None => {
+                                println!("Flush interval unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-oversample") {
+                        match args_iter.next() {
+                            Some(oversample_string) => match oversample_string.as_str() {
+                                "2" => oversample = 2,
+                                "4" => oversample = 4,
+                                _ => {
+                                    println!("Oversample must be 2 or 4: {}", oversample_string);
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Oversample unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-mono-check") {
+                        match args_iter.next() {
+                            Some(mono_check_path_string) => {
+                                mono_check_path =
+                                    Some(Path::new(mono_check_path_string.as_str()).into())
+                            }
+                            None => {
+                                println!("Mono check file unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-lfe-file") {
+                        match args_iter.next() {
+                            Some(lfe_file_path_string) => {
+                                lfe_file_path =
+                                    Some(Path::new(lfe_file_path_string.as_str()).into())
+                            }
+                            None => {
+                                println!("LFE file unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-debug-center") {
+                        match args_iter.next() {
+                            Some(debug_center_path_string) => {
+                                debug_center_path =
+                                    Some(Path::new(debug_center_path_string.as_str()).into())
+                            }
+                            None => {
+                                println!("Debug center file unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-dump-window") {
+                        let sample = match args_iter.next() {
+                            Some(sample_string) => match sample_string.parse::<usize>() {
+                                Ok(sample) => sample,
+                                Err(_) => {
+                                    println!(
+                                        "The sample to dump must be a whole number, starting at 0: {}",
+                                        sample_string
+                                    );
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Sample to dump unspecified");
+                                return None;
+                            }
+                        };
+
+                        match args_iter.next() {
+                            Some(dump_window_path_string) => {
+                                dump_window = Some((
+                                    sample,
+                                    Path::new(dump_window_path_string.as_str()).into(),
+                                ))
+                            }
+                            None => {
+                                println!("Dump window file unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-viz") {
+                        match args_iter.next() {
+                            Some(viz_path_string) => {
+                                viz_path = Some(Path::new(viz_path_string.as_str()).into())
+                            }
+                            None => {
+                                println!("Visualization file unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-lfe-file-only") {
+                        lfe_file_only = true;
+                    } else if flag.eq("-write-channels") {
+                        match args_iter.next() {
+                            Some(write_channels_string) => {
+                                let mut write_channels_arg = Vec::new();
+                                for channel_string in write_channels_string.split(',') {
+                                    match parse_output_channel(channel_string) {
+                                        Some(channel) => write_channels_arg.push(channel),
+                                        None => {
+                                            println!("Unknown channel: {}", channel_string);
+                                            return None;
+                                        }
+                                    }
+                                }
+
+                                write_channels = Some(write_channels_arg);
+                            }
+                            None => {
+                                println!("Channels to write unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-match-layout") {
+                        match args_iter.next() {
+                            Some(reference_path) => {
+                                match read_wav_from_file_path(Path::new(&reference_path)) {
+                                    Ok(reference_wav) => {
+                                        match_layout = Some(*reference_wav.channels())
+                                    }
+                                    Err(error) => {
+                                        println!("Can not open {}: {:?}", reference_path, error);
+                                        return None;
+                                    }
+                                }
+                            }
+                            None => {
+                                println!("Reference wav unspecified");
+                                return None;
+                            }
+                        }
+                    } else {
+                        println!("Unknown flag: {}", flag);
+                        return None;
+                    }
+                }
+                None => {
+                    // No more flags left, interpret the options and return them
+                    // Settings that the matrix doesn't use are errors, instead of being silently ignored
+                    for (setting, specified) in [
+                        (MatrixSetting::PhaseDeadzone, phase_deadzone.is_some()),
+                        (MatrixSetting::PhaseWeight, phase_weight.is_some()),
+                        (MatrixSetting::Widen, widen_factor.is_some()),
+                    ] {
+                        if specified && !matrix_format.uses(setting) {
+                            println!(
+                                "{} only works with {}",
+                                setting.flag(),
+                                matrix::names_of_matrixes_with(setting)
+                            );
+                            return None;
+                        }
+                    }
+
+                    let matrix_settings = MatrixSettings {
+                        phase_deadzone: phase_deadzone.unwrap_or(matrix::DEFAULT_PHASE_DEADZONE),
+                        phase_weight: phase_weight.unwrap_or(matrix::DEFAULT_PHASE_WEIGHT),
+                        widen_factor: widen_factor
+                            .unwrap_or(matrix::DEFAULT_HORSESHOE_WIDEN_FACTOR),
+                        center_adjustment,
+                    };
+
+                    let matrix: Box<dyn Matrix> = match matrix_format {
+                        MatrixFormat::Named(named_matrix) => (named_matrix.new)(&matrix_settings),
+                        MatrixFormat::File(file_matrix) => {
+                            Box::new(file_matrix.with_center_adjustment(center_adjustment))
+                        }
+                    };
+
+                    // The matrix suggests the lowest frequency, and if the layout has an LFE channel, unless they're specified
+                    let low_frequency = if draft {
+                        if low_frequency.is_some() {
+                            println!("-draft and -low can not be used together");
+                            return None;
+                        }
+
+                        if auto_window {
+                            println!("-draft and -auto-window can not be used together");
+                            return None;
+                        }
+
+                        if oversample > 1 {
+                            println!("-draft and -oversample can not be used together");
+                            return None;
+                        }
+
+                        DRAFT_LOW_FREQUENCY.max(matrix.default_low_frequency())
+                    } else {
+                        low_frequency.unwrap_or(matrix.default_low_frequency())
+                    };
+
+                    if window_size.is_some() {
+                        if draft {
+                            println!("-draft and -window can not be used together");
+                            return None;
+                        }
+
+                        if auto_window {
+                            println!("-auto-window and -window can not be used together");
+                            return None;
+                        }
+                    }
+
+                    // -match-layout: Upmixes to a layout that has all of the reference's channels
+                    let mut channel_layout = channel_layout;
+                    if let Some(match_layout) = &match_layout {
+                        if channel_layout.is_some()
+                            || write_channels.is_some()
+                            || rear_channels > 2
+                            || ambisonic_order.is_some()
+                        {
+                            println!("-match-layout can not be used with -channels, -write-channels, -rear-channels, or -ambisonic");
+                            return None;
+                        }
+
+                        match layout_to_match(match_layout) {
+                            Ok((layout_to_match, rear_channels_to_match)) => {
+                                channel_layout = Some(layout_to_match);
+                                rear_channels = rear_channels_to_match;
+                            }
+                            Err(error) => {
+                                println!("Can not match the layout of the reference: {}", error);
+                                return None;
+                            }
+                        }
+                    }
+
+                    let channel_layout = channel_layout.unwrap_or(if matrix.default_lfe() {
+                        ChannelLayout::FiveOne
+                    } else {
+                        ChannelLayout::Five
+                    });
+
+                    let transform_mono: bool;
+                    let channels: Channels;
+                    let mono_surround = matches!(channel_layout, ChannelLayout::FiveOneMono);
+
+                    match channel_layout {
+                        ChannelLayout::Three => {
+                            transform_mono = true;
+                            channels = Channels::new().front_left().front_right().front_center();
+                        }
+                        ChannelLayout::Four => {
+                            transform_mono = false;
+                            channels = Channels::new()
+                                .front_left()
+                                .front_right()
+                                .back_left()
+                                .back_right();
+                        }
+                        ChannelLayout::Five => {
+                            transform_mono = true;
+                            channels = Channels::new()
+                                .front_left()
+                                .front_right()
+                                .front_center()
+                                .back_left()
+                                .back_right();
+                        }
+                        ChannelLayout::FiveOne | ChannelLayout::FiveOneMono => {
+                            transform_mono = true;
+                            channels = Channels::new()
+                                .front_left()
+                                .front_right()
+                                .front_center()
+                                .low_frequency()
+                                .back_left()
+                                .back_right();
+                        }
+                        ChannelLayout::SixOne => {
+                            transform_mono = true;
+                            channels = Channels::new()
+                                .front_left()
+                                .front_right()
+                                .front_center()
+                                .low_frequency()
+                                .back_left()
+                                .back_right()
+                                .back_center();
+                        }
+                        ChannelLayout::Surround => {
+                            transform_mono = true;
+                            channels = Channels::new()
+                                .front_left()
+                                .front_right()
+                                .front_center()
+                                .back_center();
+                        }
+                    }
+
+                    if rear_channels > 2 {
+                        if ambisonic_order.is_some() {
+                            println!("-rear-channels only works when upmixing to speakers");
+                            return None;
+                        }
+
+                        if !channels.back_left || channels.back_center {
+                            println!("-rear-channels only works with the 4, 5, 5.1, and 5.1-mono channel layouts");
+                            return None;
+                        }
+
+                        // Crossfeed and -write-channels only know about the rear left and right
+                        if crossfeed.is_some() {
+                            println!("-rear-channels and -crossfeed can not be used together");
+                            return None;
+                        }
+
+                        if write_channels.is_some() {
+                            println!("-rear-channels and -write-channels can not be used together");
+                            return None;
+                        }
+                    }
+
+                    // -rear-channels: The rears are spread across more of the speaker positions behind the listener.
+                    // (This matches panner_and_writer::rears_by_position)
+                    let channels = match rear_channels {
+                        3 => channels.back_center(),
+                        4 => channels.side_left().side_right(),
+                        5 => channels.back_center().side_left().side_right(),
+                        _ => channels,
+                    };
+
+                    // Ambisonics replace the speaker layout
+                    let (transform_mono, channels) = match ambisonic_order {
+                        Some(ambisonic_order) => (false, ambisonics::channels(ambisonic_order)),
+                        None => (transform_mono, channels),
+                    };
+
+                    if lfe_file_path.is_some() {
+                        if ambisonic_order.is_some() {
+                            println!("-lfe-file only works when upmixing to speakers");
+                            return None;
+                        }
+
+                        if resume {
+                            println!("-lfe-file and -resume can not be used together");
+                            return None;
+                        }
+                    } else if lfe_file_only {
+                        println!("-lfe-file-only requires -lfe-file");
+                        return None;
+                    }
+
+                    let mono_detect = if mono_detect {
+                        Some(
+                            mono_detect_threshold
+                                .unwrap_or(reader::DEFAULT_MONO_DETECT_THRESHOLD_DB),
+                        )
+                    } else {
+                        if mono_detect_threshold.is_some() {
+                            println!("-mono-detect-threshold requires -mono-detect");
+                            return None;
+                        }
+
+                        None
+                    };
+
+                    let auto_headroom =
+                        if auto_headroom {
+                            Some(auto_headroom_release.unwrap_or(
+                                panner_and_writer::DEFAULT_AUTO_HEADROOM_RELEASE_SECONDS,
+                            ))
+                        } else {
+                            if auto_headroom_release.is_some() {
+                                println!("-auto-headroom-release requires -auto-headroom");
+                                return None;
+                            }
+
+                            None
+                        };
+
+                    // The LFE is derived for -lfe-file, even if it isn't in the layout
+                    let derives_lfe = channels.low_frequency || lfe_file_path.is_some();
+
+                    if (low_frequency as f32) > panner_and_writer::LFE_START
+                        && derives_lfe
+                        && ambisonic_order.is_none()
+                    {
+                        println!(
+                            "LFE channel not supported when the lowest frequency to steer ({}hz) is greater than {}hz",
+                            low_frequency,
+                            panner_and_writer::LFE_START);
+                        return None;
+                    }
+
+                    if center_highpass.is_some()
+                        && (!channels.front_center || ambisonic_order.is_some())
+                    {
+                        println!("-center-highpass only works when upmixing with a center channel");
+                        return None;
+                    }
+
+                    for band in eq.iter() {
+                        let channel_present = ambisonic_order.is_none()
+                            && match band.channel {
+                                OutputChannel::Lfe => derives_lfe,
+                                channel => channel.is_in(&channels),
+                            };
+
+                        if !channel_present {
+                            println!("-eq can only adjust channels that are in the output, and does not work with -ambisonic");
+                            return None;
+                        }
+                    }
+
+                    if front_width.is_some() && ambisonic_order.is_some() {
+                        println!("-front-width only works when upmixing to speakers");
+                        return None;
+                    }
+
+                    if let (RearPhase::Broadband90, Some(_)) = (&rear_phase, ambisonic_order) {
+                        println!("-rear-phase only works when upmixing to speakers");
+                        return None;
+                    }
+
+                    if crossfeed.is_some() && ambisonic_order.is_some() {
+                        println!("-crossfeed only works when upmixing to speakers");
+                        return None;
+                    }
+
+                    if stereo_passthrough {
+                        if ambisonic_order.is_some() {
+                            println!("-stereo-passthrough only works when upmixing to speakers");
+                            return None;
+                        }
+
+                        // Oversampled windows have zeros between the source's samples
+                        if oversample > 1 {
+                            println!(
+                                "-stereo-passthrough and -oversample can not be used together"
+                            );
+                            return None;
+                        }
+
+                        if crossfeed.is_some() {
+                            println!("-stereo-passthrough and -crossfeed can not be used together");
+                            return None;
+                        }
+
+                        // The fronts are the source exactly as it was read, so nothing can change the source
+                        // before it's transformed
+                        for (changes_source, flag) in [
+                            (input_gain != 1.0, "-input-gain"),
+                            (remove_dc, "-remove-dc"),
+                            (balance_input, "-balance-input"),
+                            (align_input, "-align-input"),
+                        ] {
+                            if changes_source {
+                                println!(
+                                    "-stereo-passthrough and {} can not be used together",
+                                    flag
+                                );
+                                return None;
+                            }
+                        }
+                    }
+
+                    if no_symmetry_shortcut && ambisonic_order.is_some() {
+                        println!("-no-symmetry-shortcut only works when upmixing to speakers");
+                        return None;
+                    }
+
+                    if dump_window.is_some() {
+                        if ambisonic_order.is_some() {
+                            println!("-dump-window only works when upmixing to speakers");
+                            return None;
+                        }
+
+                        if resume {
+                            println!("-dump-window and -resume can not be used together");
+                            return None;
+                        }
+                    }
+
+                    // The records are written in order, from the averaged pans, starting at the first sample
+                    if viz_path.is_some() {
+                        if no_average {
+                            println!("-viz and -no-average can not be used together");
+                            return None;
+                        }
+
+                        if resume || append || reverse {
+                            println!("-viz can not be used with -resume, -append, or -reverse");
+                            return None;
+                        }
+                    }
+
+                    if oversample > 1 && resume {
+                        println!("-oversample and -resume can not be used together");
+                        return None;
+                    }
+
+                    if reverse && resume {
+                        println!("-reverse and -resume can not be used together");
+                        return None;
+                    }
+
+                    if write_to_stdout && resume {
+                        println!("-resume can not be used when writing to stdout");
+                        return None;
+                    }
+
+                    if write_to_stdout && atomic {
+                        println!("-atomic can not be used when writing to stdout");
+                        return None;
+                    }
+
+                    if trim_silence.is_some() {
+                        // The partial output's length depends on how much silence was trimmed
+                        if resume {
+                            println!("-trim-silence and -resume can not be used together");
+                            return None;
+                        }
+                    } else if trim_silence_min_seconds.is_some() {
+                        println!("-trim-silence-min requires -trim-silence");
+                        return None;
+                    }
+
+                    if mono_check_path.is_some() {
+                        if ambisonic_order.is_some() {
+                            println!("-mono-check only works when upmixing to speakers");
+                            return None;
+                        }
+
+                        if resume {
+                            println!("-mono-check and -resume can not be used together");
+                            return None;
+                        }
+                    }
+
+                    if debug_center_path.is_some() {
+                        // (Ambisonics never transform the mono channel)
+                        if !transform_mono {
+                            println!("-debug-center only works when upmixing with a center or an LFE channel");
+                            return None;
+                        }
+
+                        if resume {
+                            println!("-debug-center and -resume can not be used together");
+                            return None;
+                        }
+                    }
+
+                    // -append resumes from the end of the previous output, so it has the same limitations as -resume.
+                    // The window must also be the same as when the previous output was upmixed
+                    if append {
+                        if resume {
+                            println!("-append and -resume can not be used together");
+                            return None;
+                        }
+
+                        if write_to_stdout {
+                            println!("-append can not be used when writing to stdout");
+                            return None;
+                        }
+
+                        // (The source's length is saved as how much was upmixed)
+                        if max_duration.is_some() {
+                            println!("-append and -max-duration can not be used together");
+                            return None;
+                        }
+
+                        if lfe_file_path.is_some()
+                            || dump_window.is_some()
+                            || mono_check_path.is_some()
+                            || debug_center_path.is_some()
+                            || trim_silence.is_some()
+                            || oversample > 1
+                            || reverse
+                            || auto_window
+                        {
+                            println!("-append can not be used with -lfe-file, -dump-window, -mono-check, -debug-center, -trim-silence, -oversample, -reverse, or -auto-window");
+                            return None;
+                        }
+                    }
+
+                    if let CenterHighpassRedirect::Lfe = center_highpass_redirect {
+                        if !derives_lfe {
+                            println!(
+                                "-center-highpass-redirect lfe only works when upmixing with an LFE channel, or with -lfe-file"
+                            );
+                            return None;
+                        }
+                    }
+
+                    if let CenterMethod::Correlated = center_method {
+                        if !channels.front_center || ambisonic_order.is_some() {
+                            println!(
+                                "-center-method correlated only works when upmixing with a center"
+                            );
+                            return None;
+                        }
+
+                        // sq steers the center from its own left-to-right panning
+                        if matrix.steer_right_left() {
+                            println!(
+                                "-center-method correlated does not work with {}",
+                                matrix_name
+                            );
+                            return None;
+                        }
+                    }
+
+                    if let CenterPhase::Fronts = center_phase {
+                        if !channels.front_center || ambisonic_order.is_some() {
+                            println!("-center-phase fronts only works when upmixing with a center");
+                            return None;
+                        }
+                    }
+
+                    if center_band.is_some() {
+                        if !channels.front_center || ambisonic_order.is_some() {
+                            println!("-center-band only works when upmixing with a center");
+                            return None;
+                        }
+
+                        // sq steers the center from its own left-to-right panning
+                        if matrix.steer_right_left() {
+                            println!("-center-band does not work with {}", matrix_name);
+                            return None;
+                        }
+                    }
+
+                    if center_ceiling.is_some() {
+                        if !channels.front_center || ambisonic_order.is_some() {
+                            println!("-center-ceiling only works when upmixing with a center");
+                            return None;
+                        }
+
+                        // The fronts are the source, so what's removed from the center can't be moved to them
+                        if stereo_passthrough {
+                            println!(
+                                "-center-ceiling and -stereo-passthrough can not be used together"
+                            );
+                            return None;
+                        }
+                    }
+
+                    let lfe_source = match lfe_source {
+                        Some(lfe_source) => {
+                            if !derives_lfe || ambisonic_order.is_some() {
+                                println!(
+                                    "-lfe-source only works when upmixing with an LFE channel, or with -lfe-file"
+                                );
+                                return None;
+                            }
+
+                            lfe_source
+                        }
+                        None => LfeSource::Mono,
+                    };
+
+                    if lfe_slope.is_some() && (!derives_lfe || ambisonic_order.is_some()) {
+                        println!(
+                            "-lfe-slope only works when upmixing with an LFE channel, or with -lfe-file"
+                        );
+                        return None;
+                    }
+
+                    let mut written_channels = match write_channels {
+                        Some(write_channels) => {
+                            if ambisonic_order.is_some() {
+                                println!("-write-channels only works when upmixing to speakers");
+                                return None;
+                            }
+
+                            let mut written_channels = Channels::new();
+                            for channel in write_channels {
+                                if !channel.is_in(&channels) {
+                                    println!("-write-channels can only write channels that are in the channel layout");
+                                    return None;
+                                }
+
+                                channel.add_to(&mut written_channels);
+                            }
+
+                            written_channels
+                        }
+                        // -match-layout: Only the reference's channels are written, so the output has the same
+                        // channels, in the same order
+                        None => match_layout.unwrap_or(channels),
+                    };
+
+                    if lfe_file_only {
+                        written_channels.low_frequency = false;
+                    }
+
+                    if written_channels.count() == 0 {
+                        println!("At least one channel must be written");
+                        return None;
+                    }
+
+                    if no_average
+                        && (smoothing_passes > 1 || matches!(averaging, Averaging::Median))
+                    {
+                        println!("-no-average can not be used with -average or -smoothing-passes");
+                        return None;
+                    }
+
+                    if no_average && frequency_smoothing > 0 {
+                        println!("-no-average can not be used with -freq-smooth");
+                        return None;
+                    }
+
+                    let loud = if transform_mono {
+                        loud.unwrap_or(false)
+                    } else {
+                        if loud.is_some() {
+                            println!("-loud and -quiet only work when upmixing with an LFE or a center channel");
+                            return None;
+                        }
+
+                        true
+                    };
+
+                    return Some(Options {
+                        source_wav_path: source_wav_path.into(),
+                        target_wav_path: target_wav_path.into(),
+                        num_threads,
+                        thread_ramp,
+                        max_cpu,
+                        max_memory_mb,
+                        transform_mono,
+                        channels,
+                        written_channels,
+                        ambisonic_order,
+                        matrix,
+                        low_frequency,
+                        minimum_steered_amplitude,
+                        input_gain,
+                        silent_channel,
+                        analysis,
+                        rear_gate,
+                        rear_band,
+                        max_rear,
+                        mono_detect,
+                        widen_image,
+                        center_focus,
+                        mono_surround,
+                        front_width,
+                        crossfeed,
+                        rear_channels,
+                        flip_front_back,
+                        averaging,
+                        smoothing_passes,
+                        frequency_smoothing,
+                        no_average,
+                        remove_dc,
+                        balance_input,
+                        sanitize,
+                        align_input,
+                        center_highpass,
+                        center_highpass_redirect,
+                        center_method,
+                        center_phase,
+                        center_adjustment,
+                        center_band,
+                        lfe_source,
+                        lfe_slope,
+                        rear_phase,
+                        eq,
+                        stereo_passthrough,
+                        no_symmetry_shortcut,
+                        align_pulse,
+                        mantissa_bits,
+                        auto_headroom,
+                        center_ceiling,
+                        oversample,
+                        meters,
+                        verbose,
+                        flush_interval,
+                        resume,
+                        append,
+                        atomic,
+                        trim_silence,
+                        trim_silence_min_seconds: trim_silence_min_seconds
+                            .unwrap_or(reader::DEFAULT_TRIM_SILENCE_MIN_SECONDS),
+                        max_duration,
+                        reverse,
+                        single_window,
+                        in_memory,
+                        auto_window,
+                        draft,
+                        window_size,
+                        no_split,
+                        keep_awake,
+                        diag,
+                        loud,
+                        report_file_path,
+                        mono_check_path,
+                        lfe_file_path,
+                        debug_center_path,
+                        dump_window,
+                        viz_path,
+                        matrix_name,
+                    });
+                }
+            }
+        }
+    }
+}
+
+// Parses a single channel:frequency:gain in -eq
+// Channel names, as used by -eq and -write-channels
+fn parse_output_channel(channel_string: &str) -> Option<OutputChannel> {
+    if channel_string.eq("left") {
+        Some(OutputChannel::Left)
+    } else if channel_string.eq("right") {
+        Some(OutputChannel::Right)
+    } else if channel_string.eq("center") {
+        Some(OutputChannel::Center)
+    } else if channel_string.eq("lfe") {
+        Some(OutputChannel::Lfe)
+    } else if channel_string.eq("rear-left") {
+        Some(OutputChannel::RearLeft)
+    } else if channel_string.eq("rear-right") {
+        Some(OutputChannel::RearRight)
+    } else if channel_string.eq("surround") {
+        Some(OutputChannel::Surround)
+    } else {
+        None
+    }
+}
+
+// -match-layout: The channel layout and number of rear channels that derive all of the reference's channels, or why
+// the reference can't be matched
+fn layout_to_match(reference: &Channels) -> Result<(ChannelLayout, usize), String> {
+    let mut underivable = *reference;
+    underivable.front_left = false;
+    underivable.front_right = false;
+    underivable.front_center = false;
+    underivable.low_frequency = false;
+    underivable.back_left = false;
+    underivable.back_right = false;
+    underivable.back_center = false;
+    underivable.side_left = false;
+    underivable.side_right = false;
+
+    let underivable_names = report::channel_names(&underivable);
+    if !underivable_names.is_empty() {
+        return Err(format!(
+            "{} can not be derived from stereo",
+            underivable_names.join(", ")
+        ));
+    }
+
+    let rears = reference.back_left || reference.back_right;
+    let sides = reference.side_left || reference.side_right;
+
+    if sides {
+        // The sides are only derived when the rears are spread across more positions, (-rear-channels,) which
+        // also uses the rear left and right
+        if !rears {
+            return Err(
+                "side_left and side_right are only derived along with back_left and back_right"
+                    .to_string(),
+            );
+        }
+
+        let layout = if reference.low_frequency {
+            ChannelLayout::FiveOne
+        } else if reference.front_center {
+            ChannelLayout::Five
+        } else {
+            ChannelLayout::Four
+        };
+
+        Ok((layout, if reference.back_center { 5 } else { 4 }))
+    } else if reference.back_center {
+        if rears || reference.low_frequency {
+            Ok((ChannelLayout::SixOne, 2))
+        } else {
+            Ok((ChannelLayout::Surround, 2))
+        }
+    } else if reference.low_frequency {
+        Ok((ChannelLayout::FiveOne, 2))
+    } else if rears {
+        if reference.front_center {
+            Ok((ChannelLayout::Five, 2))
+        } else {
+            Ok((ChannelLayout::Four, 2))
+        }
+    } else {
+        Ok((ChannelLayout::Three, 2))
+    }
+}
+
+fn parse_eq_band(band_string: &str) -> Option<EqBand> {
+    let parts: Vec<&str> = band_string.split(':').collect();
+    if parts.len() != 3 {
+        println!(
+            "EQ bands must be channel:frequency:gain, like center:1000:+3. Got: {}",
+            band_string
+        );
+        return None;
+    }
+
+    let channel = match parse_output_channel(parts[0]) {
+        Some(channel) => channel,
+        None => {
+            println!("Unknown EQ channel: {}", parts[0]);
+            return None;
+        }
+    };
+
+    let frequency = match parts[1].parse::<f32>() {
+        Ok(frequency) => {
+            if frequency <= 0.0 {
+                println!("EQ frequency must be > 0: {}", frequency);
+                return None;
+            }
+
+            frequency
+        }
+        Err(_) => {
+            println!("EQ frequency must be a number: {}", parts[1]);
+            return None;
+        }
+    };
+
+    let gain_db = match parts[2].parse::<f32>() {
+        Ok(gain_db) => gain_db,
+        Err(_) => {
+            println!("EQ gain must be a number of dB: {}", parts[2]);
+            return None;
+        }
+    };
+
+    Some(EqBand {
+        channel,
+        frequency,
+        gain_db,
+    })
+}
+
+// soft_matrix [source] [destination] -compare [settings A] [settings B] upmixes twice, and writes the difference
+pub struct CompareOptions {
+    pub settings_a: String,
+    pub settings_b: String,
+    // The targets of options_a and options_b are "[destination] - A" and "[destination] - B"
+    pub options_a: Options,
+    pub options_b: Options,
+    // B - A, for each channel
+    pub difference_wav_path: Box<Path>,
+}
+
+impl CompareOptions {
+    pub fn is_requested() -> bool {
+        env::args().skip(3).any(|flag| flag.eq("-compare"))
+    }
+
+    pub fn parse() -> Option<CompareOptions> {
+        let args: Vec<String> = env::args().collect();
+
+        if args.len() < 3 {
+            println!(
+                "Usage: soft_matrix [source] [destination] -compare [settings A] [settings B]"
+            );
+            return None;
+        }
+
+        // Flags outside of -compare are shared by both upmixes
+        let mut shared_args = Vec::with_capacity(args.len());
+        let mut settings = None;
+
+        let mut args_iter = args.into_iter();
+        while let Some(arg) = args_iter.next() {
+            if arg.eq("-compare") {
+                if settings.is_some() {
+                    println!("-compare can only be specified once");
+                    return None;
+                }
+
+                match (args_iter.next(), args_iter.next()) {
+                    (Some(settings_a), Some(settings_b)) => {
+                        settings = Some((settings_a, settings_b))
+                    }
+                    _ => {
+                        println!("-compare needs two settings, each quoted. Example: -compare \"-matrix sq\" \"-matrix qs\"");
+                        return None;
+                    }
+                }
+            } else {
+                shared_args.push(arg);
+            }
+        }
+
+        // is_requested() already checked that -compare is present
+        let (settings_a, settings_b) = settings.unwrap();
+
+        if shared_args[2].eq("-") {
+            println!("-compare can not write to stdout");
+            return None;
+        }
+
+        let difference_wav_path = Path::new(shared_args[2].as_str());
+        let file_stem = match difference_wav_path.file_stem() {
+            Some(file_stem) => file_stem.to_string_lossy(),
+            None => {
+                println!("Not a valid filename: {}", difference_wav_path.display());
+                return None;
+            }
+        };
+        let extension = match difference_wav_path.extension() {
+            Some(extension) => extension.to_string_lossy(),
+            None => "wav".into(),
+        };
+        let folder = difference_wav_path.parent().unwrap_or(Path::new(""));
+
+        let options_a = CompareOptions::parse_settings(
+            &shared_args,
+            &settings_a,
+            &folder.join(format!("{} - A.{}", file_stem, extension)),
+        )?;
+        let options_b = CompareOptions::parse_settings(
+            &shared_args,
+            &settings_b,
+            &folder.join(format!("{} - B.{}", file_stem, extension)),
+        )?;
+
+        if options_a.resume || options_b.resume {
+            println!("-resume can not be used with -compare");
+            return None;
+        }
+
+        if options_a.append || options_b.append {
+            println!("-append can not be used with -compare");
+            return None;
+        }
+
+        if options_a.written_channels != options_b.written_channels {
+            println!("Both settings in -compare must upmix to the same channels");
+            return None;
+        }
+
+        if options_a.report_file_path.is_some()
+            && options_a.report_file_path == options_b.report_file_path
+        {
+            println!("Both settings in -compare can not write the same -report-file");
+            return None;
+        }
+
+        if options_a.mono_check_path.is_some()
+            && options_a.mono_check_path == options_b.mono_check_path
+        {
+            println!("Both settings in -compare can not write the same -mono-check");
+            return None;
+        }
+
+        if options_a.lfe_file_path.is_some() && options_a.lfe_file_path == options_b.lfe_file_path {
+            println!("Both settings in -compare can not write the same -lfe-file");
+            return None;
+        }
+
+        if options_a.debug_center_path.is_some()
+            && options_a.debug_center_path == options_b.debug_center_path
+        {
+            println!("Both settings in -compare can not write the same -debug-center");
+            return None;
+        }
+
+        if let (Some((_, dump_window_path_a)), Some((_, dump_window_path_b))) =
+            (&options_a.dump_window, &options_b.dump_window)
+        {
+            if dump_window_path_a == dump_window_path_b {
+                println!("Both settings in -compare can not write the same -dump-window");
+                return None;
+            }
+        }
+
+        if options_a.viz_path.is_some() && options_a.viz_path == options_b.viz_path {
+            println!("Both settings in -compare can not write the same -viz");
+            return None;
+        }
+
+        Some(CompareOptions {
+            settings_a,
+            settings_b,
+            options_a,
+            options_b,
+            difference_wav_path: difference_wav_path.into(),
+        })
+    }
+
+    fn parse_settings(
+        shared_args: &[String],
+        settings: &str,
+        target_wav_path: &Path,
+    ) -> Option<Options> {
+        let mut args = shared_args.to_vec();
+        args[2] = target_wav_path.to_string_lossy().into_owned();
+        args.extend(settings.split_whitespace().map(String::from));
+
+        let mut options = Options::parse_args(args)?;
+
+        // The difference can only be written if each upmix is in a single file
+        options.no_split = true;
+
+        Some(options)
+    }
+}
+
+// soft_matrix -manifest [manifest] [options] upmixes each source and destination listed in the manifest, one after
+// the other. Each line is: [source] [destination] [options for only this line]
+pub struct ManifestOptions {
+    pub entries: Vec<ManifestEntry>,
+}
+
+pub struct ManifestEntry {
+    pub line_number: usize,
+    pub source: String,
+    pub destination: String,
+    // None when the line's options are invalid. (The error is already printed)
+    pub options: Option<Options>,
+}
+
+impl ManifestOptions {
+    pub fn is_requested() -> bool {
+        match env::args().nth(1) {
+            Some(flag) => flag.eq("-manifest"),
+            None => false,
+        }
+    }
+
+    pub fn parse() -> Option<ManifestOptions> {
+        let args: Vec<String> = env::args().collect();
+
+        if args.len() < 3 {
+            println!("Usage: soft_matrix -manifest [manifest] [options]");
+            return None;
+        }
+
+        let manifest_path = Path::new(args[2].as_str());
+        let manifest = match fs::read_to_string(manifest_path) {
+            Ok(manifest) => manifest,
+            Err(error) => {
+                println!("Can not read {}: {:?}", manifest_path.display(), error);
+                return None;
+            }
+        };
+
+        // Options after the manifest are shared by every line
+        let shared_args = &args[3..];
+
+        let mut entries = Vec::new();
+        for (line_ctr, line) in manifest.lines().enumerate() {
+            let line_number = line_ctr + 1;
+
+            // Blank lines and comments are skipped
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut line_args = match split_manifest_line(line) {
+                Some(line_args) => line_args.into_iter(),
+                None => {
+                    println!(
+                        "Line {} of the manifest has an unterminated quote",
+                        line_number
+                    );
+                    return None;
+                }
+            };
+
+            let (source, destination) = match (line_args.next(), line_args.next()) {
+                (Some(source), Some(destination)) => (source, destination),
+                _ => {
+                    println!(
+                        "Line {} of the manifest needs a source and a destination",
+                        line_number
+                    );
+                    return None;
+                }
+            };
+
+            if destination.eq("-") {
+                println!(
+                    "Line {} of the manifest can not write to stdout",
+                    line_number
+                );
+                return None;
+            }
+
+            // Options on the line come after the shared options, so they take precedence
+            let mut entry_args = vec![args[0].clone(), source.clone(), destination.clone()];
+            entry_args.extend(shared_args.iter().cloned());
+            entry_args.extend(line_args);
+
+            let options = Options::parse_args(entry_args);
+            if options.is_none() {
+                println!(
+                    "Line {} of the manifest has invalid options, and will be skipped",
+                    line_number
+                );
+            }
+
+            entries.push(ManifestEntry {
+                line_number,
+                source,
+                destination,
+                options,
+            });
+        }
+
+        if entries.is_empty() {
+            println!("{} doesn't list anything to upmix", manifest_path.display());
+            return None;
+        }
+
+        Some(ManifestOptions { entries })
+    }
+}
+
+// Splits a line of a manifest on whitespace. Paths with spaces are quoted, like "Side A.wav". None when a quote isn't
+// terminated
+fn split_manifest_line(line: &str) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut in_quotes = false;
+
+    for c in line.chars() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+            in_arg = true;
+        } else if c.is_whitespace() && !in_quotes {
+            if in_arg {
+                args.push(std::mem::take(&mut arg));
+                in_arg = false;
+            }
+        } else {
+            arg.push(c);
+            in_arg = true;
+        }
+    }
+
+    if in_quotes {
+        return None;
+    }
+
+    if in_arg {
+        args.push(arg);
+    }
+
+    Some(args)
+}
+
+// soft_matrix -gen-test [type] [destination] synthesizes a stereo test file instead of upmixing
+// soft_matrix -info [source]
+pub struct InfoOptions {
+    pub source_wav_path: Box<Path>,
+}
+
+impl InfoOptions {
+    pub fn is_requested() -> bool {
+        match env::args().nth(1) {
+            Some(flag) => flag.eq("-info"),
+            None => false,
+        }
+    }
+
+    pub fn parse() -> Option<InfoOptions> {
+        let args: Vec<String> = env::args().collect();
+
+        if args.len() != 3 {
+            println!("Usage: soft_matrix -info [source]");
+            return None;
+        }
+
+        Some(InfoOptions {
+            source_wav_path: Path::new(args[2].as_str()).into(),
+        })
+    }
+}
+
+pub struct GenerateTestOptions {
+    pub test_signal: TestSignal,
+    pub target_wav_path: Box<Path>,
+    pub noise: TestNoise,
+    // Seeds the noise generator. The same seed always generates the same noise. (Seeded from the clock when None)
+    pub seed: Option<u64>,
+}
+
+pub enum TestSignal {
+    CorrelatedNoise,
+    AnticorrelatedNoise,
+    Sweep,
+    RearPulse,
+}
+
+pub enum TestNoise {
+    Pink,
+    White,
+}
+
+impl GenerateTestOptions {
+    pub fn is_requested() -> bool {
+        match env::args().nth(1) {
+            Some(flag) => flag.eq("-gen-test"),
+            None => false,
+        }
+    }
+
+    pub fn parse() -> Option<GenerateTestOptions> {
+        let args: Vec<String> = env::args().collect();
+
+        if args.len() < 4 {
+            println!("Usage: soft_matrix -gen-test [correlated|anticorrelated|sweep|rear-pulse] [destination]");
+            return None;
+        }
+
+        let mut args_iter = args.into_iter();
+
+        // ignore the executable name and -gen-test
+        let _ = args_iter.next().unwrap();
+        let _ = args_iter.next().unwrap();
+
+        let test_signal_string = args_iter.next().unwrap();
+        let test_signal = if test_signal_string.eq("correlated") {
+            TestSignal::CorrelatedNoise
+        } else if test_signal_string.eq("anticorrelated") {
+            TestSignal::AnticorrelatedNoise
+        } else if test_signal_string.eq("sweep") {
+            TestSignal::Sweep
+        } else if test_signal_string.eq("rear-pulse") {
+            TestSignal::RearPulse
+        } else {
+            println!("Unknown test type: {}", test_signal_string);
+            return None;
+        };
+
+        let target_wav_path = args_iter.next().unwrap();
+        let target_wav_path = Path::new(target_wav_path.as_str());
+
+        let mut noise = TestNoise::Pink;
+        let mut seed = None;
+
+        loop {
+            match args_iter.next() {
+                Some(flag) => {
+                    if flag.eq("-noise") {
+                        match args_iter.next() {
+                            Some(noise_string) => {
+                                if noise_string.eq("pink") {
+                                    noise = TestNoise::Pink
+                                } else if noise_string.eq("white") {
+                                    noise = TestNoise::White
+                                } else {
+                                    println!("Unknown noise: {}", noise_string);
+                                    return None;
+                                }
+                            }
+                            None => {
+                                println!("Noise unspecified");
+                                return None;
+                            }
+                        }
+                    } else if flag.eq("-seed") {
+                        match args_iter.next() {
+                            Some(seed_string) => match seed_string.parse::<u64>() {
+                                Ok(seed_value) => seed = Some(seed_value),
+                                Err(_) => {
+                                    println!("Can not parse the seed: {}", seed_string);
+                                    return None;
+                                }
+                            },
+                            None => {
+                                println!("Seed unspecified");
+                                return None;
+                            }
+                        }
+                    } else {
+                        println!("Unknown flag: {}", flag);
+                        return None;
+                    }
+                }
+                None => {
+                    return Some(GenerateTestOptions {
+                        test_signal,
+                        target_wav_path: target_wav_path.into(),
+                        noise,
+                        seed,
                     });
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use wave_stream::wave_header::Channels;
+
+    use super::Options;
+
+    use crate::test_support::{parse_flags, sine, upmix_stereo};
+
+    #[test]
+    fn three_point_zero_writes_no_back_channels() {
+        // Out of phase, so that it would be steered to the rear in a layout with rears
+        let left = sine(1000.0, 0.5, 4410);
+        let right: Vec<f32> = left.iter().map(|sample| -sample).collect();
+
+        let upmixed = upmix_stereo(&left, &right, &["-low", "1000", "-channels", "3.0"])
+            .expect("Upmixing failed");
+        assert_eq!(
+            Channels::new().front_left().front_right().front_center(),
+            upmixed.channels
+        );
+
+        // Nothing is steered to the rear, so the rear sound stays in the fronts
+        let front_peak = upmixed
+            .samples
+            .iter()
+            .map(|samples| samples.front_left.unwrap().abs())
+            .fold(0.0f32, f32::max);
+        assert!(front_peak > 0.1, "The front left peaks at {}", front_peak);
+    }
+
+    #[test]
+    fn horseshoe_parses_with_its_widen_factor() {
+        // Panned halfway to the left
+        let steer = |options: &Options| options.matrix.steer(0.75, 0.0, 0.25, 0.0);
+
+        // DEFAULT_HORSESHOE_WIDEN_FACTOR widens halfway to the left to all the way left, but not past it
+        let options = parse_flags(&["-matrix", "horseshoe"]).expect("Invalid flags");
+        assert_eq!("horseshoe", options.matrix_name);
+        let pans = steer(&options);
+        assert_eq!(-1.0, pans.left_to_right);
+        assert_eq!(0.0, pans.back_to_front);
+
+        // A larger widen factor steers past the side, towards the rear
+        let options = parse_flags(&["-matrix", "horseshoe", "-widen", "3"]).expect("Invalid flags");
+        let pans = steer(&options);
+        assert_eq!(-1.0, pans.left_to_right);
+        assert!((pans.back_to_front - 0.5).abs() < 0.0001);
+
+        // Only the horseshoe matrix widens
+        assert!(parse_flags(&["-matrix", "default", "-widen", "3"]).is_none());
+    }
+
+    #[test]
+    fn stereo_passthrough_rejects_flags_that_change_the_source() {
+        assert!(parse_flags(&["-stereo-passthrough"]).is_some());
+        assert!(parse_flags(&["-stereo-passthrough", "-input-gain", "0"]).is_some());
+
+        for flags in [
+            &["-input-gain", "3"][..],
+            &["-remove-dc"],
+            &["-balance-input"],
+            &["-align-input"],
+        ] {
+            assert!(
+                parse_flags(&[&["-stereo-passthrough"][..], flags].concat()).is_none(),
+                "-stereo-passthrough was accepted with {:?}",
+                flags
+            );
+        }
+    }
+}
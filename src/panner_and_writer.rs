@@ -2,21 +2,65 @@ use std::{
     collections::VecDeque,
     f32::consts::PI,
     io::Result,
+    path::Path,
     sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 pub const LFE_START: f32 = 40.0;
 const LFE_FULL: f32 = 20.0;
+
+// -lfe-slope: The Butterworth low-pass is -3 dB here, the same as the gentle taper
+const LFE_CROSSOVER: f32 = (LFE_FULL + LFE_START) / 2.0;
+// An 8th order Butterworth filter
+pub const MAX_LFE_SLOPE: u32 = 48;
 const HALF_PI: f32 = PI / 2.0;
 
+// -rear-channels: Side left, rear left, rear center, rear right, and side right are the speaker positions behind the
+// listener that a wav file can have
+pub const MAX_REAR_CHANNELS: usize = 5;
+
+// A 32-bit float's mantissa, not including the implied leading 1
+pub const MAX_MANTISSA_BITS: u32 = f32::MANTISSA_DIGITS - 1;
+
+// -auto-headroom: How long the gain takes to recover after lowering the output, unless -auto-headroom-release is
+// specified. Long enough that the recovery is usually inaudible
+pub const DEFAULT_AUTO_HEADROOM_RELEASE_SECONDS: f32 = 2.0;
+
+// -align-pulse: The first samples of each output file, in every channel. A full-scale doublet is easy to find, both by
+// eye in an editor and by a script
+pub const ALIGN_PULSE: [f32; 2] = [1.0, -1.0];
+
+// The poles of the all-pass filters in -rear-phase broadband-90, spread logarithmically across the audio band
+const REAR_PHASE_POLES: [f32; 4] = [40.0, 250.0, 1600.0, 10000.0];
+
+// -channels 5.1-mono: Pans are narrowed to this fraction of their width, so nothing is hard-panned in the fronts, and
+// the rears get this fraction of what they would otherwise get
+const MONO_SURROUND_WIDTH: f32 = 0.2;
+const MONO_SURROUND_AMBIENCE: f32 = 0.5;
+
+// Debug builds check that each inverse transform is (nearly) real: Its imaginary parts, which are discarded, must be
+// smaller than this fraction of its real parts. Anything more means that the bins aren't conjugate-symmetric
+const MAX_IMAGINARY_FRACTION: f32 = 0.001;
+
 use rustfft::{num_complex::Complex, Fft};
-use wave_stream::{samples_by_channel::SamplesByChannel, wave_writer::RandomAccessWavWriter};
+use wave_stream::{
+    samples_by_channel::SamplesByChannel,
+    wave_header::{Channels, SampleFormat, WavHeader},
+    wave_writer::RandomAccessWavWriter,
+    write_wav_to_file_path,
+};
 
 use crate::{
-    matrix,
-    options::Options,
-    structs::{ThreadState, TransformedWindowAndPans},
+    ambisonics,
+    options::{
+        CenterHighpassRedirect, CenterMethod, CenterPhase, EqBand, LfeSource, Options,
+        OutputChannel, RearPhase,
+    },
+    report::{self, Meters, Report},
+    structs::{DcOffset, ResumePoint, ThreadState, TransformedWindowAndPans},
     upmixer::Upmixer,
+    window_sizes::bin_frequency,
 };
 
 pub struct PannerAndWriter {
@@ -30,13 +74,77 @@ pub struct PannerAndWriter {
 
     lfe_levels: Option<Vec<f32>>,
 
+    // Only present when -center-highpass is specified
+    center_highpass_levels: Option<Vec<f32>>,
+    // How much of the center is extracted at each frequency. Only present for -center-band
+    center_band_levels: Option<Vec<f32>>,
+
+    // Only present for channels adjusted with -eq
+    eq_levels: EqLevels,
+
+    // Added to the phase of both rears, for each frequency. Only present for -rear-phase broadband-90
+    rear_phase_offsets: Option<Vec<f32>>,
+
     max_samples_in_file: usize,
+
+    // Only present when -mantissa-bits is specified
+    mantissa_bits: Option<u32>,
+
+    // Via -oversample: Only every oversample-th sample is written
+    oversample: usize,
+    // Via -align-pulse
+    align_pulse: bool,
+    // Via -sanitize
+    sanitize: bool,
+    // Via -center-law
+    center_adjustment: f32,
+    // Via -rear-channels. 2 is the rear left and right
+    rear_channels: usize,
+    // False for layouts without rear channels, where nothing is steered to the rear
+    has_rears: bool,
+
+    // Channels that are upmixed, but not written, are removed before writing
+    written_channels: Channels,
+
+    resume_point: ResumePoint,
+}
+
+// The level of each frequency in the transform, for each channel adjusted with -eq
+struct EqLevels {
+    left_front: Option<Vec<f32>>,
+    right_front: Option<Vec<f32>>,
+    center: Option<Vec<f32>>,
+    lfe: Option<Vec<f32>>,
+    // In the surround layout, the surround channel is the average of the rears, so its EQ is applied to both
+    left_rear: Option<Vec<f32>>,
+    right_rear: Option<Vec<f32>>,
+    // Only for 6.1
+    back_center: Option<Vec<f32>>,
 }
 
 // Wraps types used during writing so they can be within a mutex
 struct WriterState {
     pub target_random_access_wav_writers: Vec<RandomAccessWavWriter<f32>>,
     pub total_samples_written: usize,
+    // Only present when -report-file is specified
+    pub report: Option<Report>,
+    // Only present when -mono-check is specified
+    pub mono_check_random_access_wav_writer: Option<RandomAccessWavWriter<f32>>,
+    // Only present when -lfe-file is specified
+    pub lfe_random_access_wav_writer: Option<RandomAccessWavWriter<f32>>,
+    // Only present when -debug-center is specified
+    pub debug_center_random_access_wav_writer: Option<RandomAccessWavWriter<f32>>,
+    // Only present when -meters is specified
+    pub meters: Option<Meters>,
+    // Only present when -auto-headroom is specified
+    pub auto_headroom: Option<Limiter>,
+    // Only present when -center-ceiling is specified
+    pub center_ceiling: Option<Limiter>,
+    // Only present when -flush-interval is specified
+    pub flush_interval: Option<Duration>,
+    pub last_flush: Instant,
+    // -sanitize: The number of samples that weren't finite after steering, and were written as silence
+    pub non_finite_samples: usize,
 }
 
 impl PannerAndWriter {
@@ -47,33 +155,38 @@ impl PannerAndWriter {
         target_random_access_wav_writers: Vec<RandomAccessWavWriter<f32>>,
         fft_inverse: Arc<dyn Fft<f32>>,
         max_samples_in_file: usize,
-    ) -> PannerAndWriter {
-        let lfe_levels = if options.channels.low_frequency {
+        resume_point: ResumePoint,
+    ) -> Result<PannerAndWriter> {
+        // With -oversample, the transforms are at a higher sample rate than the source and the output
+        let transform_sample_rate = sample_rate * options.oversample;
+
+        let lfe_levels = if options.channels.low_frequency || options.lfe_file_path.is_some() {
             let mut lfe_levels = vec![0.0f32; window_size];
             let window_midpoint = window_size / 2;
 
-            let sample_rate_f32 = sample_rate as f32;
-            let window_size_f32 = window_size as f32;
-
             lfe_levels[0] = 1.0;
             lfe_levels[window_midpoint] = 0.0;
 
             // Calculate ranges for averaging each sub frequency
             for transform_index in 1..(window_midpoint - 2) {
-                let transform_index_f32 = transform_index as f32;
-                // Out of 8
-                // 1, 2, 3, 4
-                // 8, 4, 2, 1
-                let wavelength = window_size_f32 / transform_index_f32;
-                let frequency = sample_rate_f32 / wavelength;
-
-                let level = if frequency < LFE_FULL {
-                    1.0
-                } else if frequency < LFE_START {
-                    let frequency_fraction = (frequency - LFE_FULL) / LFE_FULL;
-                    (frequency_fraction * HALF_PI).cos()
-                } else {
-                    0.0
+                let frequency = bin_frequency(transform_index, window_size, transform_sample_rate);
+
+                let level = match options.lfe_slope {
+                    // Each order of a Butterworth filter is 6 dB per octave
+                    Some(lfe_slope) => {
+                        let order = (lfe_slope / 6) as i32;
+                        1.0 / (1.0 + (frequency / LFE_CROSSOVER).powi(2 * order)).sqrt()
+                    }
+                    None => {
+                        if frequency < LFE_FULL {
+                            1.0
+                        } else if frequency < LFE_START {
+                            let frequency_fraction = (frequency - LFE_FULL) / LFE_FULL;
+                            (frequency_fraction * HALF_PI).cos()
+                        } else {
+                            0.0
+                        }
+                    }
                 };
 
                 lfe_levels[transform_index] = level;
@@ -85,16 +198,212 @@ impl PannerAndWriter {
             None
         };
 
-        PannerAndWriter {
+        let center_highpass_levels = match options.center_highpass {
+            Some(center_highpass) => {
+                let mut center_highpass_levels = vec![1.0f32; window_size];
+                let window_midpoint = window_size / 2;
+
+                // The taper starts an octave below the cutoff
+                let center_highpass_start = center_highpass / 2.0;
+
+                center_highpass_levels[0] = 0.0;
+
+                for transform_index in 1..window_midpoint {
+                    let frequency =
+                        bin_frequency(transform_index, window_size, transform_sample_rate);
+
+                    let level = if frequency < center_highpass_start {
+                        0.0
+                    } else if frequency < center_highpass {
+                        let frequency_fraction =
+                            (frequency - center_highpass_start) / center_highpass_start;
+                        ((1.0 - frequency_fraction) * HALF_PI).cos()
+                    } else {
+                        1.0
+                    };
+
+                    center_highpass_levels[transform_index] = level;
+                    center_highpass_levels[window_size - transform_index] = level;
+                }
+
+                Some(center_highpass_levels)
+            }
+            None => None,
+        };
+
+        let center_band_levels = options
+            .center_band
+            .map(|(center_band_low, center_band_high)| {
+                let mut center_band_levels = vec![1.0f32; window_size];
+                let window_midpoint = window_size / 2;
+
+                // Extraction tapers off over an octave on either side of the band
+                let low_start = center_band_low / 2.0;
+                let high_end = center_band_high * 2.0;
+
+                for transform_index in 1..(window_midpoint + 1) {
+                    let frequency =
+                        bin_frequency(transform_index, window_size, transform_sample_rate);
+
+                    let level = if frequency < low_start || frequency >= high_end {
+                        0.0
+                    } else if frequency < center_band_low {
+                        let frequency_fraction = (frequency - low_start) / low_start;
+                        ((1.0 - frequency_fraction) * HALF_PI).cos()
+                    } else if frequency > center_band_high {
+                        let frequency_fraction = (frequency - center_band_high) / center_band_high;
+                        (frequency_fraction * HALF_PI).cos()
+                    } else {
+                        1.0
+                    };
+
+                    center_band_levels[transform_index] = level;
+                    center_band_levels[window_size - transform_index] = level;
+                }
+
+                center_band_levels
+            });
+
+        let eq_levels_for = |eq_channels: &[OutputChannel]| {
+            eq_levels(&options.eq, eq_channels, window_size, transform_sample_rate)
+        };
+        // In the surround layout, the single rear channel is the average of the rears
+        let surround_layout = options.channels.back_center && !options.channels.back_left;
+        let eq_levels = if surround_layout {
+            EqLevels {
+                left_front: eq_levels_for(&[OutputChannel::Left]),
+                right_front: eq_levels_for(&[OutputChannel::Right]),
+                center: eq_levels_for(&[OutputChannel::Center]),
+                lfe: eq_levels_for(&[OutputChannel::Lfe]),
+                left_rear: eq_levels_for(&[OutputChannel::Surround]),
+                right_rear: eq_levels_for(&[OutputChannel::Surround]),
+                back_center: None,
+            }
+        } else {
+            EqLevels {
+                left_front: eq_levels_for(&[OutputChannel::Left]),
+                right_front: eq_levels_for(&[OutputChannel::Right]),
+                center: eq_levels_for(&[OutputChannel::Center]),
+                lfe: eq_levels_for(&[OutputChannel::Lfe]),
+                left_rear: eq_levels_for(&[OutputChannel::RearLeft]),
+                right_rear: eq_levels_for(&[OutputChannel::RearRight]),
+                back_center: eq_levels_for(&[OutputChannel::Surround]),
+            }
+        };
+
+        let rear_phase_offsets = match options.rear_phase {
+            RearPhase::Constant => None,
+            RearPhase::Broadband90 => Some(rear_phase_offsets(window_size, transform_sample_rate)),
+        };
+
+        let channel_names = match options.ambisonic_order {
+            Some(ambisonic_order) => ambisonics::channel_names(ambisonic_order),
+            None => report::channel_names(&options.written_channels),
+        };
+
+        let report = if options.report_file_path.is_some() {
+            Some(Report::new(channel_names.clone()))
+        } else {
+            None
+        };
+
+        let meters = if options.meters {
+            Some(Meters::new(channel_names))
+        } else {
+            None
+        };
+
+        let mono_check_random_access_wav_writer = match &options.mono_check_path {
+            Some(mono_check_path) => {
+                let header = WavHeader {
+                    sample_format: SampleFormat::Float,
+                    channels: Channels::new().front_center(),
+                    sample_rate: sample_rate as u32,
+                };
+
+                Some(
+                    write_wav_to_file_path(mono_check_path, header)?
+                        .get_random_access_f32_writer()?,
+                )
+            }
+            None => None,
+        };
+
+        let lfe_random_access_wav_writer = match &options.lfe_file_path {
+            Some(lfe_file_path) => {
+                let header = WavHeader {
+                    sample_format: SampleFormat::Float,
+                    channels: Channels::new().front_center(),
+                    sample_rate: sample_rate as u32,
+                };
+
+                Some(
+                    write_wav_to_file_path(lfe_file_path, header)?
+                        .get_random_access_f32_writer()?,
+                )
+            }
+            None => None,
+        };
+
+        let debug_center_random_access_wav_writer = match &options.debug_center_path {
+            Some(debug_center_path) => {
+                let header = WavHeader {
+                    sample_format: SampleFormat::Float,
+                    channels: Channels::new().front_center(),
+                    sample_rate: sample_rate as u32,
+                };
+
+                Some(
+                    write_wav_to_file_path(debug_center_path, header)?
+                        .get_random_access_f32_writer()?,
+                )
+            }
+            None => None,
+        };
+
+        Ok(PannerAndWriter {
             transformed_window_and_averaged_pans_queue: Mutex::new(VecDeque::new()),
             writer_state: Mutex::new(WriterState {
                 target_random_access_wav_writers,
                 total_samples_written: 0,
+                report,
+                mono_check_random_access_wav_writer,
+                lfe_random_access_wav_writer,
+                debug_center_random_access_wav_writer,
+                meters,
+                auto_headroom: options
+                    .auto_headroom
+                    .map(|release_seconds| Limiter::new(1.0, release_seconds, sample_rate)),
+                center_ceiling: options.center_ceiling.map(|center_ceiling_dbfs| {
+                    Limiter::new(
+                        10f32.powf(center_ceiling_dbfs / 20.0),
+                        DEFAULT_AUTO_HEADROOM_RELEASE_SECONDS,
+                        sample_rate,
+                    )
+                }),
+                flush_interval: options.flush_interval,
+                last_flush: Instant::now(),
+                non_finite_samples: 0,
             }),
             fft_inverse,
             lfe_levels,
+            center_highpass_levels,
+            center_band_levels,
+            eq_levels,
+            rear_phase_offsets,
             max_samples_in_file,
-        }
+            mantissa_bits: options.mantissa_bits,
+            oversample: options.oversample,
+            align_pulse: options.align_pulse,
+            sanitize: options.sanitize,
+            center_adjustment: options.center_adjustment,
+            rear_channels: options.rear_channels,
+            has_rears: options.channels.back_left
+                || options.channels.back_right
+                || options.channels.back_center,
+            written_channels: options.written_channels,
+            resume_point,
+        })
     }
 
     pub fn get_inplace_scratch_len(self: &PannerAndWriter) -> usize {
@@ -108,6 +417,52 @@ impl PannerAndWriter {
             .total_samples_written
     }
 
+    // -meters: The peak of each channel since the last time this was called. None without -meters
+    pub fn take_meters(self: &PannerAndWriter) -> Option<String> {
+        self.writer_state
+            .lock()
+            .expect("Cannot aquire lock because a thread panicked")
+            .meters
+            .as_mut()
+            .map(|meters| meters.take())
+    }
+
+    // -auto-headroom: How much the output was lowered, at most, in dB. None without -auto-headroom
+    pub fn auto_headroom_reduction_db(self: &PannerAndWriter) -> Option<f32> {
+        self.writer_state
+            .lock()
+            .expect("Cannot aquire lock because a thread panicked")
+            .auto_headroom
+            .as_ref()
+            .map(Limiter::reduction_db)
+    }
+
+    // -sanitize: The number of samples that weren't finite after steering
+    pub fn get_non_finite_samples(self: &PannerAndWriter) -> usize {
+        self.writer_state
+            .lock()
+            .expect("Cannot aquire lock because a thread panicked")
+            .non_finite_samples
+    }
+
+    // -center-ceiling: How much the center was lowered, at most, in dB. None without -center-ceiling
+    pub fn center_ceiling_reduction_db(self: &PannerAndWriter) -> Option<f32> {
+        self.writer_state
+            .lock()
+            .expect("Cannot aquire lock because a thread panicked")
+            .center_ceiling
+            .as_ref()
+            .map(Limiter::reduction_db)
+    }
+
+    // The number of averaged windows that are waiting to be transformed backwards and written
+    pub fn num_windows_waiting(self: &PannerAndWriter) -> usize {
+        self.transformed_window_and_averaged_pans_queue
+            .lock()
+            .expect("Cannot aquire lock because a thread panicked")
+            .len()
+    }
+
     pub fn enqueue(self: &PannerAndWriter, transformed_window_and_pans: TransformedWindowAndPans) {
         self.transformed_window_and_averaged_pans_queue
             .lock()
@@ -134,6 +489,17 @@ impl PannerAndWriter {
                 }
             };
 
+            if let Some(ambisonic_order) = thread_state.upmixer.options.ambisonic_order {
+                self.transform_and_write_ambisonics(
+                    thread_state,
+                    transformed_window_and_pans,
+                    ambisonic_order,
+                )?;
+
+                thread_state.upmixer.logger.log_status(thread_state)?;
+                continue 'transform_and_write;
+            }
+
             // The front channels are based on the original transforms
             let mut left_front = transformed_window_and_pans
                 .left_transformed
@@ -146,24 +512,120 @@ impl PannerAndWriter {
             let mut left_rear = left_front.clone();
             let mut right_rear = right_front.clone();
 
-            let lfe = if thread_state.upmixer.options.channels.low_frequency {
+            let lfe = if self.lfe_levels.is_some() {
+                match thread_state.upmixer.options.lfe_source {
+                    LfeSource::Mono => match &transformed_window_and_pans.mono_transformed {
+                        Some(mono_transformed) => Some(mono_transformed.clone()),
+                        // Layouts without a center don't transform the mono channel, but the transform is linear,
+                        // so the mono transform is the average of the left and right transforms
+                        None => Some(
+                            left_front
+                                .iter()
+                                .zip(right_front.iter())
+                                .map(|(left, right)| (left + right) * 0.5)
+                                .collect(),
+                        ),
+                    },
+                    LfeSource::Max => Some(
+                        left_front
+                            .iter()
+                            .zip(right_front.iter())
+                            .map(|(left, right)| {
+                                if left.norm_sqr() >= right.norm_sqr() {
+                                    *left
+                                } else {
+                                    *right
+                                }
+                            })
+                            .collect(),
+                    ),
+                    LfeSource::Rms => Some(
+                        left_front
+                            .iter()
+                            .zip(right_front.iter())
+                            .map(|(left, right)| {
+                                let amplitude = ((left.norm_sqr() + right.norm_sqr()) / 2.0).sqrt();
+                                let phase = if left.norm_sqr() >= right.norm_sqr() {
+                                    left.arg()
+                                } else {
+                                    right.arg()
+                                };
+                                Complex::from_polar(amplitude, phase)
+                            })
+                            .collect(),
+                    ),
+                    LfeSource::Left => Some(left_front.clone()),
+                    LfeSource::Right => Some(right_front.clone()),
+                }
+            } else {
+                None
+            };
+
+            // -debug-center: The mono transform, before the center is steered
+            let mut debug_center = if thread_state.upmixer.options.debug_center_path.is_some() {
                 transformed_window_and_pans.mono_transformed.clone()
             } else {
                 None
             };
 
+            // Without a center (-channels 4), there is no mono transform, and all of the center steering below is
+            // skipped
             let mut center = if thread_state.upmixer.options.channels.front_center {
                 transformed_window_and_pans.mono_transformed
             } else {
                 None
             };
 
+            // Only for 6.1. (In the surround layout, the single rear channel is the average of the rears. With
+            // -rear-channels, the rear center is one of the rear positions)
+            let mut back_center = if thread_state.upmixer.options.channels.back_center
+                && thread_state.upmixer.options.channels.back_left
+                && self.rear_channels == 2
+            {
+                Some(vec![
+                    Complex { re: 0f32, im: 0f32 };
+                    thread_state.upmixer.window_size
+                ])
+            } else {
+                None
+            };
+
+            // -rear-channels: The rears, from left to right. Each frequency is panned between the two nearest positions
+            let mut rears = if self.rear_channels > 2 {
+                Some(vec![
+                    vec![
+                        Complex { re: 0f32, im: 0f32 };
+                        thread_state.upmixer.window_size
+                    ];
+                    self.rear_channels
+                ])
+            } else {
+                None
+            };
+
             // Ultra-lows are not shitfted
             left_rear[0] = Complex { re: 0f32, im: 0f32 };
             right_rear[0] = Complex { re: 0f32, im: 0f32 };
 
+            // The upper half of the transform mirrors the lower half (conjugate symmetry,) so it's usually filled in
+            // as each frequency is steered. -no-symmetry-shortcut steers each bin in the upper half on its own, for
+            // checking that the mirroring matches
+            let symmetry_shortcut = !thread_state.upmixer.options.no_symmetry_shortcut;
+            let last_freq_ctr = if symmetry_shortcut {
+                thread_state.upmixer.window_midpoint
+            } else {
+                thread_state.upmixer.window_size - 1
+            };
+
             // Steer each frequency
-            for freq_ctr in 1..(thread_state.upmixer.window_midpoint + 1) {
+            for freq_ctr in 1..(last_freq_ctr + 1) {
+                // Bins in the upper half use the pans of the frequency that they mirror
+                let pan_freq_ctr = if freq_ctr > thread_state.upmixer.window_midpoint {
+                    thread_state.upmixer.window_size - freq_ctr
+                } else {
+                    freq_ctr
+                };
+
                 // Phase is offset from sine/cos in # of samples
                 let left = left_front[freq_ctr];
                 let (left_amplitude, mut left_front_phase) = left.to_polar();
@@ -173,21 +635,40 @@ impl PannerAndWriter {
                 let mut left_rear_phase = left_front_phase;
                 let mut right_rear_phase = right_front_phase;
 
-                let frequency_pans = &transformed_window_and_pans.frequency_pans[freq_ctr - 1];
-                let left_to_right = frequency_pans.left_to_right;
-                let back_to_front = frequency_pans.back_to_front;
+                let frequency_pans = &transformed_window_and_pans.frequency_pans[pan_freq_ctr - 1];
+                let mut left_to_right = frequency_pans.left_to_right;
+                let mut back_to_front = if thread_state.upmixer.options.flip_front_back {
+                    1.0 - frequency_pans.back_to_front
+                } else {
+                    frequency_pans.back_to_front
+                };
+
+                if let Some(widen_image) = thread_state.upmixer.options.widen_image {
+                    thread_state
+                        .upmixer
+                        .options
+                        .matrix
+                        .widen(&mut left_to_right, widen_image);
+                }
+
+                if thread_state.upmixer.options.mono_surround {
+                    left_to_right *= MONO_SURROUND_WIDTH;
+                    back_to_front *= MONO_SURROUND_AMBIENCE;
+                }
 
-                // Widening is currently disabled because it results in poor audio quality, and favors too
-                // much steering to the rear
-                //thread_state.upmixer.options.matrix.widen(&mut back_to_front, &mut left_to_right);
+                // Layouts without rears (3.0) keep everything in the front
+                if !self.has_rears {
+                    back_to_front = 0.0;
+                }
 
                 let front_to_back = 1f32 - back_to_front;
 
                 // Figure out the amplitudes for front and rear
                 let mut left_front_amplitude: f32;
-                let left_rear_amplitude: f32;
+                let mut left_rear_amplitude: f32;
                 let mut right_front_amplitude: f32;
-                let right_rear_amplitude: f32;
+                let mut right_rear_amplitude: f32;
+                let mut center_amplitude: Option<f32> = None;
 
                 // sq requires oddbal adjustment of right-left panning
                 if thread_state.upmixer.options.matrix.steer_right_left() {
@@ -201,84 +682,55 @@ impl PannerAndWriter {
                     let amplitude = (frequency_pans.amplitude * isolated_in_front_or_back)
                         + (frequency_pans.amplitude
                             * panned_between_front_or_back
-                            * matrix::CENTER_AMPLITUDE_ADJUSTMENT);
-
-                    let amplitude = if thread_state.upmixer.options.loud {
-                        amplitude
-                    } else {
-                        amplitude * thread_state.upmixer.options.matrix.amplitude_adjustment()
-                    };
+                            * self.center_adjustment);
 
                     let amplitude_front = amplitude * front_to_back;
 
                     // Steer center
                     let front_side_adjustment = left_to_right.abs();
                     let front_center_adjustment = 1.0 - front_side_adjustment;
-                    center = match center {
-                        Some(mut center) => {
-                            // Uncomment to set breakpoints
-                            /*if transformed_window_and_pans.last_sample_ctr == 17640 && freq_ctr == 46 {
-                                print!("");
-                            }*/
-
-                            let center_amplitude: f32;
-                            // Adjust the left and right channels
-                            if left_to_right == 0.0 {
-                                // Frequency is center-panned
-                                left_front_amplitude = 0.0;
-                                right_front_amplitude = 0.0;
-                                center_amplitude = amplitude_front;
-                            } else {
-                                // Adjust by .707 for tones off-center
-                                let front_side_adjustment =
-                                    ((front_side_adjustment * 2.0) - 1.0).abs();
-                                let front_center_adjustment = 1.0 - front_side_adjustment;
-                                let amplitude_mix_front = (amplitude_front * front_side_adjustment)
-                                    + (amplitude_front
-                                        * front_center_adjustment
-                                        * matrix::CENTER_AMPLITUDE_ADJUSTMENT);
-
-                                center_amplitude = amplitude_mix_front * front_center_adjustment;
-
-                                if left_to_right < 0.0 {
-                                    // Frequency is left-panned
-                                    left_front_amplitude =
-                                        amplitude_mix_front * front_side_adjustment;
-                                    right_front_amplitude = 0.0;
-                                } else {
-                                    //if left_to_right > 0.0 {
-                                    // Frequency is right-panned
-                                    left_front_amplitude = 0.0;
-                                    right_front_amplitude =
-                                        amplitude_mix_front * front_side_adjustment;
-                                }
-                            }
-
-                            let (_, phase) = center[freq_ctr].to_polar();
-                            let c = Complex::from_polar(center_amplitude, phase);
-
-                            center[freq_ctr] = c;
-                            if freq_ctr < thread_state.upmixer.window_midpoint {
-                                center[thread_state.upmixer.window_size - freq_ctr] = Complex {
-                                    re: c.re,
-                                    im: -1.0 * c.im,
-                                }
-                            }
-
-                            Some(center)
-                        }
-                        None => {
-                            // Adjust by .707 for centered tones
+                    if center.is_some() {
+                        // Uncomment to set breakpoints
+                        /*if transformed_window_and_pans.last_sample_ctr == 17640 && freq_ctr == 46 {
+                            print!("");
+                        }*/
+
+                        // Adjust the left and right channels
+                        if left_to_right == 0.0 {
+                            // Frequency is center-panned
+                            left_front_amplitude = 0.0;
+                            right_front_amplitude = 0.0;
+                            center_amplitude = Some(amplitude_front);
+                        } else {
+                            // Adjust by .707 for tones off-center
+                            let front_side_adjustment = ((front_side_adjustment * 2.0) - 1.0).abs();
+                            let front_center_adjustment = 1.0 - front_side_adjustment;
                             let amplitude_mix_front = (amplitude_front * front_side_adjustment)
                                 + (amplitude_front
                                     * front_center_adjustment
-                                    * matrix::CENTER_AMPLITUDE_ADJUSTMENT);
+                                    * self.center_adjustment);
+
+                            center_amplitude = Some(amplitude_mix_front * front_center_adjustment);
 
-                            right_front_amplitude = amplitude_mix_front * left_to_right_no_center;
-                            left_front_amplitude = amplitude_mix_front - right_front_amplitude;
-                            None
+                            if left_to_right < 0.0 {
+                                // Frequency is left-panned
+                                left_front_amplitude = amplitude_mix_front * front_side_adjustment;
+                                right_front_amplitude = 0.0;
+                            } else {
+                                //if left_to_right > 0.0 {
+                                // Frequency is right-panned
+                                left_front_amplitude = 0.0;
+                                right_front_amplitude = amplitude_mix_front * front_side_adjustment;
+                            }
                         }
-                    };
+                    } else {
+                        // Adjust by .707 for centered tones
+                        let amplitude_mix_front = (amplitude_front * front_side_adjustment)
+                            + (amplitude_front * front_center_adjustment * self.center_adjustment);
+
+                        right_front_amplitude = amplitude_mix_front * left_to_right_no_center;
+                        left_front_amplitude = amplitude_mix_front - right_front_amplitude;
+                    }
 
                     // The back pans also need to be adjusted by left_to_right, because SQ's left-right panning is phase-based
                     let amplitude_back = amplitude * back_to_front;
@@ -286,15 +738,6 @@ impl PannerAndWriter {
                     left_rear_amplitude = amplitude_back - right_rear_amplitude;
                 } else {
                     // normal matrixes don't adjust left <-> right
-                    let amplitude_adjustment = if thread_state.upmixer.options.loud {
-                        thread_state.upmixer.options.matrix.amplitude_adjustment()
-                    } else {
-                        1.0f32
-                    };
-
-                    let left_amplitude = left_amplitude / amplitude_adjustment;
-                    let right_amplitude = right_amplitude / amplitude_adjustment;
-
                     // Figure out the amplitudes for front and rear
                     left_front_amplitude = left_amplitude * front_to_back;
                     right_front_amplitude = right_amplitude * front_to_back;
@@ -302,33 +745,83 @@ impl PannerAndWriter {
                     right_rear_amplitude = right_amplitude * back_to_front;
 
                     // Steer center
-                    center = match center {
-                        Some(mut center) => {
-                            let (_, phase) = center[freq_ctr].to_polar();
-                            let center_amplitude = (1.0 - left_to_right.abs())
-                                * (left_front_amplitude + right_front_amplitude)
-                                * matrix::CENTER_AMPLITUDE_ADJUSTMENT
-                                * 0.5;
-                            let c = Complex::from_polar(center_amplitude, phase);
-
-                            center[freq_ctr] = c;
-                            if freq_ctr < thread_state.upmixer.window_midpoint {
-                                center[thread_state.upmixer.window_size - freq_ctr] = Complex {
-                                    re: c.re,
-                                    im: -1.0 * c.im,
-                                }
+                    if center.is_some() {
+                        let mut amplitude = match thread_state.upmixer.options.center_method {
+                            CenterMethod::Mono => {
+                                (1.0 - left_to_right.abs())
+                                    * (left_front_amplitude + right_front_amplitude)
+                                    * self.center_adjustment
+                                    * 0.5
                             }
+                            CenterMethod::Correlated => {
+                                // The real part of the cross-spectrum, L * conj(R), is the power of what's common to
+                                // both channels. Hard-panned and out-of-phase frequencies have none
+                                let common_power = left_front_amplitude
+                                    * right_front_amplitude
+                                    * (left_front_phase - right_front_phase).cos();
+
+                                common_power.max(0.0).sqrt() * self.center_adjustment
+                            }
+                        };
 
-                            // Subtract the center from the right and left front channels
-                            left_front_amplitude =
-                                f32::max(0.0, left_front_amplitude - center_amplitude);
-                            right_front_amplitude =
-                                f32::max(0.0, right_front_amplitude - center_amplitude);
-
-                            Some(center)
+                        // -center-band: Outside of the band, less is extracted, so more stays in the fronts
+                        if let Some(center_band_levels) = &self.center_band_levels {
+                            amplitude *= center_band_levels[pan_freq_ctr];
                         }
-                        None => None,
+
+                        // -channels 5.1-mono removes the center from the fronts at its full level, instead of at .707,
+                        // so that centered tones are entirely in the center
+                        let removed = if thread_state.upmixer.options.mono_surround {
+                            amplitude / self.center_adjustment
+                        } else {
+                            amplitude
+                        };
+
+                        // Subtract the center from the right and left front channels
+                        left_front_amplitude = f32::max(0.0, left_front_amplitude - removed);
+                        right_front_amplitude = f32::max(0.0, right_front_amplitude - removed);
+
+                        center_amplitude = Some(amplitude);
+                    }
+                }
+
+                thread_state.upmixer.options.matrix.adjust_levels(
+                    &mut left_front_amplitude,
+                    &mut right_front_amplitude,
+                    &mut left_rear_amplitude,
+                    &mut right_rear_amplitude,
+                    center_amplitude.as_mut(),
+                    thread_state.upmixer.options.loud,
+                );
+
+                if let (Some(center), Some(center_amplitude)) = (center.as_mut(), center_amplitude)
+                {
+                    let phase = match thread_state.upmixer.options.center_phase {
+                        CenterPhase::Mono => center[freq_ctr].arg(),
+                        // The average of the front phases is the direction of the sum of their unit vectors
+                        CenterPhase::Fronts => (Complex::from_polar(1.0, left_front_phase)
+                            + Complex::from_polar(1.0, right_front_phase))
+                        .arg(),
                     };
+                    let c = Complex::from_polar(center_amplitude, phase);
+
+                    center[freq_ctr] = c;
+                    if symmetry_shortcut && freq_ctr < thread_state.upmixer.window_midpoint {
+                        center[thread_state.upmixer.window_size - freq_ctr] = Complex {
+                            re: c.re,
+                            im: -c.im,
+                        }
+                    }
+                }
+
+                // The upper half of the transform is negative frequencies, where a phase shift turns the other way.
+                // (Only with -no-symmetry-shortcut)
+                let negative_frequency = freq_ctr > thread_state.upmixer.window_midpoint;
+                if negative_frequency {
+                    left_front_phase = -left_front_phase;
+                    right_front_phase = -right_front_phase;
+                    left_rear_phase = -left_rear_phase;
+                    right_rear_phase = -right_rear_phase;
                 }
 
                 // Phase shifts
@@ -339,6 +832,76 @@ impl PannerAndWriter {
                     &mut right_rear_phase,
                 );
 
+                if let Some(rear_phase_offsets) = &self.rear_phase_offsets {
+                    left_rear_phase += rear_phase_offsets[pan_freq_ctr];
+                    right_rear_phase += rear_phase_offsets[pan_freq_ctr];
+                }
+
+                if negative_frequency {
+                    left_front_phase = -left_front_phase;
+                    right_front_phase = -right_front_phase;
+                    left_rear_phase = -left_rear_phase;
+                    right_rear_phase = -right_rear_phase;
+                }
+
+                // Steer the rear center from the rears, the same way that the center is steered from the fronts
+                if let Some(back_center) = back_center.as_mut() {
+                    let amplitude = (1.0 - left_to_right.abs())
+                        * (left_rear_amplitude + right_rear_amplitude)
+                        * self.center_adjustment
+                        * 0.5;
+
+                    // The rears are in phase after the matrix's phase shift
+                    let phase = (Complex::from_polar(left_rear_amplitude, left_rear_phase)
+                        + Complex::from_polar(right_rear_amplitude, right_rear_phase))
+                    .arg();
+
+                    // Subtract the rear center from the rear left and right channels
+                    left_rear_amplitude = f32::max(0.0, left_rear_amplitude - amplitude);
+                    right_rear_amplitude = f32::max(0.0, right_rear_amplitude - amplitude);
+
+                    let c = Complex::from_polar(amplitude, phase);
+                    back_center[freq_ctr] = c;
+                    if symmetry_shortcut && freq_ctr < thread_state.upmixer.window_midpoint {
+                        back_center[thread_state.upmixer.window_size - freq_ctr] = Complex {
+                            re: c.re,
+                            im: -c.im,
+                        }
+                    }
+                }
+
+                if let Some(rears) = rears.as_mut() {
+                    // The rear left and rear right are panned with the same constant-power law, so two positions are
+                    // the same as the rear left and right
+                    let amplitude = left_rear_amplitude.hypot(right_rear_amplitude);
+                    let last_position = (rears.len() - 1) as f32;
+                    let position = ((left_to_right + 1.0) / 2.0) * last_position;
+
+                    for (position_ctr, rear) in rears.iter_mut().enumerate() {
+                        let distance = (position - position_ctr as f32).abs();
+                        let gain = if distance < 1.0 {
+                            (distance * HALF_PI).cos()
+                        } else {
+                            0.0
+                        };
+
+                        // The phase is blended from the rear left and right by how far to the right the position is
+                        let right_fraction = position_ctr as f32 / last_position;
+                        let phase = (Complex::from_polar(1.0 - right_fraction, left_rear_phase)
+                            + Complex::from_polar(right_fraction, right_rear_phase))
+                        .arg();
+
+                        let c = Complex::from_polar(amplitude * gain, phase);
+                        rear[freq_ctr] = c;
+                        if symmetry_shortcut && freq_ctr < thread_state.upmixer.window_midpoint {
+                            rear[thread_state.upmixer.window_size - freq_ctr] = Complex {
+                                re: c.re,
+                                im: -c.im,
+                            }
+                        }
+                    }
+                }
+
                 // Assign to array
                 left_front[freq_ctr] = Complex::from_polar(left_front_amplitude, left_front_phase);
                 right_front[freq_ctr] =
@@ -346,27 +909,121 @@ impl PannerAndWriter {
                 left_rear[freq_ctr] = Complex::from_polar(left_rear_amplitude, left_rear_phase);
                 right_rear[freq_ctr] = Complex::from_polar(right_rear_amplitude, right_rear_phase);
 
-                if freq_ctr < thread_state.upmixer.window_midpoint {
+                if symmetry_shortcut && freq_ctr < thread_state.upmixer.window_midpoint {
                     let inverse_freq_ctr = thread_state.upmixer.window_size - freq_ctr;
                     left_front[inverse_freq_ctr] = Complex {
                         re: left_front[freq_ctr].re,
-                        im: -1.0 * left_front[freq_ctr].im,
+                        im: -left_front[freq_ctr].im,
                     };
                     right_front[inverse_freq_ctr] = Complex {
                         re: right_front[freq_ctr].re,
-                        im: -1.0 * right_front[freq_ctr].im,
+                        im: -right_front[freq_ctr].im,
                     };
                     left_rear[inverse_freq_ctr] = Complex {
                         re: left_rear[freq_ctr].re,
-                        im: -1.0 * left_rear[freq_ctr].im,
+                        im: -left_rear[freq_ctr].im,
                     };
                     right_rear[inverse_freq_ctr] = Complex {
                         re: right_rear[freq_ctr].re,
-                        im: -1.0 * right_rear[freq_ctr].im,
+                        im: -right_rear[freq_ctr].im,
                     };
                 }
             }
 
+            // The Nyquist bin is its own mirror, so it must be real. Phase shifts can make it imaginary, which only
+            // ends up in the imaginary part of the inverse transform, which is discarded
+            let nyquist_bin = thread_state.upmixer.window_midpoint;
+            for transformed in [
+                Some(&mut left_front),
+                Some(&mut right_front),
+                Some(&mut left_rear),
+                Some(&mut right_rear),
+                back_center.as_mut(),
+                center.as_mut(),
+            ]
+            .into_iter()
+            .flatten()
+            .chain(rears.iter_mut().flatten())
+            {
+                transformed[nyquist_bin].im = 0.0;
+            }
+
+            // -dump-window: How much of each frequency is steered to each channel, before -center-highpass,
+            // -front-width, and -eq
+            if let Some(window_dump) = &transformed_window_and_pans.window_dump {
+                let mut steered: Vec<(&str, &[Complex<f32>])> =
+                    vec![("left_front", &left_front), ("right_front", &right_front)];
+                if let Some(center) = &center {
+                    steered.push(("center", center));
+                }
+                if let Some(rears) = &rears {
+                    for (name, rear) in rear_position_names(rears.len()).iter().zip(rears) {
+                        steered.push((name, rear));
+                    }
+                } else if self.has_rears {
+                    steered.push(("left_rear", &left_rear));
+                    steered.push(("right_rear", &right_rear));
+                    if let Some(back_center) = &back_center {
+                        steered.push(("back_center", back_center));
+                    }
+                }
+
+                if let Some((sample, dump_window_path)) = &thread_state.upmixer.options.dump_window
+                {
+                    window_dump.write(
+                        dump_window_path,
+                        *sample,
+                        &transformed_window_and_pans.frequency_pans,
+                        &steered,
+                    )?;
+                }
+            }
+
+            // High-pass the center channel, so that small center speakers don't get rumble
+            let mut center_lows_for_lfe = None;
+            if let (Some(center), Some(center_highpass_levels)) =
+                (center.as_mut(), self.center_highpass_levels.as_ref())
+            {
+                let redirect = &thread_state.upmixer.options.center_highpass_redirect;
+                let mut center_lows = vec![Complex { re: 0f32, im: 0f32 }; center.len()];
+
+                for window_ctr in 0..center.len() {
+                    let level = center_highpass_levels[window_ctr];
+                    if level < 1.0 {
+                        let removed = center[window_ctr] * (1.0 - level);
+                        center[window_ctr] *= level;
+
+                        if let CenterHighpassRedirect::Fronts = redirect {
+                            let removed = removed * self.center_adjustment;
+                            left_front[window_ctr] += removed;
+                            right_front[window_ctr] += removed;
+                        }
+
+                        center_lows[window_ctr] = removed;
+                    }
+                }
+
+                if let CenterHighpassRedirect::Lfe = redirect {
+                    center_lows_for_lfe = Some(center_lows);
+                }
+            }
+
+            // Mid/side scaling of the fronts. This happens after the center is extracted, so it only changes what's
+            // left in the fronts
+            if let Some(front_width) = thread_state.upmixer.options.front_width {
+                for (left, right) in left_front.iter_mut().zip(right_front.iter_mut()) {
+                    let mid = (*left + *right) * 0.5;
+                    let side = (*left - *right) * (0.5 * front_width);
+                    *left = mid + side;
+                    *right = mid - side;
+                }
+            }
+
+            apply_eq(&mut left_front, &self.eq_levels.left_front);
+            apply_eq(&mut right_front, &self.eq_levels.right_front);
+            apply_eq(&mut left_rear, &self.eq_levels.left_rear);
+            apply_eq(&mut right_rear, &self.eq_levels.right_rear);
+
             self.fft_inverse
                 .process_with_scratch(&mut left_front, &mut thread_state.scratch_inverse);
             self.fft_inverse
@@ -376,8 +1033,42 @@ impl PannerAndWriter {
             self.fft_inverse
                 .process_with_scratch(&mut right_rear, &mut thread_state.scratch_inverse);
 
+            if let Some(rears) = rears.as_mut() {
+                // Rears to the left of the middle are EQed as the rear left, and to the right as the rear right. A
+                // rear in the middle is EQed as the rear center
+                let middle_position = rears.len() / 2;
+                let has_middle = rears.len() % 2 == 1;
+                for (position_ctr, rear) in rears.iter_mut().enumerate() {
+                    let eq_levels = if has_middle && position_ctr == middle_position {
+                        &self.eq_levels.back_center
+                    } else if position_ctr < middle_position {
+                        &self.eq_levels.left_rear
+                    } else {
+                        &self.eq_levels.right_rear
+                    };
+                    apply_eq(rear, eq_levels);
+
+                    self.fft_inverse
+                        .process_with_scratch(rear, &mut thread_state.scratch_inverse);
+                }
+            }
+
+            back_center = match back_center {
+                Some(mut back_center) => {
+                    apply_eq(&mut back_center, &self.eq_levels.back_center);
+
+                    self.fft_inverse
+                        .process_with_scratch(&mut back_center, &mut thread_state.scratch_inverse);
+
+                    Some(back_center)
+                }
+                None => None,
+            };
+
             center = match center {
                 Some(mut center) => {
+                    apply_eq(&mut center, &self.eq_levels.center);
+
                     self.fft_inverse
                         .process_with_scratch(&mut center, &mut thread_state.scratch_inverse);
 
@@ -386,6 +1077,11 @@ impl PannerAndWriter {
                 None => None,
             };
 
+            if let Some(debug_center) = debug_center.as_mut() {
+                self.fft_inverse
+                    .process_with_scratch(debug_center, &mut thread_state.scratch_inverse);
+            }
+
             // Filter LFE
             let lfe = match lfe {
                 Some(mut lfe) => {
@@ -398,10 +1094,18 @@ impl PannerAndWriter {
                         lfe[window_ctr] = c;
                         lfe[thread_state.upmixer.window_size - window_ctr] = Complex {
                             re: c.re,
-                            im: -1.0 * c.im,
+                            im: -c.im,
+                        }
+                    }
+
+                    if let Some(center_lows) = &center_lows_for_lfe {
+                        for (lfe_bin, center_low) in lfe.iter_mut().zip(center_lows) {
+                            *lfe_bin += center_low;
                         }
                     }
 
+                    apply_eq(&mut lfe, &self.eq_levels.lfe);
+
                     self.fft_inverse
                         .process_with_scratch(&mut lfe, &mut thread_state.scratch_inverse);
 
@@ -410,126 +1114,781 @@ impl PannerAndWriter {
                 None => None,
             };
 
-            let sample_ctr =
-                transformed_window_and_pans.last_sample_ctr - thread_state.upmixer.window_midpoint;
-
-            if sample_ctr == thread_state.upmixer.window_midpoint {
-                // Special case for the beginning of the file
-                for sample_ctr in 0..sample_ctr {
-                    self.write_samples_in_window(
-                        &thread_state.upmixer,
-                        sample_ctr,
-                        sample_ctr as usize,
-                        &left_front,
-                        &right_front,
-                        &left_rear,
-                        &right_rear,
-                        &lfe,
-                        &center,
-                    )?;
+            // Without the symmetry shortcut, imaginary parts are what -no-symmetry-shortcut is looking for, so they
+            // aren't asserted
+            if symmetry_shortcut {
+                debug_assert_near_real("left_front", &left_front);
+                debug_assert_near_real("right_front", &right_front);
+                debug_assert_near_real("left_rear", &left_rear);
+                debug_assert_near_real("right_rear", &right_rear);
+                if let Some(back_center) = &back_center {
+                    debug_assert_near_real("back_center", back_center);
                 }
-            } else if transformed_window_and_pans.last_sample_ctr
-                == thread_state.upmixer.total_samples_to_write - 1
-            {
-                // Special case for the end of the file
-                let first_sample_in_transform = thread_state.upmixer.total_samples_to_write
-                    - thread_state.upmixer.window_size
-                    - 1;
-                for sample_in_transform in
-                    (thread_state.upmixer.window_midpoint - 2)..thread_state.upmixer.window_size
-                {
-                    self.write_samples_in_window(
-                        &thread_state.upmixer,
-                        first_sample_in_transform + sample_in_transform,
-                        sample_in_transform as usize,
-                        &left_front,
-                        &right_front,
-                        &left_rear,
-                        &right_rear,
-                        &lfe,
-                        &center,
-                    )?;
+                for rear in rears.iter().flatten() {
+                    debug_assert_near_real("rear", rear);
+                }
+                if let Some(center) = &center {
+                    debug_assert_near_real("center", center);
+                }
+                if let Some(lfe) = &lfe {
+                    debug_assert_near_real("lfe", lfe);
+                }
+                if let Some(debug_center) = &debug_center {
+                    debug_assert_near_real("debug_center", debug_center);
                 }
-            } else {
-                self.write_samples_in_window(
-                    &thread_state.upmixer,
-                    sample_ctr,
-                    thread_state.upmixer.window_midpoint,
-                    &left_front,
-                    &right_front,
-                    &left_rear,
-                    &right_rear,
-                    &lfe,
-                    &center,
-                )?;
             }
 
+            let crossfeed = thread_state.upmixer.options.crossfeed.unwrap_or(0.0);
+            let source_window = transformed_window_and_pans.source_window;
+            self.write_window(
+                &thread_state.upmixer,
+                transformed_window_and_pans.last_sample_ctr,
+                debug_center.as_deref(),
+                |sample_in_transform, scale| {
+                    let mut left_front = left_front[sample_in_transform].re;
+                    let mut right_front = right_front[sample_in_transform].re;
+                    let left_rear = left_rear[sample_in_transform].re;
+                    let right_rear = right_rear[sample_in_transform].re;
+                    let back_center = back_center
+                        .as_ref()
+                        .map(|back_center| back_center[sample_in_transform].re);
+
+                    // Crossfeed moves part of each rear into the front on the same side. The rear center is split
+                    // between both fronts
+                    left_front += crossfeed * left_rear;
+                    right_front += crossfeed * right_rear;
+                    if let Some(back_center) = back_center {
+                        let back_center_to_fronts =
+                            crossfeed * back_center * self.center_adjustment;
+                        left_front += back_center_to_fronts;
+                        right_front += back_center_to_fronts;
+                    }
+                    let rear_scale = scale * (1.0 - crossfeed);
+
+                    let mut samples_by_channel = match &source_window {
+                        // -stereo-passthrough: The fronts are the source, without the round-trip through the
+                        // transforms
+                        Some((left_source, right_source)) => SamplesByChannel::new()
+                            .front_left(left_source[sample_in_transform])
+                            .front_right(right_source[sample_in_transform]),
+                        None => SamplesByChannel::new()
+                            .front_left(scale * left_front)
+                            .front_right(scale * right_front),
+                    };
+
+                    samples_by_channel = if let Some(rears) = &rears {
+                        let rears: Vec<f32> = rears
+                            .iter()
+                            .map(|rear| rear_scale * rear[sample_in_transform].re)
+                            .collect();
+                        rears_by_position(samples_by_channel, &rears)
+                    } else if let Some(back_center) = back_center {
+                        samples_by_channel
+                            .back_left(rear_scale * left_rear)
+                            .back_right(rear_scale * right_rear)
+                            .back_center(rear_scale * back_center)
+                    } else if thread_state.upmixer.options.channels.back_center {
+                        // A single surround channel: The rears are in phase after the matrix's phase shift, and each
+                        // is already at the level of the encoded surround, so they are averaged
+                        let surround = (left_rear + right_rear) * 0.5;
+                        samples_by_channel.back_center(rear_scale * surround)
+                    } else {
+                        samples_by_channel
+                            .back_left(rear_scale * left_rear)
+                            .back_right(rear_scale * right_rear)
+                    };
+
+                    if let Some(lfe) = &lfe {
+                        samples_by_channel =
+                            samples_by_channel.low_frequency(scale * lfe[sample_in_transform].re);
+                    }
+
+                    if let Some(center) = &center {
+                        samples_by_channel =
+                            samples_by_channel.front_center(scale * center[sample_in_transform].re);
+                    }
+
+                    samples_by_channel
+                },
+            )?;
+
             thread_state.upmixer.logger.log_status(thread_state)?;
         }
 
         Ok(())
     }
 
-    fn write_samples_in_window(
+    // Encodes each frequency into the spherical harmonics at its panned direction
+    fn transform_and_write_ambisonics(
+        self: &PannerAndWriter,
+        thread_state: &mut ThreadState,
+        transformed_window_and_pans: TransformedWindowAndPans,
+        ambisonic_order: usize,
+    ) -> Result<()> {
+        let window_size = thread_state.upmixer.window_size;
+        let window_midpoint = thread_state.upmixer.window_midpoint;
+
+        let left = transformed_window_and_pans
+            .left_transformed
+            .expect("Transform expected, got a placeholder instead");
+        let right = transformed_window_and_pans
+            .right_transformed
+            .expect("Transform expected, got a placeholder instead");
+
+        let mut ambisonic_channels = vec![
+            vec![Complex { re: 0f32, im: 0f32 }; window_size];
+            ambisonics::num_channels(ambisonic_order)
+        ];
+
+        // Ultra-lows are omnidirectional
+        ambisonic_channels[0][0] = (left[0] + right[0]) / 2.0;
+
+        for freq_ctr in 1..(window_midpoint + 1) {
+            let frequency_pans = &transformed_window_and_pans.frequency_pans[freq_ctr - 1];
+
+            // The phase comes from the louder channel. (The middle channel cancels out when a frequency is steered
+            // to the rear)
+            let (left_amplitude, left_phase) = left[freq_ctr].to_polar();
+            let (right_amplitude, right_phase) = right[freq_ctr].to_polar();
+            let phase = if left_amplitude >= right_amplitude {
+                left_phase
+            } else {
+                right_phase
+            };
+
+            let back_to_front = if thread_state.upmixer.options.flip_front_back {
+                1.0 - frequency_pans.back_to_front
+            } else {
+                frequency_pans.back_to_front
+            };
+
+            let azimuth = ambisonics::azimuth(frequency_pans.left_to_right, back_to_front);
+            let gains = ambisonics::encode(ambisonic_order, azimuth);
+
+            for (ambisonic_channel, gain) in ambisonic_channels.iter_mut().zip(gains) {
+                let c = Complex::from_polar(frequency_pans.amplitude * gain, phase);
+
+                ambisonic_channel[freq_ctr] = c;
+                if freq_ctr < window_midpoint {
+                    ambisonic_channel[window_size - freq_ctr] = Complex {
+                        re: c.re,
+                        im: -c.im,
+                    };
+                }
+            }
+        }
+
+        for ambisonic_channel in ambisonic_channels.iter_mut() {
+            self.fft_inverse
+                .process_with_scratch(ambisonic_channel, &mut thread_state.scratch_inverse);
+            debug_assert_near_real("ambisonic", ambisonic_channel);
+        }
+
+        self.write_window(
+            &thread_state.upmixer,
+            transformed_window_and_pans.last_sample_ctr,
+            None,
+            |sample_in_transform, scale| {
+                let samples: Vec<f32> = ambisonic_channels
+                    .iter()
+                    .map(|ambisonic_channel| scale * ambisonic_channel[sample_in_transform].re)
+                    .collect();
+
+                ambisonics::samples_by_channel(&samples)
+            },
+        )
+    }
+
+    // Writes the samples in a transformed window that are complete
+    // debug_center is the inverse transform for -debug-center, which is written to its own file
+    // get_samples_by_channel is called with the scale that removes the transform's gain
+    fn write_window(
         self: &PannerAndWriter,
         upmixer: &Upmixer,
+        last_sample_ctr: usize,
+        debug_center: Option<&[Complex<f32>]>,
+        get_samples_by_channel: impl Fn(usize, f32) -> SamplesByChannel<f32>,
+    ) -> Result<()> {
+        let sample_ctr = last_sample_ctr - upmixer.window_midpoint;
+
+        // The window's gain is removed from each sample that is written
+        let window = upmixer.window(last_sample_ctr);
+        let scale = |sample_in_transform: usize| match window {
+            Some(window) => upmixer.scale / window[sample_in_transform],
+            None => upmixer.scale,
+        };
+        let get_samples_by_channel = |sample_in_transform: usize| {
+            get_samples_by_channel(sample_in_transform, scale(sample_in_transform))
+        };
+
+        let get_debug_center = |sample_in_transform: usize| {
+            debug_center.map(|debug_center| {
+                scale(sample_in_transform) * debug_center[sample_in_transform].re
+            })
+        };
+
+        // When upmixing backwards, the output is written backwards so that it lines up with the source
+        let target_sample_ctr = |sample_ctr: usize| {
+            if upmixer.options.reverse {
+                upmixer.total_samples_to_write - 1 - sample_ctr
+            } else {
+                sample_ctr
+            }
+        };
+
+        if upmixer.single_window {
+            // Special case: The entire file is a single transform
+            for sample_ctr in 0..upmixer.total_samples_to_write {
+                self.write_samples(
+                    target_sample_ctr(sample_ctr),
+                    get_samples_by_channel(sample_ctr),
+                    get_debug_center(sample_ctr),
+                )?;
+            }
+        } else if sample_ctr == upmixer.window_midpoint {
+            // Special case for the beginning of the file, up to and including the midpoint
+            for sample_ctr in 0..=sample_ctr {
+                self.write_samples(
+                    target_sample_ctr(sample_ctr),
+                    get_samples_by_channel(sample_ctr),
+                    get_debug_center(sample_ctr),
+                )?;
+            }
+        } else if last_sample_ctr == upmixer.total_samples_to_write - 1 {
+            // Special case for the end of the file: The samples before the midpoint were already written by the
+            // previous windows. (Writing them again would make the output depend on which thread writes last)
+            let first_sample_in_transform =
+                upmixer.total_samples_to_write - upmixer.window_size - 1;
+            for sample_in_transform in upmixer.window_midpoint..upmixer.window_size {
+                self.write_samples(
+                    target_sample_ctr(first_sample_in_transform + sample_in_transform),
+                    get_samples_by_channel(sample_in_transform),
+                    get_debug_center(sample_in_transform),
+                )?;
+            }
+        } else {
+            self.write_samples(
+                target_sample_ctr(sample_ctr),
+                get_samples_by_channel(upmixer.window_midpoint),
+                get_debug_center(upmixer.window_midpoint),
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn write_samples(
+        self: &PannerAndWriter,
         sample_ctr: usize,
-        sample_in_transform: usize,
-        left_front: &Vec<Complex<f32>>,
-        right_front: &Vec<Complex<f32>>,
-        left_rear: &Vec<Complex<f32>>,
-        right_rear: &Vec<Complex<f32>>,
-        lfe: &Option<Vec<Complex<f32>>>,
-        center: &Option<Vec<Complex<f32>>>,
+        mut samples_by_channel: SamplesByChannel<f32>,
+        debug_center: Option<f32>,
     ) -> Result<()> {
         let mut writer_state = self
             .writer_state
             .lock()
             .expect("Cannot aquire lock because a thread panicked");
 
-        let left_front_sample = left_front[sample_in_transform].re;
-        let right_front_sample = right_front[sample_in_transform].re;
-        let left_rear_sample = left_rear[sample_in_transform].re;
-        let right_rear_sample = right_rear[sample_in_transform].re;
+        // Downsamples for -oversample. Frequencies above the source's Nyquist frequency were removed from the
+        // transforms, so skipping samples doesn't alias
+        if !sample_ctr.is_multiple_of(self.oversample) {
+            writer_state.total_samples_written += 1;
+            return Ok(());
+        }
+        let sample_ctr = sample_ctr / self.oversample;
 
-        let lfe_sample = match lfe {
-            Some(lfe) => Some(lfe[sample_in_transform].re),
-            None => None,
+        let sample_ctr = sample_ctr + self.resume_point.source_offset;
+        if sample_ctr < self.resume_point.first_sample_to_write {
+            // Already copied from the partial output
+            writer_state.total_samples_written += 1;
+            return Ok(());
+        }
+
+        if let (Some(debug_center_random_access_wav_writer), Some(debug_center)) = (
+            writer_state.debug_center_random_access_wav_writer.as_mut(),
+            debug_center,
+        ) {
+            debug_center_random_access_wav_writer.write_samples(
+                sample_ctr,
+                SamplesByChannel::new().front_center(debug_center),
+            )?;
+        }
+
+        // -sanitize: A sample that isn't finite is written as silence, instead of as a click or as NaN
+        if self.sanitize {
+            let non_finite_samples = samples_by_channel
+                .to_vec()
+                .into_iter()
+                .filter(|sample| !sample.is_finite())
+                .count();
+
+            if non_finite_samples > 0 {
+                writer_state.non_finite_samples += non_finite_samples;
+                samples_by_channel = map_samples(&samples_by_channel, |sample| {
+                    if sample.is_finite() {
+                        sample
+                    } else {
+                        0.0
+                    }
+                });
+            }
+        }
+
+        // -center-ceiling: What's removed from the center is added to the fronts, (at the center law, like the
+        // center is extracted,) so that centered sound stays centered and just as loud
+        if let (Some(center_ceiling), Some(center)) = (
+            writer_state.center_ceiling.as_mut(),
+            samples_by_channel.front_center,
+        ) {
+            let gain = center_ceiling.gain(sample_ctr, center.abs());
+            let removed = center * (1.0 - gain) * self.center_adjustment;
+
+            samples_by_channel.front_center = Some(center * gain);
+            samples_by_channel.front_left =
+                samples_by_channel.front_left.map(|left| left + removed);
+            samples_by_channel.front_right =
+                samples_by_channel.front_right.map(|right| right + removed);
+        }
+
+        let samples_by_channel = match writer_state.auto_headroom.as_mut() {
+            Some(auto_headroom) => {
+                let peak = samples_by_channel
+                    .to_vec()
+                    .into_iter()
+                    .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+                let gain = auto_headroom.gain(sample_ctr, peak);
+                map_samples(&samples_by_channel, |sample| sample * gain)
+            }
+            None => samples_by_channel,
         };
 
-        let center_sample = match center {
-            Some(center) => Some(center[sample_in_transform].re),
-            None => None,
+        let samples_by_channel = match self.mantissa_bits {
+            Some(mantissa_bits) => round_mantissas(&samples_by_channel, mantissa_bits),
+            None => samples_by_channel,
         };
 
-        let mut samples_by_channel = SamplesByChannel::new()
-            .front_left(upmixer.scale * left_front_sample)
-            .front_right(upmixer.scale * right_front_sample)
-            .back_left(upmixer.scale * left_rear_sample)
-            .back_right(upmixer.scale * right_rear_sample);
+        // The LFE is written to -lfe-file before it's removed from output that doesn't include it
+        if let Some(lfe_random_access_wav_writer) =
+            writer_state.lfe_random_access_wav_writer.as_mut()
+        {
+            lfe_random_access_wav_writer.write_samples(
+                sample_ctr,
+                SamplesByChannel::new()
+                    .front_center(samples_by_channel.low_frequency.unwrap_or(0.0)),
+            )?;
+        }
 
-        match lfe_sample {
-            Some(lfe_sample) => {
-                samples_by_channel = samples_by_channel.low_frequency(upmixer.scale * lfe_sample);
-            }
-            None => {}
+        let samples_by_channel = written_samples(&samples_by_channel, &self.written_channels);
+
+        if let Some(report) = writer_state.report.as_mut() {
+            report.accumulate(&samples_by_channel);
         }
 
-        match center_sample {
-            Some(center_sample) => {
-                samples_by_channel = samples_by_channel.front_center(upmixer.scale * center_sample);
-            }
-            None => {}
+        if let Some(meters) = writer_state.meters.as_mut() {
+            meters.accumulate(&samples_by_channel);
+        }
+
+        if let Some(mono_check_random_access_wav_writer) =
+            writer_state.mono_check_random_access_wav_writer.as_mut()
+        {
+            mono_check_random_access_wav_writer.write_samples(
+                sample_ctr,
+                SamplesByChannel::new()
+                    .front_center(mono_downmix(&samples_by_channel, self.center_adjustment)),
+            )?;
         }
 
         let out_file_index = sample_ctr / self.max_samples_in_file;
         let sample_ctr_in_file = sample_ctr - (self.max_samples_in_file * out_file_index);
 
+        // The pulse replaces the upmixed samples after they are measured, so that it doesn't change -report-file or
+        // -meters
+        let samples_by_channel = match ALIGN_PULSE.get(sample_ctr_in_file) {
+            Some(pulse) if self.align_pulse => map_samples(&samples_by_channel, |_| *pulse),
+            _ => samples_by_channel,
+        };
+
         writer_state.target_random_access_wav_writers[out_file_index]
             .write_samples(sample_ctr_in_file, samples_by_channel)?;
 
         writer_state.total_samples_written += 1;
 
+        // -flush-interval: Flushing rewrites the header with the length written so far, so that the output is a
+        // valid wav if upmixing is interrupted. (Samples are written out-of-order, so the end of the output may not
+        // be upmixed yet.) Later samples are written after the header is rewritten, because each write seeks to its
+        // sample
+        if let Some(flush_interval) = writer_state.flush_interval {
+            if writer_state.last_flush.elapsed() >= flush_interval {
+                writer_state.flush()?;
+                writer_state.last_flush = Instant::now();
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn write_report(
+        self: &PannerAndWriter,
+        report_file_path: &Path,
+        options: &Options,
+        sample_rate: usize,
+        window_size: usize,
+        dc_offset: DcOffset,
+    ) -> Result<()> {
+        let writer_state = self
+            .writer_state
+            .lock()
+            .expect("Cannot aquire lock because a thread panicked");
+
+        match &writer_state.report {
+            Some(report) => report.write(
+                report_file_path,
+                options,
+                sample_rate,
+                window_size,
+                dc_offset,
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+// Calculates the level of each frequency in the transform from the -eq bands for the channel. None if the channel has
+// no bands
+fn eq_levels(
+    eq: &[EqBand],
+    eq_channels: &[OutputChannel],
+    window_size: usize,
+    sample_rate: usize,
+) -> Option<Vec<f32>> {
+    let mut bands: Vec<&EqBand> = eq
+        .iter()
+        .filter(|band| eq_channels.contains(&band.channel))
+        .collect();
+
+    if bands.is_empty() {
+        return None;
+    }
+
+    bands.sort_by(|a, b| a.frequency.total_cmp(&b.frequency));
+
+    let window_midpoint = window_size / 2;
+    let mut levels = vec![1.0f32; window_size];
+
+    for transform_index in 0..(window_midpoint + 1) {
+        let frequency = bin_frequency(transform_index, window_size, sample_rate);
+        let level = 10.0f32.powf(eq_gain_db(&bands, frequency) / 20.0);
+
+        levels[transform_index] = level;
+        if transform_index > 0 {
+            levels[window_size - transform_index] = level;
+        }
+    }
+
+    Some(levels)
+}
+
+// The phase of a cascade of first-order all-pass filters, for each frequency in the transform, up to the midpoint
+fn rear_phase_offsets(window_size: usize, sample_rate: usize) -> Vec<f32> {
+    let window_midpoint = window_size / 2;
+
+    (0..(window_midpoint + 1))
+        .map(|transform_index| {
+            let frequency = bin_frequency(transform_index, window_size, sample_rate);
+            REAR_PHASE_POLES
+                .iter()
+                .map(|pole| -2.0 * (frequency / pole).atan())
+                .sum()
+        })
+        .collect()
+}
+
+// Gains are interpolated (in dB) by octave between bands. Below the lowest band and above the highest band, the gain is
+// the same as the band
+fn eq_gain_db(bands: &[&EqBand], frequency: f32) -> f32 {
+    let first = bands[0];
+    if frequency <= first.frequency {
+        return first.gain_db;
+    }
+
+    for pair in bands.windows(2) {
+        let (lower, upper) = (pair[0], pair[1]);
+        if frequency < upper.frequency {
+            let fraction =
+                (frequency / lower.frequency).log2() / (upper.frequency / lower.frequency).log2();
+            return lower.gain_db + ((upper.gain_db - lower.gain_db) * fraction);
+        }
+    }
+
+    bands[bands.len() - 1].gain_db
+}
+
+// Only the real part of an inverse transform is written, so significant imaginary parts mean that energy is lost,
+// because a transform's bins weren't conjugate-symmetric. (This is only checked in debug builds, because it's a
+// development-time invariant)
+fn debug_assert_near_real(channel_name: &str, inverse_transformed: &[Complex<f32>]) {
+    if cfg!(debug_assertions) {
+        let (real, imaginary) = inverse_transformed
+            .iter()
+            .fold((0.0f32, 0.0f32), |(real, imaginary), sample| {
+                (real + sample.re.abs(), imaginary + sample.im.abs())
+            });
+
+        assert!(
+            imaginary <= real * MAX_IMAGINARY_FRACTION + f32::EPSILON,
+            "The inverse transform of {} is not real: the sum of its imaginary parts is {}, and of its real parts is {}",
+            channel_name,
+            imaginary,
+            real
+        );
+    }
+}
+
+fn apply_eq(transformed: &mut [Complex<f32>], eq_levels: &Option<Vec<f32>>) {
+    if let Some(eq_levels) = eq_levels {
+        for (bin, level) in transformed.iter_mut().zip(eq_levels) {
+            *bin *= level;
+        }
+    }
+}
+
+// A limiter: -auto-headroom limits all of the output channels to 0 dBFS, and -center-ceiling limits the center.
+// Samples are already written when a later sample would go over the ceiling, so the gain drops immediately to keep
+// that sample at the ceiling, and then slowly recovers
+struct Limiter {
+    // As an amplitude
+    ceiling: f32,
+    gain: f32,
+    // The lowest the gain went, for printing once upmixing completes
+    min_gain: f32,
+    // The gain recovers towards 1 by this factor each sample
+    release_per_sample: f32,
+    // Samples are written slightly out-of-order by different threads. The gain only recovers as later samples are
+    // written
+    last_sample_ctr: usize,
+}
+
+impl Limiter {
+    fn new(ceiling: f32, release_seconds: f32, sample_rate: usize) -> Limiter {
+        Limiter {
+            ceiling,
+            gain: 1.0,
+            min_gain: 1.0,
+            release_per_sample: (-1.0 / (release_seconds * sample_rate as f32)).exp(),
+            last_sample_ctr: 0,
+        }
+    }
+
+    // The gain for a sample, where peak is the loudest of the limited channels
+    fn gain(&mut self, sample_ctr: usize, peak: f32) -> f32 {
+        if sample_ctr > self.last_sample_ctr {
+            let elapsed_samples = (sample_ctr - self.last_sample_ctr) as i32;
+            self.gain = 1.0 - ((1.0 - self.gain) * self.release_per_sample.powi(elapsed_samples));
+            self.last_sample_ctr = sample_ctr;
+        }
+
+        if peak * self.gain > self.ceiling {
+            self.gain = self.ceiling / peak;
+            self.min_gain = self.min_gain.min(self.gain);
+        }
+
+        self.gain
+    }
+
+    // How much the limiter lowered its channels, at most, in dB
+    fn reduction_db(&self) -> f32 {
+        -20.0 * self.min_gain.log10()
+    }
+}
+
+fn round_mantissas(
+    samples_by_channel: &SamplesByChannel<f32>,
+    mantissa_bits: u32,
+) -> SamplesByChannel<f32> {
+    map_samples(samples_by_channel, |sample| {
+        round_mantissa(sample, mantissa_bits)
+    })
+}
+
+// Applies the function to each channel that's present
+fn map_samples(
+    samples_by_channel: &SamplesByChannel<f32>,
+    function: impl Fn(f32) -> f32,
+) -> SamplesByChannel<f32> {
+    let map = |sample: Option<f32>| sample.map(&function);
+
+    SamplesByChannel {
+        front_left: map(samples_by_channel.front_left),
+        front_right: map(samples_by_channel.front_right),
+        front_center: map(samples_by_channel.front_center),
+        low_frequency: map(samples_by_channel.low_frequency),
+        back_left: map(samples_by_channel.back_left),
+        back_right: map(samples_by_channel.back_right),
+        front_left_of_center: map(samples_by_channel.front_left_of_center),
+        front_right_of_center: map(samples_by_channel.front_right_of_center),
+        back_center: map(samples_by_channel.back_center),
+        side_left: map(samples_by_channel.side_left),
+        side_right: map(samples_by_channel.side_right),
+        top_center: map(samples_by_channel.top_center),
+        top_front_left: map(samples_by_channel.top_front_left),
+        top_front_center: map(samples_by_channel.top_front_center),
+        top_front_right: map(samples_by_channel.top_front_right),
+        top_back_left: map(samples_by_channel.top_back_left),
+        top_back_center: map(samples_by_channel.top_back_center),
+        top_back_right: map(samples_by_channel.top_back_right),
+    }
+}
+
+// Rounds to the nearest float with only mantissa_bits bits of mantissa; the dropped bits are zero
+// (Rounding up can carry into the exponent, which is still the nearest float)
+fn round_mantissa(sample: f32, mantissa_bits: u32) -> f32 {
+    let dropped_bits = MAX_MANTISSA_BITS - mantissa_bits;
+    if dropped_bits == 0 || !sample.is_finite() {
+        return sample;
+    }
+
+    let half = 1u32 << (dropped_bits - 1);
+    let mask = !((1u32 << dropped_bits) - 1);
+    f32::from_bits((sample.to_bits() + half) & mask)
+}
+
+// Only keeps the samples for channels that are written
+fn written_samples(
+    samples_by_channel: &SamplesByChannel<f32>,
+    written_channels: &Channels,
+) -> SamplesByChannel<f32> {
+    let keep = |sample: Option<f32>, written: bool| if written { sample } else { None };
+
+    SamplesByChannel {
+        front_left: keep(samples_by_channel.front_left, written_channels.front_left),
+        front_right: keep(samples_by_channel.front_right, written_channels.front_right),
+        front_center: keep(
+            samples_by_channel.front_center,
+            written_channels.front_center,
+        ),
+        low_frequency: keep(
+            samples_by_channel.low_frequency,
+            written_channels.low_frequency,
+        ),
+        back_left: keep(samples_by_channel.back_left, written_channels.back_left),
+        back_right: keep(samples_by_channel.back_right, written_channels.back_right),
+        front_left_of_center: keep(
+            samples_by_channel.front_left_of_center,
+            written_channels.front_left_of_center,
+        ),
+        front_right_of_center: keep(
+            samples_by_channel.front_right_of_center,
+            written_channels.front_right_of_center,
+        ),
+        back_center: keep(samples_by_channel.back_center, written_channels.back_center),
+        side_left: keep(samples_by_channel.side_left, written_channels.side_left),
+        side_right: keep(samples_by_channel.side_right, written_channels.side_right),
+        top_center: keep(samples_by_channel.top_center, written_channels.top_center),
+        top_front_left: keep(
+            samples_by_channel.top_front_left,
+            written_channels.top_front_left,
+        ),
+        top_front_center: keep(
+            samples_by_channel.top_front_center,
+            written_channels.top_front_center,
+        ),
+        top_front_right: keep(
+            samples_by_channel.top_front_right,
+            written_channels.top_front_right,
+        ),
+        top_back_left: keep(
+            samples_by_channel.top_back_left,
+            written_channels.top_back_left,
+        ),
+        top_back_center: keep(
+            samples_by_channel.top_back_center,
+            written_channels.top_back_center,
+        ),
+        top_back_right: keep(
+            samples_by_channel.top_back_right,
+            written_channels.top_back_right,
+        ),
+    }
+}
+
+// -rear-channels: Assigns the rears, from left to right, to the speaker positions behind the listener
+fn rears_by_position(
+    samples_by_channel: SamplesByChannel<f32>,
+    rears: &[f32],
+) -> SamplesByChannel<f32> {
+    match *rears {
+        [left, center, right] => samples_by_channel
+            .back_left(left)
+            .back_center(center)
+            .back_right(right),
+        [side_left, left, right, side_right] => samples_by_channel
+            .side_left(side_left)
+            .back_left(left)
+            .back_right(right)
+            .side_right(side_right),
+        [side_left, left, center, right, side_right] => samples_by_channel
+            .side_left(side_left)
+            .back_left(left)
+            .back_center(center)
+            .back_right(right)
+            .side_right(side_right),
+        _ => panic!("{} rear channels are not supported", rears.len()),
+    }
+}
+
+// -rear-channels: The names of the speaker positions that rears_by_position assigns the rears to
+fn rear_position_names(rear_channels: usize) -> &'static [&'static str] {
+    match rear_channels {
+        3 => &["back_left", "back_center", "back_right"],
+        4 => &["side_left", "back_left", "back_right", "side_right"],
+        5 => &[
+            "side_left",
+            "back_left",
+            "back_center",
+            "back_right",
+            "side_right",
+        ],
+        _ => panic!("{} rear channels are not supported", rear_channels),
+    }
+}
+
+// Downmixes all channels to mono, with the standard downmix coefficients: The center and rears are lowered by the
+// center law, (.707 unless -center-law is specified,) and the LFE is left out
+fn mono_downmix(samples_by_channel: &SamplesByChannel<f32>, center_adjustment: f32) -> f32 {
+    let fronts = samples_by_channel.front_left.unwrap_or(0.0)
+        + samples_by_channel.front_right.unwrap_or(0.0);
+    let center_and_rears = (samples_by_channel.front_center.unwrap_or(0.0) * 2.0)
+        + samples_by_channel.back_left.unwrap_or(0.0)
+        + samples_by_channel.back_right.unwrap_or(0.0)
+        + samples_by_channel.side_left.unwrap_or(0.0)
+        + samples_by_channel.side_right.unwrap_or(0.0)
+        + (samples_by_channel.back_center.unwrap_or(0.0) * 2.0);
+
+    // Downmix to left and right, and then average them
+    (fronts + (center_and_rears * center_adjustment)) / 2.0
+}
+
+impl WriterState {
+    fn flush(&mut self) -> Result<()> {
+        for target_random_access_wav_writer in self.target_random_access_wav_writers.iter_mut() {
+            target_random_access_wav_writer.flush()?;
+        }
+
+        if let Some(mono_check_random_access_wav_writer) =
+            self.mono_check_random_access_wav_writer.as_mut()
+        {
+            mono_check_random_access_wav_writer.flush()?;
+        }
+
+        if let Some(lfe_random_access_wav_writer) = self.lfe_random_access_wav_writer.as_mut() {
+            lfe_random_access_wav_writer.flush()?;
+        }
+
+        if let Some(debug_center_random_access_wav_writer) =
+            self.debug_center_random_access_wav_writer.as_mut()
+        {
+            debug_center_random_access_wav_writer.flush()?;
+        }
+
         Ok(())
     }
 }
@@ -540,12 +1899,77 @@ impl Drop for PannerAndWriter {
         self.writer_state
             .lock()
             .expect("Cannot aquire lock because a thread panicked")
-            .target_random_access_wav_writers
-            .iter_mut()
-            .for_each(|target_random_access_wav_writer| {
-                target_random_access_wav_writer
-                    .flush()
-                    .expect("Can not flush writer")
-            });
+            .flush()
+            .expect("Can not flush writer");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Result};
+
+    use wave_stream::{
+        read_wav_from_file_path, samples_by_channel::SamplesByChannel,
+        wave_reader::StreamOpenWavReader,
+    };
+
+    use super::mono_downmix;
+    use crate::{
+        matrix::CENTER_AMPLITUDE_ADJUSTMENT,
+        test_support::{parse_flags, sine, temp_path, upmix_stereo},
+    };
+
+    fn rms(samples: impl Iterator<Item = f32>) -> f32 {
+        let (sum, count) = samples.fold((0.0f64, 0usize), |(sum, count), sample| {
+            (sum + (sample * sample) as f64, count + 1)
+        });
+        (sum / count as f64).sqrt() as f32
+    }
+
+    #[test]
+    fn the_back_center_and_mono_check_follow_the_center_law() {
+        // Out of phase and centered, so that it's steered to the back center
+        let left = sine(1000.0, 0.5, 3000);
+        let right: Vec<f32> = left.iter().map(|sample| -sample).collect();
+        let flags = ["-low", "40", "-channels", "6.1"];
+        let minus_6_flags = [&flags[..], &["-center-law", "-6"]].concat();
+        let minus_6_adjustment = parse_flags(&minus_6_flags).unwrap().center_adjustment;
+
+        let constant_power = upmix_stereo(&left, &right, &flags).expect("Upmixing failed");
+
+        let mono_check_path = temp_path("mono.wav");
+        let mono_check_path_str = mono_check_path.to_str().unwrap();
+        let minus_6 = upmix_stereo(
+            &left,
+            &right,
+            &[&minus_6_flags[..], &["-mono-check", mono_check_path_str]].concat(),
+        )
+        .expect("Upmixing failed");
+        let mono_check = read_wav_from_file_path(&mono_check_path)
+            .unwrap()
+            .get_stream_f32_reader()
+            .unwrap()
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        fs::remove_file(&mono_check_path).unwrap();
+
+        // The back center is extracted at the center law
+        let back_center_rms = |samples: &[SamplesByChannel<f32>]| {
+            rms(samples.iter().map(|samples| samples.back_center.unwrap()))
+        };
+        let ratio = back_center_rms(&minus_6.samples) / back_center_rms(&constant_power.samples);
+        assert!(
+            (ratio - (minus_6_adjustment / CENTER_AMPLITUDE_ADJUSTMENT)).abs() < 0.001,
+            "The back center changed by {}",
+            ratio
+        );
+
+        // The mono check is downmixed at the same center law
+        assert_eq!(minus_6.samples.len(), mono_check.len());
+        for (samples, mono) in minus_6.samples.iter().zip(&mono_check) {
+            let mono = mono.front_center.unwrap();
+            assert!((mono_downmix(samples, minus_6_adjustment) - mono).abs() < 0.00001);
+        }
     }
 }
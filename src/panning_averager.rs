@@ -1,9 +1,14 @@
 use std::{
     collections::{HashMap, VecDeque},
+    io::Result,
     sync::Mutex,
 };
 
-use crate::structs::{FrequencyPans, ThreadState, TransformedWindowAndPans};
+use crate::{
+    options::Averaging,
+    structs::{FrequencyPans, ThreadState, TransformedWindowAndPans},
+    viz::VizWriter,
+};
 
 pub struct PanningAverager {
     // Temporary location for transformed windows and pans so that they can be finished out-of-order
@@ -24,10 +29,39 @@ struct EnqueueAndAverageState {
     pub transformed_window_and_pans_queue: VecDeque<TransformedWindowAndPans>,
     // The current average pans
     pub pan_averages: Vec<FrequencyPans>,
+    // Only used with -average median: The pans being averaged for each frequency, sorted
+    pub median_windows: Vec<MedianWindow>,
+    // Additional passes of averaging, via -smoothing-passes
+    pub smoothing_passes: Vec<SmoothingPass>,
+    // Via -viz: The averaged pans are written here, in order
+    pub viz_writer: Option<VizWriter>,
+}
+
+// Averages the already-averaged pans again. Each pass is a running average over the same number of transforms as
+// the first pass, so that cascading passes approximates a Gaussian
+struct SmoothingPass {
+    // Like transformed_window_and_pans_queue: The transform in the middle of the queue is the one that is averaged
+    transformed_window_and_pans_queue: VecDeque<TransformedWindowAndPans>,
+    // The sum of the pans being averaged for each frequency. None until the queue is full for the first time
+    pan_sums: Option<Vec<FrequencyPans>>,
+}
+
+struct MedianWindow {
+    left_to_right: SortedWindow,
+    back_to_front: SortedWindow,
+}
+
+// Keeps values sorted so that the median can be found
+struct SortedWindow {
+    values: Vec<f32>,
 }
 
 impl PanningAverager {
-    pub fn new(window_size: usize) -> PanningAverager {
+    pub fn new(
+        window_size: usize,
+        smoothing_passes: usize,
+        viz_writer: Option<VizWriter>,
+    ) -> PanningAverager {
         let window_midpoint = window_size / 2;
 
         // Calculate ranges for averaging each sub frequency
@@ -62,6 +96,15 @@ impl PanningAverager {
                 next_last_sample_ctr_to_enqueue: window_size - 1,
                 transformed_window_and_pans_queue: VecDeque::new(),
                 pan_averages: Vec::with_capacity(window_size - 1),
+                median_windows: Vec::new(),
+                // The first pass is the rolling average above
+                smoothing_passes: (1..smoothing_passes)
+                    .map(|_| SmoothingPass {
+                        transformed_window_and_pans_queue: VecDeque::with_capacity(window_size),
+                        pan_sums: None,
+                    })
+                    .collect(),
+                viz_writer,
             }),
         }
     }
@@ -81,14 +124,22 @@ impl PanningAverager {
         );
     }
 
+    // The number of transformed windows that are waiting for the windows before them
+    pub fn num_windows_waiting(&self) -> usize {
+        self.transformed_window_and_pans_by_sample
+            .lock()
+            .expect("Cannot aquire lock because a thread panicked")
+            .len()
+    }
+
     // Enqueues the transformed_window_and_pans and averages pans if possible
-    pub fn enqueue_and_average(&self, thread_state: &ThreadState) {
+    pub fn enqueue_and_average(&self, thread_state: &ThreadState) -> Result<()> {
         // The thread that can lock self.transformed_window_and_pans_queue will keep writing samples are long as there
         // are samples to write
         // All other threads will skip this logic and continue performing FFTs while a thread has this lock
         let mut enqueue_and_average_state = match self.enqueue_and_average_state.try_lock() {
             Ok(enqueue_and_average_state) => enqueue_and_average_state,
-            _ => return,
+            _ => return Ok(()),
         };
 
         // Get all transformed windows in order
@@ -121,6 +172,8 @@ impl PanningAverager {
                                         left_transformed: None,
                                         right_transformed: None,
                                         mono_transformed: None,
+                                        source_window: None,
+                                        window_dump: None,
                                         frequency_pans: last_transformed_window_and_pans
                                             .frequency_pans
                                             .clone(),
@@ -142,6 +195,8 @@ impl PanningAverager {
                                         left_transformed: None,
                                         right_transformed: None,
                                         mono_transformed: None,
+                                        source_window: None,
+                                        window_dump: None,
                                         frequency_pans: last_transformed_window_and_pans
                                             .frequency_pans
                                             .clone(),
@@ -168,11 +223,14 @@ impl PanningAverager {
                             for freq_ctr in 0..thread_state.upmixer.window_midpoint {
                                 let mut average_left_to_right = 0.0;
                                 let mut average_back_to_front = 0.0;
+                                // Every pan up to, but not including, the upper bound, because the pan at the
+                                // upper bound is added when the first average is calculated. (Stopping a pan sooner
+                                // left it out of the average, but it was still subtracted when it left the average,
+                                // so every average after it was off by that pan)
                                 for sample_ctr in enqueue_and_average_state
                                     .average_last_sample_ctr_lower_bounds[freq_ctr]
-                                    ..(enqueue_and_average_state
-                                        .average_last_sample_ctr_upper_bounds[freq_ctr]
-                                        - 1)
+                                    ..enqueue_and_average_state.average_last_sample_ctr_upper_bounds
+                                        [freq_ctr]
                                 {
                                     let fraction_per_frequency = enqueue_and_average_state
                                         .pan_fraction_per_frequencies[freq_ctr];
@@ -187,6 +245,32 @@ impl PanningAverager {
                                         frequency_pans.back_to_front * fraction_per_frequency;
                                 }
 
+                                if let Averaging::Median = thread_state.upmixer.options.averaging {
+                                    let mut median_window = MedianWindow {
+                                        left_to_right: SortedWindow::new(),
+                                        back_to_front: SortedWindow::new(),
+                                    };
+
+                                    for sample_ctr in enqueue_and_average_state
+                                        .average_last_sample_ctr_lower_bounds[freq_ctr]
+                                        ..enqueue_and_average_state
+                                            .average_last_sample_ctr_upper_bounds[freq_ctr]
+                                    {
+                                        let frequency_pans = &enqueue_and_average_state
+                                            .transformed_window_and_pans_queue[sample_ctr]
+                                            .frequency_pans[freq_ctr];
+
+                                        median_window
+                                            .left_to_right
+                                            .insert(frequency_pans.left_to_right);
+                                        median_window
+                                            .back_to_front
+                                            .insert(frequency_pans.back_to_front);
+                                    }
+
+                                    enqueue_and_average_state.median_windows.push(median_window);
+                                }
+
                                 enqueue_and_average_state.pan_averages.push(FrequencyPans {
                                     amplitude: 0.0, // unused
                                     left_to_right: average_left_to_right,
@@ -203,8 +287,8 @@ impl PanningAverager {
         }
 
         // Gaurd against no averaging
-        if enqueue_and_average_state.pan_averages.len() == 0 {
-            return;
+        if enqueue_and_average_state.pan_averages.is_empty() {
+            return Ok(());
         }
 
         // Calculate averages and enqueue for final transforms and writing
@@ -225,13 +309,37 @@ impl PanningAverager {
                     .frequency_pans[freq_ctr]
                     .clone();
 
-                let frequency_pan_average = &mut enqueue_and_average_state.pan_averages[freq_ctr];
+                match thread_state.upmixer.options.averaging {
+                    Averaging::Mean => {
+                        let frequency_pan_average =
+                            &mut enqueue_and_average_state.pan_averages[freq_ctr];
 
-                let adjust_left_to_right = frequency_pan.left_to_right * pan_fraction_per_frequency;
-                frequency_pan_average.left_to_right += adjust_left_to_right;
+                        let adjust_left_to_right =
+                            frequency_pan.left_to_right * pan_fraction_per_frequency;
+                        frequency_pan_average.left_to_right += adjust_left_to_right;
 
-                let adjust_back_to_front = frequency_pan.back_to_front * pan_fraction_per_frequency;
-                frequency_pan_average.back_to_front += adjust_back_to_front;
+                        let adjust_back_to_front =
+                            frequency_pan.back_to_front * pan_fraction_per_frequency;
+                        frequency_pan_average.back_to_front += adjust_back_to_front;
+                    }
+                    Averaging::Median => {
+                        let median_window = &mut enqueue_and_average_state.median_windows[freq_ctr];
+                        median_window
+                            .left_to_right
+                            .insert(frequency_pan.left_to_right);
+                        median_window
+                            .back_to_front
+                            .insert(frequency_pan.back_to_front);
+
+                        let left_to_right = median_window.left_to_right.median();
+                        let back_to_front = median_window.back_to_front.median();
+
+                        let frequency_pan_average =
+                            &mut enqueue_and_average_state.pan_averages[freq_ctr];
+                        frequency_pan_average.left_to_right = left_to_right;
+                        frequency_pan_average.back_to_front = back_to_front;
+                    }
+                }
             }
 
             // enqueue the averaged transformed window and pans
@@ -248,22 +356,28 @@ impl PanningAverager {
             let left_transformed = transformed_window_and_pans.left_transformed.take();
             let right_transformed = transformed_window_and_pans.right_transformed.take();
             let mono_transformed = transformed_window_and_pans.mono_transformed.take();
-
-            for freq_ctr in 0..frequency_pans.len() {
-                frequency_pans[freq_ctr].amplitude =
-                    transformed_window_and_pans.frequency_pans[freq_ctr].amplitude;
+            let source_window = transformed_window_and_pans.source_window.take();
+            let window_dump = transformed_window_and_pans.window_dump.take();
+
+            for (frequency_pan, transformed_frequency_pan) in frequency_pans
+                .iter_mut()
+                .zip(&transformed_window_and_pans.frequency_pans)
+            {
+                frequency_pan.amplitude = transformed_frequency_pan.amplitude;
             }
 
-            thread_state
-                .upmixer
-                .panner_and_writer
-                .enqueue(TransformedWindowAndPans {
+            enqueue_and_average_state.smooth_and_enqueue(
+                thread_state,
+                TransformedWindowAndPans {
                     last_sample_ctr,
                     left_transformed,
                     right_transformed,
                     mono_transformed,
+                    source_window,
+                    window_dump,
                     frequency_pans,
-                });
+                },
+            )?;
 
             // Special case to stop averaging
             if is_last_transform {
@@ -271,7 +385,7 @@ impl PanningAverager {
                     .transformed_window_and_pans_queue
                     .clear();
 
-                return;
+                return Ok(());
             }
 
             // Remove the unneeded pans
@@ -286,13 +400,29 @@ impl PanningAverager {
                     .frequency_pans[freq_ctr]
                     .clone();
 
-                let frequency_pan_average = &mut enqueue_and_average_state.pan_averages[freq_ctr];
+                match thread_state.upmixer.options.averaging {
+                    Averaging::Mean => {
+                        let frequency_pan_average =
+                            &mut enqueue_and_average_state.pan_averages[freq_ctr];
 
-                let adjust_left_to_right = frequency_pan.left_to_right * pan_fraction_per_frequency;
-                frequency_pan_average.left_to_right -= adjust_left_to_right;
+                        let adjust_left_to_right =
+                            frequency_pan.left_to_right * pan_fraction_per_frequency;
+                        frequency_pan_average.left_to_right -= adjust_left_to_right;
 
-                let adjust_back_to_front = frequency_pan.back_to_front * pan_fraction_per_frequency;
-                frequency_pan_average.back_to_front -= adjust_back_to_front;
+                        let adjust_back_to_front =
+                            frequency_pan.back_to_front * pan_fraction_per_frequency;
+                        frequency_pan_average.back_to_front -= adjust_back_to_front;
+                    }
+                    Averaging::Median => {
+                        let median_window = &mut enqueue_and_average_state.median_windows[freq_ctr];
+                        median_window
+                            .left_to_right
+                            .remove(frequency_pan.left_to_right);
+                        median_window
+                            .back_to_front
+                            .remove(frequency_pan.back_to_front);
+                    }
+                }
             }
 
             // dequeue
@@ -300,5 +430,277 @@ impl PanningAverager {
                 .transformed_window_and_pans_queue
                 .pop_front();
         }
+
+        Ok(())
+    }
+}
+
+impl EnqueueAndAverageState {
+    // Runs the averaged pans through the additional smoothing passes, and then enqueues them for writing
+    fn smooth_and_enqueue(
+        &mut self,
+        thread_state: &ThreadState,
+        transformed_window_and_pans: TransformedWindowAndPans,
+    ) -> Result<()> {
+        let mut smoothed = vec![transformed_window_and_pans];
+
+        for smoothing_pass in self.smoothing_passes.iter_mut() {
+            let mut next_smoothed = Vec::new();
+            for transformed_window_and_pans in smoothed {
+                smoothing_pass.average(
+                    thread_state,
+                    &self.average_last_sample_ctr_lower_bounds,
+                    &self.average_last_sample_ctr_upper_bounds,
+                    &self.pan_fraction_per_frequencies,
+                    transformed_window_and_pans,
+                    &mut next_smoothed,
+                );
+            }
+
+            smoothed = next_smoothed;
+        }
+
+        for mut transformed_window_and_pans in smoothed {
+            let frequency_smoothing = thread_state.upmixer.options.frequency_smoothing;
+            if frequency_smoothing > 0 {
+                smooth_across_frequencies(
+                    &mut transformed_window_and_pans.frequency_pans,
+                    frequency_smoothing,
+                );
+            }
+
+            if let Some(viz_writer) = self.viz_writer.as_mut() {
+                viz_writer.write(&transformed_window_and_pans)?;
+            }
+
+            thread_state
+                .upmixer
+                .panner_and_writer
+                .enqueue(transformed_window_and_pans);
+        }
+
+        Ok(())
+    }
+}
+
+impl SmoothingPass {
+    // Adds the transform to the pass, and pushes the transforms that are completely averaged into smoothed
+    fn average(
+        &mut self,
+        thread_state: &ThreadState,
+        lower_bounds: &[usize],
+        upper_bounds: &[usize],
+        pan_fraction_per_frequencies: &[f32],
+        transformed_window_and_pans: TransformedWindowAndPans,
+        smoothed: &mut Vec<TransformedWindowAndPans>,
+    ) {
+        let window_size = thread_state.upmixer.window_size;
+        let window_midpoint = thread_state.upmixer.window_midpoint;
+
+        let is_last_transform = transformed_window_and_pans.last_sample_ctr
+            == thread_state.upmixer.total_samples_to_write - 1;
+
+        // Special case: First transform
+        // Pre-seed copies of the first pans before it, so that it is in the middle of the queue
+        if self.transformed_window_and_pans_queue.is_empty() {
+            for _ in 0..window_midpoint {
+                self.transformed_window_and_pans_queue
+                    .push_back(placeholder(&transformed_window_and_pans));
+            }
+        }
+
+        // Special case: Last transform
+        // Seed copies of the last pans after it, so that it reaches the middle of the queue
+        let last_placeholder = if is_last_transform {
+            Some(placeholder(&transformed_window_and_pans))
+        } else {
+            None
+        };
+
+        self.transformed_window_and_pans_queue
+            .push_back(transformed_window_and_pans);
+
+        if let Some(last_placeholder) = last_placeholder {
+            for _ in 0..(window_midpoint - 1) {
+                self.transformed_window_and_pans_queue
+                    .push_back(placeholder(&last_placeholder));
+            }
+        }
+
+        while self.transformed_window_and_pans_queue.len() >= window_size {
+            let queue = &self.transformed_window_and_pans_queue;
+
+            // The upper bound is added below, when the average is calculated
+            let pan_sums = self.pan_sums.get_or_insert_with(|| {
+                (0..window_midpoint)
+                    .map(|freq_ctr| {
+                        let mut pan_sum = FrequencyPans {
+                            amplitude: 0.0, // unused
+                            left_to_right: 0.0,
+                            back_to_front: 0.0,
+                        };
+
+                        for transformed_window_and_pans in
+                            queue.range(lower_bounds[freq_ctr]..upper_bounds[freq_ctr])
+                        {
+                            let frequency_pans =
+                                &transformed_window_and_pans.frequency_pans[freq_ctr];
+                            pan_sum.left_to_right += frequency_pans.left_to_right;
+                            pan_sum.back_to_front += frequency_pans.back_to_front;
+                        }
+
+                        pan_sum
+                    })
+                    .collect()
+            });
+
+            // Add newly-added pans, and average
+            let mut frequency_pans = queue[window_midpoint].frequency_pans.clone();
+            for freq_ctr in 0..window_midpoint {
+                let pan_sum = &mut pan_sums[freq_ctr];
+                let frequency_pan = &queue[upper_bounds[freq_ctr]].frequency_pans[freq_ctr];
+                pan_sum.left_to_right += frequency_pan.left_to_right;
+                pan_sum.back_to_front += frequency_pan.back_to_front;
+
+                frequency_pans[freq_ctr].left_to_right =
+                    pan_sum.left_to_right * pan_fraction_per_frequencies[freq_ctr];
+                frequency_pans[freq_ctr].back_to_front =
+                    pan_sum.back_to_front * pan_fraction_per_frequencies[freq_ctr];
+            }
+
+            let transformed_window_and_pans =
+                &mut self.transformed_window_and_pans_queue[window_midpoint];
+            let is_last_transform = transformed_window_and_pans.last_sample_ctr
+                == thread_state.upmixer.total_samples_to_write - 1;
+
+            smoothed.push(TransformedWindowAndPans {
+                last_sample_ctr: transformed_window_and_pans.last_sample_ctr,
+                left_transformed: transformed_window_and_pans.left_transformed.take(),
+                right_transformed: transformed_window_and_pans.right_transformed.take(),
+                mono_transformed: transformed_window_and_pans.mono_transformed.take(),
+                source_window: transformed_window_and_pans.source_window.take(),
+                window_dump: transformed_window_and_pans.window_dump.take(),
+                frequency_pans,
+            });
+
+            // Special case to stop averaging
+            if is_last_transform {
+                self.transformed_window_and_pans_queue.clear();
+                return;
+            }
+
+            // Remove the unneeded pans
+            for freq_ctr in 0..window_midpoint {
+                let frequency_pan = &self.transformed_window_and_pans_queue[lower_bounds[freq_ctr]]
+                    .frequency_pans[freq_ctr];
+                pan_sums[freq_ctr].left_to_right -= frequency_pan.left_to_right;
+                pan_sums[freq_ctr].back_to_front -= frequency_pan.back_to_front;
+            }
+
+            self.transformed_window_and_pans_queue.pop_front();
+        }
+    }
+}
+
+// -freq-smooth: Averages each frequency's pans with the pans of this many neighboring frequencies on each side, so that
+// adjacent frequencies aren't steered to different speakers. Fewer neighbors are averaged at the lowest and highest
+// frequencies
+pub fn smooth_across_frequencies(frequency_pans: &mut [FrequencyPans], bins: usize) {
+    // Running sums, so that each average is a subtraction. sums[n] is the sum of the first n pans
+    let mut left_to_right_sums = Vec::with_capacity(frequency_pans.len() + 1);
+    let mut back_to_front_sums = Vec::with_capacity(frequency_pans.len() + 1);
+    left_to_right_sums.push(0.0);
+    back_to_front_sums.push(0.0);
+    for (freq_ctr, frequency_pan) in frequency_pans.iter().enumerate() {
+        left_to_right_sums.push(left_to_right_sums[freq_ctr] + frequency_pan.left_to_right);
+        back_to_front_sums.push(back_to_front_sums[freq_ctr] + frequency_pan.back_to_front);
+    }
+
+    for (freq_ctr, frequency_pan) in frequency_pans.iter_mut().enumerate() {
+        let lower_bound = freq_ctr.saturating_sub(bins);
+        let upper_bound = (freq_ctr + bins + 1).min(left_to_right_sums.len() - 1);
+        let num_pans = (upper_bound - lower_bound) as f32;
+
+        frequency_pan.left_to_right =
+            (left_to_right_sums[upper_bound] - left_to_right_sums[lower_bound]) / num_pans;
+        frequency_pan.back_to_front =
+            (back_to_front_sums[upper_bound] - back_to_front_sums[lower_bound]) / num_pans;
+    }
+}
+
+// A copy of a transform's pans, without the transforms, to pad the start and end of averaging
+fn placeholder(transformed_window_and_pans: &TransformedWindowAndPans) -> TransformedWindowAndPans {
+    TransformedWindowAndPans {
+        // Placeholders are never written
+        last_sample_ctr: usize::MAX,
+        left_transformed: None,
+        right_transformed: None,
+        mono_transformed: None,
+        source_window: None,
+        window_dump: None,
+        frequency_pans: transformed_window_and_pans.frequency_pans.clone(),
+    }
+}
+
+impl SortedWindow {
+    fn new() -> SortedWindow {
+        SortedWindow { values: Vec::new() }
+    }
+
+    fn insert(&mut self, value: f32) {
+        let index = self.values.partition_point(|existing| *existing < value);
+        self.values.insert(index, value);
+    }
+
+    fn remove(&mut self, value: f32) {
+        let index = self.values.partition_point(|existing| *existing < value);
+        if index < self.values.len() {
+            self.values.remove(index);
+        }
+    }
+
+    fn median(&self) -> f32 {
+        let len = self.values.len();
+        if len == 0 {
+            0.0
+        } else if len % 2 == 1 {
+            self.values[len / 2]
+        } else {
+            (self.values[(len / 2) - 1] + self.values[len / 2]) / 2.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_support::dumped_column;
+
+    #[test]
+    fn averaging_a_constant_pan_keeps_it_unchanged() {
+        // Noise, so that every frequency is loud enough to steer, only in the left
+        let mut seed = 1u32;
+        let left: Vec<f32> = (0..4410)
+            .map(|_| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+            })
+            .collect();
+        let right = vec![0.0; left.len()];
+
+        let averaged_left_to_right = dumped_column(
+            &left,
+            &right,
+            &["-low", "1000", "-channels", "4"],
+            "averaged_left_to_right",
+        );
+        assert!(!averaged_left_to_right.is_empty());
+        for (freq_ctr, left_to_right) in averaged_left_to_right.iter().enumerate() {
+            assert!(
+                (left_to_right + 1.0).abs() < 0.0001,
+                "Frequency {} is averaged to {}, instead of hard left",
+                freq_ctr,
+                left_to_right
+            );
+        }
     }
 }
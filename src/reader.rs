@@ -1,54 +1,300 @@
 use std::{
     collections::VecDeque,
-    io::Result,
-    sync::{Arc, Mutex},
+    f32::consts::{FRAC_PI_2, PI},
+    io::{Error, ErrorKind, Result},
+    mem,
+    ops::{Range, RangeInclusive},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use rustfft::{num_complex::Complex, Fft};
-use wave_stream::wave_reader::{StreamWavReader, StreamWavReaderIterator};
+use wave_stream::{
+    open_wav::OpenWav,
+    read_wav_from_file_path,
+    samples_by_channel::SamplesByChannel,
+    wave_reader::{StreamOpenWavReader, StreamWavReader},
+};
 
 use crate::{
-    options::Options,
-    structs::{ThreadState, TransformedWindowAndPans},
+    options::{Analysis, Options, SilentChannel},
+    structs::{DcOffset, ThreadState, TransformedWindowAndPans},
     vecdeque_ext::VecDequeExt,
+    window_dump::WindowDump,
+    window_sizes::bin_frequency,
 };
 
+// The largest offset between the left and right channels that -align-input detects
+const MAX_ALIGNMENT_OFFSET: usize = 8;
+
+// -trim-silence: Silence at the start or end of the source that is shorter than this is kept, so that quiet intros
+// aren't clipped
+pub const DEFAULT_TRIM_SILENCE_MIN_SECONDS: f32 = 1.0;
+
+// -mono-detect: A window is mono when its side (L - R) is at least this much quieter than its mid (L + R), unless
+// -mono-detect-threshold is specified
+pub const DEFAULT_MONO_DETECT_THRESHOLD_DB: f32 = -40.0;
+
 pub struct Reader {
     open_wav_reader_and_buffer: Mutex<OpenWavReaderAndBuffer>,
+    // Via -in-memory, or automatically for short sources: The entire source, already read. None when streaming
+    in_memory_source: Option<InMemorySource>,
     fft_forward: Arc<dyn Fft<f32>>,
+    // The amplitude, within a transform, that -minimum corresponds to
+    minimum_steered_amplitude: f32,
+    // The amplitude, within a transform, that -rear-gate corresponds to
+    rear_gate_amplitude: Option<f32>,
+    // The frequencies (by freq_ctr) that -rear-band steers to the rear
+    rear_band: Option<RangeInclusive<usize>>,
+    // Only present with -verbose. None until a frequency is measured since the last time it was logged
+    dominant_source: Option<Mutex<Option<DominantSource>>>,
+    // With -oversample, the transforms are at a higher sample rate than the source
+    transform_sample_rate: usize,
+    // -sanitize: The number of frequencies whose pans weren't finite
+    non_finite_pans: AtomicUsize,
+}
+
+// -verbose: The loudest frequency, and where it's steered, before averaging
+struct DominantSource {
+    freq_ctr: usize,
+    // The sum of the left and right amplitudes
+    amplitude: f32,
+    left_to_right: f32,
+    back_to_front: f32,
+}
+
+// The average and RMS of each channel of the source, from a scan of the entire source
+struct SourceLevels {
+    dc_offset: DcOffset,
+    // RMS around the DC offset, so that -balance-input isn't skewed by it
+    left_rms: f32,
+    right_rms: f32,
 }
 
 // Allows wrapping information about reading the wav into a single mutex
 struct OpenWavReaderAndBuffer {
-    stream_wav_reader_iterator: StreamWavReaderIterator<f32>,
+    // The source's samples, in the order that they are upmixed
+    source_samples: Box<dyn Iterator<Item = Result<SamplesByChannel<f32>>>>,
     total_samples_read: usize,
     left_buffer: VecDeque<Complex<f32>>,
     right_buffer: VecDeque<Complex<f32>>,
     mono_buffer: VecDeque<Complex<f32>>,
+    // Subtracted from every sample read. Zero unless -remove-dc is specified
+    dc_offset: DcOffset,
+    // Via -balance-input: Multiplied with every sample read, so that the left and right are equally loud. 1 otherwise
+    left_balance: f32,
+    right_balance: f32,
+    // Used to measure the DC offset of the samples read from the source
+    left_sum: f64,
+    right_sum: f64,
+    samples_read_from_source: usize,
+    // Via -oversample: The number of zeros to insert before the next sample is read from the source
+    zeros_until_next_read: usize,
+    // -sanitize: The number of samples read from the source that weren't finite
+    non_finite_samples: usize,
+    // Delays the leading channel when -align-input detects an offset between the channels. Both are empty otherwise
+    left_delay: VecDeque<f32>,
+    right_delay: VecDeque<f32>,
+}
+
+// The entire source, read before upmixing starts, so that windows are copied out of it without locking
+struct InMemorySource {
+    left: Vec<Complex<f32>>,
+    right: Vec<Complex<f32>>,
+    // Empty unless the mono channel is transformed
+    mono: Vec<Complex<f32>>,
+    total_samples_read: AtomicUsize,
 }
 
 impl Reader {
+    // source_range is the samples in the source that are upmixed. in_memory_len is the number of samples to read into
+    // memory, including padding at the end, or None to stream the source
     pub fn open(
         options: &Options,
         stream_wav_reader: StreamWavReader<f32>,
         window_size: usize,
+        transform_sample_rate: usize,
         fft_forward: Arc<dyn Fft<f32>>,
+        source_range: Range<usize>,
+        in_memory_len: Option<usize>,
     ) -> Result<Reader> {
+        // The DC offset and balance must be known before the first window is transformed, so the source is scanned
+        // ahead of time. (Both are measured in the same scan)
+        let source_levels = if options.remove_dc || options.balance_input {
+            Some(measure_source_levels(&options.source_wav_path)?)
+        } else {
+            None
+        };
+
+        let dc_offset = match &source_levels {
+            Some(source_levels) if options.remove_dc => {
+                let dc_offset = source_levels.dc_offset;
+                println!(
+                    "Removing DC offset: left {:.6}, right {:.6}",
+                    dc_offset.left, dc_offset.right
+                );
+                dc_offset
+            }
+            _ => DcOffset {
+                left: 0.0,
+                right: 0.0,
+            },
+        };
+
+        let (left_balance, right_balance) = match &source_levels {
+            Some(source_levels) if options.balance_input => {
+                if source_levels.left_rms > 0.0 && source_levels.right_rms > 0.0 {
+                    // Each channel is moved halfway, so that the overall level stays the same
+                    let left_balance = (source_levels.right_rms / source_levels.left_rms).sqrt();
+                    let right_balance = 1.0 / left_balance;
+                    println!(
+                        "Balancing input: left {:+.2} dB, right {:+.2} dB",
+                        20.0 * left_balance.log10(),
+                        20.0 * right_balance.log10()
+                    );
+                    (left_balance, right_balance)
+                } else {
+                    println!("Balancing input: not balanced, because a channel is silent");
+                    (1.0, 1.0)
+                }
+            }
+            _ => (1.0, 1.0),
+        };
+
+        // Positive when the right channel is behind the left channel
+        let channel_offset = if options.align_input {
+            // The offset must be known before the first window is transformed, so the start of the source is
+            // scanned ahead of time
+            let channel_offset = measure_channel_offset(&options.source_wav_path)?;
+            if channel_offset > 0 {
+                println!(
+                    "Aligning input: the right channel is {} sample(s) behind the left channel",
+                    channel_offset
+                );
+            } else if channel_offset < 0 {
+                println!(
+                    "Aligning input: the left channel is {} sample(s) behind the right channel",
+                    -channel_offset
+                );
+            } else {
+                println!("Aligning input: the left and right channels are aligned");
+            }
+
+            // Upmixing backwards reverses which channel is behind
+            if options.reverse {
+                -channel_offset
+            } else {
+                channel_offset
+            }
+        } else {
+            0
+        };
+
+        let source_samples: Box<dyn Iterator<Item = Result<SamplesByChannel<f32>>>> = if options
+            .reverse
+        {
+            // The entire source is read into memory so that it can be upmixed backwards. Only the left and right
+            // are kept, because every channel of every sample would be much larger. (See -max-memory)
+            let mut left_and_rights = Vec::with_capacity(stream_wav_reader.info().len_samples());
+            for samples_result in stream_wav_reader.into_iter() {
+                left_and_rights.push(left_and_right(&samples_result?));
+            }
+            left_and_rights.reverse();
+            Box::new(left_and_rights.into_iter().take(source_range.end).map(
+                |(front_left, front_right)| {
+                    Ok(SamplesByChannel::new()
+                        .front_left(front_left)
+                        .front_right(front_right))
+                },
+            ))
+        } else {
+            Box::new(stream_wav_reader.into_iter().take(source_range.end))
+        };
+
         let mut open_wav_reader_and_buffer = OpenWavReaderAndBuffer {
-            stream_wav_reader_iterator: stream_wav_reader.into_iter(),
+            source_samples,
             total_samples_read: window_size - 1,
             left_buffer: VecDeque::with_capacity(window_size),
             right_buffer: VecDeque::with_capacity(window_size),
-            mono_buffer: VecDeque::with_capacity(window_size),
+            // Layouts without a center, like 4, never transform the mono channel
+            mono_buffer: if options.transform_mono {
+                VecDeque::with_capacity(window_size)
+            } else {
+                VecDeque::new()
+            },
+            dc_offset,
+            left_balance,
+            right_balance,
+            left_sum: 0.0,
+            right_sum: 0.0,
+            samples_read_from_source: 0,
+            zeros_until_next_read: 0,
+            non_finite_samples: 0,
+            left_delay: VecDeque::from(vec![0.0f32; channel_offset.max(0) as usize]),
+            right_delay: VecDeque::from(vec![0.0f32; (-channel_offset).max(0) as usize]),
         };
 
-        for _sample_to_read in 0..(window_size - 1) {
-            open_wav_reader_and_buffer.queue_next_sample(options)?;
+        // When resuming, or with -trim-silence, samples before where upmixing starts are skipped
+        for _sample_to_skip in 0..source_range.start {
+            if let Some(samples_result) = open_wav_reader_and_buffer.source_samples.next() {
+                samples_result?;
+            }
         }
 
+        let in_memory_source = match in_memory_len {
+            Some(in_memory_len) => {
+                // Reading is the same as streaming, except that the buffers are never popped
+                for _sample_to_read in 0..in_memory_len {
+                    open_wav_reader_and_buffer.queue_next_sample(options)?;
+                }
+
+                Some(InMemorySource {
+                    left: Vec::from(mem::take(&mut open_wav_reader_and_buffer.left_buffer)),
+                    right: Vec::from(mem::take(&mut open_wav_reader_and_buffer.right_buffer)),
+                    mono: Vec::from(mem::take(&mut open_wav_reader_and_buffer.mono_buffer)),
+                    total_samples_read: AtomicUsize::new(window_size - 1),
+                })
+            }
+            None => {
+                for _sample_to_read in 0..(window_size - 1) {
+                    open_wav_reader_and_buffer.queue_next_sample(options)?;
+                }
+
+                None
+            }
+        };
+
+        // -minimum is normalized by the window size, so that it means the same thing regardless of -low and the
+        // sample rate
+        let minimum_steered_amplitude = options.minimum_steered_amplitude * (window_size as f32);
+
+        // A full-scale sine wave has an amplitude of window_size / 2 in its frequency's bin
+        let rear_gate_amplitude = options
+            .rear_gate
+            .map(|rear_gate| 10.0f32.powf(rear_gate / 20.0) * (window_size as f32) / 2.0);
+
+        let rear_band = match options.rear_band {
+            Some((low, high)) => Some(rear_band(low, high, window_size, transform_sample_rate)?),
+            None => None,
+        };
+
         Ok(Reader {
             open_wav_reader_and_buffer: Mutex::new(open_wav_reader_and_buffer),
+            in_memory_source,
             fft_forward,
+            minimum_steered_amplitude,
+            rear_gate_amplitude,
+            rear_band,
+            dominant_source: if options.verbose {
+                Some(Mutex::new(None))
+            } else {
+                None
+            },
+            transform_sample_rate,
+            non_finite_pans: AtomicUsize::new(0),
         })
     }
 
@@ -64,14 +310,38 @@ impl Reader {
         let mut right_transformed: Vec<Complex<f32>>;
         let mut mono_transformed: Option<Vec<Complex<f32>>>;
         let last_sample_ctr: usize;
-        {
+        if let Some(in_memory_source) = &self.in_memory_source {
+            // The buffers are already padded to the end of the last window
+            last_sample_ctr = in_memory_source
+                .total_samples_read
+                .fetch_add(1, Ordering::Relaxed);
+            if last_sample_ctr >= in_memory_source.left.len() {
+                return Ok(None);
+            }
+
+            let window =
+                (last_sample_ctr + 1 - thread_state.upmixer.window_size)..(last_sample_ctr + 1);
+            left_transformed = in_memory_source.left[window.clone()].to_vec();
+            right_transformed = in_memory_source.right[window.clone()].to_vec();
+            mono_transformed = if thread_state.upmixer.options.transform_mono {
+                Some(in_memory_source.mono[window].to_vec())
+            } else {
+                None
+            };
+        } else {
             let mut open_wav_reader_and_buffer = self
                 .open_wav_reader_and_buffer
                 .lock()
                 .expect("Cannot aquire lock because a thread panicked");
 
             last_sample_ctr = open_wav_reader_and_buffer.total_samples_read;
-            if last_sample_ctr >= thread_state.upmixer.total_samples_to_write {
+            // A single window can be a sample longer than the source, so that its size is even
+            if last_sample_ctr
+                >= thread_state
+                    .upmixer
+                    .total_samples_to_write
+                    .max(thread_state.upmixer.window_size)
+            {
                 return Ok(None);
             } else {
                 open_wav_reader_and_buffer.total_samples_read += 1;
@@ -96,10 +366,40 @@ impl Reader {
                 ));
                 open_wav_reader_and_buffer.mono_buffer.pop_front();
             } else {
+                debug_assert!(
+                    open_wav_reader_and_buffer.mono_buffer.is_empty(),
+                    "The mono channel is buffered, but not transformed"
+                );
                 mono_transformed = None;
             }
         }
 
+        // -stereo-passthrough: The fronts are written from the samples as they were read, instead of from the inverse
+        // transforms
+        let source_window = if thread_state.upmixer.options.stereo_passthrough {
+            Some((
+                left_transformed.iter().map(|sample| sample.re).collect(),
+                right_transformed.iter().map(|sample| sample.re).collect(),
+            ))
+        } else {
+            None
+        };
+
+        if let Some(window) = thread_state.upmixer.window(last_sample_ctr) {
+            for transformed in [
+                Some(&mut left_transformed),
+                Some(&mut right_transformed),
+                mono_transformed.as_mut(),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                for (sample, level) in transformed.iter_mut().zip(window) {
+                    *sample *= level;
+                }
+            }
+        }
+
         self.fft_forward
             .process_with_scratch(&mut left_transformed, &mut thread_state.scratch_forward);
         self.fft_forward
@@ -114,20 +414,67 @@ impl Reader {
             mono_transformed = Some(mono_transformed_value);
         }
 
+        // -oversample: Removes everything above the source's Nyquist frequency, which only has the images created by
+        // upsampling
+        let oversample = thread_state.upmixer.options.oversample;
+        if oversample > 1 {
+            let window_size = thread_state.upmixer.window_size;
+            let nyquist = window_size / (2 * oversample);
+            for transformed in [
+                Some(&mut left_transformed),
+                Some(&mut right_transformed),
+                mono_transformed.as_mut(),
+            ]
+            .into_iter()
+            .flatten()
+            {
+                for bin in &mut transformed[(nyquist + 1)..(window_size - nyquist)] {
+                    *bin = Complex { re: 0.0, im: 0.0 };
+                }
+            }
+        }
+
         let mut frequency_pans = Vec::with_capacity(thread_state.upmixer.window_midpoint);
+        let mut dominant_source: Option<DominantSource> = None;
         for freq_ctr in 1..(thread_state.upmixer.window_midpoint + 1) {
             // Phase ranges from -PI to +PI
-            let (left_amplitude, mut left_phase) = left_transformed[freq_ctr].to_polar();
-            let (right_amplitude, mut right_phase) = right_transformed[freq_ctr].to_polar();
+            let (mut left_amplitude, mut left_phase) = left_transformed[freq_ctr].to_polar();
+            let (mut right_amplitude, mut right_phase) = right_transformed[freq_ctr].to_polar();
+            let amplitude = left_amplitude + right_amplitude;
 
-            if left_amplitude < thread_state.upmixer.options.minimum_steered_amplitude
-                && right_amplitude >= thread_state.upmixer.options.minimum_steered_amplitude
-            {
-                left_phase = right_phase;
-            } else if left_amplitude >= thread_state.upmixer.options.minimum_steered_amplitude
-                && right_amplitude < thread_state.upmixer.options.minimum_steered_amplitude
-            {
-                right_phase = left_phase
+            // When one channel is silent, the phase of its noise can mis-steer the other channel
+            let left_silent = left_amplitude < self.minimum_steered_amplitude;
+            let right_silent = right_amplitude < self.minimum_steered_amplitude;
+
+            if left_silent != right_silent {
+                match thread_state.upmixer.options.silent_channel {
+                    SilentChannel::Copy => {
+                        // Hard-pan to the louder channel
+                        if left_silent {
+                            left_phase = right_phase;
+                        } else {
+                            right_phase = left_phase;
+                        }
+                    }
+                    SilentChannel::Center => {
+                        // Steer as if the tone is in-phase and centered
+                        if left_silent {
+                            left_phase = right_phase;
+                        } else {
+                            right_phase = left_phase;
+                        }
+
+                        let average_amplitude = (left_amplitude + right_amplitude) / 2.0;
+                        left_amplitude = average_amplitude;
+                        right_amplitude = average_amplitude;
+                    }
+                    SilentChannel::Keep => {}
+                }
+            }
+
+            if let Analysis::MidSide = thread_state.upmixer.options.analysis {
+                (left_phase, right_phase) =
+                    mid_side_phases(left_amplitude, left_phase, right_amplitude, right_phase);
             }
 
             /*
@@ -137,44 +484,506 @@ impl Reader {
             }
             */
 
-            let steer_result = thread_state.upmixer.options.matrix.steer(
+            let mut steer_result = thread_state.upmixer.options.matrix.steer(
                 left_amplitude,
                 left_phase,
                 right_amplitude,
                 right_phase,
             );
+
+            if let Some(center_focus) = thread_state.upmixer.options.center_focus {
+                thread_state
+                    .upmixer
+                    .options
+                    .matrix
+                    .focus_center(&mut steer_result.left_to_right, center_focus);
+            }
+
+            // Keep faint noise, like tape hiss, out of the rear. Quieter frequencies are steered further to the front
+            if let Some(rear_gate_amplitude) = self.rear_gate_amplitude {
+                if steer_result.amplitude < rear_gate_amplitude {
+                    steer_result.back_to_front *= steer_result.amplitude / rear_gate_amplitude;
+                }
+            }
+
+            // Frequencies outside of -rear-band are kept in the front
+            if let Some(rear_band) = &self.rear_band {
+                if !rear_band.contains(&freq_ctr) {
+                    steer_result.back_to_front = 0.0;
+                }
+            }
+
+            // -max-rear limits how far to the rear a frequency is steered, regardless of the matrix
+            if let Some(max_rear) = thread_state.upmixer.options.max_rear {
+                steer_result.back_to_front = steer_result.back_to_front.min(max_rear);
+            }
+
+            // -sanitize: A pan that isn't finite would stay in the running averages, and ruin every window after it,
+            // so it's replaced with silence in the front center
+            if thread_state.upmixer.options.sanitize
+                && !(steer_result.amplitude.is_finite()
+                    && steer_result.left_to_right.is_finite()
+                    && steer_result.back_to_front.is_finite())
+            {
+                steer_result.amplitude = 0.0;
+                steer_result.left_to_right = 0.0;
+                steer_result.back_to_front = 0.0;
+                self.non_finite_pans.fetch_add(1, Ordering::Relaxed);
+            }
+
+            if self.dominant_source.is_some()
+                && dominant_source
+                    .as_ref()
+                    .is_none_or(|dominant_source| amplitude > dominant_source.amplitude)
+            {
+                dominant_source = Some(DominantSource {
+                    freq_ctr,
+                    amplitude,
+                    left_to_right: steer_result.left_to_right,
+                    back_to_front: steer_result.back_to_front,
+                });
+            }
+
             frequency_pans.push(steer_result);
         }
 
+        // -mono-detect: When the left and right are (almost) the same, there is nothing to steer to the rear, so the phase
+        // of noise isn't allowed to steer anything there
+        if let Some(mono_detect_threshold) = thread_state.upmixer.options.mono_detect {
+            let window_midpoint = thread_state.upmixer.window_midpoint;
+            if is_mono(
+                &left_transformed[1..(window_midpoint + 1)],
+                &right_transformed[1..(window_midpoint + 1)],
+                mono_detect_threshold,
+            ) {
+                for pans in frequency_pans.iter_mut() {
+                    pans.back_to_front = 0.0;
+                }
+            }
+        }
+
+        // -dump-window: The window's analysis is kept with it, so that it can be written once the pans are averaged
+        let window_dump =
+            if thread_state.upmixer.dump_window_last_sample_ctr == Some(last_sample_ctr) {
+                let window_midpoint = thread_state.upmixer.window_midpoint;
+                Some(WindowDump::new(
+                    thread_state.upmixer.window_size,
+                    self.transform_sample_rate,
+                    &left_transformed[1..(window_midpoint + 1)],
+                    &right_transformed[1..(window_midpoint + 1)],
+                    &frequency_pans,
+                ))
+            } else {
+                None
+            };
+
+        // -verbose: Keeps the loudest frequency since the last time it was logged
+        if let (Some(loudest), Some(dominant_source)) = (&self.dominant_source, dominant_source) {
+            let mut loudest = loudest
+                .lock()
+                .expect("Cannot aquire lock because a thread panicked");
+            if loudest
+                .as_ref()
+                .is_none_or(|loudest| dominant_source.amplitude > loudest.amplitude)
+            {
+                *loudest = Some(dominant_source);
+            }
+        }
+
         let transformed_window_and_pans = TransformedWindowAndPans {
             last_sample_ctr,
             left_transformed: Some(left_transformed),
             right_transformed: Some(right_transformed),
             mono_transformed,
+            source_window,
+            window_dump,
             frequency_pans,
         };
 
-        return Ok(Some(transformed_window_and_pans));
+        Ok(Some(transformed_window_and_pans))
+    }
+
+    // -verbose: Where the loudest frequency since the last time this was called is steered. None without -verbose
+    pub fn take_dominant_source(&self, window_size: usize) -> Option<String> {
+        let dominant_source = self
+            .dominant_source
+            .as_ref()?
+            .lock()
+            .expect("Cannot aquire lock because a thread panicked")
+            .take()?;
+
+        // A full-scale sine wave in both channels has an amplitude of window_size / 2 in each channel's bin
+        let dbfs = 20.0 * (dominant_source.amplitude / window_size as f32).log10();
+
+        Some(format!(
+            "loudest {:.0}hz {:.0} dBFS, left_to_right {:.2}, back_to_front {:.2}",
+            bin_frequency(
+                dominant_source.freq_ctr,
+                window_size,
+                self.transform_sample_rate
+            ),
+            dbfs,
+            dominant_source.left_to_right,
+            dominant_source.back_to_front
+        ))
     }
 
     pub fn get_total_samples_read(&self) -> usize {
+        if let Some(in_memory_source) = &self.in_memory_source {
+            // Threads that find that there is nothing left to read still count a sample
+            return in_memory_source
+                .total_samples_read
+                .load(Ordering::Relaxed)
+                .min(in_memory_source.left.len());
+        }
+
         self.open_wav_reader_and_buffer
             .lock()
             .expect("Cannot aquire lock because a thread panicked")
             .total_samples_read
     }
+
+    // -sanitize: The number of samples read from the source, and the number of pans, that weren't finite and were
+    // replaced
+    pub fn get_non_finite_counts(&self) -> (usize, usize) {
+        let non_finite_samples = self
+            .open_wav_reader_and_buffer
+            .lock()
+            .expect("Cannot aquire lock because a thread panicked")
+            .non_finite_samples;
+
+        (
+            non_finite_samples,
+            self.non_finite_pans.load(Ordering::Relaxed),
+        )
+    }
+
+    // The DC offset of the source, as measured from all samples read so far (before any DC offset is removed)
+    pub fn get_measured_dc_offset(&self) -> DcOffset {
+        let open_wav_reader_and_buffer = self
+            .open_wav_reader_and_buffer
+            .lock()
+            .expect("Cannot aquire lock because a thread panicked");
+
+        open_wav_reader_and_buffer.measured_dc_offset()
+    }
+}
+
+// Sources that aren't labeled front_left and front_right are read as left and right in the order of their channels
+pub fn left_and_right(samples: &SamplesByChannel<f32>) -> (f32, f32) {
+    if let (Some(front_left), Some(front_right)) = (samples.front_left, samples.front_right) {
+        return (front_left, front_right);
+    }
+
+    // In the same order as the channels in a wav file
+    let mut channels = [
+        samples.front_left,
+        samples.front_right,
+        samples.front_center,
+        samples.low_frequency,
+        samples.back_left,
+        samples.back_right,
+        samples.front_left_of_center,
+        samples.front_right_of_center,
+        samples.back_center,
+        samples.side_left,
+        samples.side_right,
+        samples.top_center,
+        samples.top_front_left,
+        samples.top_front_center,
+        samples.top_front_right,
+        samples.top_back_left,
+        samples.top_back_center,
+        samples.top_back_right,
+    ]
+    .into_iter()
+    .flatten();
+
+    let left = channels.next().expect("left channel missing when reading");
+    let right = channels.next().expect("right channel missing when reading");
+    (left, right)
+}
+
+// The transform indexes of the frequencies in -rear-band. Each transform index is a band of frequencies, as wide as
+// the window's frequency resolution, so the band must include at least one of them
+fn rear_band(
+    low: f32,
+    high: f32,
+    window_size: usize,
+    transform_sample_rate: usize,
+) -> Result<RangeInclusive<usize>> {
+    let frequency_resolution = bin_frequency(1, window_size, transform_sample_rate);
+    let highest_transform_index = window_size / 2;
+
+    let low_transform_index = ((low / frequency_resolution).ceil() as usize).max(1);
+    let high_transform_index =
+        ((high / frequency_resolution).floor() as usize).min(highest_transform_index);
+
+    if low_transform_index > high_transform_index {
+        let error = format!(
+            "-rear-band {}hz to {}hz does not include any frequencies in the transform, which are {:.1}hz apart up to {:.0}hz. Widen the band, or lower the lowest frequency via -low",
+            low,
+            high,
+            frequency_resolution,
+            bin_frequency(highest_transform_index, window_size, transform_sample_rate)
+        );
+        return Err(Error::new(ErrorKind::InvalidInput, error));
+    }
+
+    Ok(low_transform_index..=high_transform_index)
+}
+
+// -mono-detect: If the energy of the side (L - R) is threshold_db below the energy of the mid (L + R)
+fn is_mono(left: &[Complex<f32>], right: &[Complex<f32>], threshold_db: f32) -> bool {
+    let (mid_energy, side_energy) = left.iter().zip(right.iter()).fold(
+        (0.0f64, 0.0f64),
+        |(mid_energy, side_energy), (left, right)| {
+            (
+                mid_energy + (left + right).norm_sqr() as f64,
+                side_energy + (left - right).norm_sqr() as f64,
+            )
+        },
+    );
+
+    side_energy <= mid_energy * 10.0f64.powf(threshold_db as f64 / 10.0)
+}
+
+// -analysis ms: The left and right phases, with the phase difference measured from the ratio of the side to the mid.
+// Matrixes steer by the phase difference, so this replaces comparing the two phases directly
+fn mid_side_phases(
+    left_amplitude: f32,
+    left_phase: f32,
+    right_amplitude: f32,
+    right_phase: f32,
+) -> (f32, f32) {
+    let amplitude_sum = left_amplitude + right_amplitude;
+    if amplitude_sum == 0.0 {
+        return (left_phase, right_phase);
+    }
+
+    let left = Complex::from_polar(left_amplitude, left_phase);
+    let right = Complex::from_polar(right_amplitude, right_phase);
+    let mid = (left + right) * 0.5;
+    let side = (left - right) * 0.5;
+
+    // The side to mid ratio is |L - R| / (L + R) when the left and right are in phase, and its inverse when they are
+    // out of phase. Where the ratio is between them is how far out of phase the frequency is. (A frequency that's only
+    // in one channel is always in phase, because the side and the mid are the same)
+    let in_phase_angle = ((left_amplitude - right_amplitude).abs() / amplitude_sum).atan();
+    let angle_range = FRAC_PI_2 - (2.0 * in_phase_angle);
+    let out_of_phase_fraction = if angle_range > 0.0 {
+        ((side.norm().atan2(mid.norm()) - in_phase_angle) / angle_range).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+
+    // The side leads the mid when the left leads the right
+    let phase_difference = (out_of_phase_fraction * PI).copysign((side * mid.conj()).im);
+
+    let mid_phase = mid.arg();
+    (
+        mid_phase + (phase_difference / 2.0),
+        mid_phase - (phase_difference / 2.0),
+    )
+}
+
+// Reads the entire source to determine the average and RMS of each channel
+fn measure_source_levels(source_wav_path: &Path) -> Result<SourceLevels> {
+    let source_wav_reader = read_wav_from_file_path(source_wav_path)?.get_stream_f32_reader()?;
+
+    let mut left_sum = 0.0f64;
+    let mut right_sum = 0.0f64;
+    let mut left_sum_of_squares = 0.0f64;
+    let mut right_sum_of_squares = 0.0f64;
+    let mut num_samples = 0usize;
+
+    for samples_result in source_wav_reader.into_iter() {
+        let (left, right) = left_and_right(&samples_result?);
+
+        // NaN and infinity would make the measurements meaningless. (-sanitize replaces them with silence)
+        if !(left.is_finite() && right.is_finite()) {
+            continue;
+        }
+
+        left_sum += left as f64;
+        right_sum += right as f64;
+        left_sum_of_squares += (left as f64) * (left as f64);
+        right_sum_of_squares += (right as f64) * (right as f64);
+        num_samples += 1;
+    }
+
+    if num_samples == 0 {
+        return Ok(SourceLevels {
+            dc_offset: DcOffset {
+                left: 0.0,
+                right: 0.0,
+            },
+            left_rms: 0.0,
+            right_rms: 0.0,
+        });
+    }
+
+    let num_samples = num_samples as f64;
+    let left_mean = left_sum / num_samples;
+    let right_mean = right_sum / num_samples;
+
+    // The variance is the mean of the squares minus the square of the mean
+    let rms = |sum_of_squares: f64, mean: f64| {
+        ((sum_of_squares / num_samples) - (mean * mean))
+            .max(0.0)
+            .sqrt() as f32
+    };
+
+    Ok(SourceLevels {
+        dc_offset: DcOffset {
+            left: left_mean as f32,
+            right: right_mean as f32,
+        },
+        left_rms: rms(left_sum_of_squares, left_mean),
+        right_rms: rms(right_sum_of_squares, right_mean),
+    })
+}
+
+// -trim-silence: The samples from where the source first rises above the threshold, until after it last falls below
+// the threshold. Silence at the start or end that is shorter than min_silence_samples is kept
+pub fn measure_non_silent_range(
+    source_wav_path: &Path,
+    threshold_db: f32,
+    min_silence_samples: usize,
+) -> Result<Range<usize>> {
+    let source_wav_reader = read_wav_from_file_path(source_wav_path)?.get_stream_f32_reader()?;
+    let threshold = 10.0f32.powf(threshold_db / 20.0);
+
+    let mut first_loud_sample = None;
+    let mut last_loud_sample = 0;
+    let mut num_samples = 0usize;
+
+    for samples_result in source_wav_reader.into_iter() {
+        let (left, right) = left_and_right(&samples_result?);
+        if left.abs() > threshold || right.abs() > threshold {
+            first_loud_sample.get_or_insert(num_samples);
+            last_loud_sample = num_samples;
+        }
+
+        num_samples += 1;
+    }
+
+    let first_loud_sample = match first_loud_sample {
+        Some(first_loud_sample) => first_loud_sample,
+        None => {
+            let error = format!(
+                "The source never rises above {} dBFS, so -trim-silence would trim all of it",
+                threshold_db
+            );
+            return Err(Error::new(ErrorKind::InvalidData, error));
+        }
+    };
+
+    let start = if first_loud_sample >= min_silence_samples {
+        first_loud_sample
+    } else {
+        0
+    };
+
+    let end = if num_samples - (last_loud_sample + 1) >= min_silence_samples {
+        last_loud_sample + 1
+    } else {
+        num_samples
+    };
+
+    Ok(start..end)
+}
+
+// Cross-correlates the left and right channels over the first second of the source, to find how many samples the right
+// channel is behind the left channel. (Negative when the left channel is behind.) Zero when the start of the source is
+// silent, or too short
+fn measure_channel_offset(source_wav_path: &Path) -> Result<isize> {
+    let source_wav = read_wav_from_file_path(source_wav_path)?;
+    let num_samples = source_wav.sample_rate() as usize;
+    let source_wav_reader = source_wav.get_stream_f32_reader()?;
+
+    let mut left = Vec::with_capacity(num_samples);
+    let mut right = Vec::with_capacity(num_samples);
+
+    for samples_result in source_wav_reader.into_iter().take(num_samples) {
+        let (left_sample, right_sample) = left_and_right(&samples_result?);
+        left.push(left_sample as f64);
+        right.push(right_sample as f64);
+    }
+
+    if left.len() <= MAX_ALIGNMENT_OFFSET * 2 {
+        return Ok(0);
+    }
+
+    // Every offset is correlated over the same samples, so that the correlations can be compared
+    let max_offset = MAX_ALIGNMENT_OFFSET as isize;
+    let left_range = MAX_ALIGNMENT_OFFSET..(left.len() - MAX_ALIGNMENT_OFFSET);
+
+    let mut best_offset = 0isize;
+    let mut best_correlation = 0.0f64;
+    for offset in -max_offset..(max_offset + 1) {
+        let correlation: f64 = left_range
+            .clone()
+            .map(|sample_ctr| left[sample_ctr] * right[(sample_ctr as isize + offset) as usize])
+            .sum();
+
+        // Out-of-phase sources are strongly anti-correlated, which is still aligned
+        let correlation = correlation.abs();
+
+        // Ties go to the smallest offset, so a source that is the same in both channels is never offset
+        if correlation > best_correlation
+            || (correlation == best_correlation && offset.abs() < best_offset.abs())
+        {
+            best_offset = offset;
+            best_correlation = correlation;
+        }
+    }
+
+    Ok(best_offset)
 }
 
 impl OpenWavReaderAndBuffer {
     fn queue_next_sample(&mut self, options: &Options) -> Result<()> {
-        match self.stream_wav_reader_iterator.next() {
+        // -oversample upsamples by inserting zeros between the source's samples. The images that this creates above
+        // the source's Nyquist frequency are removed from each transform
+        if self.zeros_until_next_read > 0 {
+            self.zeros_until_next_read -= 1;
+            self.queue_silence(options);
+            return Ok(());
+        }
+
+        match self.source_samples.next() {
             Some(samples_result) => {
                 let samples = samples_result?;
 
-                let front_left = samples.front_left.expect("front_left missing when reading");
-                let front_right = samples
-                    .front_right
-                    .expect("front_right missing when reading");
+                let (mut front_left, mut front_right) = left_and_right(&samples);
+
+                // -sanitize: A sample that isn't finite would ruin every transform that it's in
+                if options.sanitize {
+                    for sample in [&mut front_left, &mut front_right] {
+                        if !sample.is_finite() {
+                            *sample = 0.0;
+                            self.non_finite_samples += 1;
+                        }
+                    }
+                }
+
+                self.left_sum += front_left as f64;
+                self.right_sum += front_right as f64;
+                self.samples_read_from_source += 1;
+
+                // When -align-input detects an offset, the channel that is ahead is delayed
+                self.left_delay.push_back(front_left);
+                self.right_delay.push_back(front_right);
+                let front_left = self.left_delay.pop_front().expect("Left delay is empty");
+                let front_right = self.right_delay.pop_front().expect("Right delay is empty");
+
+                // With -oversample, the source is raised so that it is the same level once the zeros between its
+                // samples are filtered out
+                let gain = options.input_gain * options.oversample as f32;
+                let front_left = (front_left - self.dc_offset.left) * gain * self.left_balance;
+                let front_right = (front_right - self.dc_offset.right) * gain * self.right_balance;
+                self.zeros_until_next_read = options.oversample - 1;
 
                 self.left_buffer.push_back(Complex {
                     re: front_left,
@@ -197,27 +1006,210 @@ impl OpenWavReaderAndBuffer {
                 // The read buffer needs to be padded with empty samples, this way there is a full window to
                 // run an fft on the end of the wav
 
-                // TODO: Is this really needed? Probably should just abort if the file is shorter than the window length
-                // (Or just make the window length the entire length of the file?)
+                // (-single-window makes the window the entire length of a short file instead, so that only an
+                // odd-length file is padded, by a single sample)
                 // https://github.com/GWBasic/soft_matrix/issues/24
+                self.queue_silence(options);
+            }
+        }
+        Ok(())
+    }
 
-                self.left_buffer.push_back(Complex {
-                    re: 0.0f32,
-                    im: 0.0f32,
-                });
-                self.right_buffer.push_back(Complex {
-                    re: 0.0f32,
-                    im: 0.0f32,
-                });
+    fn queue_silence(&mut self, options: &Options) {
+        self.left_buffer.push_back(Complex {
+            re: 0.0f32,
+            im: 0.0f32,
+        });
+        self.right_buffer.push_back(Complex {
+            re: 0.0f32,
+            im: 0.0f32,
+        });
 
-                if options.transform_mono {
-                    self.mono_buffer.push_back(Complex {
-                        re: 0.0f32,
-                        im: 0.0f32,
-                    });
-                }
+        if options.transform_mono {
+            self.mono_buffer.push_back(Complex {
+                re: 0.0f32,
+                im: 0.0f32,
+            });
+        }
+    }
+
+    fn measured_dc_offset(&self) -> DcOffset {
+        if self.samples_read_from_source == 0 {
+            return DcOffset {
+                left: 0.0,
+                right: 0.0,
+            };
+        }
+
+        let samples_read_from_source = self.samples_read_from_source as f64;
+        DcOffset {
+            left: (self.left_sum / samples_read_from_source) as f32,
+            right: (self.right_sum / samples_read_from_source) as f32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rustfft::FftPlanner;
+    use wave_stream::{read_wav_from_file_path, wave_reader::StreamOpenWavReader};
+
+    use super::Reader;
+    use crate::test_support::{
+        channel_values, dumped_column, parse_flags, sine, temp_path, upmix_stereo, write_stereo,
+    };
+
+    // The average of each output channel
+    fn dc_offset_by_channel(left: &[f32], right: &[f32], flags: &[&str]) -> Vec<f32> {
+        let upmixed = upmix_stereo(left, right, flags).expect("Upmixing failed");
+        let mut sums = Vec::new();
+        for samples in &upmixed.samples {
+            let values = channel_values(samples);
+            sums.resize(values.len(), 0.0f64);
+            for (sum, value) in sums.iter_mut().zip(values) {
+                *sum += value as f64;
             }
         }
-        Ok(())
+
+        sums.into_iter()
+            .map(|sum| (sum / upmixed.samples.len() as f64) as f32)
+            .collect()
+    }
+
+    #[test]
+    fn remove_dc_removes_the_offset_from_every_channel() {
+        // Whole cycles, so that the tones themselves average to zero
+        let left: Vec<f32> = sine(1000.0, 0.25, 4410)
+            .into_iter()
+            .map(|sample| sample + 0.2)
+            .collect();
+        let right: Vec<f32> = sine(1500.0, 0.25, 4410)
+            .into_iter()
+            .map(|sample| sample - 0.1)
+            .collect();
+
+        let with_dc = dc_offset_by_channel(&left, &right, &["-low", "1000", "-channels", "5"]);
+        assert!(
+            with_dc.iter().any(|dc_offset| dc_offset.abs() > 0.05),
+            "The offset should pass through without -remove-dc: {:?}",
+            with_dc
+        );
+
+        let removed = dc_offset_by_channel(
+            &left,
+            &right,
+            &["-low", "1000", "-channels", "5", "-remove-dc"],
+        );
+        for dc_offset in removed {
+            assert!(dc_offset.abs() < 0.005, "DC offset {} remains", dc_offset);
+        }
+    }
+
+    #[test]
+    fn sanitize_writes_only_finite_samples_from_a_source_with_nan() {
+        let mut left = sine(1000.0, 0.5, 4410);
+        let mut right = sine(1500.0, 0.5, 4410);
+        left[1000] = f32::NAN;
+        right[2000] = f32::NAN;
+        left[3000] = f32::INFINITY;
+        right[3000] = f32::NEG_INFINITY;
+
+        let upmixed = upmix_stereo(
+            &left,
+            &right,
+            &["-low", "1000", "-channels", "5", "-sanitize"],
+        )
+        .expect("Upmixing failed");
+
+        let mut loudest = 0.0f32;
+        for (sample_ctr, samples) in upmixed.samples.iter().enumerate() {
+            for value in channel_values(samples) {
+                assert!(value.is_finite(), "Sample {} is {}", sample_ctr, value);
+                loudest = loudest.max(value.abs());
+            }
+        }
+
+        // The rest of the source is still upmixed
+        assert!(loudest > 0.1, "The output is silent");
+    }
+
+    // Opens a reader for the flags, either in memory or streaming, and returns if the mono channel was buffered
+    fn mono_is_buffered(flags: &[&str], in_memory: bool) -> bool {
+        let window_size = 48;
+        let len_samples = 4410;
+        let source_wav_path = temp_path("source.wav");
+        write_stereo(
+            &source_wav_path,
+            &sine(1000.0, 0.5, len_samples),
+            &sine(1500.0, 0.5, len_samples),
+        )
+        .unwrap();
+
+        let options = parse_flags(flags).expect("Invalid flags");
+        let reader = Reader::open(
+            &options,
+            read_wav_from_file_path(&source_wav_path)
+                .unwrap()
+                .get_stream_f32_reader()
+                .unwrap(),
+            window_size,
+            44100,
+            FftPlanner::new().plan_fft_forward(window_size),
+            0..len_samples,
+            if in_memory { Some(len_samples) } else { None },
+        )
+        .unwrap();
+        let _ = fs::remove_file(&source_wav_path);
+
+        match &reader.in_memory_source {
+            Some(in_memory_source) => !in_memory_source.mono.is_empty(),
+            None => !reader
+                .open_wav_reader_and_buffer
+                .lock()
+                .unwrap()
+                .mono_buffer
+                .is_empty(),
+        }
+    }
+
+    #[test]
+    fn quad_never_buffers_the_mono_channel() {
+        for in_memory in [true, false] {
+            assert!(!mono_is_buffered(
+                &["-low", "1000", "-channels", "4"],
+                in_memory
+            ));
+
+            // Layouts with a center need it
+            assert!(mono_is_buffered(
+                &["-low", "1000", "-channels", "5"],
+                in_memory
+            ));
+        }
+    }
+
+    #[test]
+    fn max_rear_limits_how_far_each_frequency_steers_to_the_rear() {
+        // Out of phase, so that it's steered fully to the rear
+        let left = sine(1000.0, 0.5, 4410);
+        let right: Vec<f32> = left.iter().map(|sample| -sample).collect();
+        let flags = ["-low", "1000", "-channels", "4"];
+
+        let unlimited = dumped_column(&left, &right, &flags, "back_to_front");
+        assert!(unlimited.iter().any(|back_to_front| *back_to_front > 0.9));
+
+        let limited = dumped_column(
+            &left,
+            &right,
+            &[&flags[..], &["-max-rear", "0.3"]].concat(),
+            "back_to_front",
+        );
+        assert_eq!(unlimited.len(), limited.len());
+        for (unlimited, limited) in unlimited.iter().zip(&limited) {
+            assert!(*limited <= 0.3, "{} is past -max-rear", limited);
+            assert!((unlimited.min(0.3) - limited).abs() < 0.0001);
+        }
     }
 }
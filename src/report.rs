@@ -0,0 +1,201 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Result, Write},
+    path::Path,
+};
+
+use wave_stream::{samples_by_channel::SamplesByChannel, wave_header::Channels};
+
+use crate::{options::Options, structs::DcOffset};
+
+// Accumulates per-channel levels as samples are written, so that they can be written to a report when upmixing
+// completes
+pub struct Report {
+    channel_levels: Vec<ChannelLevels>,
+}
+
+struct ChannelLevels {
+    name: &'static str,
+    peak: f32,
+    sum_of_squares: f64,
+    clipped_samples: usize,
+    num_samples: usize,
+}
+
+impl Report {
+    pub fn new(channel_names: Vec<&'static str>) -> Report {
+        let channel_levels = channel_names
+            .into_iter()
+            .map(|name| ChannelLevels {
+                name,
+                peak: 0.0,
+                sum_of_squares: 0.0,
+                clipped_samples: 0,
+                num_samples: 0,
+            })
+            .collect();
+
+        Report { channel_levels }
+    }
+
+    pub fn accumulate(&mut self, samples_by_channel: &SamplesByChannel<f32>) {
+        // to_vec() returns samples in the same order as channel_names()
+        let samples = samples_by_channel.to_vec();
+
+        for (channel_levels, sample) in self.channel_levels.iter_mut().zip(samples) {
+            let amplitude = sample.abs();
+
+            channel_levels.peak = channel_levels.peak.max(amplitude);
+            channel_levels.sum_of_squares += (sample as f64) * (sample as f64);
+            channel_levels.num_samples += 1;
+
+            if amplitude > 1.0 {
+                channel_levels.clipped_samples += 1;
+            }
+        }
+    }
+
+    // Writes the report as tab-separated values, so that it can be parsed by scripts
+    pub fn write(
+        &self,
+        report_file_path: &Path,
+        options: &Options,
+        sample_rate: usize,
+        window_size: usize,
+        dc_offset: DcOffset,
+    ) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(report_file_path)?);
+
+        writeln!(writer, "source\t{}", options.source_wav_path.display())?;
+        writeln!(writer, "target\t{}", options.target_wav_path.display())?;
+        writeln!(writer, "matrix\t{}", options.matrix_name)?;
+        let channel_names: Vec<&str> = self
+            .channel_levels
+            .iter()
+            .map(|channel_levels| channel_levels.name)
+            .collect();
+        writeln!(writer, "channels\t{}", channel_names.join(","))?;
+        writeln!(writer, "sample_rate\t{}", sample_rate)?;
+        writeln!(writer, "window_size\t{}", window_size)?;
+        writeln!(writer, "low_frequency\t{}", options.low_frequency)?;
+        writeln!(writer, "dc_offset_left\t{:.6}", dc_offset.left)?;
+        writeln!(writer, "dc_offset_right\t{:.6}", dc_offset.right)?;
+        writeln!(writer, "dc_offset_removed\t{}", options.remove_dc)?;
+        writeln!(writer)?;
+
+        writeln!(
+            writer,
+            "channel\tpeak_dbfs\trms_dbfs\tclipped\tclipped_samples"
+        )?;
+        for channel_levels in &self.channel_levels {
+            let rms = if channel_levels.num_samples > 0 {
+                (channel_levels.sum_of_squares / channel_levels.num_samples as f64).sqrt()
+            } else {
+                0.0
+            };
+
+            writeln!(
+                writer,
+                "{}\t{:.2}\t{:.2}\t{}\t{}",
+                channel_levels.name,
+                to_dbfs(channel_levels.peak as f64),
+                to_dbfs(rms),
+                channel_levels.clipped_samples > 0,
+                channel_levels.clipped_samples
+            )?;
+        }
+
+        writer.flush()
+    }
+}
+
+// -meters: The peak of each channel since the meters were last printed, so that a silent or dead channel is obvious
+// while upmixing
+pub struct Meters {
+    channel_names: Vec<&'static str>,
+    peaks: Vec<f32>,
+}
+
+impl Meters {
+    pub fn new(channel_names: Vec<&'static str>) -> Meters {
+        let peaks = vec![0.0; channel_names.len()];
+        Meters {
+            channel_names,
+            peaks,
+        }
+    }
+
+    pub fn accumulate(&mut self, samples_by_channel: &SamplesByChannel<f32>) {
+        // to_vec() returns samples in the same order as channel_names()
+        for (peak, sample) in self.peaks.iter_mut().zip(samples_by_channel.to_vec()) {
+            *peak = peak.max(sample.abs());
+        }
+    }
+
+    // Formats the peaks as dBFS, with short channel names, and then resets them
+    pub fn take(&mut self) -> String {
+        let meters: Vec<String> = self
+            .channel_names
+            .iter()
+            .zip(self.peaks.iter())
+            .map(|(channel_name, peak)| {
+                format!(
+                    "{} {:.0}",
+                    short_channel_name(channel_name),
+                    to_dbfs(*peak as f64)
+                )
+            })
+            .collect();
+
+        self.peaks.fill(0.0);
+
+        meters.join(" ")
+    }
+}
+
+fn short_channel_name(channel_name: &'static str) -> &'static str {
+    match channel_name {
+        "front_left" => "L",
+        "front_right" => "R",
+        "front_center" => "C",
+        "low_frequency" => "LFE",
+        "back_left" => "Ls",
+        "back_right" => "Rs",
+        "back_center" => "Cs",
+        _ => channel_name,
+    }
+}
+
+fn to_dbfs(amplitude: f64) -> f64 {
+    20.0 * amplitude.log10()
+}
+
+// The names of all channels present, in the order that they are written to the wav file
+pub fn channel_names(channels: &Channels) -> Vec<&'static str> {
+    let all_channels = [
+        (channels.front_left, "front_left"),
+        (channels.front_right, "front_right"),
+        (channels.front_center, "front_center"),
+        (channels.low_frequency, "low_frequency"),
+        (channels.back_left, "back_left"),
+        (channels.back_right, "back_right"),
+        (channels.front_left_of_center, "front_left_of_center"),
+        (channels.front_right_of_center, "front_right_of_center"),
+        (channels.back_center, "back_center"),
+        (channels.side_left, "side_left"),
+        (channels.side_right, "side_right"),
+        (channels.top_center, "top_center"),
+        (channels.top_front_left, "top_front_left"),
+        (channels.top_front_center, "top_front_center"),
+        (channels.top_front_right, "top_front_right"),
+        (channels.top_back_left, "top_back_left"),
+        (channels.top_back_center, "top_back_center"),
+        (channels.top_back_right, "top_back_right"),
+    ];
+
+    all_channels
+        .into_iter()
+        .filter(|(present, _)| *present)
+        .map(|(_, name)| name)
+        .collect()
+}
@@ -0,0 +1,308 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use wave_stream::{
+    open_wav::OpenWav, read_wav_from_file_path, wave_header::WavHeader,
+    wave_reader::StreamOpenWavReader, wave_writer::RandomAccessWavWriter,
+};
+
+// The output of an interrupted upmix. It is moved aside so that its samples can be copied into the new target
+pub struct PartialOutput {
+    pub partial_wav_path: PathBuf,
+    // The number of complete samples in the partial output
+    pub valid_samples: usize,
+    // -append: The partial output is complete, so its end was written as the end of the source
+    pub complete: bool,
+}
+
+impl PartialOutput {
+    // Returns None if there is no previous output to resume from
+    pub fn open(target_wav_path: &Path, header: &WavHeader) -> Result<Option<PartialOutput>> {
+        let mut partial_wav_path = target_wav_path.as_os_str().to_owned();
+        partial_wav_path.push(".partial");
+        let partial_wav_path = PathBuf::from(partial_wav_path);
+
+        // An earlier resume was also interrupted. Its partial output may have samples that the target doesn't, so it
+        // can't be replaced
+        if partial_wav_path.exists() {
+            let error = format!(
+                "{} is left over from an earlier resume that was interrupted. Keep whichever of it and {} is longer, as {}, and delete the other",
+                partial_wav_path.display(),
+                target_wav_path.display(),
+                target_wav_path.display()
+            );
+            return Err(Error::new(ErrorKind::AlreadyExists, error));
+        }
+
+        if !target_wav_path.exists() {
+            return Ok(None);
+        }
+
+        // Checked before the header is fixed, so that a target that can't be resumed isn't modified
+        let partial_wav = read_wav_from_file_path(target_wav_path)?;
+        if partial_wav.sample_format() != header.sample_format
+            || partial_wav.channels() != &header.channels
+            || partial_wav.sample_rate() != header.sample_rate
+        {
+            let error = format!(
+                "{} was not written with the same options, it can not be resumed",
+                target_wav_path.display()
+            );
+            return Err(Error::new(ErrorKind::InvalidData, error));
+        }
+        drop(partial_wav);
+
+        let (data_start, valid_samples) = count_valid_samples(target_wav_path, header)?;
+        let bytes_per_sample = header.channels.count() as usize * 4;
+
+        // Fix the header so the partial output can be read
+        {
+            let mut file = OpenOptions::new().write(true).open(target_wav_path)?;
+            let chunk_size = (valid_samples * bytes_per_sample) as u32;
+
+            file.seek(SeekFrom::Start(4))?;
+            file.write_all(&(chunk_size + data_start as u32 - 8).to_le_bytes())?;
+            file.seek(SeekFrom::Start(data_start as u64 - 4))?;
+            file.write_all(&chunk_size.to_le_bytes())?;
+            file.flush()?;
+        }
+
+        fs::rename(target_wav_path, &partial_wav_path)?;
+
+        Ok(Some(PartialOutput {
+            partial_wav_path,
+            valid_samples,
+            complete: false,
+        }))
+    }
+
+    // -append: Opens the output of the previous upmix, so that only what was appended to the source is upmixed.
+    // Returns None if there is no previous output, (or it wasn't upmixed with -append,) so the entire source is upmixed
+    pub fn open_appended(
+        target_wav_path: &Path,
+        header: &WavHeader,
+        source_len: usize,
+    ) -> Result<Option<PartialOutput>> {
+        let append_state_path = append_state_path(target_wav_path);
+        if !append_state_path.exists() || !target_wav_path.exists() {
+            return Ok(None);
+        }
+
+        let appended_samples_string = fs::read_to_string(&append_state_path)?;
+        let appended_samples = match appended_samples_string.trim().parse::<usize>() {
+            Ok(appended_samples) => appended_samples,
+            Err(_) => {
+                let error = format!("{} is not valid", append_state_path.display());
+                return Err(Error::new(ErrorKind::InvalidData, error));
+            }
+        };
+
+        if source_len < appended_samples {
+            let error = format!(
+                "The source is {} samples long, but {} samples were already upmixed. Only sources that are appended to can be upmixed with -append",
+                source_len, appended_samples
+            );
+            return Err(Error::new(ErrorKind::InvalidData, error));
+        }
+
+        // (The output is one sample shorter than the source)
+        let (_, valid_samples) = count_valid_samples(target_wav_path, header)?;
+        if valid_samples > appended_samples || valid_samples + 1 < appended_samples {
+            let error = format!(
+                "{} has {} samples, but {} samples were upmixed into it. It was changed, or upmixed with different options",
+                target_wav_path.display(),
+                valid_samples,
+                appended_samples
+            );
+            return Err(Error::new(ErrorKind::InvalidData, error));
+        }
+
+        Ok(
+            PartialOutput::open(target_wav_path, header)?.map(|partial_output| PartialOutput {
+                complete: true,
+                ..partial_output
+            }),
+        )
+    }
+
+    // Copies the samples that do not need to be upmixed again into the new target
+    pub fn copy_to(
+        &self,
+        target_random_access_wav_writer: &mut RandomAccessWavWriter<f32>,
+        num_samples: usize,
+    ) -> Result<()> {
+        let partial_wav_reader =
+            read_wav_from_file_path(&self.partial_wav_path)?.get_stream_f32_reader()?;
+
+        for (sample_ctr, samples_result) in
+            partial_wav_reader.into_iter().take(num_samples).enumerate()
+        {
+            target_random_access_wav_writer.write_samples(sample_ctr, samples_result?)?;
+        }
+
+        Ok(())
+    }
+
+    // Called once the upmix completes
+    pub fn remove(self) -> Result<()> {
+        fs::remove_file(&self.partial_wav_path)
+    }
+}
+
+// -append: Remembers how many samples of the source were upmixed, in a file next to the target
+pub fn write_append_state(target_wav_path: &Path, appended_samples: usize) -> Result<()> {
+    fs::write(
+        append_state_path(target_wav_path),
+        format!("{}\n", appended_samples),
+    )
+}
+
+fn append_state_path(target_wav_path: &Path) -> PathBuf {
+    let mut append_state_path = target_wav_path.as_os_str().to_owned();
+    append_state_path.push(".append");
+    PathBuf::from(append_state_path)
+}
+
+// Returns where the samples start, and the number of complete samples. The data length in the header is only written
+// when the upmix completes, so the number of valid samples is determined from the length of the file
+fn count_valid_samples(wav_path: &Path, header: &WavHeader) -> Result<(usize, usize)> {
+    let data_start = find_data_start(wav_path)?;
+    let file_length = fs::metadata(wav_path)?.len() as usize;
+    let bytes_per_sample = header.channels.count() as usize * 4;
+
+    Ok((
+        data_start,
+        file_length.saturating_sub(data_start) / bytes_per_sample,
+    ))
+}
+
+// Walks the RIFF chunks to find where the samples start
+fn find_data_start(wav_path: &Path) -> Result<usize> {
+    let mut reader = BufReader::new(File::open(wav_path)?);
+
+    let mut riff_header = [0u8; 12];
+    reader.read_exact(&mut riff_header)?;
+    if &riff_header[0..4] != b"RIFF" || &riff_header[8..12] != b"WAVE" {
+        let error = format!("{} is not a wav file", wav_path.display());
+        return Err(Error::new(ErrorKind::InvalidData, error));
+    }
+
+    let mut position = 12usize;
+    loop {
+        let mut chunk_header = [0u8; 8];
+        reader.read_exact(&mut chunk_header)?;
+        position += 8;
+
+        if &chunk_header[0..4] == b"data" {
+            return Ok(position);
+        }
+
+        let chunk_size = u32::from_le_bytes([
+            chunk_header[4],
+            chunk_header[5],
+            chunk_header[6],
+            chunk_header[7],
+        ]) as usize;
+
+        // Chunks with an odd size are padded to an even size
+        let padded_chunk_size = chunk_size + (chunk_size & 1);
+        reader.seek(SeekFrom::Current(padded_chunk_size as i64))?;
+        position += padded_chunk_size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::ErrorKind};
+
+    use wave_stream::wave_header::{Channels, SampleFormat, WavHeader};
+
+    use super::{find_data_start, PartialOutput};
+    use crate::test_support::{sine, temp_path, write_stereo, SAMPLE_RATE};
+
+    #[test]
+    fn a_target_written_with_other_options_is_not_modified() {
+        let target_wav_path = temp_path("target.wav");
+        write_stereo(
+            &target_wav_path,
+            &sine(1000.0, 0.5, 100),
+            &sine(1500.0, 0.5, 100),
+        )
+        .unwrap();
+
+        // The sizes in the header are only written when an upmix completes
+        let mut before = fs::read(&target_wav_path).unwrap();
+        before[4..8].copy_from_slice(&0u32.to_le_bytes());
+        fs::write(&target_wav_path, &before).unwrap();
+
+        let header = WavHeader {
+            sample_format: SampleFormat::Float,
+            channels: Channels::new()
+                .front_left()
+                .front_right()
+                .front_center()
+                .back_left()
+                .back_right(),
+            sample_rate: SAMPLE_RATE,
+        };
+        let error = PartialOutput::open(&target_wav_path, &header)
+            .err()
+            .expect("A target with different channels was resumed");
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+
+        assert_eq!(before, fs::read(&target_wav_path).unwrap());
+        fs::remove_file(&target_wav_path).unwrap();
+    }
+
+    #[test]
+    fn a_partial_output_from_an_earlier_resume_is_not_overwritten() {
+        let target_wav_path = temp_path("target.wav");
+        let mut partial_wav_path = target_wav_path.as_os_str().to_owned();
+        partial_wav_path.push(".partial");
+
+        write_stereo(&target_wav_path, &[0.5; 100], &[0.5; 100]).unwrap();
+        fs::write(&partial_wav_path, b"samples from the earlier resume").unwrap();
+
+        let header = WavHeader {
+            sample_format: SampleFormat::Float,
+            channels: Channels::new().front_left().front_right(),
+            sample_rate: SAMPLE_RATE,
+        };
+        let error = PartialOutput::open(&target_wav_path, &header)
+            .err()
+            .expect("The partial output was overwritten");
+        assert_eq!(ErrorKind::AlreadyExists, error.kind());
+
+        // Even when the target is gone, the partial output is the only copy of what was upmixed
+        fs::remove_file(&target_wav_path).unwrap();
+        assert!(PartialOutput::open(&target_wav_path, &header).is_err());
+
+        assert_eq!(
+            b"samples from the earlier resume".to_vec(),
+            fs::read(&partial_wav_path).unwrap()
+        );
+        fs::remove_file(&partial_wav_path).unwrap();
+    }
+
+    #[test]
+    fn find_data_start_skips_the_padding_after_an_odd_sized_chunk() {
+        let wav_path = temp_path("odd_chunk.wav");
+
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&0u32.to_le_bytes());
+        wav.extend_from_slice(b"WAVE");
+        wav.extend_from_slice(b"junk");
+        wav.extend_from_slice(&3u32.to_le_bytes());
+        wav.extend_from_slice(&[1, 2, 3, 0]);
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&0u32.to_le_bytes());
+        fs::write(&wav_path, &wav).unwrap();
+
+        assert_eq!(wav.len(), find_data_start(&wav_path).unwrap());
+        fs::remove_file(&wav_path).unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    env,
+    fs::File,
+    io::{Cursor, Result, Seek, SeekFrom, Write},
+    sync::{Arc, Mutex},
+};
+
+#[cfg(not(any(unix, windows)))]
+use std::io::{Error, ErrorKind};
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, FromRawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawHandle, FromRawHandle, RawHandle};
+
+#[cfg(unix)]
+use nix::unistd::{dup, dup2};
+use wave_stream::{wave_header::WavHeader, wave_writer::OpenWavWriter, write_wav};
+
+#[cfg(windows)]
+const STD_OUTPUT_HANDLE: u32 = -11i32 as u32;
+
+// Rust looks up the process's stdout every time it writes to it, so replacing the stdout handle also redirects
+// println!
+#[cfg(windows)]
+#[link(name = "kernel32")]
+extern "system" {
+    fn SetStdHandle(std_handle: u32, handle: RawHandle) -> i32;
+}
+
+// soft_matrix [source] - writes the upmixed wav to stdout, so that it can be piped into another program
+// The wav's header can only be written once its length is known, so the wav is written into memory, and then written
+// to stdout when upmixing completes
+pub struct StdoutOutput {
+    stdout: File,
+    wav: Arc<Mutex<Cursor<Vec<u8>>>>,
+}
+
+// Lets the wav writer share the buffer with StdoutOutput
+struct SharedBuffer {
+    wav: Arc<Mutex<Cursor<Vec<u8>>>>,
+}
+
+impl StdoutOutput {
+    pub fn is_requested() -> bool {
+        match env::args().nth(2) {
+            Some(target) => target.eq("-"),
+            None => false,
+        }
+    }
+
+    // Everything that would normally be printed goes to stderr instead, so that it doesn't end up in the wav
+    pub fn redirect_messages_to_stderr() -> Result<StdoutOutput> {
+        Ok(StdoutOutput {
+            stdout: redirect_stdout_to_stderr()?,
+            wav: Arc::new(Mutex::new(Cursor::new(Vec::new()))),
+        })
+    }
+
+    pub fn open_wav_writer(&self, header: WavHeader) -> Result<OpenWavWriter> {
+        write_wav(
+            SharedBuffer {
+                wav: self.wav.clone(),
+            },
+            header,
+        )
+    }
+
+    // Called once the upmix completes, and the wav writer is dropped
+    pub fn write_to_stdout(mut self) -> Result<()> {
+        let wav = self
+            .wav
+            .lock()
+            .expect("Cannot aquire lock because a thread panicked");
+
+        self.stdout.write_all(wav.get_ref())?;
+        self.stdout.flush()
+    }
+}
+
+// Points stdout at stderr. Returns the original stdout, which only the wav is written to
+#[cfg(unix)]
+fn redirect_stdout_to_stderr() -> Result<File> {
+    let stdout = dup(std::io::stdout().as_raw_fd())?;
+    dup2(std::io::stderr().as_raw_fd(), std::io::stdout().as_raw_fd())?;
+
+    // Safe because the file descriptor was just duplicated, and nothing else owns it
+    Ok(unsafe { File::from_raw_fd(stdout) })
+}
+
+#[cfg(windows)]
+fn redirect_stdout_to_stderr() -> Result<File> {
+    let stdout = std::io::stdout().as_raw_handle();
+
+    // Safe because the original stdout handle stays open: Only the File below uses it after this
+    if unsafe { SetStdHandle(STD_OUTPUT_HANDLE, std::io::stderr().as_raw_handle()) } == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(unsafe { File::from_raw_handle(stdout) })
+}
+
+#[cfg(not(any(unix, windows)))]
+fn redirect_stdout_to_stderr() -> Result<File> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "Writing the upmixed wav to stdout is not supported on this platform",
+    ))
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.wav
+            .lock()
+            .expect("Cannot aquire lock because a thread panicked")
+            .write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SharedBuffer {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.wav
+            .lock()
+            .expect("Cannot aquire lock because a thread panicked")
+            .seek(pos)
+    }
+}
@@ -2,7 +2,7 @@ use std::sync::Arc;
 
 use rustfft::num_complex::Complex;
 
-use crate::upmixer::Upmixer;
+use crate::{upmixer::Upmixer, window_dump::WindowDump};
 
 // State that is local to a thread
 pub struct ThreadState {
@@ -21,6 +21,10 @@ pub struct TransformedWindowAndPans {
     pub left_transformed: Option<Vec<Complex<f32>>>,
     pub right_transformed: Option<Vec<Complex<f32>>>,
     pub mono_transformed: Option<Vec<Complex<f32>>>,
+    // -stereo-passthrough: The left and right samples in the window, before they were transformed
+    pub source_window: Option<(Vec<f32>, Vec<f32>)>,
+    // -dump-window: Only present for the window that is dumped
+    pub window_dump: Option<WindowDump>,
     pub frequency_pans: Vec<FrequencyPans>,
 }
 
@@ -34,3 +38,19 @@ pub struct FrequencyPans {
     // Front to back panning: 0 is front, 1 is back
     pub back_to_front: f32,
 }
+
+// The average of each input channel. Recordings transferred from vinyl or tape may have significant DC offset
+#[derive(Debug, Clone, Copy)]
+pub struct DcOffset {
+    pub left: f32,
+    pub right: f32,
+}
+
+// Where upmixing restarts when resuming. Both are zero when not resuming
+#[derive(Debug, Clone, Copy)]
+pub struct ResumePoint {
+    // The first sample read from the source. Sample counters start here
+    pub source_offset: usize,
+    // Samples before this were already copied from the partial output
+    pub first_sample_to_write: usize,
+}
@@ -0,0 +1,161 @@
+use std::{
+    f32::consts::{PI, TAU},
+    io::Result,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use wave_stream::{
+    samples_by_channel::SamplesByChannel,
+    wave_header::{Channels, SampleFormat, WavHeader},
+    write_wav_to_file_path,
+};
+
+use crate::options::{GenerateTestOptions, TestNoise, TestSignal};
+
+const SAMPLE_RATE: u32 = 44100;
+const LENGTH_SECONDS: usize = 10;
+const AMPLITUDE: f32 = 0.5;
+
+const SWEEP_START: f32 = 20.0;
+const SWEEP_END: f32 = 20000.0;
+
+const PULSE_FREQUENCY: f32 = 1000.0;
+const PULSE_SECONDS: f32 = 0.1;
+
+// Synthesizes a stereo test file with known steering, so that the upmixer can be checked against it
+pub fn generate_test(options: &GenerateTestOptions) -> Result<()> {
+    let header = WavHeader {
+        sample_format: SampleFormat::Float,
+        channels: Channels::new().front_left().front_right(),
+        sample_rate: SAMPLE_RATE,
+    };
+
+    let mut target_wav_writer =
+        write_wav_to_file_path(&options.target_wav_path, header)?.get_random_access_f32_writer()?;
+
+    // Without -seed, the noise is different every time. The seed is printed so that the file can be generated again
+    let seed = options.seed.unwrap_or_else(seed_from_time);
+    if matches!(
+        options.test_signal,
+        TestSignal::CorrelatedNoise | TestSignal::AnticorrelatedNoise
+    ) {
+        println!(
+            "Seed: {} (Pass -seed {} to generate the same noise)",
+            seed, seed
+        );
+    }
+
+    let mut noise_generator = NoiseGenerator::new(&options.noise, seed);
+
+    let sample_rate = SAMPLE_RATE as f32;
+    let len_samples = SAMPLE_RATE as usize * LENGTH_SECONDS;
+
+    // Phase is accumulated, because the sweep's frequency changes with every sample
+    let mut phase = 0.0f32;
+
+    for sample_ctr in 0..len_samples {
+        let (left, right) = match options.test_signal {
+            // Identical in both channels: Should be steered to the front (center)
+            TestSignal::CorrelatedNoise => {
+                let noise = noise_generator.next() * AMPLITUDE;
+                (noise, noise)
+            }
+            // Out of phase: Should be steered to the rear
+            TestSignal::AnticorrelatedNoise => {
+                let noise = noise_generator.next() * AMPLITUDE;
+                (noise, -noise)
+            }
+            // A logarithmic sweep that pans from left to right
+            TestSignal::Sweep => {
+                let fraction = sample_ctr as f32 / len_samples as f32;
+                let frequency = SWEEP_START * (SWEEP_END / SWEEP_START).powf(fraction);
+
+                phase = (phase + (TAU * frequency / sample_rate)) % TAU;
+                let sample = phase.sin() * AMPLITUDE;
+
+                // Constant-power panning
+                let pan = fraction * PI / 2.0;
+                (sample * pan.cos(), sample * pan.sin())
+            }
+            // A tone burst every second, encoded out-of-phase so that it should only be in the rear
+            TestSignal::RearPulse => {
+                let time_in_second = (sample_ctr % SAMPLE_RATE as usize) as f32 / sample_rate;
+                if time_in_second < PULSE_SECONDS {
+                    let sample = (TAU * PULSE_FREQUENCY * time_in_second).sin() * AMPLITUDE;
+                    (sample, -sample)
+                } else {
+                    (0.0, 0.0)
+                }
+            }
+        };
+
+        target_wav_writer.write_samples(
+            sample_ctr,
+            SamplesByChannel::new().front_left(left).front_right(right),
+        )?;
+    }
+
+    target_wav_writer.flush()
+}
+
+fn seed_from_time() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch.as_nanos() as u64,
+        // The clock is before 1970, so the seed only needs to be a number that changes
+        Err(error) => error.duration().as_nanos() as u64,
+    }
+}
+
+// Generates reproducible noise: The same seed always generates the same noise, so results can be compared
+// (Different seeds generate different noise)
+struct NoiseGenerator {
+    pink: bool,
+    state: u32,
+    // Filter state for pink noise
+    b: [f32; 7],
+}
+
+impl NoiseGenerator {
+    fn new(noise: &TestNoise, seed: u64) -> NoiseGenerator {
+        // xorshift's state can't be 0
+        let state = ((seed >> 32) as u32 ^ seed as u32).max(1);
+
+        NoiseGenerator {
+            pink: match noise {
+                TestNoise::Pink => true,
+                TestNoise::White => false,
+            },
+            state,
+            b: [0.0; 7],
+        }
+    }
+
+    fn next(&mut self) -> f32 {
+        // xorshift
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+
+        // -1.0 to 1.0
+        let white = (self.state as f32 / u32::MAX as f32) * 2.0 - 1.0;
+
+        if !self.pink {
+            return white;
+        }
+
+        // Paul Kellet's pink noise filter
+        // See https://www.firstpr.com.au/dsp/pink-noise/
+        let b = &mut self.b;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.153852;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+
+        // The filter has a gain of roughly 5
+        (pink * 0.2).clamp(-1.0, 1.0)
+    }
+}
@@ -0,0 +1,183 @@
+// Helpers for tests that upmix synthesized sources from end to end, the same way that main.rs does
+use std::{
+    env, fs,
+    io::Result,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use wave_stream::{
+    open_wav::OpenWav,
+    read_wav_from_file_path,
+    samples_by_channel::SamplesByChannel,
+    wave_header::{Channels, SampleFormat, WavHeader},
+    wave_reader::StreamOpenWavReader,
+    write_wav_to_file_path,
+};
+
+use crate::{options::Options, upmixer::upmix};
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+// Keeps tests that run at the same time from using the same files
+static NEXT_PATH: AtomicUsize = AtomicUsize::new(0);
+
+// The upmixed output, read back from the target
+pub struct Upmixed {
+    pub channels: Channels,
+    pub samples: Vec<SamplesByChannel<f32>>,
+}
+
+// A path in the temporary folder that no other test uses
+pub fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!(
+        "soft_matrix_test_{}_{}_{}",
+        process::id(),
+        NEXT_PATH.fetch_add(1, Ordering::Relaxed),
+        name
+    ))
+}
+
+pub fn sine(frequency: f32, amplitude: f32, len_samples: usize) -> Vec<f32> {
+    (0..len_samples)
+        .map(|sample_ctr| {
+            amplitude
+                * (std::f32::consts::TAU * frequency * sample_ctr as f32 / SAMPLE_RATE as f32).sin()
+        })
+        .collect()
+}
+
+pub fn write_stereo(path: &Path, left: &[f32], right: &[f32]) -> Result<()> {
+    let header = WavHeader {
+        sample_format: SampleFormat::Float,
+        channels: Channels::new().front_left().front_right(),
+        sample_rate: SAMPLE_RATE,
+    };
+
+    let mut writer = write_wav_to_file_path(path, header)?.get_random_access_f32_writer()?;
+    for (sample_ctr, (left, right)) in left.iter().zip(right).enumerate() {
+        writer.write_samples(
+            sample_ctr,
+            SamplesByChannel::new()
+                .front_left(*left)
+                .front_right(*right),
+        )?;
+    }
+
+    writer.flush()
+}
+
+// Parses the flags as if they followed the source and target on the command line
+pub fn parse_flags(flags: &[&str]) -> Option<Options> {
+    let args = ["soft_matrix", "source.wav", "target.wav"]
+        .iter()
+        .chain(flags)
+        .map(|arg| arg.to_string())
+        .collect();
+
+    Options::parse_args(args)
+}
+
+// Upmixes the left and right with the flags. The output is a single file
+pub fn upmix_stereo(left: &[f32], right: &[f32], flags: &[&str]) -> Result<Upmixed> {
+    let source_wav_path = temp_path("source.wav");
+    let target_wav_path = temp_path("target.wav");
+    write_stereo(&source_wav_path, left, right)?;
+
+    let args = [
+        "soft_matrix",
+        source_wav_path
+            .to_str()
+            .expect("Temporary path isn't valid UTF-8"),
+        target_wav_path
+            .to_str()
+            .expect("Temporary path isn't valid UTF-8"),
+    ]
+    .iter()
+    .chain(flags)
+    .map(|arg| arg.to_string())
+    .collect();
+    let options = Options::parse_args(args).expect("Invalid flags");
+
+    let header = WavHeader {
+        sample_format: SampleFormat::Float,
+        channels: options.written_channels,
+        sample_rate: SAMPLE_RATE,
+    };
+
+    let upmix_result = upmix(
+        options,
+        read_wav_from_file_path(&source_wav_path)?,
+        vec![write_wav_to_file_path(&target_wav_path, header)?],
+        None,
+    );
+
+    let upmixed = upmix_result.and_then(|_| {
+        let target_wav = read_wav_from_file_path(&target_wav_path)?;
+        let channels = *target_wav.channels();
+        let samples = target_wav
+            .get_stream_f32_reader()?
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Upmixed { channels, samples })
+    });
+
+    let _ = fs::remove_file(&source_wav_path);
+    let _ = fs::remove_file(&target_wav_path);
+
+    upmixed
+}
+
+// Every channel in the samples, in the same order as the channels in a wav file
+pub fn channel_values(samples: &SamplesByChannel<f32>) -> Vec<f32> {
+    [
+        samples.front_left,
+        samples.front_right,
+        samples.front_center,
+        samples.low_frequency,
+        samples.back_left,
+        samples.back_right,
+        samples.front_left_of_center,
+        samples.front_right_of_center,
+        samples.back_center,
+        samples.side_left,
+        samples.side_right,
+        samples.top_center,
+        samples.top_front_left,
+        samples.top_front_center,
+        samples.top_front_right,
+        samples.top_back_left,
+        samples.top_back_center,
+        samples.top_back_right,
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+// A column of the -dump-window file, with a value for every frequency, in the window at 2205 samples
+pub fn dumped_column(left: &[f32], right: &[f32], flags: &[&str], column: &str) -> Vec<f32> {
+    let dump_path = temp_path("window.txt");
+    let dump_path_str = dump_path
+        .to_str()
+        .expect("Temporary path isn't valid UTF-8");
+    let flags = [flags, &["-dump-window", "2205", dump_path_str]].concat();
+    upmix_stereo(left, right, &flags).expect("Upmixing failed");
+
+    let dump = fs::read_to_string(&dump_path).expect("The window wasn't dumped");
+    let _ = fs::remove_file(&dump_path);
+
+    let mut lines = dump.lines().skip_while(|line| !line.starts_with("bin\t"));
+    let header: Vec<&str> = lines.next().expect("No header").split('\t').collect();
+    let column_index = header
+        .iter()
+        .position(|header_column| *header_column == column)
+        .expect("No such column");
+
+    lines
+        .map(|line| line.split('\t').nth(column_index).expect("Missing column"))
+        .map(|value| value.parse().expect("Not a number"))
+        .collect()
+}
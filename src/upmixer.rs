@@ -1,22 +1,48 @@
 use std::io::{stdout, Error, ErrorKind, Read, Result, Seek, Write};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::thread::available_parallelism;
-use std::time::Duration;
+use std::thread::{available_parallelism, JoinHandle};
+use std::time::{Duration, Instant};
 
 use rustfft::{num_complex::Complex, FftPlanner};
 use wave_stream::open_wav::OpenWav;
 use wave_stream::wave_reader::{OpenWavReader, StreamOpenWavReader};
 use wave_stream::wave_writer::OpenWavWriter;
 
+use crate::ambisonics;
+use crate::auto_window;
 use crate::logger::Logger;
+use crate::memory_limit;
 use crate::options::Options;
 use crate::panner_and_writer::PannerAndWriter;
-use crate::panning_averager::PanningAverager;
-use crate::reader::Reader;
-use crate::structs::ThreadState;
-use crate::window_sizes::get_ideal_window_size;
+use crate::panning_averager::{self, PanningAverager};
+use crate::reader::{self, Reader};
+use crate::report;
+use crate::resume::PartialOutput;
+use crate::structs::{ResumePoint, ThreadState};
+use crate::viz::VizWriter;
+use crate::window_dump;
+use crate::window_sizes::{
+    bin_frequency, check_window_size, end_of_file_window, get_ideal_window_size, hann_window,
+    start_of_file_window,
+};
+
+// The smallest window that can be steered
+const MIN_SINGLE_WINDOW_SIZE: usize = 6;
+
+// Additional threads only start after the first thread transforms this many windows, (via -thread-ramp,) so that
+// threads don't all start by contending for the reader
+pub const DEFAULT_THREAD_RAMP: usize = 256;
+
+// Sources outside of these sample rates are upmixed, but with a warning
+const MIN_RECOMMENDED_SAMPLE_RATE: usize = 22050;
+const MAX_RECOMMENDED_SAMPLE_RATE: usize = 192000;
+
+// Sources up to this many samples (about 47 seconds at 44.1khz) are read into memory instead of streamed. Reading
+// the left, right, and mono channels into memory uses up to 24 bytes per sample
+const IN_MEMORY_MAX_SAMPLES: usize = 1 << 21;
 
 pub struct Upmixer {
     pub options: Options,
@@ -24,6 +50,18 @@ pub struct Upmixer {
     pub window_midpoint: usize,
     pub total_samples_to_write: usize,
     pub scale: f32,
+    // Each transform is multiplied by this, except for the first and last transforms, (see window())
+    pub hann_window: Vec<f32>,
+    pub start_of_file_window: Vec<f32>,
+    pub end_of_file_window: Vec<f32>,
+    // The entire source is a single transform, via -single-window
+    pub single_window: bool,
+    // Via -dump-window: The window whose analysis is written to a file
+    pub dump_window_last_sample_ctr: Option<usize>,
+    // Via -max-memory: Reading pauses when this many windows are waiting to be averaged or written
+    max_windows_in_flight: Option<usize>,
+    // Via -thread-ramp: Additional threads start after this many windows are read
+    thread_ramp: usize,
 
     // Handles periodic logging to the console
     pub logger: Logger,
@@ -39,15 +77,20 @@ pub struct Upmixer {
 
     // The number of running threads
     num_running_threads: AtomicUsize,
+
+    // The first error in any thread. Once there is an error, every thread stops, and upmix() returns it
+    failed: AtomicBool,
+    error: Mutex<Option<Error>>,
 }
 
 unsafe impl Send for Upmixer {}
 unsafe impl Sync for Upmixer {}
 
 pub fn upmix<TReader: 'static + Read + Seek>(
-    options: Options,
+    mut options: Options,
     source_wav_reader: OpenWavReader<TReader>,
     target_open_wav_writers: Vec<OpenWavWriter>,
+    partial_output: Option<&PartialOutput>,
 ) -> Result<()> {
     let max_low_frequency = (source_wav_reader.sample_rate() / 8) as f32;
     if options.low_frequency >= max_low_frequency {
@@ -59,29 +102,150 @@ pub fn upmix<TReader: 'static + Read + Seek>(
         return Err(Error::new(ErrorKind::InvalidInput, error));
     }
 
-    let min_window_size =
-        ((source_wav_reader.sample_rate() as f32) / options.low_frequency).ceil() as usize;
-    let mut window_size = get_ideal_window_size(min_window_size)?;
+    let sample_rate = source_wav_reader.sample_rate() as usize;
+    if sample_rate < MIN_RECOMMENDED_SAMPLE_RATE {
+        println!(
+            "Warning: {} samples / second is unusually low. The source has no frequencies above {}hz, so steering, and the output, will likely sound poor. Consider resampling the source to 44100 or 48000 samples / second before upmixing",
+            sample_rate,
+            sample_rate / 2
+        );
+    } else if sample_rate > MAX_RECOMMENDED_SAMPLE_RATE {
+        println!(
+            "Warning: {} samples / second is unusually high. The window, and memory use, grow with the sample rate, so upmixing may use a lot of memory. Consider resampling the source to 48000 or 96000 samples / second before upmixing, or use -max-memory",
+            sample_rate
+        );
+    }
 
-    println!(
-        "Lowest frequency: {}hz. With input at {} samples / second, using an optimized window size of {} samples",
-        options.low_frequency,
-        source_wav_reader.sample_rate(),
-        window_size);
+    // -trim-silence: Only the samples between the silence at the start and the end are upmixed
+    let source_len = source_wav_reader.len_samples();
+    let source_range = match options.trim_silence {
+        Some(trim_silence) => {
+            let non_silent_range = reader::measure_non_silent_range(
+                &options.source_wav_path,
+                trim_silence,
+                (options.trim_silence_min_seconds * sample_rate as f32) as usize,
+            )?;
+            println!(
+                "Trimming silence: {:.2} seconds at the start, {:.2} seconds at the end",
+                non_silent_range.start as f64 / sample_rate as f64,
+                (source_len - non_silent_range.end) as f64 / sample_rate as f64
+            );
+
+            // Upmixing backwards reads the source from its end
+            if options.reverse {
+                (source_len - non_silent_range.end)..(source_len - non_silent_range.start)
+            } else {
+                non_silent_range
+            }
+        }
+        None => 0..source_len,
+    };
+
+    // -max-duration: Only the start of the output is upmixed. The end of the source range is handled as the end of the
+    // source, so the last window is written as if the source ended there
+    let samples_to_upmix = options.samples_to_upmix(source_range.len(), sample_rate as u32);
+    let source_range = if options.reverse {
+        // Upmixing backwards reads the start of the output last
+        (source_range.end - samples_to_upmix)..source_range.end
+    } else {
+        source_range.start..(source_range.start + samples_to_upmix)
+    };
+
+    // With -oversample, the transforms run at a multiple of the source's sample rate
+    let transform_sample_rate = source_wav_reader.sample_rate() as usize * options.oversample;
+    let min_window_size = ((transform_sample_rate as f32) / options.low_frequency).ceil() as usize;
+    let mut window_size = match options.window_size {
+        Some(window_size) => {
+            println!(
+                "Using a window size of {} samples, via -window. With input at {} samples / second, the lowest frequency is {}hz",
+                window_size,
+                transform_sample_rate,
+                bin_frequency(1, window_size, transform_sample_rate)
+            );
+
+            if window_size < min_window_size {
+                println!(
+                    "Warning: A window of {} samples is too small to steer {}hz, the lowest frequency. (See -low.) Frequencies below {}hz can not be steered separately. Use a window of at least {} samples to steer {}hz",
+                    window_size,
+                    options.low_frequency,
+                    bin_frequency(1, window_size, transform_sample_rate),
+                    get_ideal_window_size(min_window_size)?,
+                    options.low_frequency
+                );
+            }
+
+            window_size
+        }
+        None => {
+            let window_size = get_ideal_window_size(min_window_size)?;
+
+            println!(
+                "Lowest frequency: {}hz. With input at {} samples / second, using an optimized window size of {} samples",
+                options.low_frequency,
+                transform_sample_rate,
+                window_size);
 
-    if source_wav_reader.len_samples() < window_size {
-        window_size = min_window_size;
+            window_size
+        }
+    };
+
+    let len_samples = source_range.len() * options.oversample;
+    // Averaging pans needs more than a window and a half of transforms
+    let single_window = options.single_window && len_samples <= window_size + (window_size / 2);
+
+    if single_window {
+        if len_samples < MIN_SINGLE_WINDOW_SIZE {
+            let error = format!(
+                "Input is too short, {} samples; a single window must be at least {} samples",
+                len_samples, MIN_SINGLE_WINDOW_SIZE
+            );
+            return Err(Error::new(ErrorKind::InvalidInput, error));
+        }
+
+        // Rounded up to an even size. (An odd-length source is padded by a single sample)
+        window_size = len_samples + (len_samples % 2);
+
+        println!(
+            "Input is too short to average pans, upmixing it as a single window of {} samples",
+            window_size
+        );
+    } else if len_samples < window_size {
+        // Rounded up to an even size
+        window_size = min_window_size + (min_window_size % 2);
     }
 
-    if source_wav_reader.len_samples() < window_size {
+    if !single_window && len_samples < window_size {
         let error = format!(
             "Input is too short, {} samples; minimum window size {} samples. Consider raising the lowest frequency via -low {}",
-            source_wav_reader.len_samples(),
+            len_samples,
             min_window_size,
-            (source_wav_reader.sample_rate() as usize / source_wav_reader.len_samples()) + 1);
+            (transform_sample_rate / len_samples) + 1);
         return Err(Error::new(ErrorKind::InvalidInput, error));
     }
 
+    if options.auto_window && !single_window {
+        let auto_window_size =
+            auto_window::choose_window_size(&options.source_wav_path, min_window_size)?;
+
+        // A larger window is only used when the source is long enough to average pans with it
+        if len_samples > auto_window_size * 2 {
+            window_size = auto_window_size;
+        } else if auto_window_size > window_size {
+            println!(
+                "Input is too short for a window size of {} samples, using {} samples instead",
+                auto_window_size, window_size
+            );
+        }
+    }
+
+    if options.draft {
+        println!("Draft: Upmixing with a smaller window for a quick preview. Steering, especially of low frequencies, is less accurate than without -draft");
+    }
+
+    if options.no_average && !single_window {
+        println!("Warning: -no-average steers each transform without averaging its pans, so the output will likely have more \"zipper\" noise");
+    }
+
     let source_wav_reader = source_wav_reader.get_stream_f32_reader()?;
     let mut target_random_access_wav_writers = Vec::with_capacity(target_open_wav_writers.len());
     for target_open_wav_writer in target_open_wav_writers {
@@ -89,23 +253,142 @@ pub fn upmix<TReader: 'static + Read + Seek>(
             .push(target_open_wav_writer.get_random_access_f32_writer()?);
     }
 
-    let max_samples_in_file =
-        (source_wav_reader.info().len_samples() / target_random_access_wav_writers.len()) + 1;
+    // (The same length that main.rs splits the output by)
+    let max_samples_in_file = (options
+        .samples_to_upmix(source_wav_reader.info().len_samples(), sample_rate as u32)
+        / target_random_access_wav_writers.len())
+        + 1;
 
     // rustfft states that the scale is 1/len()
     // See "noramlization": https://docs.rs/rustfft/latest/rustfft/#normalization
     let scale: f32 = 1.0 / (window_size as f32);
 
+    check_window_size(window_size)?;
     let window_midpoint = window_size / 2;
 
-    let total_samples_to_write = source_wav_reader.info().len_samples();
-    let sample_rate = source_wav_reader.info().sample_rate() as usize;
+    let hann_window = hann_window(window_size);
+    let start_of_file_window = start_of_file_window(window_size);
+    let end_of_file_window = end_of_file_window(window_size);
+
+    // When resuming, the samples that were already written are copied from the partial output. The last window of
+    // the partial output is discarded because samples are written out-of-order. Upmixing restarts two windows before
+    // the first sample that needs to be written, so that the averages are re-seeded. (Plus another window for each
+    // additional smoothing pass)
+    // With -append, the end of the previous output was averaged with copies of the last pans, instead of with what was
+    // appended, so a window is discarded for each smoothing pass
+    let resume_point = match partial_output {
+        Some(partial_output) => {
+            let samples_to_discard = if partial_output.complete {
+                window_size * options.smoothing_passes
+            } else {
+                window_size
+            };
+
+            let first_sample_to_write = partial_output
+                .valid_samples
+                .min(source_wav_reader.info().len_samples())
+                .saturating_sub(samples_to_discard);
+
+            partial_output.copy_to(
+                &mut target_random_access_wav_writers[0],
+                first_sample_to_write,
+            )?;
+
+            if partial_output.complete {
+                println!(
+                    "Appending: {} seconds were already upmixed, upmixing from {} seconds",
+                    partial_output.valid_samples as f64
+                        / source_wav_reader.info().sample_rate() as f64,
+                    first_sample_to_write as f64 / source_wav_reader.info().sample_rate() as f64
+                );
+            } else {
+                println!(
+                    "Resuming at {} seconds",
+                    first_sample_to_write as f64 / source_wav_reader.info().sample_rate() as f64
+                );
+            }
+
+            ResumePoint {
+                source_offset: first_sample_to_write
+                    .saturating_sub(window_size * (1 + options.smoothing_passes)),
+                first_sample_to_write,
+            }
+        }
+        None => ResumePoint {
+            source_offset: 0,
+            first_sample_to_write: 0,
+        },
+    };
+
+    // (-trim-silence can not be used with -resume)
+    let source_range = (source_range.start + resume_point.source_offset)..source_range.end;
+    let total_samples_to_write = source_range.len() * options.oversample;
+
+    let dump_window_last_sample_ctr = match &options.dump_window {
+        Some((sample, _)) => {
+            if *sample >= source_range.len() {
+                let error = format!(
+                    "-dump-window sample {} is past the end of the output, which is {} samples",
+                    sample,
+                    source_range.len()
+                );
+                return Err(Error::new(ErrorKind::InvalidInput, error));
+            }
+
+            Some(window_dump::last_sample_ctr_to_dump(
+                *sample,
+                window_size,
+                total_samples_to_write,
+                options.oversample,
+                options.reverse,
+                single_window,
+            ))
+        }
+        None => None,
+    };
 
     let mut planner = FftPlanner::new();
     let fft_forward = planner.plan_fft_forward(window_size);
     let fft_inverse = planner.plan_fft_inverse(window_size);
 
-    let reader = Reader::open(&options, source_wav_reader, window_size, fft_forward)?;
+    // Short sources are read into memory, unless memory is limited. The source is padded to the end of the last
+    // window. (A single window can be a sample longer than the source)
+    let in_memory_len = if options.in_memory
+        || (options.max_memory_mb.is_none() && total_samples_to_write <= IN_MEMORY_MAX_SAMPLES)
+    {
+        Some(total_samples_to_write.max(window_size))
+    } else {
+        None
+    };
+
+    let max_windows_in_flight = match options.max_memory_mb {
+        Some(max_memory_mb) => {
+            let memory_limits = memory_limit::limit_memory(
+                &options,
+                window_size,
+                source_wav_reader.info().len_samples(),
+                max_memory_mb,
+            )?;
+            println!(
+                "Limiting memory to {} MB: Using up to {} threads, with up to {} windows in flight",
+                max_memory_mb, memory_limits.num_threads, memory_limits.max_windows_in_flight
+            );
+
+            options.num_threads = Some(memory_limits.num_threads);
+            Some(memory_limits.max_windows_in_flight)
+        }
+        None => None,
+    };
+
+    let reader = Reader::open(
+        &options,
+        source_wav_reader,
+        window_size,
+        transform_sample_rate,
+        fft_forward,
+        source_range,
+        in_memory_len,
+    )?;
     let panner_and_writer = PannerAndWriter::new(
         &options,
         window_size,
@@ -113,11 +396,39 @@ pub fn upmix<TReader: 'static + Read + Seek>(
         target_random_access_wav_writers,
         fft_inverse,
         max_samples_in_file,
-    );
+        resume_point,
+    )?;
+
+    if options.diag {
+        print_diagnostics(
+            &options,
+            sample_rate,
+            window_size,
+            window_midpoint,
+            total_samples_to_write,
+        )?;
+    }
+
+    print!("Starting...");
+    stdout().flush()?;
+
+    let viz_writer = match &options.viz_path {
+        Some(viz_path) => Some(VizWriter::create(
+            viz_path,
+            window_size,
+            sample_rate,
+            options.oversample,
+            options.low_frequency,
+            total_samples_to_write - 1,
+        )?),
+        None => None,
+    };
+
+    let panning_averager = PanningAverager::new(window_size, options.smoothing_passes, viz_writer);
 
-    let mut stdout = stdout();
-    stdout.write(format!("Starting...").as_bytes())?;
-    stdout.flush()?;
+    // A source with fewer windows than -thread-ramp starts additional threads once it's entirely read
+    let total_windows = total_samples_to_write.max(window_size) - (window_size - 1);
+    let thread_ramp = options.thread_ramp.min(total_windows);
 
     let upmixer = Arc::new(Upmixer {
         options,
@@ -125,18 +436,90 @@ pub fn upmix<TReader: 'static + Read + Seek>(
         window_size,
         window_midpoint,
         scale,
+        hann_window,
+        start_of_file_window,
+        end_of_file_window,
+        single_window,
+        dump_window_last_sample_ctr,
+        max_windows_in_flight,
+        thread_ramp,
         logger: Logger::new(Duration::from_secs_f32(1.0 / 10.0), total_samples_to_write),
         reader,
-        panning_averager: PanningAverager::new(window_size),
+        panning_averager,
         panner_and_writer,
         num_running_threads: AtomicUsize::new(1),
+        failed: AtomicBool::new(false),
+        error: Mutex::new(None),
     });
 
     // Start upmixing (will start additional threads)
     upmixer.run_upmix_thread(0);
 
+    if let Some(error) = upmixer
+        .error
+        .lock()
+        .expect("Cannot aquire lock because a thread panicked")
+        .take()
+    {
+        return Err(error);
+    }
+
     upmixer.logger.finish_logging()?;
 
+    if let Some(reduction_db) = upmixer.panner_and_writer.auto_headroom_reduction_db() {
+        if reduction_db > 0.0 {
+            println!(
+                "Auto headroom lowered the output by up to {:.1} dB to prevent clipping",
+                reduction_db
+            );
+        } else {
+            println!("Auto headroom did not need to lower the output");
+        }
+    }
+
+    if upmixer.options.sanitize {
+        let (non_finite_source_samples, non_finite_pans) = upmixer.reader.get_non_finite_counts();
+        let non_finite_output_samples = upmixer.panner_and_writer.get_non_finite_samples();
+        if non_finite_source_samples + non_finite_pans + non_finite_output_samples > 0 {
+            println!(
+                "Warning: Replaced NaN or infinity with silence in {} source samples, {} steered frequencies, and {} output samples",
+                non_finite_source_samples, non_finite_pans, non_finite_output_samples
+            );
+        } else {
+            println!("Sanitize: The source and output had no NaN or infinity");
+        }
+    }
+
+    if let Some(reduction_db) = upmixer.panner_and_writer.center_ceiling_reduction_db() {
+        if reduction_db > 0.0 {
+            println!(
+                "The center ceiling lowered the center by up to {:.1} dB",
+                reduction_db
+            );
+        } else {
+            println!("The center never reached the center ceiling");
+        }
+    }
+
+    if let Some(report_file_path) = &upmixer.options.report_file_path {
+        upmixer.panner_and_writer.write_report(
+            report_file_path,
+            &upmixer.options,
+            sample_rate,
+            window_size,
+            upmixer.reader.get_measured_dc_offset(),
+        )?;
+        println!("Report written to {}", report_file_path.display());
+    }
+
+    if let Some(viz_path) = &upmixer.options.viz_path {
+        println!("Visualization written to {}", viz_path.display());
+    }
+
+    if let Some((_, dump_window_path)) = &upmixer.options.dump_window {
+        println!("Window written to {}", dump_window_path.display());
+    }
+
     // In general, this should be a no-op
     // This is to help with debugging
     upmixer.options.matrix.print_debugging_information();
@@ -144,19 +527,90 @@ pub fn upmix<TReader: 'static + Read + Seek>(
     Ok(())
 }
 
+// Prints the configuration as a single line of JSON to stderr, so that it can be pasted into bug reports
+fn print_diagnostics(
+    options: &Options,
+    sample_rate: usize,
+    window_size: usize,
+    window_midpoint: usize,
+    total_samples_to_write: usize,
+) -> Result<()> {
+    let num_threads = max_threads(options);
+
+    let channel_names = match options.ambisonic_order {
+        Some(ambisonic_order) => ambisonics::channel_names(ambisonic_order),
+        None => report::channel_names(&options.written_channels),
+    };
+    let channels_json: Vec<String> = channel_names
+        .iter()
+        .map(|channel_name| format!("\"{}\"", channel_name))
+        .collect();
+
+    eprintln!(
+        "{{\"version\":\"{}\",\"sample_rate\":{},\"window_size\":{},\"window_midpoint\":{},\"frequency_resolution\":{},\"low_frequency\":{},\"channels\":[{}],\"matrix\":\"{}\",\"threads\":{},\"total_samples_to_write\":{}}}",
+        env!("CARGO_PKG_VERSION"),
+        sample_rate,
+        window_size,
+        window_midpoint,
+        // The distance between the frequencies in each transform, in hz
+        bin_frequency(1, window_size, sample_rate * options.oversample),
+        options.low_frequency,
+        channels_json.join(","),
+        options.matrix_name,
+        num_threads,
+        total_samples_to_write
+    );
+
+    Ok(())
+}
+
+// The most threads that can run: -threads, or available_parallelism()
+pub fn max_threads(options: &Options) -> usize {
+    match options.num_threads {
+        Some(num_threads) => num_threads,
+        None => threads_available(available_parallelism()),
+    }
+}
+
+// available_parallelism() can fail where the number of CPUs can't be detected, such as in some containers, so upmixing
+// falls back to a single thread instead of failing. (With a single thread, no other threads are ever started)
+fn threads_available(available_parallelism: Result<NonZeroUsize>) -> usize {
+    match available_parallelism {
+        Ok(available_parallelism) => available_parallelism.into(),
+        Err(_) => 1,
+    }
+}
+
 impl Upmixer {
-    // Runs the upmix thread. Aborts the process if there is an error
+    // Runs the upmix thread. If there is an error, it's kept for upmix() to return, and every thread stops
     fn run_upmix_thread(self: &Arc<Upmixer>, thread_id: usize) {
-        match self.run_upmix_thread_int(thread_id) {
-            Err(error) => {
-                println!("Error upmixing: {:?}", error);
-                std::process::exit(-1);
+        // If this thread starts another thread, it will wait for the next thread to end before ending
+        // This way, all threads are finished before cleanup runs
+        let mut join_handle: Option<JoinHandle<()>> = None;
+
+        if let Err(error) = self.run_upmix_thread_int(thread_id, &mut join_handle) {
+            let mut first_error = self
+                .error
+                .lock()
+                .expect("Cannot aquire lock because a thread panicked");
+            if first_error.is_none() {
+                *first_error = Some(error);
             }
-            _ => {}
+
+            // The next thread must stop before this thread can wait for it
+            self.failed.store(true, Ordering::SeqCst);
+        }
+
+        if let Some(join_handle) = join_handle {
+            join_handle.join().expect("Could not join thread");
         }
     }
 
-    fn run_upmix_thread_int(self: &Arc<Upmixer>, thread_id: usize) -> Result<()> {
+    fn run_upmix_thread_int(
+        self: &Arc<Upmixer>,
+        thread_id: usize,
+        join_handle: &mut Option<JoinHandle<()>>,
+    ) -> Result<()> {
         // Each thread has a separate FFT scratch space
         let scratch_forward = vec![
             Complex {
@@ -182,57 +636,106 @@ impl Upmixer {
         // Initial log
         self.logger.log_status(&thread_state)?;
 
-        // If this thread starts another thread, it will wait for the next thread to end before ending
-        // This way, all threads are finished before cleanup runs
-        let mut join_handle = None;
+        let mut throttle = self.options.max_cpu.map(Throttle::new);
 
         'upmix_each_sample: loop {
-            // Start/stop threads
-            let thread_id_plus_one = thread_id + 1;
+            // Another thread failed
+            if self.failed.load(Ordering::SeqCst) {
+                break 'upmix_each_sample;
+            }
+
+            if let Some(throttle) = throttle.as_mut() {
+                throttle.start_working();
+            }
 
-            if thread_id_plus_one == self.num_running_threads.load(Ordering::Relaxed) {
-                let num_running_threads = thread_id_plus_one;
+            // Start/stop threads
+            // Only the last thread starts and stops threads. num_running_threads is changed with compare_exchange, and
+            // is always changed before a thread starts and after a thread stops working, so that the number of
+            // working threads never exceeds -threads
+            let num_running_threads = thread_id + 1;
 
-                let available_parallelism = match self.options.num_threads {
-                    Some(num_threads) => num_threads,
-                    None => available_parallelism()?.into(),
-                };
+            if num_running_threads == self.num_running_threads.load(Ordering::SeqCst) {
+                let available_parallelism = max_threads(&self.options);
 
                 if available_parallelism < num_running_threads && num_running_threads > 1 {
                     // End the thread if available_parallelism lowers
                     //println!();
                     //println!("Ending thread {} because available_parallelism() is {}", thread_id, available_parallelism);
-                    self.num_running_threads
-                        .store(num_running_threads - 1, Ordering::Relaxed);
-                    break 'upmix_each_sample;
-                } else if available_parallelism > num_running_threads {
+                    if self
+                        .num_running_threads
+                        .compare_exchange(
+                            num_running_threads,
+                            num_running_threads - 1,
+                            Ordering::SeqCst,
+                            Ordering::SeqCst,
+                        )
+                        .is_ok()
+                    {
+                        break 'upmix_each_sample;
+                    }
+                } else if available_parallelism > num_running_threads
+                    && self.ramped_up()
+                    && self
+                        .num_running_threads
+                        .compare_exchange(
+                            num_running_threads,
+                            num_running_threads + 1,
+                            Ordering::SeqCst,
+                            Ordering::SeqCst,
+                        )
+                        .is_ok()
+                {
                     // Start a new thread if available_parallelism raises
                     //println!();
                     //println!("Starting thread {} because available_parallelism() is {}", thread_id + 1, available_parallelism);
-                    self.num_running_threads
-                        .store(num_running_threads + 1, Ordering::Relaxed);
+
+                    // A thread that previously ended may not have exited yet
+                    if let Some(join_handle) = join_handle.take() {
+                        join_handle.join().expect("Could not join thread");
+                    }
 
                     let upmixer_for_thread = self.clone();
-                    join_handle = Some(thread::spawn(move || {
+                    *join_handle = Some(thread::spawn(move || {
                         upmixer_for_thread.run_upmix_thread(thread_id + 1);
                     }));
                 }
             }
 
-            let transformed_window_and_pans_option = self
-                .reader
-                .read_transform_and_measure_pans(&mut thread_state)?;
+            // When too many windows are in flight, this thread only averages and writes until they are finished
+            let transformed_window_and_pans_option = if self.too_many_windows_in_flight() {
+                thread::yield_now();
+                None
+            } else {
+                self.reader
+                    .read_transform_and_measure_pans(&mut thread_state)?
+            };
 
             self.logger.log_status(&thread_state)?;
 
             // Read samples and perform forward transforms
-            match transformed_window_and_pans_option {
-                Some(transformed_window_and_pans) => {
+            if let Some(transformed_window_and_pans) = transformed_window_and_pans_option {
+                if self.single_window {
+                    // There is nothing to average with, except for neighboring frequencies
+                    let mut transformed_window_and_pans = transformed_window_and_pans;
+                    if self.options.frequency_smoothing > 0 {
+                        panning_averager::smooth_across_frequencies(
+                            &mut transformed_window_and_pans.frequency_pans,
+                            self.options.frequency_smoothing,
+                        );
+                    }
+
+                    self.panner_and_writer.enqueue(transformed_window_and_pans);
+                } else if self.options.no_average {
+                    // Like averaging, the first transform is skipped, because the second transform writes the
+                    // start of the file
+                    if transformed_window_and_pans.last_sample_ctr != self.window_size - 1 {
+                        self.panner_and_writer.enqueue(transformed_window_and_pans);
+                    }
+                } else {
                     self.panning_averager
                         .enqueue_transformed_window_and_pans(transformed_window_and_pans);
                 }
-                _ => {}
-            };
+            }
 
             // If a lock can be aquired
             // - Enqueues completed transformed_window_and_pans
@@ -240,7 +743,7 @@ impl Upmixer {
             //
             // The conditional lock is because these calculations require global state and can not be
             // performed in parallel
-            self.panning_averager.enqueue_and_average(&thread_state);
+            self.panning_averager.enqueue_and_average(&thread_state)?;
             self.panner_and_writer
                 .perform_backwards_transform_and_write_samples(&mut thread_state)?;
 
@@ -248,21 +751,225 @@ impl Upmixer {
 
             // Determine if the loop should end
             let total_samples_written = self.panner_and_writer.get_total_samples_written();
-            if total_samples_written >= self.total_samples_to_write {
+            if total_samples_written >= self.samples_written_by_windows() {
                 break 'upmix_each_sample;
             }
-        }
 
-        match join_handle {
-            // Note that threads will terminate the process if there is an unhandled error
-            Some(join_handle) => join_handle.join().expect("Could not join thread"),
-            None => {}
+            if let Some(throttle) = throttle.as_mut() {
+                throttle.stop_working();
+            }
         }
 
         Ok(())
     }
 
+    // If enough windows are read to start additional threads
+    fn ramped_up(&self) -> bool {
+        let windows_read = self.reader.get_total_samples_read() + 1 - self.window_size;
+        windows_read >= self.thread_ramp
+    }
+
+    fn too_many_windows_in_flight(&self) -> bool {
+        match self.max_windows_in_flight {
+            Some(max_windows_in_flight) => {
+                self.panning_averager.num_windows_waiting()
+                    + self.panner_and_writer.num_windows_waiting()
+                    >= max_windows_in_flight
+            }
+            None => false,
+        }
+    }
+
     pub fn num_running_threads(&self) -> usize {
         self.num_running_threads.load(Ordering::Relaxed)
     }
+
+    // How many samples are written when every window is written. A single window writes every sample. Otherwise, each
+    // transform writes one sample ahead of the samples it was read from, so that the first transform that is written
+    // can start the file, and the last sample is never written
+    fn samples_written_by_windows(&self) -> usize {
+        if self.single_window {
+            self.total_samples_to_write
+        } else {
+            self.total_samples_to_write - 1
+        }
+    }
+
+    // The window that a transform is multiplied by. Only the midpoint of a Hann-windowed transform is written, where
+    // the window is at its peak. The first and last transforms write every sample towards the start or end of the
+    // file, so their windows are only tapered towards the middle of the file. A single window, (or a first window that
+    // is also the last,) writes samples at both of its edges, so it isn't windowed
+    pub fn window(&self, last_sample_ctr: usize) -> Option<&[f32]> {
+        let is_first = last_sample_ctr == self.window_size;
+        let is_last = last_sample_ctr == self.total_samples_to_write - 1;
+        if self.single_window || (is_first && is_last) {
+            None
+        } else if is_first {
+            Some(&self.start_of_file_window)
+        } else if is_last {
+            Some(&self.end_of_file_window)
+        } else {
+            Some(&self.hann_window)
+        }
+    }
+}
+
+// Limits how much of the time a thread spends working, via -max-cpu
+// Each loop through the upmix is too short to sleep after, so time is accumulated until it's worth sleeping
+struct Throttle {
+    // How long to sleep for each second of work
+    sleep_per_work: f32,
+    started_working: Instant,
+    time_to_sleep: Duration,
+}
+
+const MINIMUM_THROTTLE_SLEEP: Duration = Duration::from_millis(10);
+
+impl Throttle {
+    fn new(max_cpu: f32) -> Throttle {
+        Throttle {
+            sleep_per_work: (100.0 - max_cpu) / max_cpu,
+            started_working: Instant::now(),
+            time_to_sleep: Duration::ZERO,
+        }
+    }
+
+    fn start_working(&mut self) {
+        self.started_working = Instant::now();
+    }
+
+    fn stop_working(&mut self) {
+        self.time_to_sleep += self.started_working.elapsed().mul_f32(self.sleep_per_work);
+
+        if self.time_to_sleep >= MINIMUM_THROTTLE_SLEEP {
+            thread::sleep(self.time_to_sleep);
+            self.time_to_sleep = Duration::ZERO;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error, ErrorKind};
+    use std::num::NonZeroUsize;
+
+    use super::{max_threads, threads_available};
+    use crate::test_support::{channel_values, parse_flags, sine, temp_path, upmix_stereo};
+
+    #[test]
+    fn single_window_upmixes_a_short_source_without_padding() {
+        // Shorter than a window and a half at 20hz, and an odd length, so that it's padded by a single sample
+        for len_samples in [3000, 3001] {
+            let left = sine(1000.0, 0.5, len_samples);
+            let right = vec![0.0; len_samples];
+
+            let upmixed = upmix_stereo(&left, &right, &["-single-window", "-channels", "4"])
+                .expect("Upmixing failed");
+            assert_eq!(len_samples, upmixed.samples.len());
+
+            // A hard-left tone is steered to the front left at a constant level, all the way to the edges of the
+            // source
+            let front_left: Vec<f32> = upmixed
+                .samples
+                .iter()
+                .map(|samples| samples.front_left.unwrap())
+                .collect();
+            let rms = |samples: &[f32]| {
+                (samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32)
+                    .sqrt()
+            };
+            let gain = rms(&front_left) / rms(&left);
+            assert!(gain > 0.5, "The tone is missing from the front left");
+
+            for (sample_ctr, front_left) in front_left.iter().enumerate() {
+                assert!(
+                    (front_left - (left[sample_ctr] * gain)).abs() < 0.01,
+                    "Sample {}: {} instead of {}",
+                    sample_ctr,
+                    front_left,
+                    left[sample_ctr] * gain
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn a_single_thread_upmixes_the_same_as_many_threads() {
+        let left = sine(1000.0, 0.5, 4410);
+        let right = sine(1500.0, 0.5, 4410);
+        let flags = ["-low", "1000", "-channels", "5"];
+
+        let single_thread_flags = [&flags[..], &["-threads", "1"]].concat();
+        let single_thread_options = parse_flags(&single_thread_flags).expect("Invalid flags");
+        assert_eq!(1, max_threads(&single_thread_options));
+
+        let single_thread = upmix_stereo(&left, &right, &single_thread_flags).unwrap();
+        let many_threads = upmix_stereo(
+            &left,
+            &right,
+            &[&flags[..], &["-threads", "4", "-thread-ramp", "1"]].concat(),
+        )
+        .unwrap();
+
+        assert_eq!(single_thread.samples.len(), many_threads.samples.len());
+        for (single_thread, many_threads) in single_thread.samples.iter().zip(&many_threads.samples)
+        {
+            assert_eq!(channel_values(single_thread), channel_values(many_threads));
+        }
+    }
+
+    #[test]
+    fn every_sample_of_a_tone_is_written() {
+        let left = sine(1000.0, 0.5, 4410);
+        let right = sine(1500.0, 0.5, 4410);
+        let upmixed = upmix_stereo(&left, &right, &["-low", "1000", "-channels", "4"]).unwrap();
+
+        // The first window's midpoint was skipped, so it was silent in every channel
+        for (sample_ctr, samples) in upmixed.samples.iter().enumerate() {
+            assert!(
+                channel_values(samples).iter().any(|value| *value != 0.0),
+                "Sample {} is silent",
+                sample_ctr
+            );
+        }
+    }
+
+    #[test]
+    fn upmixing_falls_back_to_a_single_thread_when_the_cpus_can_not_be_counted() {
+        assert_eq!(8, threads_available(Ok(NonZeroUsize::new(8).unwrap())));
+
+        let error = Error::new(ErrorKind::Unsupported, "The number of CPUs is unknown");
+        assert_eq!(1, threads_available(Err(error)));
+    }
+
+    #[test]
+    fn an_error_in_an_upmix_thread_is_returned() {
+        let left = sine(1000.0, 0.5, 4410);
+        let right = sine(1500.0, 0.5, 4410);
+
+        // The window is dumped from whichever thread steers it, so that thread fails
+        let missing_folder = temp_path("missing");
+        let dump_path = missing_folder.join("window.txt");
+        let error = match upmix_stereo(
+            &left,
+            &right,
+            &[
+                "-low",
+                "1000",
+                "-channels",
+                "5",
+                "-threads",
+                "4",
+                "-thread-ramp",
+                "1",
+                "-dump-window",
+                "2205",
+                dump_path.to_str().unwrap(),
+            ],
+        ) {
+            Ok(_) => panic!("Upmixing succeeded without a folder to dump the window to"),
+            Err(error) => error,
+        };
+        assert_eq!(ErrorKind::NotFound, error.kind());
+    }
 }
@@ -8,8 +8,8 @@ impl<T: Clone> VecDequeExt<T> for VecDeque<T> {
     fn to_vec(&self) -> Vec<T> {
         let mut vec = Vec::with_capacity(self.len());
 
-        for ctr in 0..self.len() {
-            vec.push(self[ctr].clone());
+        for item in self.iter() {
+            vec.push(item.clone());
         }
 
         vec
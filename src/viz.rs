@@ -0,0 +1,130 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Result, Write},
+    path::Path,
+};
+
+use crate::{ambisonics, structs::TransformedWindowAndPans, window_sizes::bin_frequency};
+
+// The number of frequency bands in each record. Bands are spaced evenly by octave
+pub const VIZ_BANDS: usize = 24;
+
+// Often enough for smooth animation
+pub const VIZ_RECORDS_PER_SECOND: usize = 60;
+
+// Identifies the file, and the version of its format
+const VIZ_MAGIC: &[u8; 4] = b"SMVZ";
+const VIZ_VERSION: u32 = 1;
+
+// -viz: Streams where each frequency band is in the room, from the averaged pans, as fixed-size binary records
+pub struct VizWriter {
+    writer: BufWriter<File>,
+    window_size: usize,
+    window_midpoint: usize,
+    // With -oversample, the transforms are at a multiple of the output's sample rate
+    oversample: usize,
+    // In transforms, (which are at the transform sample rate,) between records
+    transforms_per_record: usize,
+    // The band that each frequency in the pans is in, or None if it's outside of all bands
+    band_by_frequency: Vec<Option<usize>>,
+    // The samples in the last record. It's flushed once this is written
+    last_sample_ctr: usize,
+}
+
+impl VizWriter {
+    pub fn create(
+        path: &Path,
+        window_size: usize,
+        sample_rate: usize,
+        oversample: usize,
+        low_frequency: f32,
+        last_sample_ctr: usize,
+    ) -> Result<VizWriter> {
+        let transform_sample_rate = sample_rate * oversample;
+
+        // From the lowest steered frequency to the output's highest frequency
+        let high_frequency = sample_rate as f32 / 2.0;
+        let octaves = (high_frequency / low_frequency).log2();
+        let band_edges: Vec<f32> = (0..=VIZ_BANDS)
+            .map(|band_ctr| low_frequency * 2f32.powf(octaves * band_ctr as f32 / VIZ_BANDS as f32))
+            .collect();
+
+        // The pans start at the first frequency, (not the DC offset,) and end at the window midpoint
+        let band_by_frequency = (1..=(window_size / 2))
+            .map(|freq_ctr| {
+                let frequency = bin_frequency(freq_ctr, window_size, transform_sample_rate);
+                band_edges
+                    .windows(2)
+                    .position(|edges| frequency >= edges[0] && frequency < edges[1])
+            })
+            .collect();
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(VIZ_MAGIC)?;
+        writer.write_all(&VIZ_VERSION.to_le_bytes())?;
+        writer.write_all(&(sample_rate as u32).to_le_bytes())?;
+        writer.write_all(&(VIZ_RECORDS_PER_SECOND as u32).to_le_bytes())?;
+        writer.write_all(&(VIZ_BANDS as u32).to_le_bytes())?;
+        for band_edge in &band_edges {
+            writer.write_all(&band_edge.to_le_bytes())?;
+        }
+
+        Ok(VizWriter {
+            writer,
+            window_size,
+            window_midpoint: window_size / 2,
+            oversample,
+            transforms_per_record: (transform_sample_rate / VIZ_RECORDS_PER_SECOND).max(1),
+            band_by_frequency,
+            last_sample_ctr,
+        })
+    }
+
+    // Called with each transform's averaged pans, in order. Only some transforms are written, so that there are
+    // VIZ_RECORDS_PER_SECOND records
+    pub fn write(&mut self, transformed_window_and_pans: &TransformedWindowAndPans) -> Result<()> {
+        let last_sample_ctr = transformed_window_and_pans.last_sample_ctr;
+
+        // A transform is written at the middle of its window
+        let sample_ctr = last_sample_ctr - self.window_midpoint;
+        if sample_ctr.is_multiple_of(self.transforms_per_record) {
+            // Each band's direction is the average of its frequencies' directions, weighted by amplitude. Its
+            // amplitude is the RMS of its frequencies, where a full-scale sine wave is 1.0
+            let mut towards_front = [0f32; VIZ_BANDS];
+            let mut towards_left = [0f32; VIZ_BANDS];
+            let mut power = [0f32; VIZ_BANDS];
+            for (frequency_pans, band) in transformed_window_and_pans
+                .frequency_pans
+                .iter()
+                .zip(self.band_by_frequency.iter())
+            {
+                if let Some(band) = band {
+                    let azimuth = ambisonics::azimuth(
+                        frequency_pans.left_to_right,
+                        frequency_pans.back_to_front,
+                    );
+                    towards_front[*band] += frequency_pans.amplitude * azimuth.cos();
+                    towards_left[*band] += frequency_pans.amplitude * azimuth.sin();
+                    power[*band] += frequency_pans.amplitude * frequency_pans.amplitude;
+                }
+            }
+
+            let to_full_scale = 2.0 / self.window_size as f32;
+
+            self.writer
+                .write_all(&((sample_ctr / self.oversample) as u64).to_le_bytes())?;
+            for band in 0..VIZ_BANDS {
+                let azimuth = towards_left[band].atan2(towards_front[band]);
+                let amplitude = power[band].sqrt() * to_full_scale;
+                self.writer.write_all(&azimuth.to_le_bytes())?;
+                self.writer.write_all(&amplitude.to_le_bytes())?;
+            }
+        }
+
+        if last_sample_ctr == self.last_sample_ctr {
+            self.writer.flush()?;
+        }
+
+        Ok(())
+    }
+}
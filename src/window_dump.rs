@@ -0,0 +1,127 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Result, Write},
+    path::Path,
+};
+
+use rustfft::num_complex::Complex;
+
+use crate::{structs::FrequencyPans, window_sizes::bin_frequency};
+
+// -dump-window: A single window's analysis, from the reader. It's written to a file, along with how the window is
+// steered, once its pans are averaged
+#[derive(Debug)]
+pub struct WindowDump {
+    window_size: usize,
+    transform_sample_rate: usize,
+    // Each frequency's left and right, as transformed, from the first frequency through the window midpoint
+    left: Vec<Complex<f32>>,
+    right: Vec<Complex<f32>>,
+    // Where each frequency is steered, before averaging
+    frequency_pans: Vec<FrequencyPans>,
+}
+
+// Finds the window that -dump-window writes: The window whose middle sample is the requested sample. Samples at the
+// start and end of the output are written from the first and last windows
+pub fn last_sample_ctr_to_dump(
+    sample: usize,
+    window_size: usize,
+    total_samples_to_write: usize,
+    oversample: usize,
+    reverse: bool,
+    single_window: bool,
+) -> usize {
+    if single_window {
+        return window_size - 1;
+    }
+
+    // When upmixing backwards, the output is written backwards
+    let sample_ctr = if reverse {
+        total_samples_to_write - 1 - (sample * oversample)
+    } else {
+        sample * oversample
+    };
+
+    // The first window that is written is the one after the first window that is transformed
+    (sample_ctr + (window_size / 2))
+        .max(window_size)
+        .min(total_samples_to_write - 1)
+}
+
+impl WindowDump {
+    pub fn new(
+        window_size: usize,
+        transform_sample_rate: usize,
+        left: &[Complex<f32>],
+        right: &[Complex<f32>],
+        frequency_pans: &[FrequencyPans],
+    ) -> WindowDump {
+        WindowDump {
+            window_size,
+            transform_sample_rate,
+            left: left.to_vec(),
+            right: right.to_vec(),
+            frequency_pans: frequency_pans.to_vec(),
+        }
+    }
+
+    // Writes the dump as tab-separated values, with a row for each frequency. steered is each channel's transform
+    // after steering, by name
+    pub fn write(
+        &self,
+        path: &Path,
+        sample: usize,
+        averaged_pans: &[FrequencyPans],
+        steered: &[(&str, &[Complex<f32>])],
+    ) -> Result<()> {
+        // A full-scale sine wave has an amplitude of window_size / 2 in its bin, so it's written as 1.0
+        let to_full_scale = 2.0 / self.window_size as f32;
+
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        writeln!(writer, "sample\t{}", sample)?;
+        writeln!(writer, "window_size\t{}", self.window_size)?;
+        writeln!(writer, "sample_rate\t{}", self.transform_sample_rate)?;
+        writeln!(writer)?;
+
+        let steered_names: Vec<&str> = steered.iter().map(|(name, _)| *name).collect();
+        writeln!(
+            writer,
+            "bin\tfrequency\tleft_amplitude\tleft_phase\tright_amplitude\tright_phase\tamplitude\tleft_to_right\tback_to_front\taveraged_left_to_right\taveraged_back_to_front\t{}",
+            steered_names.join("\t")
+        )?;
+
+        for (pan_ctr, frequency_pans) in self.frequency_pans.iter().enumerate() {
+            let freq_ctr = pan_ctr + 1;
+            let (left_amplitude, left_phase) = self.left[pan_ctr].to_polar();
+            let (right_amplitude, right_phase) = self.right[pan_ctr].to_polar();
+            let averaged_pans = &averaged_pans[pan_ctr];
+
+            let steered_amplitudes: Vec<String> = steered
+                .iter()
+                .map(|(_, transformed)| {
+                    format!("{:.6}", transformed[freq_ctr].norm() * to_full_scale)
+                })
+                .collect();
+
+            writeln!(
+                writer,
+                "{}\t{:.2}\t{:.6}\t{:.4}\t{:.6}\t{:.4}\t{:.6}\t{:.4}\t{:.4}\t{:.4}\t{:.4}\t{}",
+                freq_ctr,
+                bin_frequency(freq_ctr, self.window_size, self.transform_sample_rate),
+                left_amplitude * to_full_scale,
+                left_phase,
+                right_amplitude * to_full_scale,
+                right_phase,
+                frequency_pans.amplitude * to_full_scale,
+                frequency_pans.left_to_right,
+                frequency_pans.back_to_front,
+                averaged_pans.left_to_right,
+                averaged_pans.back_to_front,
+                steered_amplitudes.join("\t")
+            )?;
+        }
+
+        writer.flush()
+    }
+}
@@ -1,12 +1,16 @@
-use std::io::{Error, ErrorKind, Result};
+use std::{
+    f32::consts::PI,
+    io::{Error, ErrorKind, Result},
+};
 
 // All of the optimial window sizes
 // See https://docs.rs/rustfft/latest/rustfft/#avx-performance-tips
-static WINDOW_SIZES: [usize; 61] = [
+static WINDOW_SIZES: [usize; 70] = [
     6, 12, 18, 24, 36, 48, 54, 72, 96, 108, 144, 162, 192, 216, 288, 324, 384, 432, 486, 576, 648,
     768, 864, 972, 1152, 1296, 1458, 1536, 1728, 1944, 2304, 2592, 2916, 3072, 3456, 3888, 4374,
     4608, 5184, 5832, 6144, 6912, 7776, 8748, 9216, 10368, 11664, 12288, 13122, 13824, 15552,
-    17496, 18432, 20736, 23328, 24576, 26244, 27648, 31104, 34992, 36864,
+    17496, 18432, 20736, 23328, 24576, 26244, 27648, 31104, 34992, 36864, 39366, 41472, 46656,
+    49152, 52488, 55296, 62208, 69984, 73728,
 ];
 
 /*
@@ -21,7 +25,7 @@ namespace FourierSizes
     class Program
     {
         const int HIGHEST_SAMPLING_RATE = 192000;
-        const int LOWEST_FREQUENCY = 10;
+        const int LOWEST_FREQUENCY = 5;
         const int SMALLEST_WINDOW = HIGHEST_SAMPLING_RATE / LOWEST_FREQUENCY;
         const int LARGEST_WINDOW = SMALLEST_WINDOW * 2;
 
@@ -55,10 +59,298 @@ namespace FourierSizes
 pub fn get_ideal_window_size(min_window_size: usize) -> Result<usize> {
     for window_size in WINDOW_SIZES {
         if window_size >= min_window_size {
+            check_window_size(window_size)?;
             return Ok(window_size);
         }
     }
 
     let error = format!("Can not find an ideal window size for {}", min_window_size);
-    return Err(Error::new(ErrorKind::NotFound, error));
+    Err(Error::new(ErrorKind::NotFound, error))
+}
+
+// -window: Only the optimized window sizes can be chosen
+pub fn is_window_size(window_size: usize) -> bool {
+    WINDOW_SIZES.contains(&window_size)
+}
+
+// -window: The two optimized window sizes below, and the two above, a window size that isn't optimized
+pub fn nearby_window_sizes(window_size: usize) -> Vec<usize> {
+    let larger_ctr =
+        WINDOW_SIZES.partition_point(|optimized_window_size| *optimized_window_size < window_size);
+
+    WINDOW_SIZES[larger_ctr.saturating_sub(2)..(larger_ctr + 2).min(WINDOW_SIZES.len())].to_vec()
+}
+
+// The frequency, in hz, at the center of a bin (transform index) of a transform. Each bin is
+// sample_rate / window_size hz wide, so bin 1 is the lowest frequency that a window can hold (one cycle per window,)
+// and bin window_size / 2 is the Nyquist frequency. (With -oversample, sample_rate is the transform's sample rate)
+pub fn bin_frequency(bin: usize, window_size: usize, sample_rate: usize) -> f32 {
+    (sample_rate as f64 * bin as f64 / window_size as f64) as f32
+}
+
+// The Hann window that each transform is multiplied by, so that the edges of the window don't spread each frequency
+// across the spectrum. It's scaled so that its average is 1.0: A sine wave has the same amplitude in its bin as it would
+// without the window, and the window is 2.0 at the window midpoint
+pub fn hann_window(window_size: usize) -> Vec<f32> {
+    (0..window_size)
+        .map(|sample_ctr| 1.0 - (2.0 * PI * sample_ctr as f32 / window_size as f32).cos())
+        .collect()
+}
+
+// The first transform writes every sample up to and including its midpoint, so it's only tapered after its midpoint:
+// Before that, it stays at the Hann window's peak, so that each written sample can be divided by the window. Like
+// hann_window(), it's scaled so that its average is 1.0
+pub fn start_of_file_window(window_size: usize) -> Vec<f32> {
+    let window_midpoint = window_size / 2;
+    let hann_window = hann_window(window_size);
+    normalize_window(
+        (0..window_size)
+            .map(|sample_ctr| hann_window[sample_ctr.max(window_midpoint)])
+            .collect(),
+    )
+}
+
+// The last transform writes every sample from its midpoint, so it's only tapered before its midpoint. (See
+// start_of_file_window())
+pub fn end_of_file_window(window_size: usize) -> Vec<f32> {
+    let window_midpoint = window_size / 2;
+    let hann_window = hann_window(window_size);
+    normalize_window(
+        (0..window_size)
+            .map(|sample_ctr| hann_window[sample_ctr.min(window_midpoint)])
+            .collect(),
+    )
+}
+
+fn normalize_window(mut window: Vec<f32>) -> Vec<f32> {
+    let average = window.iter().sum::<f32>() / window.len() as f32;
+    for sample in window.iter_mut() {
+        *sample /= average;
+    }
+
+    window
+}
+
+// The window is split at window_size / 2, and each frequency is mirrored at window_size - freq_ctr. Both only work
+// when the window size is even
+pub fn check_window_size(window_size: usize) -> Result<()> {
+    if !window_size.is_multiple_of(2) {
+        let error = format!("Window size must be even, got {}", window_size);
+        return Err(Error::new(ErrorKind::InvalidInput, error));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Range;
+
+    use rustfft::{num_complex::Complex, FftPlanner};
+
+    use super::*;
+
+    #[test]
+    fn check_window_size_rejects_odd_sizes() {
+        assert!(check_window_size(2304).is_ok());
+
+        let error = check_window_size(2305).expect_err("An odd window size was accepted");
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        assert!(error.to_string().contains("2305"));
+    }
+
+    #[test]
+    fn bin_frequency_spans_zero_to_nyquist() {
+        assert_eq!(0.0, bin_frequency(0, 2304, 44100));
+        assert_eq!(19.140625, bin_frequency(1, 2304, 44100));
+        assert_eq!(22050.0, bin_frequency(1152, 2304, 44100));
+        assert_eq!(24000.0, bin_frequency(1024, 2048, 48000));
+
+        // -oversample 2 doubles the window and the transform's sample rate, so the bins are just as wide, but the
+        // Nyquist frequency is twice as high
+        assert_eq!(19.140625, bin_frequency(1, 4608, 88200));
+        assert_eq!(44100.0, bin_frequency(2304, 4608, 88200));
+    }
+
+    #[test]
+    fn a_sine_at_a_bin_frequency_peaks_in_that_bin() {
+        let window_size = 48;
+        let fft = FftPlanner::new().plan_fft_forward(window_size);
+
+        for bin in 1..(window_size / 2) {
+            let frequency = bin_frequency(bin, window_size, 44100);
+            let mut transform: Vec<Complex<f32>> = (0..window_size)
+                .map(|sample_ctr| Complex {
+                    re: (2.0 * PI * frequency * sample_ctr as f32 / 44100.0).sin(),
+                    im: 0.0,
+                })
+                .collect();
+            fft.process(&mut transform);
+
+            let peak = (0..=(window_size / 2))
+                .max_by(|a, b| transform[*a].norm().total_cmp(&transform[*b].norm()))
+                .unwrap();
+            assert_eq!(bin, peak, "{}hz peaked in the wrong bin", frequency);
+        }
+    }
+
+    // How much windows that are hop_size apart stray from a constant level when they're summed, as a fraction of the
+    // level. A window and hop that aren't constant overlap-add (COLA) make the output's level ripple
+    fn overlap_add_ripple(window: &[f32], hop_size: usize) -> f32 {
+        let sums: Vec<f64> = (0..hop_size)
+            .map(|offset| {
+                window
+                    .iter()
+                    .skip(offset)
+                    .step_by(hop_size)
+                    .map(|value| *value as f64)
+                    .sum()
+            })
+            .collect();
+        let level = sums.iter().sum::<f64>() / hop_size as f64;
+
+        sums.iter()
+            .map(|sum| ((sum - level) / level).abs())
+            .fold(0.0f64, f64::max) as f32
+    }
+
+    #[test]
+    fn hann_window_is_constant_overlap_add() {
+        // Each transform starts a single sample after the previous transform
+        let window = hann_window(2304);
+        assert!(overlap_add_ripple(&window, 1) < 0.0001);
+
+        // Hann windows also overlap-add to a constant level at a quarter and half of the window
+        assert!(overlap_add_ripple(&window, 576) < 0.0001);
+        assert!(overlap_add_ripple(&window, 1152) < 0.0001);
+
+        // Without enough overlap, the level ripples from silence at the window's edges to twice as loud at its midpoint
+        assert!(overlap_add_ripple(&window, 2304) > 0.5);
+        assert!(overlap_add_ripple(&window, 1000) > 0.01);
+    }
+
+    // Windows, transforms, and inverse transforms source, and then removes the gain: rustfft's 1/len(), and the window
+    fn round_trip(source: &[f32], window: &[f32]) -> Vec<f32> {
+        let window_size = window.len();
+        let mut planner = FftPlanner::new();
+        let forward = planner.plan_fft_forward(window_size);
+        let inverse = planner.plan_fft_inverse(window_size);
+
+        let mut transform: Vec<Complex<f32>> = source
+            .iter()
+            .zip(window)
+            .map(|(sample, level)| Complex {
+                re: sample * level,
+                im: 0.0,
+            })
+            .collect();
+        forward.process(&mut transform);
+        inverse.process(&mut transform);
+
+        transform
+            .iter()
+            .zip(window)
+            .map(|(sample, level)| sample.re / (window_size as f32 * level))
+            .collect()
+    }
+
+    #[test]
+    fn windowed_transforms_round_trip_where_they_are_written() {
+        let window_size = 48;
+        let window_midpoint = window_size / 2;
+        let window = hann_window(window_size);
+        assert_eq!(2.0, window[window_midpoint]);
+
+        // Frequencies between bins, and a phase that doesn't start at zero, so that every window is different
+        let source: Vec<f32> = (0..200)
+            .map(|sample_ctr| {
+                let seconds = sample_ctr as f32 / 44100.0;
+                0.5 * (2.0 * PI * 1234.5 * seconds + 0.3).sin()
+                    + 0.25 * (2.0 * PI * 9876.5 * seconds).cos()
+            })
+            .collect();
+
+        let assert_round_trip = |first_sample: usize, window: &[f32], written: Range<usize>| {
+            let actual = round_trip(&source[first_sample..(first_sample + window_size)], window);
+            for sample_in_transform in written {
+                let expected = source[first_sample + sample_in_transform];
+                assert!(
+                    (expected - actual[sample_in_transform]).abs() < 0.0001,
+                    "The window starting at {} wrote {} at {}, instead of {}",
+                    first_sample,
+                    actual[sample_in_transform],
+                    sample_in_transform,
+                    expected
+                );
+            }
+        };
+
+        // Most transforms only write their midpoint
+        for first_sample in 0..(source.len() - window_size) {
+            assert_round_trip(
+                first_sample,
+                &window,
+                window_midpoint..(window_midpoint + 1),
+            );
+        }
+
+        // The same ranges as write_window(): The first transform writes up to and including its midpoint, and the last
+        // transform writes from its midpoint
+        assert_round_trip(
+            0,
+            &start_of_file_window(window_size),
+            0..(window_midpoint + 1),
+        );
+        assert_round_trip(
+            source.len() - window_size,
+            &end_of_file_window(window_size),
+            window_midpoint..window_size,
+        );
+    }
+
+    #[test]
+    fn edge_windows_are_tapered_away_from_the_edge() {
+        let window_size = 48;
+        let window_midpoint = window_size / 2;
+
+        for window in [
+            start_of_file_window(window_size),
+            end_of_file_window(window_size),
+        ] {
+            let average = window.iter().sum::<f32>() / window_size as f32;
+            assert!((1.0 - average).abs() < 0.0001);
+        }
+
+        // Flat towards the edge of the file, so a sample there isn't divided by a nearly silent window, and the same
+        // taper as a Hann window towards the middle of the file
+        let start_window = start_of_file_window(window_size);
+        assert_eq!(start_window[0], start_window[window_midpoint]);
+        assert!(start_window[window_size - 1] < 0.01);
+
+        let end_window = end_of_file_window(window_size);
+        assert_eq!(end_window[window_size - 1], end_window[window_midpoint]);
+        assert_eq!(0.0, end_window[0]);
+    }
+
+    #[test]
+    fn optimized_window_sizes_are_even() {
+        for window_size in WINDOW_SIZES {
+            assert!(
+                check_window_size(window_size).is_ok(),
+                "{} is odd",
+                window_size
+            );
+        }
+    }
+
+    #[test]
+    fn get_ideal_window_size_is_even_and_large_enough() {
+        for min_window_size in [1, 5, 7, 2205, 2305, 73728] {
+            let window_size = get_ideal_window_size(min_window_size).unwrap();
+            assert!(window_size >= min_window_size);
+            assert!(window_size.is_multiple_of(2));
+        }
+
+        assert!(get_ideal_window_size(73729).is_err());
+    }
 }